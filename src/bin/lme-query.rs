@@ -0,0 +1,113 @@
+use std::{collections::BTreeSet, path::PathBuf};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use lmers::{
+    io::{quote_field, BasicIOMolecule},
+    results::{ArchivedStructure, PropertyFilter, ResultsDatabase, ResultsQuery},
+};
+
+#[derive(Parser)]
+/// Search the structures archived by workflows with `results` across runs,
+/// print them as CSV with their properties, and export the structure files
+struct Args {
+    /// Specify the results database file
+    database: PathBuf,
+    /// Glob pattern of the run identifiers, e.g. `1700*`
+    #[clap(long)]
+    run: Option<String>,
+    /// Glob pattern of the absolute paths of the workflow files, e.g. `*/ligands/*`
+    #[clap(long)]
+    workflow: Option<String>,
+    /// Glob pattern of the structure titles, e.g. `*_CF3*`
+    #[clap(long)]
+    title: Option<String>,
+    /// Property comparisons the structures must all meet, e.g. `energy<-1000`
+    #[clap(long = "where", short = 'w')]
+    filters: Vec<PropertyFilter>,
+    /// Export the structures to the directory as `<run>_<title>.<format>`
+    #[clap(long, short)]
+    export: Option<PathBuf>,
+    /// Format of the exported structure files
+    #[clap(long, short, default_value = "xyz")]
+    format: String,
+    /// Decimal places of the coordinates in the exported structure files
+    #[clap(long)]
+    precision: Option<usize>,
+}
+
+fn print_csv(structures: &[ArchivedStructure]) {
+    let names = structures
+        .iter()
+        .flat_map(|structure| structure.properties.keys())
+        .collect::<BTreeSet<_>>();
+    let mut header = vec!["run_id", "workflow", "created", "title"];
+    header.extend(names.iter().map(|name| name.as_str()));
+    let header = header
+        .iter()
+        .map(|column| quote_field(column, ","))
+        .collect::<Vec<_>>();
+    println!("{}", header.join(","));
+    for structure in structures {
+        let mut row = vec![
+            structure.run_id.to_string(),
+            structure.workflow.to_string(),
+            structure.created.to_string(),
+            structure.title.to_string(),
+        ];
+        row.extend(names.iter().map(|name| {
+            structure
+                .properties
+                .get(*name)
+                .map(|value| value.to_string())
+                .unwrap_or_default()
+        }));
+        let row = row
+            .iter()
+            .map(|field| quote_field(field, ","))
+            .collect::<Vec<_>>();
+        println!("{}", row.join(","));
+    }
+}
+
+fn export(
+    structures: &[ArchivedStructure],
+    directory: &PathBuf,
+    format: &str,
+    precision: Option<usize>,
+) -> Result<()> {
+    std::fs::create_dir_all(directory)
+        .with_context(|| format!("Unable to create directory {:?}", directory))?;
+    for structure in structures {
+        let title = format!("{}_{}", structure.run_id, structure.title);
+        let content = BasicIOMolecule::from((structure.molecule.clone(), title.to_string()))
+            .with_precision(precision)
+            .output(format)
+            .with_context(|| format!("Unable to convert {} to {}", title, format))?;
+        let path = directory.join(format!("{}.{}", title, format));
+        std::fs::write(&path, content)
+            .with_context(|| format!("Unable to write structure file {:?}", path))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = Args::parse();
+    let database = ResultsDatabase::open(&args.database).unwrap();
+    let query = ResultsQuery {
+        run_id: args.run,
+        workflow: args.workflow,
+        title: args.title,
+        filters: args.filters,
+    };
+    let structures = database.query(&query).unwrap();
+    print_csv(&structures);
+    if let Some(directory) = args.export {
+        export(&structures, &directory, &args.format, args.precision).unwrap();
+        eprintln!(
+            "{} structures exported to {:?}",
+            structures.len(),
+            directory
+        );
+    }
+}
@@ -2,12 +2,12 @@ use std::fs::File;
 
 use anyhow::{Context, Result, anyhow};
 use clap::Parser;
-use lmers::{layer::Layer, sparse_molecule::SparseMolecule};
+use lmers::{layer::Layer, sparse_molecule::SparseMolecule, utils::input::{from_input_list_reader, from_input_reader}};
 
 #[derive(Parser)]
 /// Merge given layers on the given base SparseMolecular
 struct Args {
-    /// Specify the layers file (one file, YAML format)
+    /// Specify the layers file (one file, YAML, JSON or TOML format decided by the extension, the list is `layers = [...]` in TOML)
     #[clap(long, short)]
     layers: String,
     /// Specify the base SparseMolecular file, ignore this to use an empty SparseMolecular 
@@ -20,10 +20,10 @@ struct Args {
 
 fn merge_layers(layers: String, base: Option<String>) -> Result<SparseMolecule> {
     let layers_file = File::open(&layers).with_context(|| format!("Failed to open layers file at {}", layers))?;
-    let layers: Vec<Layer> = serde_yaml::from_reader(layers_file).with_context(|| format!("Failed to read or parse layers file at {}", layers))?;
+    let layers: Vec<Layer> = from_input_list_reader(layers_file, layers.as_ref(), "layers")?;
     let mut base = if let Some(base_file_path) = base {
         let base_file = File::open(&base_file_path).with_context(|| format!("Failed to open base file at {}", base_file_path))?;
-        let base: SparseMolecule = serde_yaml::from_reader(base_file).with_context(|| format!("Failed to read or parse base file at {}", base_file_path))?;
+        let base: SparseMolecule = from_input_reader(base_file, base_file_path.as_ref())?;
         base
     } else {
         Default::default()
@@ -39,8 +39,8 @@ fn main() {
     let result = merge_layers(layers, base).unwrap();
     if let Some(output) = output {
         let output_file = File::create(&output).with_context(|| format!("Failed to create output file at {}", output)).unwrap();
-        serde_json::to_writer(output_file, &result).with_context(|| format!("Failed to write or serialize processed sparse molecule")).unwrap();
+        serde_json::to_writer(output_file, &result).with_context(|| "Failed to write or serialize processed sparse molecule").unwrap();
     } else {
-        serde_json::to_writer(std::io::stdout(), &result).with_context(|| format!("Failed to write or serialize processed sparse molecule")).unwrap();
+        serde_json::to_writer(std::io::stdout(), &result).with_context(|| "Failed to write or serialize processed sparse molecule").unwrap();
     }
 }
\ No newline at end of file
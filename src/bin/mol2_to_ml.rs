@@ -46,6 +46,7 @@ fn main() {
                 bonds,
                 ids: None,
                 groups: None,
+                ..Default::default()
             }
         };
 
@@ -1,7 +1,7 @@
 use std::{fs::File, io::{Cursor, Read, Write}};
 
 use clap::Parser;
-use lmers::{external::obabel::obabel, io::BasicIOMolecule, layer::{Layer, SelectOne}, sparse_molecule::SparseMolecule, utils::sterimol::{self, auto_connect_bonds, get_molecular_graph, RadiisTable}};
+use lmers::{chemistry::Atom3D, external::obabel::obabel, io::{BasicIOMolecule, NamespaceMapping}, layer::{Layer, SelectOne}, sparse_molecule::SparseMolecule, utils::{sterimol::{self, auto_connect_bonds, buried_volume, get_molecular_graph, RadiisTable}, symmetry::point_group}};
 use nalgebra::Vector3;
 use rayon::prelude::*;
 use glob::glob;
@@ -24,10 +24,18 @@ enum Operation {
         #[clap(short='s')]
         as_substituent: bool,
         /// Specify the pathway of radius table for generate sterimol descriptors
+        /// (L, B1, B5, Tolman cone angle and percent buried volume around the first atom)
         #[clap(short='S')]
-        sterimol: Option<String>
+        sterimol: Option<String>,
+        /// Sphere radius for the percent buried volume
+        #[clap(long, default_value_t = 3.5)]
+        sphere_radius: f64,
+        /// Mesh spacing for the percent buried volume
+        #[clap(long, default_value_t = 0.1)]
+        mesh: f64,
     },
-    /// Export LME files to common formats
+    /// Export LME files to common formats, the formula and molecular weight of each
+    /// exported structure are printed
     Export {
         /// Input LME files
         #[clap(short)]
@@ -35,13 +43,26 @@ enum Operation {
         /// Output file format
         #[clap(short)]
         output_format: String,
+        /// Write the namespace mapping (LME index to index in the output file, ids and groups)
+        /// next to each output file with `map.json` extension
+        #[clap(short = 'm')]
+        export_map: bool,
+    },
+    /// Print the point group of LME files, with atoms matched within the tolerance
+    Symmetry {
+        /// Input LME files
+        #[clap(short)]
+        input_filepath: String,
+        /// Distance tolerance in Angstrom to match the atoms moved by symmetry operations
+        #[clap(short, long, default_value_t = 0.1)]
+        tolerance: f64,
     }
 }
 
 impl Operation {
     fn operate(self) -> Result<()> {
         match self {
-            Self::Import { input_filepath, input_format, gen3d, as_substituent, sterimol } => {
+            Self::Import { input_filepath, input_format, gen3d, as_substituent, sterimol, sphere_radius, mesh } => {
                 let matched_paths = glob(&input_filepath).with_context(|| format!("Invalid file match pattern: {}", input_filepath))?;
                 let set_center_layer = Layer::SetCenter {
                     select: SelectOne::Index(0),
@@ -60,7 +81,7 @@ impl Operation {
                 };
                 let _ = matched_paths.par_bridge()
                     .map(|entry| {
-                        let mut input = entry.with_context(|| format!("Unable to read path matched"))?;
+                        let mut input = entry.with_context(|| "Unable to read path matched")?;
                         let mut input_content = String::new();
                         File::open(&input).with_context(|| format!("Failed to open matched file {:?}", input))?
                             .read_to_string(&mut input_content)
@@ -74,8 +95,8 @@ impl Operation {
                         serde_json::to_writer(File::create(&input).with_context(|| format!("Unable to create output file at {:?}", input))?, &molecule)?;
                         if let Some(radiis_table) = &radiis_table {
                             let bonds = molecule.bonds.to_continuous_list(&molecule.atoms);
-                            let atoms = molecule.atoms.into();
-                            let bonds = if bonds.len() == 0 {
+                            let atoms: Vec<Atom3D> = molecule.atoms.into();
+                            let bonds = if bonds.is_empty() {
                                 auto_connect_bonds(&atoms, radiis_table)?
                             } else {
                                 bonds
@@ -83,9 +104,10 @@ impl Operation {
                             let molecular_graph = get_molecular_graph(&atoms, &bonds);
                             let (l, b1, b5) = sterimol::sterimol(&molecular_graph, radiis_table)?;
                             let tca = sterimol::tolman_cone_angle(&molecular_graph)?;
+                            let vbur = buried_volume(&atoms[1..], &atoms[0].position, radiis_table, sphere_radius, mesh)?;
                             input.set_extension("sterimol");
                             File::create(&input).with_context(|| format!("Unable to create sterimol file at {:?}", input))?
-                                .write_all(format!("{l},{b1},{b5},{tca}").as_bytes())
+                                .write_all(format!("{l},{b1},{b5},{tca},{vbur}").as_bytes())
                                 .with_context(|| format!("Unable to write sterimol file at {:?}", input))?;
                         }
                         Ok(())
@@ -93,23 +115,37 @@ impl Operation {
                     .collect::<Result<Vec<()>>>()?;
                 Ok(())
             },
-            Self::Export { input_filepath, output_format } => {
+            Self::Export { input_filepath, output_format, export_map } => {
                 let matched_paths = glob(&input_filepath).with_context(|| format!("Invalid file match pattern: {}", input_filepath))?;
                 let _ = matched_paths.par_bridge()
                     .map(|entry| {
-                        let mut input = entry.with_context(|| format!("Unable to read path matched"))?;
+                        let mut input = entry.with_context(|| "Unable to read path matched")?;
                         let structure: SparseMolecule = serde_yaml::from_reader(File::open(&input).with_context(|| format!("Failed to open matched file {:?}", input))?)?;
+                        if export_map {
+                            NamespaceMapping::from(structure.clone()).write_to(&NamespaceMapping::sidecar_path(&input))?;
+                        }
+                        let (formula, weight) = (structure.formula(), structure.molecular_weight());
                         let mol2 = BasicIOMolecule::from((structure, input.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default())).output("mol2").with_context(|| format!("Failed to convert to intermediate format {:?}", input))?;
                         let output = obabel(&mol2, "mol2", &output_format, true, false)?;
                         input.set_extension(output_format.clone());
                         File::create(&input).with_context(|| format!("Failed to create output file {:?}", input))?
                             .write_all(output.as_bytes())
                             .with_context(|| format!("Failed to write to output file {:?}", input))?;
+                        println!("{:?}: {}, {:.3} g/mol", input, formula, weight);
                         Ok(())
                     })
                     .collect::<Result<Vec<()>>>()?;
                 Ok(())
             }
+            Self::Symmetry { input_filepath, tolerance } => {
+                let matched_paths = glob(&input_filepath).with_context(|| format!("Invalid file match pattern: {}", input_filepath))?;
+                for entry in matched_paths {
+                    let input = entry.with_context(|| "Unable to read path matched")?;
+                    let structure: SparseMolecule = serde_yaml::from_reader(File::open(&input).with_context(|| format!("Failed to open matched file {:?}", input))?)?;
+                    println!("{:?}: {}", input, point_group(&structure, tolerance));
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -44,6 +44,7 @@ fn main() {
                 bonds,
                 ids: None,
                 groups: None,
+                ..Default::default()
             }
         };
 
@@ -1,8 +1,12 @@
 use bincode::{Decode, Encode};
 use lazy_static::lazy_static;
 use nalgebra::Point3;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{borrow::Borrow, collections::BTreeSet};
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, BTreeSet},
+};
 
 lazy_static! {
     static ref ELEMENT_SET: BTreeSet<(usize, &'static str)> = BTreeSet::from([
@@ -154,11 +158,210 @@ pub fn element_symbol_to_num(input: &str) -> Option<usize> {
     })
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize, Encode, Decode)]
+/// Normal valences of main group elements in ascending order, empty for the
+/// other elements. Only valence 1 is given for the halogens, as their higher
+/// valences (e.g. chlorine dioxide) are never completed by hydrogens.
+pub fn default_valences(element: usize) -> &'static [usize] {
+    match element {
+        1 | 3 | 11 | 19 => &[1],
+        4 | 12 => &[2],
+        5 | 13 => &[3],
+        6 | 14 => &[4],
+        7 => &[3, 5],
+        8 => &[2],
+        15 | 33 => &[3, 5],
+        16 | 34 => &[2, 4, 6],
+        9 => &[1],
+        17 | 35 | 53 => &[1],
+        _ => &[],
+    }
+}
+
+/// Covalent radii (Angstrom) of H to Cm by Cordero et al. (2008), the low spin
+/// ones for Mn, Fe and Co.
+#[rustfmt::skip]
+const COVALENT_RADII: [f64; 96] = [
+    0.31, 0.28, 1.28, 0.96, 0.84, 0.76, 0.71, 0.66, 0.57, 0.58,
+    1.66, 1.41, 1.21, 1.11, 1.07, 1.05, 1.02, 1.06, 2.03, 1.76,
+    1.70, 1.60, 1.53, 1.39, 1.39, 1.32, 1.26, 1.24, 1.32, 1.22,
+    1.22, 1.20, 1.19, 1.20, 1.20, 1.16, 2.20, 1.95, 1.90, 1.75,
+    1.64, 1.54, 1.47, 1.46, 1.42, 1.39, 1.45, 1.44, 1.42, 1.39,
+    1.39, 1.38, 1.39, 1.40, 2.44, 2.15, 2.07, 2.04, 2.03, 2.01,
+    1.99, 1.98, 1.98, 1.96, 1.94, 1.92, 1.92, 1.89, 1.90, 1.87,
+    1.87, 1.75, 1.70, 1.62, 1.51, 1.44, 1.41, 1.36, 1.36, 1.32,
+    1.45, 1.46, 1.48, 1.40, 1.50, 1.50, 2.60, 2.21, 2.15, 2.06,
+    2.00, 1.96, 1.90, 1.87, 1.80, 1.69,
+];
+
+/// Covalent radius (Angstrom) of the element, not available after Cm.
+pub fn covalent_radius(element: usize) -> Option<f64> {
+    COVALENT_RADII.get(element.checked_sub(1)?).copied()
+}
+
+/// Standard atomic weights of the elements, the mass numbers of the most stable
+/// isotopes for the elements without a standard atomic weight.
+#[rustfmt::skip]
+const ATOMIC_MASSES: [f64; 118] = [
+    1.008, 4.0026, 6.94, 9.0122, 10.81, 12.011, 14.007, 15.999, 18.998, 20.180,
+    22.990, 24.305, 26.982, 28.085, 30.974, 32.06, 35.45, 39.948, 39.098, 40.078,
+    44.956, 47.867, 50.942, 51.996, 54.938, 55.845, 58.933, 58.693, 63.546, 65.38,
+    69.723, 72.630, 74.922, 78.971, 79.904, 83.798, 85.468, 87.62, 88.906, 91.224,
+    92.906, 95.95, 98., 101.07, 102.91, 106.42, 107.87, 112.41, 114.82, 118.71,
+    121.76, 127.60, 126.90, 131.29, 132.91, 137.33, 138.91, 140.12, 140.91, 144.24,
+    145., 150.36, 151.96, 157.25, 158.93, 162.50, 164.93, 167.26, 168.93, 173.05,
+    174.97, 178.49, 180.95, 183.84, 186.21, 190.23, 192.22, 195.08, 196.97, 200.59,
+    204.38, 207.2, 208.98, 209., 210., 222., 223., 226., 227., 232.04,
+    231.04, 238.03, 237., 244., 243., 247., 247., 251., 252., 257.,
+    258., 259., 266., 267., 268., 269., 270., 269., 278., 281.,
+    282., 285., 286., 289., 290., 293., 294., 294.,
+];
+
+/// Atomic mass (Dalton) of the element.
+pub fn atomic_mass(element: usize) -> Option<f64> {
+    ATOMIC_MASSES.get(element.checked_sub(1)?).copied()
+}
+
+/// Mass (Dalton) of the isotope of the element by its mass number, the mass
+/// number itself for the isotopes not in the table of the common ones.
+pub fn isotope_mass(element: usize, mass_number: u32) -> f64 {
+    match (element, mass_number) {
+        (1, 1) => 1.007825,
+        (1, 2) => 2.014102,
+        (1, 3) => 3.016049,
+        (3, 6) => 6.015123,
+        (3, 7) => 7.016003,
+        (5, 10) => 10.012937,
+        (5, 11) => 11.009305,
+        (6, 12) => 12.,
+        (6, 13) => 13.003355,
+        (6, 14) => 14.003242,
+        (7, 14) => 14.003074,
+        (7, 15) => 15.000109,
+        (8, 16) => 15.994915,
+        (8, 17) => 16.999132,
+        (8, 18) => 17.999160,
+        (9, 19) => 18.998403,
+        (14, 28) => 27.976927,
+        (14, 29) => 28.976495,
+        (14, 30) => 29.973770,
+        (15, 31) => 30.973762,
+        (16, 32) => 31.972071,
+        (16, 33) => 32.971459,
+        (16, 34) => 33.967867,
+        (17, 35) => 34.968853,
+        (17, 37) => 36.965903,
+        (35, 79) => 78.918338,
+        (35, 81) => 80.916291,
+        (53, 127) => 126.904473,
+        (_, mass_number) => mass_number as f64,
+    }
+}
+
+/// Van der Waals radius (Angstrom) of the element by Bondi (1964), with the
+/// main group elements completed by Mantina et al. (2009). Not available for
+/// most transition metals and lanthanides.
+pub fn vdw_radius(element: usize) -> Option<f64> {
+    Some(match element {
+        1 => 1.20,
+        2 => 1.40,
+        3 => 1.81,
+        4 => 1.53,
+        5 => 1.92,
+        6 => 1.70,
+        7 => 1.55,
+        8 => 1.52,
+        9 => 1.47,
+        10 => 1.54,
+        11 => 2.27,
+        12 => 1.73,
+        13 => 1.84,
+        14 => 2.10,
+        15 => 1.80,
+        16 => 1.80,
+        17 => 1.75,
+        18 => 1.88,
+        19 => 2.75,
+        20 => 2.31,
+        28 => 1.63,
+        29 => 1.40,
+        30 => 1.39,
+        31 => 1.87,
+        32 => 2.11,
+        33 => 1.85,
+        34 => 1.90,
+        35 => 1.85,
+        36 => 2.02,
+        37 => 3.03,
+        38 => 2.49,
+        46 => 1.63,
+        47 => 1.72,
+        48 => 1.58,
+        49 => 1.93,
+        50 => 2.17,
+        51 => 2.06,
+        52 => 2.06,
+        53 => 1.98,
+        54 => 2.16,
+        55 => 3.43,
+        56 => 2.68,
+        78 => 1.72,
+        79 => 1.66,
+        80 => 1.55,
+        81 => 1.96,
+        82 => 2.02,
+        83 => 2.07,
+        84 => 1.97,
+        85 => 2.02,
+        86 => 2.20,
+        87 => 3.48,
+        88 => 2.83,
+        92 => 1.86,
+        _ => None?,
+    })
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize, Encode, Decode, JsonSchema,
+)]
 pub struct Atom3D {
     pub element: usize,
     #[bincode(with_serde)]
+    #[schemars(with = "[f64; 3]")]
     pub position: Point3<f64>,
     #[serde(default)]
     pub formal_charge: f64
 }
+
+/// Optional data of an atom besides the element and position, stored sparsely
+/// in SparseAtomList. When a layer is applied, each field set in the upper
+/// metadata overrides the lower one, and the tags are merged.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize, Encode, Decode, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AtomMetadata {
+    /// Partial charge, e.g. ESP charges from the calculation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partial_charge: Option<f64>,
+    /// Mass number, the most abundant isotope if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub isotope: Option<u32>,
+    /// Atom name, e.g. the atom name column of mol2 and PDB files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Annotations of the atom, e.g. colors and notes, see the Annotate layer
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl AtomMetadata {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Put the fields set in `upper` over self.
+    pub fn overlay(&mut self, upper: Self) {
+        self.partial_charge = upper.partial_charge.or(self.partial_charge);
+        self.isotope = upper.isotope.or(self.isotope);
+        self.label = upper.label.or(self.label.take());
+        self.tags.extend(upper.tags);
+    }
+}
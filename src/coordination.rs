@@ -0,0 +1,288 @@
+use bincode::{Decode, Encode};
+use nalgebra::{Isometry3, Point3, Rotation3, Translation3, Unit, UnitQuaternion, Vector3};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chemistry::validated_element_num,
+    layer::{SelectMany, SelectOne},
+    sparse_molecule::SparseMolecule,
+    utils::geometric::kabsch,
+};
+
+/// Ideal coordination geometries around a metal center.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Encode, Decode, JsonSchema)]
+pub enum CoordinationGeometry {
+    /// +x, -x
+    Linear,
+    /// +x, then every 120 degrees in the xy plane
+    TrigonalPlanar,
+    /// (1, 1, 1), (1, -1, -1), (-1, 1, -1), (-1, -1, 1)
+    Tetrahedral,
+    /// +x, +y, -x, -y
+    SquarePlanar,
+    /// +z, -z (axial), then +x and every 120 degrees in the xy plane
+    TrigonalBipyramidal,
+    /// +z (apical), then +x, +y, -x, -y
+    SquarePyramidal,
+    /// +x, -x, +y, -y, +z, -z
+    Octahedral,
+}
+
+impl CoordinationGeometry {
+    /// Unit vectors from the metal to each coordination site, in the order
+    /// documented on the variants.
+    pub fn sites(&self) -> Vec<Vector3<f64>> {
+        let (c, s) = (-0.5, 3f64.sqrt() / 2.);
+        let (x, y, z) = (Vector3::x(), Vector3::y(), Vector3::z());
+        match self {
+            Self::Linear => vec![x, -x],
+            Self::TrigonalPlanar => vec![x, Vector3::new(c, s, 0.), Vector3::new(c, -s, 0.)],
+            Self::Tetrahedral => [
+                Vector3::new(1., 1., 1.),
+                Vector3::new(1., -1., -1.),
+                Vector3::new(-1., 1., -1.),
+                Vector3::new(-1., -1., 1.),
+            ]
+            .into_iter()
+            .map(|site| site.normalize())
+            .collect(),
+            Self::SquarePlanar => vec![x, y, -x, -y],
+            Self::TrigonalBipyramidal => {
+                vec![z, -z, x, Vector3::new(c, s, 0.), Vector3::new(c, -s, 0.)]
+            }
+            Self::SquarePyramidal => vec![z, x, y, -x, -y],
+            Self::Octahedral => vec![x, -x, y, -y, z, -z],
+        }
+    }
+}
+
+/// A ligand fragment bound to a coordination site through its donor atom.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Ligand {
+    /// Group name of the ligand atoms, ids and groups of the fragment are
+    /// prefixed with it like the Append layer
+    pub name: String,
+    /// Index of the site in the template
+    pub site: usize,
+    pub donor: SelectOne,
+    /// Metal-donor distance in angstrom
+    #[serde(default = "Ligand::default_distance")]
+    pub distance: f64,
+    /// The second donor of a bidentate ligand and its site, the ligand is
+    /// oriented to fit both sites at once, see `place_chelate`
+    #[serde(default)]
+    pub chelate: Option<Chelate>,
+    pub data: SparseMolecule,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Chelate {
+    pub site: usize,
+    pub donor: SelectOne,
+}
+
+impl Ligand {
+    fn default_distance() -> f64 {
+        2.0
+    }
+}
+
+/// Move the ligand so the donor atom lies at `distance` from `center` along
+/// `direction`, with the other atoms pointing away from the center.
+///
+/// The ligand is rotated so the vector from the donor to the centroid of its
+/// neighbors is aligned to `direction`, a monatomic ligand is only translated.
+pub fn place_ligand(
+    ligand: &SparseMolecule,
+    donor: usize,
+    center: Point3<f64>,
+    direction: Vector3<f64>,
+    distance: f64,
+) -> Option<SparseMolecule> {
+    let direction = direction.normalize();
+    let donor_position = ligand.atoms.read_atom(donor)?.position;
+    let neighbors = ligand
+        .neighbors(donor)
+        .into_iter()
+        .filter_map(|neighbor| ligand.atoms.read_atom(neighbor))
+        .map(|atom| atom.position.coords)
+        .collect::<Vec<_>>();
+    let rotation = if neighbors.is_empty() {
+        Rotation3::identity()
+    } else {
+        let centroid = neighbors.iter().sum::<Vector3<f64>>() / neighbors.len() as f64;
+        let outward = centroid - donor_position.coords;
+        if outward.norm() < 1e-8 {
+            Rotation3::identity()
+        } else {
+            Rotation3::rotation_between(&outward, &direction).unwrap_or_else(|| {
+                let axis = outward.cross(&Vector3::x());
+                let axis = if axis.norm() < 1e-8 {
+                    outward.cross(&Vector3::y())
+                } else {
+                    axis
+                };
+                Rotation3::from_axis_angle(&Unit::new_normalize(axis), std::f64::consts::PI)
+            })
+        }
+    };
+    let target = center + direction * distance;
+    let translation = target - rotation * donor_position;
+    let isometry = Isometry3::from_parts(
+        Translation3::from(translation),
+        UnitQuaternion::from_rotation_matrix(&rotation),
+    );
+    let mut placed = ligand.clone();
+    placed
+        .atoms
+        .isometry(isometry, &SelectMany::All.to_indexes(ligand));
+    Some(placed)
+}
+
+/// Move a bidentate ligand so its two donors best match the two sites at
+/// `distance` from `center`.
+///
+/// The donors and the centroid of the ligand are fitted by least squares to
+/// the site positions and a point beyond their midpoint, so the ligand backbone
+/// points away from the center. The donors match the sites exactly only if
+/// the bite angle of the ligand matches the template.
+pub fn place_chelate(
+    ligand: &SparseMolecule,
+    donors: [usize; 2],
+    center: Point3<f64>,
+    directions: [Vector3<f64>; 2],
+    distance: f64,
+) -> Option<SparseMolecule> {
+    let [first, second] =
+        donors.map(|donor| ligand.atoms.read_atom(donor).map(|atom| atom.position));
+    let (first, second) = (first?, second?);
+    let positions = ligand
+        .atoms
+        .data()
+        .iter()
+        .flatten()
+        .filter(|atom| validated_element_num(atom.element))
+        .map(|atom| atom.position.coords)
+        .collect::<Vec<_>>();
+    let centroid = Point3::from(positions.iter().sum::<Vector3<f64>>() / positions.len() as f64);
+    let donor_middle = Point3::from((first.coords + second.coords) / 2.);
+    let targets = directions.map(|direction| center + direction.normalize() * distance);
+    let target_middle = Point3::from((targets[0].coords + targets[1].coords) / 2.);
+    let outward = target_middle - center;
+    let outward = if outward.norm() < 1e-8 {
+        // trans sites, any direction perpendicular to the sites works
+        let axis = directions[0].cross(&Vector3::x());
+        if axis.norm() < 1e-8 {
+            directions[0].cross(&Vector3::y())
+        } else {
+            axis
+        }
+    } else {
+        outward
+    };
+    let centroid_target = target_middle + outward.normalize() * (centroid - donor_middle).norm();
+    let isometry = kabsch(
+        &[first, second, centroid],
+        &[targets[0], targets[1], centroid_target],
+    );
+    let mut placed = ligand.clone();
+    placed
+        .atoms
+        .isometry(isometry, &SelectMany::All.to_indexes(ligand));
+    Some(placed)
+}
+
+#[test]
+fn build_octahedral_complex() {
+    use crate::{chemistry::Atom3D, layer::Layer, sparse_molecule::SparseAtomList};
+    let atom = |element, x| Atom3D {
+        element,
+        position: Point3::new(x, 0., 0.),
+        formal_charge: 0.,
+    };
+    let metal = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(27, 0.)]),
+        ..Default::default()
+    };
+    // ammonia-like ligand, the donor is the second atom
+    let mut ammine = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(1, -1.), atom(7, 0.)]),
+        ..Default::default()
+    };
+    ammine.bonds.set_bond(0, 1, Some(1.));
+    let layer = Layer::Coordinate {
+        metal: SelectOne::Index(0),
+        geometry: CoordinationGeometry::Octahedral,
+        ligands: (0..6)
+            .map(|site| Ligand {
+                name: format!("NH{}", site),
+                site,
+                donor: SelectOne::Index(1),
+                distance: 2.0,
+                chelate: None,
+                data: ammine.clone(),
+            })
+            .collect(),
+    };
+    let complex = layer.filter(metal).unwrap();
+    assert_eq!(complex.len(), 13);
+    assert_eq!(complex.neighbors(0).len(), 6);
+    let position = |index| complex.atoms.read_atom(index).unwrap().position;
+    for (site, direction) in CoordinationGeometry::Octahedral
+        .sites()
+        .into_iter()
+        .enumerate()
+    {
+        let donor = 2 + site * 2;
+        assert!((position(donor) - Point3::from(direction * 2.)).norm() < 1e-8);
+        assert!((position(donor - 1) - Point3::from(direction * 3.)).norm() < 1e-8);
+    }
+    assert!(Layer::Coordinate {
+        metal: SelectOne::Index(0),
+        geometry: CoordinationGeometry::SquarePlanar,
+        ligands: vec![Ligand {
+            name: "X".to_string(),
+            site: 4,
+            donor: SelectOne::Index(1),
+            distance: 2.0,
+            chelate: None,
+            data: ammine,
+        }],
+    }
+    .filter(complex)
+    .is_err());
+}
+
+#[test]
+fn place_bidentate_ligand() {
+    use crate::{chemistry::Atom3D, sparse_molecule::SparseAtomList};
+    let atom = |element, x, y| Atom3D {
+        element,
+        position: Point3::new(x, y, 0.),
+        formal_charge: 0.,
+    };
+    // ethylenediamine-like backbone N-C-C-N with the donors 2.8 angstrom apart
+    let ligand = SparseMolecule {
+        atoms: SparseAtomList::from(vec![
+            atom(7, 10., 0.),
+            atom(6, 10.6, 1.2),
+            atom(6, 12.2, 1.2),
+            atom(7, 12.8, 0.),
+        ]),
+        ..Default::default()
+    };
+    let sites = CoordinationGeometry::Octahedral.sites();
+    let placed =
+        place_chelate(&ligand, [0, 3], Point3::origin(), [sites[0], sites[2]], 2.).unwrap();
+    let position = |index| placed.atoms.read_atom(index).unwrap().position;
+    let bite = (position(3) - position(0)).norm();
+    assert!((bite - 2.8).abs() < 1e-8);
+    // the donors are symmetric to the sites, and the backbone points outward
+    let site_middle = Point3::from(sites[0] + sites[2]);
+    assert!((position(0).coords.norm() - position(3).coords.norm()).abs() < 1e-6);
+    assert!(((position(0) - site_middle).norm() - (position(3) - site_middle).norm()).abs() < 1e-6);
+    assert!(position(1).coords.norm() > position(0).coords.norm());
+}
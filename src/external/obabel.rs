@@ -1,9 +1,8 @@
-use std::{
-    io::Write,
-    process::{Command, Stdio},
-};
+use std::{io::Write, process::Stdio};
 
-use anyhow::{anyhow, Context, Ok, Result};
+use anyhow::{Context, Ok, Result};
+
+use crate::utils::process::{new_command, ProgramFailure};
 
 pub fn obabel(
     input: &str,
@@ -24,7 +23,7 @@ pub fn obabel(
             format!("-o{}", output_format),
         ]
     };
-    let mut command = Command::new("obabel")
+    let mut command = new_command("obabel")
         .args(args)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
@@ -34,12 +33,15 @@ pub fn obabel(
             Stdio::null()
         })
         .spawn()
-        .with_context(|| "Failed to start openbabel")?;
+        .with_context(|| ProgramFailure("Failed to start openbabel".to_string()))?;
     command.stdin.take().unwrap().write_all(input.as_bytes())?;
     let output = command.wait_with_output()?;
     if output.status.success() {
         Ok(String::from_utf8(output.stdout)?)
     } else {
-        Err(anyhow!("Failed to convert with openbabel, {:?}", output.status.code()))
+        Err(ProgramFailure(format!(
+            "Failed to convert with openbabel, {:?}",
+            output.status.code()
+        )))?
     }
 }
@@ -1,12 +1,11 @@
-use std::{
-    io::Write,
-    process::{Command, Stdio},
-};
+use std::{io::Write, process::Stdio};
 
 use anyhow::{Ok, Result};
 
+use crate::utils::process::new_command;
+
 pub fn regex_sed(input: &str, regex: &str) -> Result<String> {
-    let mut command = Command::new("sed")
+    let mut command = new_command("sed")
         .args(["-e", regex])
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
@@ -1,4 +1,5 @@
 use bincode::{Decode, Encode};
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
 use serde::{Deserialize, Serialize};
 use std::collections::btree_set::IntoIter;
 use std::collections::{BTreeMap, BTreeSet};
@@ -10,6 +11,16 @@ type GroupStorage = BTreeSet<(String, usize)>;
 #[serde(from = "FriendlyGroupName")]
 pub struct GroupName(GroupStorage);
 
+impl JsonSchema for GroupName {
+    fn schema_name() -> String {
+        "GroupName".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        FriendlyGroupName::json_schema(gen)
+    }
+}
+
 impl GroupName {
     pub fn new() -> Self {
         Self(BTreeSet::new())
@@ -59,12 +70,12 @@ impl GroupName {
     where
         T: Iterator<Item = String>,
     {
-        let lefts = lefts.into_iter().map(|left| (left, right.clone()));
+        let lefts = lefts.into_iter().map(|left| (left, right));
         self.data_mut().extend(lefts);
     }
 
-    pub fn remove(&mut self, left: &String, right: &usize) -> bool {
-        self.data_mut().remove(&(left.clone(), right.clone()))
+    pub fn remove(&mut self, left: &str, right: &usize) -> bool {
+        self.data_mut().remove(&(left.to_string(), *right))
     }
 
     pub fn remove_left(&mut self, left: &String) {
@@ -95,9 +106,9 @@ impl<T: Iterator<Item = (String, usize)>> From<T> for GroupName {
     }
 }
 
-impl Into<GroupStorage> for GroupName {
-    fn into(self) -> GroupStorage {
-        self.0
+impl From<GroupName> for GroupStorage {
+    fn from(value: GroupName) -> Self {
+        value.0
     }
 }
 
@@ -115,7 +126,7 @@ impl FromIterator<(String, usize)> for GroupName {
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, JsonSchema)]
 #[serde(untagged)]
 pub enum IndexCollect {
     Collect(BTreeSet<usize>),
@@ -148,7 +159,7 @@ impl IndexCollect {
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, JsonSchema)]
 #[serde(untagged)]
 pub enum FriendlyGroupName {
     UnFriendly(BTreeSet<(String, usize)>),
@@ -161,8 +172,7 @@ impl From<FriendlyGroupName> for GroupName {
             FriendlyGroupName::Friendly(value) => Self::from_iter(
                 value
                     .into_iter()
-                    .map(|(k, v)| v.collect().into_iter().map(move |v| ((&k).to_string(), v)))
-                    .flatten(),
+                    .flat_map(|(k, v)| v.collect().into_iter().map(move |v| (k.to_string(), v))),
             ),
             FriendlyGroupName::UnFriendly(value) => Self(value),
         })
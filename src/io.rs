@@ -1,17 +1,43 @@
 use std::{
     collections::{BTreeMap, BTreeSet},
+    fs::File,
     io::Read,
+    path::{Path, PathBuf},
 };
 
 use crate::{
-    chemistry::{element_num_to_symbol, element_symbol_to_num, Atom3D},
+    chemistry::{element_num_to_symbol, element_symbol_to_num, Atom3D, AtomMetadata},
+    oniom::{link_atoms, OniomLevel},
     sparse_molecule::{SparseAtomList, SparseBondMatrix, SparseMolecule},
 };
 use anyhow::{anyhow, Context, Error, Result};
 use nalgebra::Point3;
 use rayon::prelude::*;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+/// Sections of a complete Gaussian input file around the molecule specification.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct GaussianOptions {
+    /// Link 0 commands without the leading `%`, e.g. `nprocshared=16`
+    #[serde(default)]
+    pub link0: Vec<String>,
+    /// Route section, e.g. `#p opt freq b3lyp/def2svp`
+    pub route: String,
+    /// Title section, the title of the molecule is used if not set
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub charge: Option<i32>,
+    #[serde(default)]
+    pub multiplicity: Option<u32>,
+    /// Input sections after the molecule specification (e.g. ModRedundant
+    /// lines, custom basis sets), each is followed by a blank line
+    #[serde(default)]
+    pub tail: Vec<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NamespaceMapping {
     pub len: usize,
@@ -63,16 +89,109 @@ impl From<SparseMolecule> for NamespaceMapping {
     }
 }
 
+impl NamespaceMapping {
+    /// Default path of the mapping sidecar of a structure file, the extension is
+    /// replaced by `map.json`, e.g. `a.xyz` to `a.map.json`.
+    pub fn sidecar_path(path: &Path) -> PathBuf {
+        let mut sidecar_path = path.to_path_buf();
+        sidecar_path.set_extension("map.json");
+        sidecar_path
+    }
+
+    pub fn write_to(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("Unable to create map file at {:?}", path))?;
+        serde_json::to_writer(file, self).with_context(|| {
+            format!(
+                "Unable to serialize map file at {:?}, content: {:#?}",
+                path, self
+            )
+        })?;
+        Ok(())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BasicIOMolecule {
     pub atoms: Vec<Atom3D>,
     pub bonds: Vec<(usize, usize, f64)>,
     pub title: String,
+    /// Data fields of the molecule, e.g. the data items of SDF
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, String>,
+    /// Metadata of each atom, empty if no atom has metadata
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub metadata: Vec<AtomMetadata>,
+    /// Decimal places of the coordinates written, see `with_precision`
+    #[serde(skip)]
+    precision: Option<usize>,
+}
+
+/// The value rounded to the decimal places (15 at most) without negative zero,
+/// so that it is written in the same way by different runs and platforms.
+pub fn canonical_float(value: f64, precision: usize) -> f64 {
+    let scale = 10_f64.powi(precision.min(15) as i32);
+    // too large to have decimal places
+    let value = if (value * scale).is_finite() {
+        (value * scale).round() / scale
+    } else {
+        value
+    };
+    if value == 0. {
+        0.
+    } else {
+        value
+    }
+}
+
+/// The numbers in the property rounded to the decimal places (see
+/// `canonical_float`) and written in the shortest form that reads back the same
+/// value, in scientific notation if the magnitude is 1e16 or larger or below
+/// 1e-5, e.g. `-0.000 1.50 1e300` to `0 1.5 1e300`. Other words and the spaces
+/// and lines between them are kept.
+pub fn canonical_property(value: &str, precision: usize) -> String {
+    value
+        .split('\n')
+        .map(|line| {
+            line.split(' ')
+                .map(|word| match word.parse::<f64>() {
+                    Ok(number) if number.is_finite() => {
+                        let number = canonical_float(number, precision);
+                        if number != 0. && !(1e-5..1e16).contains(&number.abs()) {
+                            format!("{:e}", number)
+                        } else {
+                            number.to_string()
+                        }
+                    }
+                    _ => word.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Properties with the numbers in canonical form, see `canonical_property`.
+pub fn canonical_properties(
+    properties: &BTreeMap<String, String>,
+    precision: usize,
+) -> BTreeMap<String, String> {
+    properties
+        .iter()
+        .map(|(name, value)| (name.to_string(), canonical_property(value, precision)))
+        .collect()
 }
 
 impl From<BasicIOMolecule> for SparseMolecule {
     fn from(value: BasicIOMolecule) -> Self {
-        let atoms = SparseAtomList::from(value.atoms);
+        let metadata = value.atom_metadata().unwrap_or(value.metadata);
+        let mut atoms = SparseAtomList::from(value.atoms);
+        for (index, metadata) in metadata.into_iter().enumerate() {
+            if !metadata.is_empty() {
+                atoms.set_metadata(index, metadata);
+            }
+        }
         let mut bonds = SparseBondMatrix::new(atoms.len());
         for (a, b, bond) in value.bonds {
             bonds.set_bond(a, b, Some(bond));
@@ -82,6 +201,7 @@ impl From<BasicIOMolecule> for SparseMolecule {
             bonds,
             ids: None,
             groups: None,
+            ..Default::default()
         }
     }
 }
@@ -89,10 +209,27 @@ impl From<BasicIOMolecule> for SparseMolecule {
 impl From<(SparseMolecule, String)> for BasicIOMolecule {
     fn from((molecule, title): (SparseMolecule, String)) -> Self {
         let bonds = molecule.bonds.to_continuous_list(&molecule.atoms);
+        let metadata = if molecule.atoms.metadata().is_empty() {
+            vec![]
+        } else {
+            BTreeMap::<usize, usize>::from(molecule.atoms.clone())
+                .into_keys()
+                .map(|index| {
+                    molecule
+                        .atoms
+                        .read_metadata(index)
+                        .cloned()
+                        .unwrap_or_default()
+                })
+                .collect()
+        };
         Self {
             atoms: molecule.atoms.into(),
             bonds,
             title,
+            properties: BTreeMap::new(),
+            metadata,
+            precision: None,
         }
     }
 }
@@ -103,13 +240,60 @@ impl BasicIOMolecule {
             title,
             atoms,
             bonds,
+            properties: BTreeMap::new(),
+            metadata: vec![],
+            precision: None,
+        }
+    }
+
+    /// Round the coordinates to the decimal places (see `canonical_float`),
+    /// and write them with exactly the decimal places in the text formats,
+    /// except SDF and PDB which have fixed columns of 4 and 3 places. Numbers
+    /// in the properties are rounded too, see `canonical_property`. Diffs of
+    /// the files written by different runs are then only the real changes.
+    pub fn with_precision(mut self, precision: Option<usize>) -> Self {
+        if let Some(precision) = precision {
+            for atom in self.atoms.iter_mut() {
+                atom.position = atom.position.map(|value| canonical_float(value, precision));
+            }
+            self.properties = canonical_properties(&self.properties, precision);
+        }
+        self.precision = precision;
+        self
+    }
+
+    /// A coordinate formatted with the precision if set.
+    fn coordinate(&self, value: f64) -> String {
+        match self.precision {
+            Some(precision) => format!("{:.*}", precision.min(15), value),
+            None => value.to_string(),
         }
     }
 
     pub fn output(&self, format: &str) -> Result<String> {
+        self.output_with_charge(format, None, None)
+    }
+
+    /// Output the molecule in the format, the charge and multiplicity are used
+    /// by formats have a charge-multiplicity line (`gjf` and `orca`), and
+    /// computed from the atoms if not given (see `charge_multiplicity`).
+    pub fn output_with_charge(
+        &self,
+        format: &str,
+        charge: Option<i32>,
+        multiplicity: Option<u32>,
+    ) -> Result<String> {
+        let (default_charge, default_multiplicity) = self.charge_multiplicity();
+        let charge = charge.unwrap_or(default_charge);
+        let multiplicity = positive_multiplicity(multiplicity.unwrap_or(default_multiplicity))?;
         match format {
             "xyz" => self.output_to_xyz(),
             "mol2" => self.output_to_mol2(),
+            "pdb" => self.output_to_pdb(),
+            "cjson" => self.output_to_cjson(),
+            "sdf" | "mol" => self.output_to_sdf(),
+            "gjf" => self.output_to_gjf(charge, multiplicity),
+            "orca" => self.output_to_orca(charge, multiplicity),
             "lme_json" => Ok(serde_json::to_string(&self)?),
             "nothing" => Ok(String::from("")),
             format => Err(anyhow!("Unsupported format {format}")),
@@ -120,16 +304,321 @@ impl BasicIOMolecule {
         match format {
             "xyz" => Self::input_from_xyz(r),
             "mol2" => Self::input_from_mol2(r),
+            "sdf" | "mol" => Self::input_from_sdf(r),
+            "g16log" | "orcaout" => Self::input_multi(format, r)?
+                .pop()
+                .with_context(|| format!("No geometry found in {} file", format)),
             "lme_json" => Ok(serde_json::from_reader(r)?),
             format => Err(anyhow!("Unsupported format {format}")),
         }
     }
 
+    /// Read all frames (records) in the file, e.g. a trajectory of optimization.
+    ///
+    /// Multiple frames are supported for `xyz`, `sdf` and the quantum chemistry
+    /// output logs `g16log` (Gaussian) and `orcaout` (ORCA), other formats are
+    /// read as a single frame. Frames of the output logs are the geometries of
+    /// each step, with the SCF energy in Hartree as property `energy` and the
+    /// frequencies in cm^-1 of the last frame as property `frequencies`
+    /// (separated by spaces, imaginary ones are negative). ESP charges (e.g.
+    /// CHELPG or Merz-Kollman) of the last frame are the property `charges`
+    /// separated by spaces in the order of atoms, see `partial_charges`. If
+    /// the lowest frequency is imaginary, the Cartesian displacements of its
+    /// mode (x, y, z of each atom) are the property `imaginary_mode`. The sums
+    /// of electronic energy and thermal corrections of frequency calculations
+    /// are the properties `enthalpy` and `free_energy` in Hartree.
+    pub fn input_multi<R: Read>(format: &str, mut r: R) -> Result<Vec<Self>> {
+        match format {
+            "xyz" => {
+                let mut content = String::new();
+                r.read_to_string(&mut content)?;
+                let mut lines = content.lines();
+                let mut frames = vec![];
+                while let Some(count) = lines.by_ref().find(|line| !line.trim().is_empty()) {
+                    let amount: usize = count.trim().parse().with_context(|| {
+                        format!(
+                            "Count line of frame {} is not a integer: {}",
+                            frames.len(),
+                            count
+                        )
+                    })?;
+                    let title = lines.next().with_context(|| {
+                        format!("Unable to read title line of frame {}", frames.len())
+                    })?;
+                    let atoms = lines
+                        .by_ref()
+                        .take(amount)
+                        .map(xyz_atom)
+                        .collect::<Result<Vec<_>>>()?;
+                    if atoms.len() != amount {
+                        Err(anyhow!(
+                            "Count of atom lines in frame {} is not matched to count line: {} vs. {}",
+                            frames.len(),
+                            atoms.len(),
+                            amount
+                        ))?
+                    }
+                    frames.push(Self::new(title.to_string(), atoms, vec![]));
+                }
+                Ok(frames)
+            }
+            "sdf" => {
+                let mut content = String::new();
+                r.read_to_string(&mut content)?;
+                let mut frames = vec![];
+                let mut record = String::new();
+                for line in content.lines() {
+                    record.push_str(line);
+                    record.push('\n');
+                    if line.starts_with("$$$$") {
+                        frames.push(Self::input_from_sdf(record.as_bytes())?);
+                        record.clear();
+                    }
+                }
+                if !record.trim().is_empty() {
+                    frames.push(Self::input_from_sdf(record.as_bytes())?);
+                }
+                Ok(frames)
+            }
+            "g16log" | "orcaout" => {
+                let mut content = String::new();
+                r.read_to_string(&mut content)?;
+                let frames = if format == "g16log" {
+                    Self::input_from_g16log(&content)?
+                } else {
+                    Self::input_from_orcaout(&content)?
+                };
+                if frames.is_empty() {
+                    Err(anyhow!("No geometry found in {} file", format))?
+                }
+                Ok(frames)
+            }
+            format => Ok(vec![Self::input(format, r)?]),
+        }
+    }
+
+    /// Geometries in Gaussian output, from the `Standard orientation` blocks,
+    /// or the `Input orientation` blocks if symmetry is turned off.
+    fn input_from_g16log(content: &str) -> Result<Vec<Self>> {
+        let header = if content.contains("Standard orientation:") {
+            "Standard orientation:"
+        } else {
+            "Input orientation:"
+        };
+        let lines = content.lines().collect::<Vec<_>>();
+        let mut frames: Vec<Self> = vec![];
+        let mut frequencies = vec![];
+        let mut charges = vec![];
+        let mut mode = vec![];
+        let mut index = 0;
+        while index < lines.len() {
+            let line = lines[index].trim();
+            if line.contains(header) {
+                // dash line, 2 lines of column titles and another dash line
+                let atoms = lines
+                    .iter()
+                    .skip(index + 5)
+                    .take_while(|line| !line.trim().starts_with("---"))
+                    .map(|line| {
+                        let items = line.split_whitespace().collect::<Vec<_>>();
+                        if items.len() < 5 {
+                            Err(anyhow!("Invalid atom line in Gaussian output: {}", line))?
+                        }
+                        let [x, y, z] = [3, 2, 1].map(|offset| items[items.len() - offset]);
+                        Ok(Atom3D {
+                            element: items[1].parse().with_context(|| {
+                                format!("Invalid atomic number in line {}", line)
+                            })?,
+                            position: Point3::new(x.parse()?, y.parse()?, z.parse()?),
+                            formal_charge: 0.,
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                index += 5 + atoms.len();
+                frames.push(Self::new("g16log".to_string(), atoms, vec![]));
+            } else if let Some(energy) = line.strip_prefix("SCF Done:") {
+                let energy = energy
+                    .split('=')
+                    .nth(1)
+                    .and_then(|value| value.split_whitespace().next())
+                    .with_context(|| format!("Unable to read SCF energy in line {}", line))?;
+                if let Some(frame) = frames.last_mut() {
+                    frame
+                        .properties
+                        .insert("energy".to_string(), energy.to_string());
+                }
+            } else if let Some((name, value)) = line
+                .strip_prefix("Sum of electronic and thermal Enthalpies=")
+                .map(|value| ("enthalpy", value))
+                .or_else(|| {
+                    line.strip_prefix("Sum of electronic and thermal Free Energies=")
+                        .map(|value| ("free_energy", value))
+                })
+            {
+                if let Some(frame) = frames.last_mut() {
+                    frame
+                        .properties
+                        .insert(name.to_string(), value.trim().to_string());
+                }
+            } else if line.starts_with("ESP charges:") || line.starts_with("Charges from ESP fit") {
+                // lines of atom index, symbol and charge, with a column header
+                charges = lines
+                    .iter()
+                    .skip(index + 1)
+                    .take_while(|line| !line.contains("Sum of ESP charges"))
+                    .map(|line| line.split_whitespace().collect::<Vec<_>>())
+                    .filter(|items| items.len() == 3 && items[0].parse::<usize>().is_ok())
+                    .map(|items| parse_charge(items[2]))
+                    .collect::<Result<Vec<_>>>()?;
+            } else if let Some(values) = line.strip_prefix("Frequencies --") {
+                // `Frequencies ---` lines of HPModes duplicate the normal ones
+                if !values.starts_with('-') {
+                    let first_block = frequencies.is_empty();
+                    frequencies.extend(values.split_whitespace().map(String::from));
+                    let lowest = frequencies[0].parse::<f64>().unwrap_or_default();
+                    if first_block && lowest < 0. {
+                        // the first column of the displacement table after the header
+                        mode = lines
+                            .iter()
+                            .skip(index + 1)
+                            .skip_while(|line| !line.trim().starts_with("Atom  AN"))
+                            .skip(1)
+                            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+                            .take_while(|items| {
+                                items.len() >= 5 && items[0].parse::<usize>().is_ok()
+                            })
+                            .flat_map(|items| items[2..5].to_vec())
+                            .map(parse_displacement)
+                            .collect::<Result<Vec<_>>>()?;
+                    }
+                }
+            }
+            index += 1;
+        }
+        if let Some(frame) = frames.last_mut().filter(|_| !frequencies.is_empty()) {
+            frame
+                .properties
+                .insert("frequencies".to_string(), frequencies.join(" "));
+        }
+        insert_charges(&mut frames, &charges);
+        if let Some(frame) = frames
+            .last_mut()
+            .filter(|frame| !mode.is_empty() && mode.len() == frame.atoms.len() * 3)
+        {
+            frame.properties.insert(
+                "imaginary_mode".to_string(),
+                mode.iter()
+                    .map(|value| value.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            );
+        }
+        Ok(frames)
+    }
+
+    /// Geometries in ORCA output, from the `CARTESIAN COORDINATES (ANGSTROEM)`
+    /// blocks. The zero frequencies of translations and rotations are skipped.
+    fn input_from_orcaout(content: &str) -> Result<Vec<Self>> {
+        let lines = content.lines().collect::<Vec<_>>();
+        let mut frames: Vec<Self> = vec![];
+        let mut frequencies = vec![];
+        let mut charges = vec![];
+        // index of the first imaginary mode in the normal modes table
+        let mut imaginary = None;
+        let mut mode = vec![];
+        let mut index = 0;
+        while index < lines.len() {
+            let line = lines[index].trim();
+            if line == "CARTESIAN COORDINATES (ANGSTROEM)" {
+                let atoms = lines
+                    .iter()
+                    .skip(index + 2)
+                    .take_while(|line| !line.trim().is_empty())
+                    .map(|line| xyz_atom(line.trim()))
+                    .collect::<Result<Vec<_>>>()?;
+                index += 2 + atoms.len();
+                frames.push(Self::new("orcaout".to_string(), atoms, vec![]));
+            } else if let Some(energy) = line.strip_prefix("FINAL SINGLE POINT ENERGY") {
+                if let Some(frame) = frames.last_mut() {
+                    frame
+                        .properties
+                        .insert("energy".to_string(), energy.trim().to_string());
+                }
+            } else if let Some((name, value)) = line
+                .strip_prefix("Total Enthalpy")
+                .map(|value| ("enthalpy", value))
+                .or_else(|| {
+                    line.strip_prefix("Final Gibbs free energy")
+                        .map(|value| ("free_energy", value))
+                })
+            {
+                // `Total Enthalpy                    ...    -76.38124 Eh`
+                let value = value
+                    .trim_start_matches([' ', '.'])
+                    .split_whitespace()
+                    .next()
+                    .with_context(|| format!("Unable to read {} in line {}", name, line))?;
+                if let Some(frame) = frames.last_mut() {
+                    frame.properties.insert(name.to_string(), value.to_string());
+                }
+            } else if line == "CHELPG Charges" {
+                // dash line, then lines of atom index, symbol, colon and charge
+                charges = lines
+                    .iter()
+                    .skip(index + 2)
+                    .take_while(|line| !line.trim().starts_with("---"))
+                    .map(|line| parse_charge(line.split_whitespace().last().unwrap_or_default()))
+                    .collect::<Result<Vec<_>>>()?;
+            } else if line == "VIBRATIONAL FREQUENCIES" {
+                frequencies.clear();
+                imaginary = None;
+                mode.clear();
+            } else if let Some((column, value)) = line
+                .strip_suffix("cm**-1")
+                .or_else(|| line.strip_suffix("cm**-1 ***imaginary mode***"))
+                .and_then(|line| line.split_once(':'))
+            {
+                let value = value.trim();
+                let frequency = value.parse::<f64>().unwrap_or_default();
+                if frequency != 0. {
+                    frequencies.push(value.to_string());
+                }
+                if frequency < 0. && imaginary.is_none() {
+                    imaginary = column.trim().parse::<usize>().ok();
+                }
+            } else if line == "NORMAL MODES" {
+                if let Some(imaginary) = imaginary {
+                    mode = orca_normal_mode(&lines[index + 1..], imaginary)?;
+                }
+            }
+            index += 1;
+        }
+        if let Some(frame) = frames.last_mut().filter(|_| !frequencies.is_empty()) {
+            frame
+                .properties
+                .insert("frequencies".to_string(), frequencies.join(" "));
+        }
+        insert_charges(&mut frames, &charges);
+        if let Some(frame) = frames
+            .last_mut()
+            .filter(|frame| !mode.is_empty() && mode.len() == frame.atoms.len() * 3)
+        {
+            frame.properties.insert(
+                "imaginary_mode".to_string(),
+                mode.iter()
+                    .map(|value| value.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            );
+        }
+        Ok(frames)
+    }
+
     fn input_from_xyz<R: Read>(mut r: R) -> Result<Self> {
         let mut content = String::new();
         r.read_to_string(&mut content)?;
         let lines = content.lines();
-        let mut lines = lines.filter(|line| line.len() != 0);
+        let mut lines = lines.filter(|line| !line.is_empty());
         let amount: usize = lines
             .next()
             .with_context(|| "Unable to read count line of XYZ file")?
@@ -140,44 +629,7 @@ impl BasicIOMolecule {
             .with_context(|| "Unable to read title line of XYZ file")?;
         let atoms: Vec<_> = lines
             .chain(std::iter::empty())
-            .map(|line| {
-                let items = line
-                    .split(" ")
-                    .filter(|item| item.len() != 0)
-                    .collect::<Vec<_>>();
-                let element = items.get(0).with_context(|| {
-                    format!("Invalid atom line {line} in XYZ file, no element token found")
-                })?;
-                let element = element_symbol_to_num(element)
-                    .with_context(|| format!("Invalid element token in {line}"))?;
-                let x = items
-                    .get(1)
-                    .with_context(|| {
-                        format!("Invalid atom line {line} in XYZ file, no x token found")
-                    })?
-                    .parse()
-                    .with_context(|| format!("Unable to parse x token in line {line}"))?;
-                let y = items
-                    .get(2)
-                    .with_context(|| {
-                        format!("Invalid atom line {line} in XYZ file, no y token found")
-                    })?
-                    .parse()
-                    .with_context(|| format!("Unable to parse y token in line {line}"))?;
-                let z = items
-                    .get(3)
-                    .with_context(|| {
-                        format!("Invalid atom line {line} in XYZ file, no z token found")
-                    })?
-                    .parse()
-                    .with_context(|| format!("Unable to parse z token in line {line}"))?;
-                let position = Point3::new(x, y, z);
-                Ok(Atom3D {
-                    element,
-                    position,
-                    formal_charge: 0.,
-                })
-            })
+            .map(xyz_atom)
             .collect::<Result<Vec<_>>>()?;
         if amount != atoms.len() {
             Err(anyhow!(
@@ -186,11 +638,7 @@ impl BasicIOMolecule {
                 amount
             ))
         } else {
-            Ok(Self {
-                title: title.to_string(),
-                atoms,
-                bonds: vec![],
-            })
+            Ok(Self::new(title.to_string(), atoms, vec![]))
         }
     }
 
@@ -198,7 +646,7 @@ impl BasicIOMolecule {
         let mut content = String::new();
         r.read_to_string(&mut content)?;
         let lines = content.lines();
-        let lines = lines.filter(|line| line.len() != 0 || line.starts_with("#"));
+        let lines = lines.filter(|line| !line.is_empty() || line.starts_with("#"));
         let mut molecule_block = lines
             .clone()
             .skip_while(|line| line != &"@<TRIPOS>MOLECULE")
@@ -218,7 +666,7 @@ impl BasicIOMolecule {
             .filter(|line| line != &"");
         let title = molecule_block
             .next()
-            .with_context(|| format!("Unable to read title line of the mol2 file"))?;
+            .with_context(|| "Unable to read title line of the mol2 file")?;
         let atoms = atom_block
             .map(|line| {
                 let mut line_items = line.split(" ").filter(|item| item != &"").skip(1);
@@ -293,36 +741,523 @@ impl BasicIOMolecule {
                 Ok((a - 1, b - 1, bond))
             })
             .collect::<Result<Vec<_>>>()?;
-        Ok(Self {
-            title: title.to_string(),
-            atoms,
-            bonds,
-        })
+        Ok(Self::new(title.to_string(), atoms, bonds))
     }
 
-    fn output_to_xyz(&self) -> Result<String> {
-        let title = self.title.clone();
-        let count = self.atoms.len().to_string();
-        let xyz = self
+    /// Per-atom partial charges in the property `charges`, e.g. the ESP
+    /// charges read from quantum chemistry output logs, or the partial charges
+    /// in the metadata if every atom has one.
+    pub fn partial_charges(&self) -> Result<Option<Vec<f64>>> {
+        let Some(charges) = self.properties.get("charges") else {
+            return Ok(self
+                .metadata
+                .iter()
+                .map(|metadata| metadata.partial_charge)
+                .collect::<Option<Vec<_>>>()
+                .filter(|charges| !charges.is_empty() && charges.len() == self.atoms.len()));
+        };
+        let charges = charges
+            .split_whitespace()
+            .map(parse_charge)
+            .collect::<Result<Vec<_>>>()?;
+        if charges.len() != self.atoms.len() {
+            Err(anyhow!(
+                "{} partial charges found for {} atoms",
+                charges.len(),
+                self.atoms.len()
+            ))?
+        }
+        Ok(Some(charges))
+    }
+
+    /// Metadata of each atom, with the partial charges set from the property
+    /// `charges` if there is.
+    pub fn atom_metadata(&self) -> Result<Vec<AtomMetadata>> {
+        let mut metadata = self.metadata.clone();
+        metadata.resize(self.atoms.len(), Default::default());
+        if let Some(charges) = self.partial_charges()? {
+            for (metadata, charge) in metadata.iter_mut().zip(charges) {
+                metadata.partial_charge = Some(charge);
+            }
+        }
+        if metadata.iter().all(AtomMetadata::is_empty) {
+            metadata.clear();
+        }
+        Ok(metadata)
+    }
+
+    /// Total charge as the sum of formal charges, and the lowest multiplicity
+    /// allowed by the parity of electron count.
+    pub fn charge_multiplicity(&self) -> (i32, u32) {
+        let charge = self
+            .atoms
+            .iter()
+            .map(|atom| atom.formal_charge)
+            .sum::<f64>()
+            .round() as i32;
+        let electrons = self
             .atoms
+            .iter()
+            .map(|atom| atom.element as i64)
+            .sum::<i64>()
+            - charge as i64;
+        let multiplicity = if electrons % 2 == 0 { 1 } else { 2 };
+        (charge, multiplicity)
+    }
+
+    fn atom_lines(&self) -> Result<Vec<String>> {
+        self.atoms
             .iter()
             .map(|atom| {
                 Ok(format!(
                     "{} {} {} {}",
-                    element_num_to_symbol(&atom.element).with_context(|| format!(
+                    element_num_to_symbol(atom.element).with_context(|| format!(
                         "Invalid element number found {}",
                         atom.element
                     ))?,
-                    atom.position.x,
-                    atom.position.y,
-                    atom.position.z
+                    self.coordinate(atom.position.x),
+                    self.coordinate(atom.position.y),
+                    self.coordinate(atom.position.z)
                 ))
             })
-            .collect::<Result<Vec<_>, Error>>()?;
+            .collect()
+    }
+
+    /// Title and molecule specification sections of Gaussian input, the route
+    /// section should be given before it and separated with a blank line.
+    fn output_to_gjf(&self, charge: i32, multiplicity: u32) -> Result<String> {
+        Ok([
+            vec![
+                String::new(),
+                self.title.clone(),
+                String::new(),
+                format!("{} {}", charge, multiplicity),
+            ],
+            self.atom_lines()?,
+            vec![String::new(), String::new()],
+        ]
+        .concat()
+        .join("\n"))
+    }
+
+    /// A complete Gaussian input file, the charge and multiplicity are computed
+    /// from the atoms if not given in the options.
+    pub fn output_to_gaussian(&self, options: &GaussianOptions) -> Result<String> {
+        self.gaussian_input(options, self.atom_lines()?)
+    }
+
+    /// A complete Gaussian input file for ONIOM calculation, with the level of
+    /// each atom (see `oniom_levels`). Atoms bonded to a higher level are
+    /// replaced by hydrogen link atoms on their hosts.
+    ///
+    /// Only one charge-multiplicity pair is written, the ones of other levels
+    /// must be added to the options if they differ.
+    pub fn output_to_oniom(
+        &self,
+        options: &GaussianOptions,
+        levels: &[OniomLevel],
+    ) -> Result<String> {
+        if levels.len() != self.atoms.len() {
+            Err(anyhow!(
+                "ONIOM levels of {} atoms given for {} atoms",
+                levels.len(),
+                self.atoms.len()
+            ))?
+        }
+        let links = link_atoms(levels, &self.bonds)
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+        let lines = self
+            .atom_lines()?
+            .into_iter()
+            .enumerate()
+            .map(|(index, line)| {
+                let line = format!("{} {}", line, levels[index].symbol());
+                if let Some(host) = links.get(&index) {
+                    format!("{} H {}", line, host + 1)
+                } else {
+                    line
+                }
+            })
+            .collect();
+        self.gaussian_input(options, lines)
+    }
+
+    fn gaussian_input(&self, options: &GaussianOptions, atom_lines: Vec<String>) -> Result<String> {
+        let route = options.route.trim();
+        if !route.starts_with('#') {
+            Err(anyhow!(
+                "Route section of Gaussian input must start with #, found {:?}",
+                route
+            ))?
+        }
+        // a blank line terminates the title section, and an empty title is not allowed
+        let title = options
+            .title
+            .as_ref()
+            .unwrap_or(&self.title)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let title = if title.is_empty() {
+            "LME".to_string()
+        } else {
+            title
+        };
+        let (default_charge, default_multiplicity) = self.charge_multiplicity();
+        let mut lines = options
+            .link0
+            .iter()
+            .map(|command| format!("%{}", command.trim_start_matches('%')))
+            .collect::<Vec<_>>();
+        lines.extend([
+            route.to_string(),
+            String::new(),
+            title,
+            String::new(),
+            format!(
+                "{} {}",
+                options.charge.unwrap_or(default_charge),
+                positive_multiplicity(options.multiplicity.unwrap_or(default_multiplicity))?
+            ),
+        ]);
+        lines.extend(atom_lines);
+        lines.push(String::new());
+        for section in &options.tail {
+            lines.push(section.trim_end().to_string());
+            lines.push(String::new());
+        }
+        Ok(lines.join("\n") + "\n")
+    }
+
+    /// Coordinates block of ORCA input, the keyword lines should be given before it.
+    fn output_to_orca(&self, charge: i32, multiplicity: u32) -> Result<String> {
+        Ok([
+            vec![format!("* xyz {} {}", charge, multiplicity)],
+            self.atom_lines()?,
+            vec!["*".to_string()],
+        ]
+        .concat()
+        .join("\n"))
+    }
+
+    /// Read the first record of a SDF (MOL V2000) file, including bond orders,
+    /// charges in atom block and `M  CHG` lines, and the data items.
+    fn input_from_sdf<R: Read>(mut r: R) -> Result<Self> {
+        let mut content = String::new();
+        r.read_to_string(&mut content)?;
+        let mut lines = content.lines();
+        let title = lines
+            .next()
+            .with_context(|| "Unable to read title line of SDF file")?;
+        let counts = lines
+            .nth(2)
+            .with_context(|| "Unable to read counts line of SDF file")?;
+        if counts.contains("V3000") {
+            Err(anyhow!("MOL V3000 is not supported"))?
+        }
+        let atom_count: usize = sdf_column(counts, 0, 3, "atom count")?;
+        let bond_count: usize = sdf_column(counts, 3, 6, "bond count")?;
+        let mut atoms = (0..atom_count)
+            .map(|_| {
+                let line = lines
+                    .next()
+                    .with_context(|| "Atom block of SDF file is shorter than atom count")?;
+                let x = sdf_column(line, 0, 10, "x")?;
+                let y = sdf_column(line, 10, 20, "y")?;
+                let z = sdf_column(line, 20, 30, "z")?;
+                let symbol = line
+                    .get(31..34.min(line.len()))
+                    .with_context(|| format!("Unable to read element token in line {line}"))?
+                    .trim();
+                let element = element_symbol_to_num(symbol)
+                    .with_context(|| format!("Invalid element token in {line}"))?;
+                let charge_code: usize = sdf_optional_column(line, 36, 39, "charge")?;
+                let formal_charge = match charge_code {
+                    1..=3 | 5..=7 => 4. - charge_code as f64,
+                    _ => 0.,
+                };
+                Ok(Atom3D {
+                    element,
+                    position: Point3::new(x, y, z),
+                    formal_charge,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let bonds = (0..bond_count)
+            .map(|_| {
+                let line = lines
+                    .next()
+                    .with_context(|| "Bond block of SDF file is shorter than bond count")?;
+                let a: usize = sdf_column(line, 0, 3, "first atom")?;
+                let b: usize = sdf_column(line, 3, 6, "second atom")?;
+                let bond_type: usize = sdf_column(line, 6, 9, "bond type")?;
+                // 5 to 8 are query bond types of substructure searching
+                let bond = match bond_type {
+                    1..=3 => bond_type as f64,
+                    4 => 1.5,
+                    bond_type => Err(anyhow!("Unsupported bond type {bond_type} in line {line}"))?,
+                };
+                if a == 0 || b == 0 || a > atom_count || b > atom_count {
+                    Err(anyhow!("Invalid atom index in bond line {line}"))?
+                }
+                Ok((a - 1, b - 1, bond))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut charges_reset = false;
+        for line in lines.by_ref() {
+            if line.starts_with("M  END") {
+                break;
+            }
+            if let Some(entries) = line.strip_prefix("M  CHG") {
+                // charges in atom block are ignored if any M  CHG line exists
+                if !charges_reset {
+                    atoms.iter_mut().for_each(|atom| atom.formal_charge = 0.);
+                    charges_reset = true;
+                }
+                let entries = entries.split_whitespace().skip(1).collect::<Vec<_>>();
+                for entry in entries.chunks(2) {
+                    if let [index, charge] = entry {
+                        let index: usize = index.parse()?;
+                        let atom = atoms
+                            .get_mut(index.wrapping_sub(1))
+                            .with_context(|| format!("Invalid atom index in {line}"))?;
+                        atom.formal_charge = charge.parse()?;
+                    }
+                }
+            }
+        }
+        let mut properties = BTreeMap::new();
+        let mut current: Option<(String, Vec<&str>)> = None;
+        for line in lines {
+            if line.starts_with("$$$$") {
+                break;
+            }
+            if let Some((name, value)) = current.as_mut() {
+                if line.trim().is_empty() {
+                    properties.insert(name.to_string(), value.join("\n"));
+                    current = None;
+                } else {
+                    value.push(line);
+                }
+            } else if line.starts_with('>') {
+                let name = line
+                    .split_once('<')
+                    .and_then(|(_, rest)| rest.split_once('>'))
+                    .map(|(name, _)| name.to_string())
+                    .with_context(|| format!("Invalid data header {line}"))?;
+                current = Some((name, vec![]));
+            }
+        }
+        if let Some((name, value)) = current {
+            properties.insert(name, value.join("\n"));
+        }
+        let mut molecule = Self::new(title.to_string(), atoms, bonds);
+        molecule.properties = properties;
+        Ok(molecule)
+    }
+
+    fn output_to_sdf(&self) -> Result<String> {
+        let mut lines = vec![
+            self.title.clone(),
+            "  LME".to_string(),
+            String::new(),
+            format!(
+                "{:>3}{:>3}  0  0  0  0  0  0  0  0999 V2000",
+                self.atoms.len(),
+                self.bonds.len()
+            ),
+        ];
+        for atom in &self.atoms {
+            let symbol = element_num_to_symbol(atom.element)
+                .with_context(|| format!("Invalid element number found {}", atom.element))?;
+            lines.push(format!(
+                "{:>10.4}{:>10.4}{:>10.4} {:<3} 0  0  0  0  0  0  0  0  0  0  0  0",
+                atom.position.x, atom.position.y, atom.position.z, symbol
+            ))
+        }
+        for (a, b, bond) in &self.bonds {
+            let bond_type = if *bond == 1.5 {
+                4
+            } else {
+                bond.round() as usize
+            };
+            lines.push(format!("{:>3}{:>3}{:>3}  0", a + 1, b + 1, bond_type))
+        }
+        let charges = self
+            .atoms
+            .iter()
+            .enumerate()
+            .filter(|(_, atom)| atom.formal_charge.round() != 0.)
+            .map(|(index, atom)| format!(" {:>3} {:>3}", index + 1, atom.formal_charge.round()))
+            .collect::<Vec<_>>();
+        for chunk in charges.chunks(8) {
+            lines.push(format!("M  CHG{:>3}{}", chunk.len(), chunk.concat()))
+        }
+        lines.push("M  END".to_string());
+        for (name, value) in &self.properties {
+            lines.push(format!(">  <{}>", name));
+            lines.push(value.to_string());
+            lines.push(String::new());
+        }
+        lines.push("$$$$".to_string());
+        Ok(lines.join("\n"))
+    }
+
+    fn output_to_xyz(&self) -> Result<String> {
+        let title = self.title.clone();
+        let count = self.atoms.len().to_string();
+        let xyz = self.atom_lines()?;
         Ok([vec![count, title], xyz].concat().join("\n"))
     }
 
+    /// Atom name in the metadata.
+    fn label(&self, index: usize) -> Option<&str> {
+        self.metadata.get(index)?.label.as_deref()
+    }
+
+    /// Tag of the atom in the metadata.
+    fn tag(&self, index: usize, key: &str) -> Option<&str> {
+        self.metadata.get(index)?.tags.get(key).map(String::as_str)
+    }
+
+    /// PDB file with all atoms as HETATM records of one residue, atom names are
+    /// the labels in the metadata or the element symbol with the serial number.
+    /// The occupancy and B-factor columns are the tags `occupancy` and
+    /// `b_factor` of the atoms if set, e.g. to color atoms by a property.
+    fn output_to_pdb(&self) -> Result<String> {
+        let mut lines = vec![format!("COMPND    {}", self.title)];
+        for (index, atom) in self.atoms.iter().enumerate() {
+            let symbol = element_num_to_symbol(atom.element)
+                .with_context(|| format!("Invalid element number found {}", atom.element))?;
+            let name = self
+                .label(index)
+                .map(String::from)
+                .unwrap_or_else(|| format!("{}{}", symbol, index + 1));
+            let charge = match atom.formal_charge.round() as i32 {
+                0 => String::new(),
+                charge if charge > 0 => format!("{}+", charge),
+                charge => format!("{}-", -charge),
+            };
+            let column = |key: &str, default: f64| -> Result<f64> {
+                self.tag(index, key)
+                    .map(|value| {
+                        value.trim().parse::<f64>().with_context(|| {
+                            format!("Invalid {} {:?} of atom {}", key, value, index + 1)
+                        })
+                    })
+                    .unwrap_or(Ok(default))
+            };
+            lines.push(format!(
+                "HETATM{:>5} {:<4} UNL A   1    {:>8.3}{:>8.3}{:>8.3}{:>6.2}{:>6.2}          {:>2}{:<2}",
+                index + 1,
+                name,
+                atom.position.x,
+                atom.position.y,
+                atom.position.z,
+                column("occupancy", 1.)?,
+                column("b_factor", 0.)?,
+                symbol.to_uppercase(),
+                charge
+            ));
+        }
+        let mut neighbors: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (a, b, bond) in &self.bonds {
+            if *bond != 0. {
+                neighbors.entry(*a).or_default().push(*b);
+                neighbors.entry(*b).or_default().push(*a);
+            }
+        }
+        for (atom, neighbors) in neighbors {
+            for chunk in neighbors.chunks(4) {
+                let serials = chunk
+                    .iter()
+                    .map(|neighbor| format!("{:>5}", neighbor + 1))
+                    .collect::<String>();
+                lines.push(format!("CONECT{:>5}{}", atom + 1, serials));
+            }
+        }
+        lines.push("END".to_string());
+        Ok(lines.join("\n") + "\n")
+    }
+
+    /// Chemical JSON of Avogadro, the labels, tags and partial charges of the
+    /// atoms are written if there are, tags as a list of objects `atoms.tags`.
+    fn output_to_cjson(&self) -> Result<String> {
+        let metadata = self.atom_metadata()?;
+        let mut atoms = serde_json::json!({
+            "elements": {
+                "number": self.atoms.iter().map(|atom| atom.element).collect::<Vec<_>>()
+            },
+            "coords": {
+                "3d": self
+                    .atoms
+                    .iter()
+                    .flat_map(|atom| [atom.position.x, atom.position.y, atom.position.z])
+                    .collect::<Vec<_>>()
+            },
+            "formalCharges": self
+                .atoms
+                .iter()
+                .map(|atom| atom.formal_charge.round() as i32)
+                .collect::<Vec<_>>(),
+        });
+        if metadata.iter().any(|metadata| metadata.label.is_some()) {
+            atoms["labels"] = serde_json::json!(metadata
+                .iter()
+                .map(|metadata| metadata.label.clone().unwrap_or_default())
+                .collect::<Vec<_>>());
+        }
+        if metadata.iter().any(|metadata| !metadata.tags.is_empty()) {
+            atoms["tags"] = serde_json::json!(metadata
+                .iter()
+                .map(|metadata| &metadata.tags)
+                .collect::<Vec<_>>());
+        }
+        let mut cjson = serde_json::json!({
+            "chemicalJson": 1,
+            "name": self.title,
+            "atoms": atoms,
+            "bonds": {
+                "connections": {
+                    "index": self
+                        .bonds
+                        .iter()
+                        .flat_map(|(a, b, _)| [*a, *b])
+                        .collect::<Vec<_>>()
+                },
+                "order": self
+                    .bonds
+                    .iter()
+                    .map(|(_, _, bond)| if bond.fract() == 0. {
+                        serde_json::json!(*bond as i64)
+                    } else {
+                        serde_json::json!(bond)
+                    })
+                    .collect::<Vec<_>>()
+            },
+        });
+        if metadata
+            .iter()
+            .all(|metadata| metadata.partial_charge.is_some())
+            && !metadata.is_empty()
+        {
+            cjson["partialCharges"] = serde_json::json!({
+                "LME": metadata
+                    .iter()
+                    .map(|metadata| metadata.partial_charge)
+                    .collect::<Vec<_>>()
+            });
+        }
+        Ok(serde_json::to_string_pretty(&cjson)?)
+    }
+
+    /// The charge column is the partial charges if available, or the formal
+    /// charges.
     fn output_to_mol2(&self) -> Result<String> {
+        let charges = self.partial_charges()?;
         let title = self.title.clone();
         let atom_count = self.atoms.len().to_string();
         let bond_count = self.bonds.len();
@@ -331,19 +1266,22 @@ impl BasicIOMolecule {
             .iter()
             .enumerate()
             .map(|(index, atom)| {
-                let element_symbol = element_num_to_symbol(&atom.element)
+                let element_symbol = element_num_to_symbol(atom.element)
                     .with_context(|| format!("Invalid element number found {}", atom.element))?;
                 Ok(format!(
                     "{} {} {} {} {} {} {} {} {}",
                     index,
-                    element_symbol,
-                    atom.position.x,
-                    atom.position.y,
-                    atom.position.z,
+                    self.label(index).unwrap_or(element_symbol),
+                    self.coordinate(atom.position.x),
+                    self.coordinate(atom.position.y),
+                    self.coordinate(atom.position.z),
                     element_symbol,
                     "1",
                     "UNL1",
-                    atom.formal_charge
+                    charges
+                        .as_ref()
+                        .map(|charges| charges[index])
+                        .unwrap_or(atom.formal_charge)
                 ))
             })
             .collect::<Result<Vec<_>, Error>>()?;
@@ -360,13 +1298,17 @@ impl BasicIOMolecule {
                 format!("{} {} {} {}", index + 1, a + 1, b + 1, bond)
             })
             .collect::<Vec<_>>();
-        let content = vec![
+        let content = [
             vec![
                 "@<TRIPOS>MOLECULE".to_string(),
                 title,
                 format!("{} {} 0 0 0", atom_count, bond_count),
                 "SMALL".to_string(),
-                "GASTEIGER".to_string(),
+                if charges.is_some() {
+                    "USER_CHARGES".to_string()
+                } else {
+                    "GASTEIGER".to_string()
+                },
                 "".to_string(),
                 "@<TRIPOS>ATOM".to_string(),
             ],
@@ -381,3 +1323,636 @@ impl BasicIOMolecule {
         Ok(content)
     }
 }
+
+/// Parse an atom line of XYZ file.
+fn xyz_atom(line: &str) -> Result<Atom3D> {
+    let items = line
+        .split(" ")
+        .filter(|item| !item.is_empty())
+        .collect::<Vec<_>>();
+    let element = items
+        .first()
+        .with_context(|| format!("Invalid atom line {line} in XYZ file, no element token found"))?;
+    let element = element_symbol_to_num(element)
+        .with_context(|| format!("Invalid element token in {line}"))?;
+    let x = items
+        .get(1)
+        .with_context(|| format!("Invalid atom line {line} in XYZ file, no x token found"))?
+        .parse()
+        .with_context(|| format!("Unable to parse x token in line {line}"))?;
+    let y = items
+        .get(2)
+        .with_context(|| format!("Invalid atom line {line} in XYZ file, no y token found"))?
+        .parse()
+        .with_context(|| format!("Unable to parse y token in line {line}"))?;
+    let z = items
+        .get(3)
+        .with_context(|| format!("Invalid atom line {line} in XYZ file, no z token found"))?
+        .parse()
+        .with_context(|| format!("Unable to parse z token in line {line}"))?;
+    let position = Point3::new(x, y, z);
+    Ok(Atom3D {
+        element,
+        position,
+        formal_charge: 0.,
+    })
+}
+
+fn parse_charge(value: &str) -> Result<f64> {
+    value
+        .parse()
+        .with_context(|| format!("Invalid partial charge {:?}", value))
+}
+
+fn parse_displacement(value: &str) -> Result<f64> {
+    value
+        .parse()
+        .with_context(|| format!("Invalid normal mode displacement {:?}", value))
+}
+
+/// The column of the mode in the normal modes table of ORCA output, which is
+/// split into blocks of a few columns, each with a header line of the column
+/// indexes and lines of the row index and displacements.
+fn orca_normal_mode(lines: &[&str], column: usize) -> Result<Vec<f64>> {
+    let mut mode = BTreeMap::new();
+    let mut columns: Vec<usize> = vec![];
+    for line in lines {
+        let items = line.split_whitespace().collect::<Vec<_>>();
+        if line.contains("SPECTRUM") || line.trim().starts_with("-----") && !mode.is_empty() {
+            break;
+        }
+        if items.is_empty() || items.iter().any(|item| item.parse::<f64>().is_err()) {
+            continue;
+        }
+        if items.iter().all(|item| item.parse::<usize>().is_ok()) && !line.contains('.') {
+            columns = items.iter().map(|item| item.parse().unwrap()).collect();
+        } else if let Some(position) = columns.iter().position(|index| *index == column) {
+            let row = items[0]
+                .parse::<usize>()
+                .with_context(|| format!("Invalid row index of normal modes in line {}", line))?;
+            let value = items
+                .get(position + 1)
+                .with_context(|| format!("Normal mode {} not found in line {}", column, line))?;
+            mode.insert(row, parse_displacement(value)?);
+        }
+    }
+    Ok(mode.into_values().collect())
+}
+
+/// Store the charges as property of the last frame if they match its atoms.
+fn insert_charges(frames: &mut [BasicIOMolecule], charges: &[f64]) {
+    if let Some(frame) = frames
+        .last_mut()
+        .filter(|frame| !charges.is_empty() && frame.atoms.len() == charges.len())
+    {
+        frame.properties.insert(
+            "charges".to_string(),
+            charges
+                .iter()
+                .map(|charge| charge.to_string())
+                .collect::<Vec<_>>()
+                .join(" "),
+        );
+    }
+}
+
+/// Parse the fixed-width column of a line in MOL V2000 format.
+fn sdf_column<T: std::str::FromStr>(line: &str, start: usize, end: usize, name: &str) -> Result<T> {
+    line.get(start..end.min(line.len()))
+        .map(str::trim)
+        .and_then(|token| token.parse().ok())
+        .with_context(|| format!("Unable to read {} in line {}", name, line))
+}
+
+/// Optional column of a SDF line, the default value is used if the line is
+/// shorter than the column or the column is blank.
+fn sdf_optional_column<T: std::str::FromStr + Default>(
+    line: &str,
+    start: usize,
+    end: usize,
+    name: &str,
+) -> Result<T> {
+    match line.get(start.min(line.len())..end.min(line.len())) {
+        Some(token) if !token.trim().is_empty() => sdf_column(line, start, end, name),
+        _ => Ok(T::default()),
+    }
+}
+
+/// Field of a CSV table, quoted if it contains the delimiter, quotes or line
+/// breaks.
+pub fn quote_field(field: &str, delimiter: &str) -> String {
+    if field.contains(delimiter) || field.contains(['"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Multiplicity of the charge-multiplicity lines, which is at least 1.
+fn positive_multiplicity(multiplicity: u32) -> Result<u32> {
+    if multiplicity == 0 {
+        Err(anyhow!("Multiplicity must be positive, found 0"))?
+    }
+    Ok(multiplicity)
+}
+
+#[test]
+fn charge_multiplicity_line() {
+    let atom = |element, formal_charge| Atom3D {
+        element,
+        position: Point3::origin(),
+        formal_charge,
+    };
+    let methyl = BasicIOMolecule::new(
+        "methyl".to_string(),
+        vec![atom(6, 0.), atom(1, 0.), atom(1, 0.), atom(1, 0.)],
+        vec![],
+    );
+    assert_eq!(methyl.charge_multiplicity(), (0, 2));
+    assert!(methyl
+        .output("gjf")
+        .unwrap()
+        .contains("\nmethyl\n\n0 2\nC 0 0 0\n"));
+    let cation = BasicIOMolecule::new(
+        "methyl cation".to_string(),
+        vec![atom(6, 1.), atom(1, 0.), atom(1, 0.), atom(1, 0.)],
+        vec![],
+    );
+    assert!(cation
+        .output("orca")
+        .unwrap()
+        .starts_with("* xyz 1 1\nC 0 0 0\n"));
+    assert!(cation
+        .output_with_charge("orca", None, Some(3))
+        .unwrap()
+        .starts_with("* xyz 1 3\n"));
+    assert!(cation.output_with_charge("orca", None, Some(0)).is_err());
+}
+
+#[test]
+fn gaussian_input() {
+    let water = BasicIOMolecule::new(
+        "water".to_string(),
+        vec![
+            Atom3D {
+                element: 8,
+                position: Point3::origin(),
+                formal_charge: 0.,
+            },
+            Atom3D {
+                element: 1,
+                position: Point3::new(1., 0., 0.),
+                formal_charge: 0.,
+            },
+            Atom3D {
+                element: 1,
+                position: Point3::new(0., 1., 0.),
+                formal_charge: 0.,
+            },
+        ],
+        vec![],
+    );
+    let options = GaussianOptions {
+        link0: vec!["nprocshared=4".to_string(), "%chk=water.chk".to_string()],
+        route: "#p opt b3lyp/def2svp".to_string(),
+        multiplicity: Some(3),
+        tail: vec!["1 2 F".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(
+        water.output_to_gaussian(&options).unwrap(),
+        "%nprocshared=4\n%chk=water.chk\n#p opt b3lyp/def2svp\n\nwater\n\n0 3\nO 0 0 0\nH 1 0 0\nH 0 1 0\n\n1 2 F\n\n"
+    );
+    assert!(water
+        .output_to_gaussian(&GaussianOptions {
+            route: "opt".to_string(),
+            ..Default::default()
+        })
+        .is_err());
+    let water = BasicIOMolecule::new(
+        water.title.clone(),
+        water.atoms.clone(),
+        vec![(0, 1, 1.), (0, 2, 1.)],
+    );
+    let oniom = water
+        .output_to_oniom(
+            &GaussianOptions {
+                route: "#p oniom(b3lyp/def2svp:uff)".to_string(),
+                ..Default::default()
+            },
+            &[OniomLevel::High, OniomLevel::High, OniomLevel::Low],
+        )
+        .unwrap();
+    assert!(oniom.ends_with("0 1\nO 0 0 0 H\nH 1 0 0 H\nH 0 1 0 L H 1\n\n"));
+}
+
+#[test]
+fn quantum_chemistry_logs() {
+    let g16log = " Input orientation:
+ ---------------------------------------------------------------------
+ Center     Atomic      Atomic             Coordinates (Angstroms)
+ Number     Number       Type             X           Y           Z
+ ---------------------------------------------------------------------
+      1          8           0        0.000000    0.000000    0.119262
+      2          1           0        0.000000    0.763239   -0.477047
+      3          1           0        0.000000   -0.763239   -0.477047
+ ---------------------------------------------------------------------
+ SCF Done:  E(RB3LYP) =  -76.4089533079     A.U. after    9 cycles
+ Frequencies --  -1602.1234              3812.4417              3920.8976
+";
+    let frames = BasicIOMolecule::input_multi("g16log", g16log.as_bytes()).unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].atoms.len(), 3);
+    assert_eq!(frames[0].atoms[1].position.y, 0.763239);
+    assert_eq!(frames[0].properties["energy"], "-76.4089533079");
+    assert_eq!(
+        frames[0].properties["frequencies"],
+        "-1602.1234 3812.4417 3920.8976"
+    );
+    let orcaout = "---------------------------------
+CARTESIAN COORDINATES (ANGSTROEM)
+---------------------------------
+  O      0.000000    0.000000    0.119262
+  H      0.000000    0.763239   -0.477047
+  H      0.000000   -0.763239   -0.477047
+
+FINAL SINGLE POINT ENERGY       -76.408953307900
+---------------------------------
+CARTESIAN COORDINATES (ANGSTROEM)
+---------------------------------
+  O      0.000000    0.000000    0.120000
+  H      0.000000    0.760000   -0.480000
+  H      0.000000   -0.760000   -0.480000
+
+FINAL SINGLE POINT ENERGY       -76.409000000000
+-----------------------
+VIBRATIONAL FREQUENCIES
+-----------------------
+   0:         0.00 cm**-1
+   6:      1602.12 cm**-1
+   7:      3812.44 cm**-1
+";
+    let frames = BasicIOMolecule::input_multi("orcaout", orcaout.as_bytes()).unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].properties["energy"], "-76.408953307900");
+    let last = BasicIOMolecule::input("orcaout", orcaout.as_bytes()).unwrap();
+    assert_eq!(last.atoms[0].position.z, 0.12);
+    assert_eq!(last.properties["frequencies"], "1602.12 3812.44");
+}
+
+#[test]
+fn esp_charges() {
+    let g16log = " Input orientation:
+ ---------------------------------------------------------------------
+ Center     Atomic      Atomic             Coordinates (Angstroms)
+ Number     Number       Type             X           Y           Z
+ ---------------------------------------------------------------------
+      1          8           0        0.000000    0.000000    0.119262
+      2          1           0        0.000000    0.763239   -0.477047
+      3          1           0        0.000000   -0.763239   -0.477047
+ ---------------------------------------------------------------------
+ ESP charges:
+               1
+     1  O   -0.834512
+     2  H    0.417256
+     3  H    0.417256
+ Sum of ESP charges =   0.00000
+";
+    let water = BasicIOMolecule::input("g16log", g16log.as_bytes()).unwrap();
+    assert_eq!(
+        water.partial_charges().unwrap(),
+        Some(vec![-0.834512, 0.417256, 0.417256])
+    );
+    let mol2 = water.output("mol2").unwrap();
+    assert!(mol2.contains("USER_CHARGES"));
+    assert!(mol2.contains("UNL1 -0.834512"));
+    let orcaout = "---------------------------------
+CARTESIAN COORDINATES (ANGSTROEM)
+---------------------------------
+  O      0.000000    0.000000    0.119262
+  H      0.000000    0.763239   -0.477047
+  H      0.000000   -0.763239   -0.477047
+
+--------------------------------
+CHELPG Charges
+--------------------------------
+  0   O   :      -0.823741
+  1   H   :       0.411870
+  2   H   :       0.411871
+--------------------------------
+Total charge:    -0.00000
+";
+    let water = BasicIOMolecule::input("orcaout", orcaout.as_bytes()).unwrap();
+    assert_eq!(water.properties["charges"], "-0.823741 0.41187 0.411871");
+}
+
+#[test]
+fn imaginary_mode() {
+    let g16log = " Input orientation:
+ ---------------------------------------------------------------------
+ Center     Atomic      Atomic             Coordinates (Angstroms)
+ Number     Number       Type             X           Y           Z
+ ---------------------------------------------------------------------
+      1          8           0        0.000000    0.000000    0.119262
+      2          1           0        0.000000    0.763239   -0.477047
+      3          1           0        0.000000   -0.763239   -0.477047
+ ---------------------------------------------------------------------
+                      1                      2                      3
+                      A1                     A1                     B2
+ Frequencies --  -1602.1234              3812.4417              3920.8976
+ Red. masses --      1.0825                 1.0453                 1.0810
+  Atom  AN      X      Y      Z        X      Y      Z        X      Y      Z
+     1   8     0.00   0.00   0.07     0.00   0.00  -0.05     0.00   0.07   0.00
+     2   1     0.00   0.43  -0.56     0.00   0.58   0.40     0.00  -0.56   0.43
+     3   1     0.00  -0.43  -0.56     0.00  -0.58   0.40     0.00  -0.56  -0.43
+ Sum of electronic and thermal Enthalpies=           -76.381240
+ Sum of electronic and thermal Free Energies=        -76.402688
+";
+    let water = BasicIOMolecule::input("g16log", g16log.as_bytes()).unwrap();
+    assert_eq!(water.properties["enthalpy"], "-76.381240");
+    assert_eq!(
+        water.properties["imaginary_mode"],
+        "0 0 0.07 0 0.43 -0.56 0 -0.43 -0.56"
+    );
+    let orcaout = "---------------------------------
+CARTESIAN COORDINATES (ANGSTROEM)
+---------------------------------
+  O      0.000000    0.000000    0.119262
+  H      0.000000    0.763239   -0.477047
+  H      0.000000   -0.763239   -0.477047
+
+-----------------------
+VIBRATIONAL FREQUENCIES
+-----------------------
+   5:         0.00 cm**-1
+   6:     -1602.12 cm**-1 ***imaginary mode***
+   7:      3812.44 cm**-1
+   8:      3920.90 cm**-1
+
+------------
+NORMAL MODES
+------------
+
+These modes are the Cartesian displacements weighted by the diagonal matrix
+M(i,i)=1/sqrt(m[i]) where m[i] is the mass of the displaced atom
+
+                  0          1          2          3          4          5
+      0       0.000000   0.000000   0.000000   0.000000   0.000000   0.000000
+      1       0.000000   0.000000   0.000000   0.000000   0.000000   0.000000
+      2       0.000000   0.000000   0.000000   0.000000   0.000000   0.000000
+      3       0.000000   0.000000   0.000000   0.000000   0.000000   0.000000
+      4       0.000000   0.000000   0.000000   0.000000   0.000000   0.000000
+      5       0.000000   0.000000   0.000000   0.000000   0.000000   0.000000
+      6       0.000000   0.000000   0.000000   0.000000   0.000000   0.000000
+      7       0.000000   0.000000   0.000000   0.000000   0.000000   0.000000
+      8       0.000000   0.000000   0.000000   0.000000   0.000000   0.000000
+                  6          7          8
+      0       0.000000   0.000000   0.000000
+      1       0.000000   0.000000   0.070000
+      2       0.070000  -0.050000   0.000000
+      3       0.000000   0.000000   0.000000
+      4       0.430000   0.580000  -0.560000
+      5      -0.560000   0.400000   0.430000
+      6       0.000000   0.000000   0.000000
+      7      -0.430000  -0.580000  -0.560000
+      8      -0.560000   0.400000  -0.430000
+
+-----------
+IR SPECTRUM
+-----------
+
+Final Gibbs free energy         ...    -76.40268 Eh
+";
+    let water = BasicIOMolecule::input("orcaout", orcaout.as_bytes()).unwrap();
+    assert_eq!(water.properties["frequencies"], "-1602.12 3812.44 3920.90");
+    assert_eq!(water.properties["free_energy"], "-76.40268");
+    assert_eq!(
+        water.properties["imaginary_mode"],
+        "0 0 0.07 0 0.43 -0.56 0 -0.43 -0.56"
+    );
+}
+
+#[test]
+fn sdf_round_trip() {
+    let content = "acetate
+  LME
+
+  4  3  0  0  0  0  0  0  0  0999 V2000
+    0.0000    0.0000    0.0000 C   0  0  0  0  0  0  0  0  0  0  0  0
+    1.5000    0.0000    0.0000 C   0  0  0  0  0  0  0  0  0  0  0  0
+    2.1000    1.0000    0.0000 O   0  0  0  0  0  0  0  0  0  0  0  0
+    2.1000   -1.0000    0.0000 O   0  5  0  0  0  0  0  0  0  0  0  0
+  1  2  1  0
+  2  3  2  0
+  2  4  1  0
+M  END
+>  <energy>
+-228.5
+
+$$$$
+";
+    let molecule = BasicIOMolecule::input("sdf", content.as_bytes()).unwrap();
+    assert_eq!(molecule.title, "acetate");
+    assert_eq!(molecule.atoms[3].formal_charge, -1.);
+    assert_eq!(molecule.bonds[1], (1, 2, 2.));
+    assert_eq!(molecule.properties["energy"], "-228.5");
+    let output = molecule.output("sdf").unwrap();
+    assert!(output.contains("M  CHG  1   4  -1"));
+    let reloaded = BasicIOMolecule::input("sdf", output.as_bytes()).unwrap();
+    assert_eq!(reloaded.atoms, molecule.atoms);
+    assert_eq!(reloaded.bonds, molecule.bonds);
+    assert_eq!(reloaded.properties, molecule.properties);
+    let short = "short\n\n\n  2  1  0  0  0  0  0  0  0  0999 V2000
+    0.0000    0.0000    0.0000 C
+    1.5000    0.0000    0.0000 O  0
+  1  2  2
+M  END
+";
+    let molecule = BasicIOMolecule::input("sdf", short.as_bytes()).unwrap();
+    assert_eq!(molecule.atoms[1].element, 8);
+    assert_eq!(molecule.atoms[1].formal_charge, 0.);
+    assert_eq!(molecule.bonds[0], (0, 1, 2.));
+    let query = short.replace("  1  2  2", "  1  2  8");
+    assert!(BasicIOMolecule::input("sdf", query.as_bytes()).is_err());
+}
+
+#[test]
+fn multi_frame_xyz() {
+    let content = "2\nframe 1\nH 0 0 0\nH 0 0 0.74\n2\n\nH 0 0 0\nH 0 0 0.75\n\n";
+    let frames = BasicIOMolecule::input_multi("xyz", content.as_bytes()).unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].title, "frame 1");
+    assert_eq!(frames[1].title, "");
+    assert_eq!(frames[1].atoms[1].position.z, 0.75);
+    assert!(BasicIOMolecule::input_multi("xyz", "3\n\nH 0 0 0\n".as_bytes()).is_err());
+}
+
+#[test]
+fn export_atom_metadata() {
+    let sdf = "water
+  lme
+
+  3  2  0  0  0  0  0  0  0  0999 V2000
+    0.0000    0.0000    0.1193 O   0  0  0  0  0  0  0  0  0  0  0  0
+    0.0000    0.7632   -0.4770 H   0  0  0  0  0  0  0  0  0  0  0  0
+    0.0000   -0.7632   -0.4770 H   0  0  0  0  0  0  0  0  0  0  0  0
+  1  2  1  0
+  1  3  1  0
+M  END
+> <charges>
+-0.8 0.4 0.4
+
+$$$$
+";
+    let water = BasicIOMolecule::input("sdf", sdf.as_bytes()).unwrap();
+    let mut molecule = SparseMolecule::from(water);
+    assert_eq!(
+        molecule.atoms.read_metadata(1).unwrap().partial_charge,
+        Some(0.4)
+    );
+    molecule.atoms.set_metadata(
+        0,
+        AtomMetadata {
+            label: Some("OW".to_string()),
+            ..Default::default()
+        },
+    );
+    let water = BasicIOMolecule::from((molecule.clone(), "water".to_string()));
+    let mol2 = water.output("mol2").unwrap();
+    assert!(mol2.contains("USER_CHARGES"));
+    assert!(mol2.contains("0 OW 0 0 0.1193 O 1 UNL1 -0.8"));
+    let pdb = water.output("pdb").unwrap();
+    let lines = pdb.lines().collect::<Vec<_>>();
+    assert_eq!(
+        lines[1],
+        "HETATM    1 OW   UNL A   1       0.000   0.000   0.119  1.00  0.00           O  "
+    );
+    assert!(lines[2].starts_with("HETATM    2 H2   UNL"));
+    assert_eq!(lines[4], "CONECT    1    2    3");
+    assert_eq!(lines.last(), Some(&"END"));
+    molecule.atoms.set_metadata(
+        2,
+        AtomMetadata {
+            tags: BTreeMap::from([
+                ("b_factor".to_string(), "12.5".to_string()),
+                ("color".to_string(), "red".to_string()),
+            ]),
+            ..Default::default()
+        },
+    );
+    let water = BasicIOMolecule::from((molecule, "water".to_string()));
+    let pdb = water.output("pdb").unwrap();
+    assert!(pdb.lines().nth(3).unwrap().contains("  1.00 12.50"));
+    let cjson: serde_json::Value = serde_json::from_str(&water.output("cjson").unwrap()).unwrap();
+    assert_eq!(
+        cjson["atoms"]["elements"]["number"],
+        serde_json::json!([8, 1, 1])
+    );
+    assert_eq!(cjson["atoms"]["labels"], serde_json::json!(["OW", "", ""]));
+    assert_eq!(cjson["atoms"]["tags"][2]["color"], "red");
+    assert_eq!(
+        cjson["bonds"]["connections"]["index"],
+        serde_json::json!([0, 1, 0, 2])
+    );
+    assert_eq!(cjson["bonds"]["order"], serde_json::json!([1, 1]));
+    assert_eq!(cjson["partialCharges"]["LME"][0], -0.8);
+}
+
+#[test]
+fn coordinate_precision() {
+    let atoms = vec![
+        Atom3D {
+            element: 8,
+            position: Point3::new(-0.00001, 0.1193049, 1.),
+            formal_charge: 0.,
+        },
+        Atom3D {
+            element: 1,
+            position: Point3::new(0.76324999, -0.47701, 2.5),
+            formal_charge: 0.,
+        },
+    ];
+    let water = BasicIOMolecule::new("water".to_string(), atoms, vec![(0, 1, 1.)]);
+    assert_eq!(canonical_float(-0.00001, 4), 0.);
+    assert!(canonical_float(-0.00001, 4).is_sign_positive());
+    let water = water.with_precision(Some(4));
+    assert_eq!(
+        water.output("xyz").unwrap(),
+        "2\nwater\nO 0.0000 0.1193 1.0000\nH 0.7632 -0.4770 2.5000"
+    );
+    assert!(water
+        .output("mol2")
+        .unwrap()
+        .contains("1 H 0.7632 -0.4770 2.5000 H"));
+    assert_eq!(
+        canonical_property(
+            "-0.000 1.50 1e-20 1e300 0.00000015\n  -76.40895330790 C1",
+            10
+        ),
+        "0 1.5 0 1e300 1.5e-7\n  -76.4089533079 C1"
+    );
+    let mut water = water;
+    water
+        .properties
+        .insert("energy".to_string(), "-76.40895330790".to_string());
+    let water = water.with_precision(None);
+    assert_eq!(water.properties["energy"], "-76.40895330790");
+    let water = water.with_precision(Some(4));
+    let json: serde_json::Value = serde_json::from_str(&water.output("lme_json").unwrap()).unwrap();
+    assert_eq!(
+        json["atoms"][1]["position"],
+        serde_json::json!([0.7632, -0.477, 2.5])
+    );
+    assert_eq!(json["properties"]["energy"], "-76.409");
+}
+
+#[test]
+fn namespace_mapping_sidecar() {
+    use crate::group_name::GroupName;
+    assert_eq!(
+        NamespaceMapping::sidecar_path(Path::new("out/a.xyz")),
+        PathBuf::from("out/a.map.json")
+    );
+    assert_eq!(
+        NamespaceMapping::sidecar_path(Path::new("a")),
+        PathBuf::from("a.map.json")
+    );
+    let atom = |element| {
+        Some(Atom3D {
+            element,
+            position: Point3::origin(),
+            formal_charge: 0.,
+        })
+    };
+    let molecule = SparseMolecule {
+        atoms: SparseAtomList::from(vec![None, atom(6), None, atom(8)]),
+        ids: Some(BTreeMap::from([
+            ("carbon".to_string(), 1),
+            ("removed".to_string(), 2),
+        ])),
+        groups: Some(GroupName::from_iter([
+            ("carbonyl".to_string(), 1),
+            ("carbonyl".to_string(), 3),
+        ])),
+        ..Default::default()
+    };
+    let directory = tempfile::tempdir().unwrap();
+    let path = NamespaceMapping::sidecar_path(&directory.path().join("a.xyz"));
+    NamespaceMapping::from(molecule).write_to(&path).unwrap();
+    let mapping: NamespaceMapping = serde_json::from_reader(File::open(&path).unwrap()).unwrap();
+    assert_eq!(mapping.len, 2);
+    assert_eq!(mapping.indexes, BTreeMap::from([(1, 0), (3, 1)]));
+    assert_eq!(mapping.ids, BTreeMap::from([("carbon".to_string(), 0)]));
+    assert_eq!(mapping.groups["carbonyl"], BTreeSet::from([0, 1]));
+    assert!(NamespaceMapping::from(SparseMolecule::default())
+        .write_to(&directory.path().join("missing/a.map.json"))
+        .is_err());
+}
+
+#[test]
+fn csv_field_quoting() {
+    assert_eq!(quote_field("water", ","), "water");
+    assert_eq!(quote_field("a,b", ","), "\"a,b\"");
+    assert_eq!(quote_field("a \"b\"", ","), "\"a \"\"b\"\"\"");
+    assert_eq!(quote_field("a\nb", "\t"), "\"a\nb\"");
+}
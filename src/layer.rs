@@ -5,20 +5,41 @@ use std::{
     ops::RangeInclusive,
 };
 
-use bincode::{Decode, Encode};
-use nalgebra::{Isometry3, Point3, Translation3, Vector3};
+use bincode::{
+    de::Decoder,
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+    impl_borrow_decode, Decode, Encode,
+};
+use fancy_regex::Regex;
+use nalgebra::{
+    Isometry3, Matrix3, Point3, Rotation3, SymmetricEigen, Translation3, Unit, UnitQuaternion,
+    Vector3,
+};
 use redb::Value;
-use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::{
-    chemistry::Atom3D,
+    chemistry::{atomic_mass, covalent_radius, validated_element_num, Atom3D, AtomMetadata},
+    coordination::{place_chelate, place_ligand, CoordinationGeometry, Ligand},
     group_name::GroupName,
+    migration::{with_layer_format, LayerV0},
+    oniom::{cap_qm_region, OniomLevel, LINK_ATOMS_GROUP},
+    smiles::parse_smiles,
     sparse_molecule::{SparseAtomList, SparseMolecule},
-    utils::geometric::axis_angle_for_b2a,
+    structural_group::StructuralGroup,
+    utils::{
+        bond_orders::assign_bond_orders,
+        charges::gasteiger_charges,
+        geometric::{axis_angle_for_b2a, dihedral_angle, kabsch},
+        hydrogens::{add_hydrogens, bonded_hydrogens},
+        neighbors::KdTree,
+    },
 };
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode)]
-#[serde(tag = "type")]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode, JsonSchema)]
+#[serde(tag = "type", deny_unknown_fields)]
 pub enum Layer {
     Transparent,
     Fill {
@@ -52,12 +73,15 @@ pub enum Layer {
         select: SelectOne,
         #[serde(default)]
         #[bincode(with_serde)]
+        #[schemars(with = "[f64; 3]")]
         center: Point3<f64>,
     },
+    #[serde(alias = "DirectionAlgin")]
     DirectionAlign {
         select: SelectOne,
-        #[serde(default = "Vector3::x")]
+        #[serde(default = "x_axis")]
         #[bincode(with_serde)]
+        #[schemars(with = "[f64; 3]")]
         direction: Vector3<f64>,
     },
     XYAlign {
@@ -70,6 +94,7 @@ pub enum Layer {
     Translation {
         select: SelectMany,
         #[bincode(with_serde)]
+        #[schemars(with = "[f64; 3]")]
         vector: Vector3<f64>,
     },
     TranslationTo {
@@ -77,23 +102,27 @@ pub enum Layer {
         target: SelectOne,
         #[serde(default)]
         #[bincode(with_serde)]
+        #[schemars(with = "[f64; 3]")]
         position: Point3<f64>,
     },
     RotationTo {
         a: SelectOne,
         b: SelectOne,
         select: SelectMany,
-        #[serde(default = "Vector3::x")]
+        #[serde(default = "x_axis")]
         #[bincode(with_serde)]
+        #[schemars(with = "[f64; 3]")]
         direction: Vector3<f64>,
     },
     Rotation {
         select: SelectMany,
         #[bincode(with_serde)]
         #[serde(default)]
+        #[schemars(with = "[f64; 3]")]
         center: Point3<f64>,
         #[bincode(with_serde)]
-        #[serde(default = "Vector3::x")]
+        #[serde(default = "x_axis")]
+        #[schemars(with = "[f64; 3]")]
         axis: Vector3<f64>,
         angle: f64,
         #[serde(default)]
@@ -102,6 +131,7 @@ pub enum Layer {
     Isometry {
         select: SelectMany,
         #[bincode(with_serde)]
+        #[schemars(with = "IsometrySchema")]
         isometry: Isometry3<f64>,
     },
     Mirror {
@@ -109,9 +139,11 @@ pub enum Layer {
         select: SelectMany,
         #[bincode(with_serde)]
         #[serde(default)]
+        #[schemars(with = "[f64; 3]")]
         center: Point3<f64>,
         #[bincode(with_serde)]
-        #[serde(default = "Vector3::x")]
+        #[serde(default = "x_axis")]
+        #[schemars(with = "[f64; 3]")]
         law_vector: Vector3<f64>,
     },
     RemoveAtoms {
@@ -123,6 +155,225 @@ pub enum Layer {
     UnHide {
         select: SelectMany,
     },
+    /// Fill with a fragment parsed from SMILES, same as Fill with the parsed
+    /// structure, see `smiles::parse_smiles` for the generated geometry.
+    FromSmiles {
+        smiles: String,
+    },
+    /// Set the dihedral angle a-b-c-d by rotating the selected atoms around
+    /// the b-c axis, the selection should be the moving part on the c-d side.
+    SetDihedral {
+        a: SelectOne,
+        b: SelectOne,
+        c: SelectOne,
+        d: SelectOne,
+        select: SelectMany,
+        angle: f64,
+        #[serde(default)]
+        degree: bool,
+    },
+    /// Set the distance of a and b by translating the selected atoms along
+    /// the a-b vector, the selection should be the moving part on the b side.
+    SetBondLength {
+        a: SelectOne,
+        b: SelectOne,
+        select: SelectMany,
+        length: f64,
+    },
+    /// Set the angle a-b-c by rotating the selected atoms around the axis
+    /// through b perpendicular to the abc plane, the selection should be the
+    /// moving part on the c side.
+    SetAngle {
+        a: SelectOne,
+        b: SelectOne,
+        c: SelectOne,
+        select: SelectMany,
+        angle: f64,
+        #[serde(default)]
+        degree: bool,
+    },
+    /// Append the missing hydrogens of the selected atoms by their normal
+    /// valences and bond orders, see `utils::hydrogens::add_hydrogens`.
+    AddHydrogens {
+        #[serde(default)]
+        select: SelectMany,
+    },
+    /// Attach ligand fragments to the metal at the sites of an ideal
+    /// coordination geometry, the template axes are the axes of the current
+    /// coordinate system. See `coordination::place_ligand` for the placement.
+    Coordinate {
+        metal: SelectOne,
+        geometry: CoordinationGeometry,
+        ligands: Vec<Ligand>,
+    },
+    /// Remove the hydrogens bonded to the selected atoms, e.g. to make
+    /// attachment points for substituents.
+    RemoveHydrogens {
+        #[serde(default)]
+        select: SelectMany,
+    },
+    /// Tag the selected atoms as a level of ONIOM calculation, an atom only
+    /// belongs to the last level tagged. Untagged atoms are in the low level.
+    Oniom {
+        select: SelectMany,
+        level: OniomLevel,
+    },
+    /// Keep only the selected QM region, with hydrogen link atoms capping the
+    /// cut bonds (see `cap_qm_region`) in the group `LINK_ATOMS`.
+    QmSubsystem {
+        select: SelectMany,
+        #[serde(default)]
+        scale: Option<f64>,
+    },
+    /// Append `copies` copies of the selected fragment, the k-th copy is rotated
+    /// by k times `angle` around the axis through `center` and then translated
+    /// by k times `translation`. The angle is 360 / (copies + 1) degrees if not
+    /// given, which completes a rotational symmetry (e.g. 2 copies for C3).
+    /// Each copy is appended like the Append layer with the name `<name>_<k>`,
+    /// bonds between the selected atoms are copied.
+    Duplicate {
+        select: SelectMany,
+        name: String,
+        copies: usize,
+        #[bincode(with_serde)]
+        #[serde(default)]
+        #[schemars(with = "[f64; 3]")]
+        center: Point3<f64>,
+        #[bincode(with_serde)]
+        #[serde(default = "x_axis")]
+        #[schemars(with = "[f64; 3]")]
+        axis: Vector3<f64>,
+        #[serde(default)]
+        angle: Option<f64>,
+        #[serde(default)]
+        degree: bool,
+        #[bincode(with_serde)]
+        #[serde(default)]
+        #[schemars(with = "[f64; 3]")]
+        translation: Vector3<f64>,
+    },
+    /// Replicate the whole structure into a supercell of `size` (na, nb, nc)
+    /// unit cells along the lattice vectors a, b and c. The structure itself is
+    /// the image (0, 0, 0), other images are appended like the Append layer with
+    /// the name `<name>_<i>_<j>_<k>`. Each bond is remapped to the nearest image
+    /// of its second atom, so bonds crossing the cell boundary connect adjacent
+    /// images and are cut at the surface of the supercell, e.g. for slab models.
+    Supercell {
+        #[bincode(with_serde)]
+        #[schemars(with = "[[f64; 3]; 3]")]
+        lattice: [Vector3<f64>; 3],
+        size: [usize; 3],
+        name: String,
+    },
+    /// Put the metadata over the current metadata of the atoms, see
+    /// `AtomMetadata::overlay`.
+    SetMetadata {
+        atoms: Vec<(SelectOne, AtomMetadata)>,
+    },
+    /// Set the total charge and spin multiplicity of the molecule, which are
+    /// used by the `gjf` and `orca` writers. Fields not given are unchanged.
+    SetCharge {
+        #[serde(default)]
+        charge: Option<i32>,
+        #[serde(default)]
+        multiplicity: Option<u32>,
+    },
+    /// Set the tags of the selected atoms in the metadata, and remove the tags
+    /// of the keys in `remove`, e.g. colors and notes for visualization. The
+    /// tags `b_factor` and `occupancy` are written to the columns of PDB files.
+    Annotate {
+        select: SelectMany,
+        #[serde(default)]
+        tags: BTreeMap<String, String>,
+        #[serde(default)]
+        remove: Vec<String>,
+    },
+    /// Add the atoms matching the structural criteria to the groups like
+    /// `GroupMap`, see `StructuralGroup`. The criteria are evaluated once when
+    /// the layer is applied, so later steps can select the atoms by the group
+    /// names even if the structure is changed.
+    StructuralGroups {
+        groups: Vec<(String, StructuralGroup)>,
+    },
+    /// Superimpose the structure onto the reference by the rotation and
+    /// translation minimizing the RMSD of the mapped atoms (Kabsch), pairs of
+    /// an atom of the structure and an atom of the reference. The atoms with
+    /// the same indexes in both structures are paired if `mapping` is empty,
+    /// e.g. conformers aligned onto one of them.
+    AlignTo {
+        reference: SparseMolecule,
+        #[serde(default)]
+        mapping: Vec<(SelectOne, SelectOne)>,
+    },
+    /// Translate the molecule so its center of mass is at the origin, see
+    /// `SparseMolecule::center_of_mass`.
+    CenterOfMassToOrigin,
+    /// Add single bonds between the selected atoms closer than the sum of their
+    /// covalent radii plus `tolerance` (0.45 Angstrom by default), e.g. for
+    /// structures read from XYZ files. Existing bonds are kept, and atoms of
+    /// elements without covalent radius are not bonded. If `orders` is set, the
+    /// orders of the single bonds between the selected atoms are then assigned
+    /// from the geometry, see `utils::bond_orders::assign_bond_orders`.
+    PerceiveBonds {
+        #[serde(default)]
+        select: SelectMany,
+        #[serde(default = "bond_tolerance")]
+        tolerance: f64,
+        #[serde(default)]
+        orders: bool,
+    },
+    /// Set the partial charges of the atoms to the Gasteiger-Marsili charges,
+    /// see `utils::charges::gasteiger_charges`, e.g. for mol2 files.
+    GasteigerCharges {
+        #[serde(default = "gasteiger_iterations")]
+        iterations: usize,
+    },
+    /// Translate the selected atoms so their center of mass is at `center`,
+    /// see `SelectMany::center_of_mass`. Nothing is changed if no atom is
+    /// selected.
+    CenterOfMassAt {
+        #[serde(default)]
+        select: SelectMany,
+        #[serde(default)]
+        #[bincode(with_serde)]
+        #[schemars(with = "[f64; 3]")]
+        center: Point3<f64>,
+    },
+    /// Rotate the molecule about the center of mass of the selected atoms so
+    /// their principal axes of inertia (see `SelectMany::principal_axes`) are
+    /// the x, y and z axes, from the smallest moment to the largest, and move
+    /// the center of mass to the origin, e.g. reproducible orientations for
+    /// grids and figures. Nothing is changed if no atom is selected.
+    PrincipalAxesAlign {
+        #[serde(default)]
+        select: SelectMany,
+    },
+    /// Replace the whole molecule by `data`, including the ids, groups, atom
+    /// metadata, charge and multiplicity, which `Fill` keeps if `data` doesn't
+    /// have them, e.g. to switch to another base structure.
+    Replace {
+        data: SparseMolecule,
+    },
+}
+
+fn x_axis() -> Vector3<f64> {
+    Vector3::x()
+}
+
+fn bond_tolerance() -> f64 {
+    0.45
+}
+
+fn gasteiger_iterations() -> usize {
+    6
+}
+
+/// Serialized form of Isometry3, the rotation is the unit quaternion as [i, j, k, w].
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+struct IsometrySchema {
+    rotation: [f64; 4],
+    translation: [f64; 3],
 }
 
 impl Default for Layer {
@@ -171,7 +422,157 @@ impl Layer {
             }
             Self::SetAtom { atoms } => {
                 for (select, atom) in atoms {
-                    select.set_atom(&mut current, atom.clone());
+                    select.set_atom(&mut current, *atom);
+                }
+            }
+            Self::SetCharge {
+                charge,
+                multiplicity,
+            } => {
+                current.charge = charge.or(current.charge);
+                current.multiplicity = multiplicity.or(current.multiplicity);
+            }
+            Self::Annotate {
+                select,
+                tags,
+                remove,
+            } => {
+                for index in select.to_indexes(&current) {
+                    if current.atoms.read_atom(index).is_none() {
+                        continue;
+                    }
+                    current.atoms.set_metadata(
+                        index,
+                        AtomMetadata {
+                            tags: tags.clone(),
+                            ..Default::default()
+                        },
+                    );
+                    current.atoms.remove_tags(index, remove);
+                }
+            }
+            Self::StructuralGroups { groups } => {
+                for (name, group) in groups {
+                    let selects = group
+                        .to_indexes(&current)?
+                        .into_iter()
+                        .map(|index| (name.to_string(), index));
+                    current
+                        .groups
+                        .get_or_insert_with(GroupName::new)
+                        .extend(selects);
+                }
+            }
+            Self::AlignTo { reference, mapping } => {
+                let pairs = if mapping.is_empty() {
+                    current
+                        .atoms
+                        .data()
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(index, atom)| {
+                            let target = reference.atoms.read_atom(index)?;
+                            Some((atom.as_ref()?.position, target.position))
+                        })
+                        .collect::<Vec<_>>()
+                } else {
+                    mapping
+                        .iter()
+                        .map(|(atom, target)| {
+                            let atom = atom.get_atom(&current).ok_or(atom.clone())?;
+                            let target = target.get_atom(reference).ok_or(target.clone())?;
+                            Ok((atom.position, target.position))
+                        })
+                        .collect::<Result<Vec<_>, SelectOne>>()?
+                };
+                if !pairs.is_empty() {
+                    let (from, to) = pairs.into_iter().unzip::<_, _, Vec<_>, Vec<_>>();
+                    let selected = SelectMany::All.to_indexes(&current);
+                    current.atoms.isometry(kabsch(&from, &to), &selected);
+                }
+            }
+            Self::CenterOfMassToOrigin => {
+                if let Some(center) = current.center_of_mass() {
+                    current = Self::Translation {
+                        select: SelectMany::All,
+                        vector: -center.coords,
+                    }
+                    .filter(current)?;
+                }
+            }
+            Self::CenterOfMassAt { select, center } => {
+                if let Some(current_center) = select.center_of_mass(&current) {
+                    current = Self::Translation {
+                        select: select.clone(),
+                        vector: center - current_center,
+                    }
+                    .filter(current)?;
+                }
+            }
+            Self::Replace { data } => current = data.clone(),
+            Self::PrincipalAxesAlign { select } => {
+                if let (Some(center), Some((_, axes))) = (
+                    select.center_of_mass(&current),
+                    select.principal_axes(&current),
+                ) {
+                    // Rows of the rotation are the principal axes
+                    let rotation = UnitQuaternion::from_rotation_matrix(
+                        &Rotation3::from_matrix_unchecked(axes.transpose()),
+                    );
+                    let isometry = Isometry3::from_parts(
+                        Translation3::from(-(rotation * center.coords)),
+                        rotation,
+                    );
+                    let selected = SelectMany::All.to_indexes(&current);
+                    current.atoms.isometry(isometry, &selected);
+                }
+            }
+            Self::PerceiveBonds {
+                select,
+                tolerance,
+                orders,
+            } => {
+                let selected = select.to_indexes(&current);
+                let (atoms, radii): (Vec<_>, Vec<_>) = selected
+                    .iter()
+                    .copied()
+                    .filter_map(|index| {
+                        let atom = current.atoms.read_atom(index)?;
+                        Some(((index, atom.position), covalent_radius(atom.element)?))
+                    })
+                    .unzip();
+                let r_max = radii.iter().copied().fold(0., f64::max);
+                let positions = atoms
+                    .iter()
+                    .map(|(_, position)| *position)
+                    .collect::<Vec<_>>();
+                for (a, b) in KdTree::new(&positions).pairs_within(2. * r_max + tolerance) {
+                    let ((a_index, a_position), (b_index, b_position)) = (atoms[a], atoms[b]);
+                    if (a_position - b_position).norm() <= radii[a] + radii[b] + tolerance
+                        && current.bonds.read_bond(a_index, b_index).is_none()
+                    {
+                        current.bonds.set_bond(a_index, b_index, Some(1.));
+                    }
+                }
+                if *orders {
+                    assign_bond_orders(&mut current, &selected);
+                }
+            }
+            Self::GasteigerCharges { iterations } => {
+                for (index, charge) in gasteiger_charges(&current, *iterations) {
+                    current.atoms.set_metadata(
+                        index,
+                        AtomMetadata {
+                            partial_charge: Some(charge),
+                            ..Default::default()
+                        },
+                    );
+                }
+            }
+            Self::SetMetadata { atoms } => {
+                for (select, metadata) in atoms {
+                    let index = select.to_index(&current).ok_or(select.clone())?;
+                    current.atoms.set_metadata(index, metadata.clone());
                 }
             }
             Self::UpdateFormalCharge { charges } => {
@@ -424,12 +825,335 @@ impl Layer {
 
                 current.atoms.migrate(SparseAtomList::from(atoms));
             }
+            Self::FromSmiles { smiles } => {
+                let data = parse_smiles(smiles)
+                    .map_err(|err| LayerStorageError::InvalidSmiles(format!("{:#}", err)))?;
+                current.migrate(data);
+            }
+            Self::SetDihedral {
+                a,
+                b,
+                c,
+                d,
+                select,
+                angle,
+                degree,
+            } => {
+                let coincident = || LayerStorageError::CoincidentAtoms(b.clone(), c.clone());
+                let [a, b, c, d] = [a, b, c, d].map(|select| {
+                    select
+                        .get_atom(&current)
+                        .map(|atom| atom.position)
+                        .ok_or(select.clone())
+                });
+                let (a, b, c, d) = (a?, b?, c?, d?);
+                let axis = (c - b).try_normalize(1e-8).ok_or_else(coincident)?;
+                let target = if *degree { *angle } else { angle * 180. / PI };
+                current = Self::Rotation {
+                    select: select.clone(),
+                    center: b,
+                    axis,
+                    angle: target - dihedral_angle(&a, &b, &c, &d),
+                    degree: true,
+                }
+                .filter(current)?;
+            }
+            Self::SetBondLength {
+                a,
+                b,
+                select,
+                length,
+            } => {
+                let a_position = a.get_atom(&current).ok_or(a.clone())?.position;
+                let b_position = b.get_atom(&current).ok_or(b.clone())?.position;
+                let bond = b_position - a_position;
+                let direction = bond
+                    .try_normalize(1e-8)
+                    .ok_or_else(|| LayerStorageError::CoincidentAtoms(a.clone(), b.clone()))?;
+                current = Self::Translation {
+                    select: select.clone(),
+                    vector: direction * (length - bond.norm()),
+                }
+                .filter(current)?;
+            }
+            Self::SetAngle {
+                a,
+                b,
+                c,
+                select,
+                angle,
+                degree,
+            } => {
+                let a = a.get_atom(&current).ok_or(a.clone())?.position;
+                let b_position = b.get_atom(&current).ok_or(b.clone())?.position;
+                let c = c.get_atom(&current).ok_or(c.clone())?.position;
+                let (ba, bc) = (a - b_position, c - b_position);
+                let axis = ba.cross(&bc);
+                // a, b and c are collinear, any axis perpendicular to b-c works
+                let axis = if axis.norm() < 1e-8 {
+                    let axis = bc.cross(&Vector3::x());
+                    if axis.norm() < 1e-8 {
+                        bc.cross(&Vector3::y())
+                    } else {
+                        axis
+                    }
+                } else {
+                    axis
+                };
+                let target = if *degree { angle * PI / 180. } else { *angle };
+                current = Self::Rotation {
+                    select: select.clone(),
+                    center: b_position,
+                    axis: axis.normalize(),
+                    angle: target - ba.angle(&bc),
+                    degree: false,
+                }
+                .filter(current)?;
+            }
+            Self::AddHydrogens { select } => {
+                let selected = select.to_indexes(&current);
+                add_hydrogens(&mut current, &selected);
+            }
+            Self::Coordinate {
+                metal,
+                geometry,
+                ligands,
+            } => {
+                let metal_index = metal.to_index(&current).ok_or(metal.clone())?;
+                let center = metal.get_atom(&current).ok_or(metal.clone())?.position;
+                let sites = geometry.sites();
+                for ligand in ligands {
+                    let direction =
+                        sites
+                            .get(ligand.site)
+                            .ok_or(LayerStorageError::InvalidSite {
+                                site: ligand.site,
+                                sites: sites.len(),
+                            })?;
+                    let donor = ligand
+                        .donor
+                        .to_index(&ligand.data)
+                        .ok_or(ligand.donor.clone())?;
+                    let (data, donors) = if let Some(chelate) = &ligand.chelate {
+                        let second_direction =
+                            sites
+                                .get(chelate.site)
+                                .ok_or(LayerStorageError::InvalidSite {
+                                    site: chelate.site,
+                                    sites: sites.len(),
+                                })?;
+                        let second = chelate
+                            .donor
+                            .to_index(&ligand.data)
+                            .ok_or(chelate.donor.clone())?;
+                        let data = place_chelate(
+                            &ligand.data,
+                            [donor, second],
+                            center,
+                            [*direction, *second_direction],
+                            ligand.distance,
+                        )
+                        .ok_or(chelate.donor.clone())?;
+                        (data, vec![donor, second])
+                    } else {
+                        let data =
+                            place_ligand(&ligand.data, donor, center, *direction, ligand.distance)
+                                .ok_or(ligand.donor.clone())?;
+                        (data, vec![donor])
+                    };
+                    let offset = current.len();
+                    current = Self::Append {
+                        name: ligand.name.clone(),
+                        data,
+                    }
+                    .filter(current)?;
+                    for donor in donors {
+                        current
+                            .bonds
+                            .set_bond(metal_index, offset + donor, Some(1.));
+                    }
+                }
+            }
+            Self::RemoveHydrogens { select } => {
+                let hydrogens = bonded_hydrogens(&current, &select.to_indexes(&current));
+                current = Self::RemoveAtoms {
+                    select: SelectMany::Indexes(
+                        hydrogens.into_iter().map(SelectOne::Index).collect(),
+                    ),
+                }
+                .filter(current)?;
+            }
+            Self::Oniom { select, level } => {
+                let selected = select.to_indexes(&current);
+                let groups = current.groups.get_or_insert_with(GroupName::new);
+                for index in selected {
+                    for other in OniomLevel::ALL {
+                        groups.remove(other.group_name(), &index);
+                    }
+                    groups.insert(level.group_name().to_string(), index);
+                }
+            }
+            Self::QmSubsystem { select, scale } => {
+                let selected = select.to_indexes(&current);
+                let links = cap_qm_region(&mut current, &selected, *scale);
+                let outside = (0..current.len())
+                    .filter(|index| !selected.contains(index) && !links.contains(index))
+                    .map(SelectOne::Index)
+                    .collect();
+                current = Self::RemoveAtoms {
+                    select: SelectMany::Indexes(outside),
+                }
+                .filter(current)?;
+                current
+                    .groups
+                    .get_or_insert_with(GroupName::new)
+                    .insert_left(LINK_ATOMS_GROUP.to_string(), links.into_iter());
+            }
+            Self::Duplicate {
+                select,
+                name,
+                copies,
+                center,
+                axis,
+                angle,
+                degree,
+                translation,
+            } => {
+                let selected = select
+                    .to_indexes(&current)
+                    .into_iter()
+                    .filter(|index| current.atoms.read_atom(*index).is_some())
+                    .collect::<Vec<_>>();
+                let fragment_index = |index: &usize| selected.binary_search(index).ok();
+                let mut fragment = SparseMolecule {
+                    atoms: SparseAtomList::from(
+                        selected
+                            .iter()
+                            .filter_map(|index| current.atoms.read_atom(*index))
+                            .collect::<Vec<_>>(),
+                    ),
+                    ..Default::default()
+                };
+                for (a, index) in selected.iter().enumerate() {
+                    for neighbor in current.neighbors(*index) {
+                        if let (Some(b), Some(bond)) = (
+                            fragment_index(&neighbor),
+                            current.bonds.read_bond(*index, neighbor),
+                        ) {
+                            fragment.bonds.set_bond(a, b, Some(bond));
+                        }
+                    }
+                }
+                fragment.ids = current.ids.as_ref().map(|ids| {
+                    ids.iter()
+                        .filter_map(|(id, index)| Some((id.to_string(), fragment_index(index)?)))
+                        .collect()
+                });
+                fragment.groups = current.groups.as_ref().map(|groups| {
+                    GroupName::from_iter(groups.data().iter().filter_map(|(group, index)| {
+                        Some((group.to_string(), fragment_index(index)?))
+                    }))
+                });
+                let angle = match angle {
+                    Some(angle) if *degree => angle.to_radians(),
+                    Some(angle) => *angle,
+                    None => 2. * PI / (*copies + 1) as f64,
+                };
+                let axis = Unit::new_normalize(*axis);
+                for copy in 1..=*copies {
+                    let rotation = UnitQuaternion::from_axis_angle(&axis, angle * copy as f64);
+                    let isometry = Translation3::from(translation * copy as f64)
+                        * Isometry3::rotation_wrt_point(rotation, *center);
+                    let mut data = fragment.clone();
+                    data.atoms
+                        .isometry(isometry, &(0..selected.len()).collect());
+                    current = Self::Append {
+                        name: format!("{}_{}", name, copy),
+                        data,
+                    }
+                    .filter(current)?;
+                }
+            }
+            Self::Supercell {
+                lattice,
+                size,
+                name,
+            } => {
+                let cell = current.clone();
+                let cell_size = cell.len();
+                let [na, nb, nc] = size.map(|n| n.max(1) as isize);
+                let images = (0..na)
+                    .flat_map(|i| (0..nb).flat_map(move |j| (0..nc).map(move |k| [i, j, k])))
+                    .collect::<Vec<_>>();
+                let shift = |[i, j, k]: [isize; 3]| {
+                    lattice[0] * i as f64 + lattice[1] * j as f64 + lattice[2] * k as f64
+                };
+                for image in &images[1..] {
+                    let mut data = SparseMolecule {
+                        bonds: Default::default(),
+                        ..cell.clone()
+                    };
+                    data.atoms.isometry(
+                        Isometry3::from(Translation3::from(shift(*image))),
+                        &(0..cell_size).collect(),
+                    );
+                    current = Self::Append {
+                        name: format!("{}_{}_{}_{}", name, image[0], image[1], image[2]),
+                        data,
+                    }
+                    .filter(current)?;
+                }
+                let inside = |[i, j, k]: [isize; 3]| {
+                    (0..na).contains(&i) && (0..nb).contains(&j) && (0..nc).contains(&k)
+                };
+                let image_index = |[i, j, k]: [isize; 3]| ((i * nb + j) * nc + k) as usize;
+                for a in 0..cell_size {
+                    let Some(atom_a) = cell.atoms.read_atom(a) else {
+                        continue;
+                    };
+                    for b in cell.neighbors(a).into_iter().filter(|b| *b > a) {
+                        let (Some(atom_b), Some(bond)) =
+                            (cell.atoms.read_atom(b), cell.bonds.read_bond(a, b))
+                        else {
+                            continue;
+                        };
+                        let mut nearest = [0; 3];
+                        let mut distance = (atom_b.position - atom_a.position).norm();
+                        for direction in (-1..=1).flat_map(|i| {
+                            (-1..=1).flat_map(move |j| (-1..=1).map(move |k| [i, j, k]))
+                        }) {
+                            let candidate =
+                                (atom_b.position + shift(direction) - atom_a.position).norm();
+                            if candidate < distance - 1e-8 {
+                                nearest = direction;
+                                distance = candidate;
+                            }
+                        }
+                        for image in &images {
+                            let target = [0, 1, 2].map(|axis| image[axis] + nearest[axis]);
+                            let from = image_index(*image) * cell_size + a;
+                            if nearest != [0; 3] && image_index(*image) == 0 {
+                                current.bonds.set_bond(from, b, None);
+                            }
+                            if inside(target) {
+                                current.bonds.set_bond(
+                                    from,
+                                    image_index(target) * cell_size + b,
+                                    Some(bond),
+                                );
+                            }
+                        }
+                    }
+                }
+            }
         }
         Ok(current)
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, PartialOrd, Ord, Eq, Encode, Decode)]
+#[derive(
+    Debug, Clone, PartialEq, Serialize, Deserialize, PartialOrd, Ord, Eq, Encode, Decode, JsonSchema,
+)]
 #[serde(untagged)]
 pub enum SelectOne {
     Index(usize),
@@ -471,11 +1195,11 @@ impl SelectOne {
 
     pub fn set_atom(&self, layer: &mut SparseMolecule, atom: Option<Atom3D>) -> Option<()> {
         self.to_index(layer)
-            .and_then(|index| Some(layer.atoms.set_atoms(index, vec![atom])))
+            .map(|index| layer.atoms.set_atoms(index, vec![atom]))
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode, Default)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode, Default, JsonSchema)]
 #[serde(untagged)]
 pub enum SelectMany {
     #[default]
@@ -489,6 +1213,27 @@ pub enum SelectMany {
     Indexes(BTreeSet<SelectOne>),
     Range(RangeInclusive<usize>),
     GroupName(String),
+    /// Atoms within `radius` angstrom of the center atom (itself included), or
+    /// the other atoms if `outside` is set, e.g. a solvation shell
+    WithinRadius {
+        center: SelectOne,
+        radius: f64,
+        #[serde(default)]
+        outside: bool,
+    },
+    /// Atoms with an id matched by the regular expression, which may match a
+    /// part of the id unless anchored with `^` and `$`. An invalid expression
+    /// is an error when the selection is loaded.
+    IdRegex {
+        #[schemars(with = "String")]
+        id_regex: Pattern,
+    },
+    /// Atoms in groups whose names are matched by the regular expression, like
+    /// `IdRegex`
+    GroupRegex {
+        #[schemars(with = "String")]
+        group_regex: Pattern,
+    },
 }
 
 impl SelectMany {
@@ -507,13 +1252,47 @@ impl SelectMany {
             Self::GroupName(group_name) => layer
                 .groups
                 .as_ref()
-                .map(|groups| groups.get_left(group_name).into_iter().copied().collect())
+                .map(|groups| groups.get_left(group_name).copied().collect())
                 .unwrap_or_default(),
             Self::Indexes(indexes) => indexes
                 .iter()
                 .filter_map(|select| select.to_index(layer))
                 .collect(),
             Self::Range(range) => range.clone().collect(),
+            Self::WithinRadius {
+                center,
+                radius,
+                outside,
+            } => {
+                let Some(center) = center.get_atom(layer) else {
+                    return BTreeSet::new();
+                };
+                (0..layer.atoms.len())
+                    .filter(|index| {
+                        layer
+                            .atoms
+                            .read_atom(*index)
+                            .map(|atom| {
+                                ((atom.position - center.position).norm() <= *radius) != *outside
+                            })
+                            .unwrap_or_default()
+                    })
+                    .collect()
+            }
+            Self::IdRegex { id_regex } => layer
+                .ids
+                .iter()
+                .flatten()
+                .filter(|(id, _)| id_regex.is_match(id))
+                .map(|(_, index)| *index)
+                .collect(),
+            Self::GroupRegex { group_regex } => layer
+                .groups
+                .iter()
+                .flat_map(|groups| groups.data().iter())
+                .filter(|(group, _)| group_regex.is_match(group))
+                .map(|(_, index)| *index)
+                .collect(),
             Self::Complex { includes, excludes } => {
                 let mut selected = BTreeSet::new();
                 for include in includes {
@@ -527,6 +1306,156 @@ impl SelectMany {
             }
         }
     }
+
+    /// Geometric center of the selected atoms, `None` if no atom is selected.
+    pub fn centroid(&self, layer: &SparseMolecule) -> Option<Point3<f64>> {
+        self.weighted_center(layer, |_| Some(1.))
+    }
+
+    /// Center of mass of the selected atoms weighted by the atomic masses (see
+    /// `atomic_mass`), `None` if no atom with mass is selected.
+    pub fn center_of_mass(&self, layer: &SparseMolecule) -> Option<Point3<f64>> {
+        self.weighted_center(layer, |atom| atomic_mass(atom.element))
+    }
+
+    /// Principal moments of inertia of the selected atoms about their center
+    /// of mass in ascending order, and the principal axes as the columns of a
+    /// right-handed rotation matrix. The sign of the first two axes is chosen
+    /// so the third moment of the masses along them is not negative, making the
+    /// axes reproducible for the same structure. `None` if no atom with mass is
+    /// selected.
+    pub fn principal_axes(&self, layer: &SparseMolecule) -> Option<(Vector3<f64>, Matrix3<f64>)> {
+        let center = self.center_of_mass(layer)?;
+        let atoms = self
+            .to_indexes(layer)
+            .into_iter()
+            .filter_map(|index| layer.atoms.read_atom(index))
+            .filter(|atom| validated_element_num(atom.element))
+            .filter_map(|atom| Some((atomic_mass(atom.element)?, atom.position - center)))
+            .collect::<Vec<_>>();
+        let tensor = atoms.iter().fold(Matrix3::zeros(), |tensor, (mass, r)| {
+            tensor + (Matrix3::identity() * r.norm_squared() - r * r.transpose()) * *mass
+        });
+        let eigen = SymmetricEigen::new(tensor);
+        let mut order = [0, 1, 2];
+        order.sort_by(|a, b| eigen.eigenvalues[*a].total_cmp(&eigen.eigenvalues[*b]));
+        let moments = Vector3::from_fn(|row, _| eigen.eigenvalues[order[row]]);
+        let mut axes = Matrix3::from_fn(|row, column| eigen.eigenvectors[(row, order[column])]);
+        for column in 0..2 {
+            let skewness = atoms
+                .iter()
+                .map(|(mass, r)| mass * r.dot(&axes.column(column)).powi(3))
+                .sum::<f64>();
+            if skewness < 0. {
+                axes.set_column(column, &-axes.column(column));
+            }
+        }
+        let z = axes.column(0).cross(&axes.column(1));
+        axes.set_column(2, &z);
+        Some((moments, axes))
+    }
+
+    fn weighted_center(
+        &self,
+        layer: &SparseMolecule,
+        weight: impl Fn(&Atom3D) -> Option<f64>,
+    ) -> Option<Point3<f64>> {
+        let (total, sum) = self
+            .to_indexes(layer)
+            .into_iter()
+            .filter_map(|index| layer.atoms.read_atom(index))
+            .filter(|atom| validated_element_num(atom.element))
+            .fold((0., Vector3::zeros()), |(total, sum), atom| {
+                let weight = weight(&atom).unwrap_or_default();
+                (total + weight, sum + atom.position.coords * weight)
+            });
+        (total > 0.).then(|| Point3::from(sum / total))
+    }
+}
+
+/// Regular expression of the selections, compiled once when it's loaded and
+/// stored as the pattern string.
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn new(pattern: &str) -> anyhow::Result<Self> {
+        Ok(Self(Regex::new(pattern)?))
+    }
+
+    /// Names failed to be matched (e.g. the backtrack limit is exceeded) are
+    /// not matched.
+    fn is_match(&self, name: &str) -> bool {
+        self.0.is_match(name).unwrap_or_default()
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Self::new(&pattern).map_err(|err| {
+            D::Error::custom(format!("Invalid regular expression {:?}: {}", pattern, err))
+        })
+    }
+}
+
+impl Encode for Pattern {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.0.as_str().encode(encoder)
+    }
+}
+
+impl Decode for Pattern {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Self::new(&String::decode(decoder)?)
+            .map_err(|_| DecodeError::Other("invalid regular expression of selection"))
+    }
+}
+
+impl_borrow_decode!(Pattern);
+
+/// First byte of a versioned layer in the layer database. The bincode variant
+/// index of legacy layers is always smaller than it, so the two can be told apart.
+const LAYER_FORMAT_TAG: u8 = 250;
+
+/// Version of the binary layer format, increase it and add a migration in
+/// `crate::migration` when the bincode layout of Layer changes.
+pub const LAYER_FORMAT_VERSION: u8 = 3;
+
+impl Layer {
+    /// Decode a layer stored in the layer database, in the current or an old
+    /// format version, an error if the bytes are damaged.
+    pub fn decode(data: &[u8]) -> anyhow::Result<Layer> {
+        let config = bincode::config::standard();
+        Ok(match data {
+            [LAYER_FORMAT_TAG, version, payload @ ..] => match *version {
+                LAYER_FORMAT_VERSION => bincode::decode_from_slice(payload, config)?.0,
+                1 | 2 => {
+                    with_layer_format(*version, || bincode::decode_from_slice(payload, config))?.0
+                }
+                version => Err(anyhow::anyhow!(
+                    "Layer format version {} is not supported, current version is {}",
+                    version,
+                    LAYER_FORMAT_VERSION
+                ))?,
+            },
+            legacy => bincode::decode_from_slice::<LayerV0, _>(legacy, config)?
+                .0
+                .into(),
+        })
+    }
 }
 
 impl Value for Layer {
@@ -537,20 +1466,22 @@ impl Value for Layer {
         None
     }
 
+    /// Layers without the version tag are written before the tag is introduced
+    /// and will be decoded as LayerV0 then migrated. Panics if the layer is
+    /// unable to be decoded, see `Layer::decode`.
     fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
     where
         Self: 'a,
     {
-        bincode::decode_from_slice(data, bincode::config::standard())
-            .unwrap()
-            .0
+        Layer::decode(data).unwrap_or_else(|err| panic!("{}", err))
     }
 
     fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
     where
         Self: 'b,
     {
-        bincode::encode_to_vec(value, bincode::config::standard()).unwrap()
+        let payload = bincode::encode_to_vec(value, bincode::config::standard()).unwrap();
+        [vec![LAYER_FORMAT_TAG, LAYER_FORMAT_VERSION], payload].concat()
     }
 
     fn type_name() -> redb::TypeName {
@@ -563,6 +1494,9 @@ pub enum LayerStorageError {
     NoSuchLayer(u64),
     SelectNotFound(SelectOne),
     HideOverflow { idx: usize, current_value: usize },
+    InvalidSmiles(String),
+    InvalidSite { site: usize, sites: usize },
+    CoincidentAtoms(SelectOne, SelectOne),
 }
 
 impl From<SelectOne> for LayerStorageError {
@@ -587,3 +1521,478 @@ impl std::fmt::Display for LayerStorageError {
 }
 
 impl std::error::Error for LayerStorageError {}
+
+#[test]
+fn set_dihedral() {
+    let atom = |x, y, z| Atom3D {
+        element: 6,
+        position: Point3::new(x, y, z),
+        formal_charge: 0.,
+    };
+    let butane = SparseMolecule {
+        atoms: SparseAtomList::from(vec![
+            atom(1., 0., 0.),
+            atom(0., 0., 0.),
+            atom(0., 0., 1.5),
+            atom(1., 0., 1.5),
+        ]),
+        ..Default::default()
+    };
+    let layer = Layer::SetDihedral {
+        a: SelectOne::Index(0),
+        b: SelectOne::Index(1),
+        c: SelectOne::Index(2),
+        d: SelectOne::Index(3),
+        select: SelectMany::Indexes(BTreeSet::from([SelectOne::Index(3)])),
+        angle: -60.,
+        degree: true,
+    };
+    let updated = layer.filter(butane.clone()).unwrap();
+    let position = |index| updated.atoms.read_atom(index).unwrap().position;
+    let angle = dihedral_angle(&position(0), &position(1), &position(2), &position(3));
+    assert!((angle + 60.).abs() < 1e-8);
+    assert!(((position(3) - position(2)).norm() - 1.).abs() < 1e-8);
+    let degenerate = Layer::SetDihedral {
+        a: SelectOne::Index(0),
+        b: SelectOne::Index(1),
+        c: SelectOne::Index(1),
+        d: SelectOne::Index(3),
+        select: SelectMany::Indexes(BTreeSet::from([SelectOne::Index(3)])),
+        angle: -60.,
+        degree: true,
+    };
+    assert!(matches!(
+        degenerate.filter(butane),
+        Err(LayerStorageError::CoincidentAtoms(..))
+    ));
+}
+
+#[test]
+fn set_bond_length() {
+    let atom = |x| Atom3D {
+        element: 6,
+        position: Point3::new(x, 0., 0.),
+        formal_charge: 0.,
+    };
+    let ethane = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(0.), atom(1.2), atom(2.4)]),
+        ..Default::default()
+    };
+    let layer = Layer::SetBondLength {
+        a: SelectOne::Index(0),
+        b: SelectOne::Index(1),
+        select: SelectMany::Range(1..=2),
+        length: 1.5,
+    };
+    let updated = layer.filter(ethane.clone()).unwrap();
+    let x = |index| updated.atoms.read_atom(index).unwrap().position.x;
+    assert!((x(1) - 1.5).abs() < 1e-8);
+    assert!((x(2) - 2.7).abs() < 1e-8);
+    let same = Layer::SetBondLength {
+        a: SelectOne::Index(1),
+        b: SelectOne::Index(1),
+        select: SelectMany::Range(1..=2),
+        length: 1.5,
+    };
+    assert!(matches!(
+        same.filter(ethane),
+        Err(LayerStorageError::CoincidentAtoms(..))
+    ));
+}
+
+#[test]
+fn set_angle() {
+    let atom = |x, y| Atom3D {
+        element: 6,
+        position: Point3::new(x, y, 0.),
+        formal_charge: 0.,
+    };
+    let propane = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(1., 0.), atom(0., 0.), atom(0., 1.5)]),
+        ..Default::default()
+    };
+    let layer = Layer::SetAngle {
+        a: SelectOne::Index(0),
+        b: SelectOne::Index(1),
+        c: SelectOne::Index(2),
+        select: SelectMany::Range(2..=2),
+        angle: 109.5,
+        degree: true,
+    };
+    let updated = layer.filter(propane).unwrap();
+    let position = |index| updated.atoms.read_atom(index).unwrap().position;
+    let angle = (position(0) - position(1))
+        .angle(&(position(2) - position(1)))
+        .to_degrees();
+    assert!((angle - 109.5).abs() < 1e-8);
+    assert!(((position(2) - position(1)).norm() - 1.5).abs() < 1e-8);
+}
+
+#[test]
+fn select_within_radius() {
+    let atom = |x| Atom3D {
+        element: 8,
+        position: Point3::new(x, 0., 0.),
+        formal_charge: 0.,
+    };
+    let waters = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(0.), atom(2.8), atom(5.6), atom(8.4)]),
+        ..Default::default()
+    };
+    let shell: SelectMany = serde_json::from_str(r#"{"center": 1, "radius": 3.0}"#).unwrap();
+    assert_eq!(shell.to_indexes(&waters), BTreeSet::from([0, 1, 2]));
+    let bulk = SelectMany::WithinRadius {
+        center: SelectOne::Index(1),
+        radius: 3.0,
+        outside: true,
+    };
+    assert_eq!(bulk.to_indexes(&waters), BTreeSet::from([3]));
+}
+
+#[test]
+fn select_by_regex() {
+    let atom = |x| Atom3D {
+        element: 15,
+        position: Point3::new(x, 0., 0.),
+        formal_charge: 0.,
+    };
+    let molecule = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(0.), atom(1.), atom(2.)]),
+        ids: Some(BTreeMap::from([
+            ("ligand_1_P".to_string(), 0),
+            ("ligand_2_P".to_string(), 1),
+            ("ligand_2_Pd".to_string(), 2),
+        ])),
+        ..Default::default()
+    };
+    let molecule = Layer::GroupMap {
+        groups: vec![
+            ("ligand_1".to_string(), SelectMany::Range(0..=0)),
+            ("ligand_2".to_string(), SelectMany::Range(1..=2)),
+        ],
+    }
+    .filter(molecule)
+    .unwrap();
+    let phosphines: SelectMany = serde_json::from_str(r#"{"id_regex": "^ligand_.*_P$"}"#).unwrap();
+    assert_eq!(phosphines.to_indexes(&molecule), BTreeSet::from([0, 1]));
+    let ligand = SelectMany::GroupRegex {
+        group_regex: Pattern::new("_2$").unwrap(),
+    };
+    assert_eq!(ligand.to_indexes(&molecule), BTreeSet::from([1, 2]));
+    assert!(serde_json::from_str::<SelectMany>(r#"{"id_regex": "("}"#).is_err());
+    let layer = Layer::RemoveAtoms { select: ligand };
+    assert_eq!(Layer::decode(&Layer::as_bytes(&layer)).unwrap(), layer);
+}
+
+#[test]
+fn duplicate_with_rotation() {
+    let atom = |element, x, z| Atom3D {
+        element,
+        position: Point3::new(x, 0., z),
+        formal_charge: 0.,
+    };
+    // a metal on the z axis and one arm of a C3 symmetric ligand
+    let mut molecule = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(26, 0., 0.), atom(7, 2., 0.), atom(6, 3., 0.5)]),
+        ids: Some(BTreeMap::from([("N".to_string(), 1)])),
+        ..Default::default()
+    };
+    molecule.bonds.set_bond(1, 2, Some(1.));
+    let layer = Layer::Duplicate {
+        select: SelectMany::Range(1..=2),
+        name: "arm".to_string(),
+        copies: 2,
+        center: Point3::origin(),
+        axis: Vector3::z(),
+        angle: None,
+        degree: false,
+        translation: Vector3::zeros(),
+    };
+    let complex = layer.filter(molecule).unwrap();
+    assert_eq!(complex.len(), 7);
+    let nitrogen = SelectOne::IdName("arm_2_N".to_string())
+        .get_atom(&complex)
+        .unwrap();
+    assert_eq!(nitrogen.element, 7);
+    let expected = Point3::new(2. * (4. * PI / 3.).cos(), 2. * (4. * PI / 3.).sin(), 0.);
+    assert!((nitrogen.position - expected).norm() < 1e-8);
+    assert_eq!(complex.neighbors(3), vec![4]);
+    assert_eq!(
+        SelectMany::GroupName("arm_1".to_string()).to_indexes(&complex),
+        BTreeSet::from([3, 4])
+    );
+}
+
+#[test]
+fn supercell_remaps_periodic_bonds() {
+    let atom = |x| Atom3D {
+        element: 6,
+        position: Point3::new(x, 0., 0.),
+        formal_charge: 0.,
+    };
+    // a periodic carbon chain, the bond 1-0 crosses the cell boundary
+    let mut molecule = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(0.), atom(1.5)]),
+        ids: Some(BTreeMap::from([("C1".to_string(), 0)])),
+        ..Default::default()
+    };
+    molecule.bonds.set_bond(0, 1, Some(1.));
+    molecule.bonds.set_bond(1, 0, Some(1.));
+    let lattice = [
+        Vector3::new(3., 0., 0.),
+        Vector3::y() * 10.,
+        Vector3::z() * 10.,
+    ];
+    // the bond is within the cell, so it is kept in every image
+    let chain = Layer::Supercell {
+        lattice,
+        size: [3, 1, 1],
+        name: "cell".to_string(),
+    }
+    .filter(molecule.clone())
+    .unwrap();
+    assert_eq!(chain.len(), 6);
+    let carbon = SelectOne::IdName("cell_2_0_0_C1".to_string())
+        .get_atom(&chain)
+        .unwrap();
+    assert!((carbon.position.x - 6.).abs() < 1e-8);
+    assert_eq!(chain.neighbors(2), vec![3]);
+    // an atom wrapped to the other side of the cell bonds to the next image
+    molecule.atoms.set_atoms(1, vec![Some(atom(2.5))]);
+    let chain = Layer::Supercell {
+        lattice,
+        size: [3, 1, 1],
+        name: "cell".to_string(),
+    }
+    .filter(molecule)
+    .unwrap();
+    assert!(chain.neighbors(0).is_empty());
+    assert_eq!(chain.neighbors(1), vec![2]);
+    assert_eq!(chain.neighbors(3), vec![4]);
+    assert!(chain.neighbors(5).is_empty());
+}
+
+#[test]
+fn annotate_atoms() {
+    let atom = |element| Atom3D {
+        element,
+        ..Default::default()
+    };
+    let mut molecule = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(8), atom(1), atom(1)]),
+        ..Default::default()
+    };
+    molecule.atoms.set_metadata(
+        1,
+        AtomMetadata {
+            label: Some("H1".to_string()),
+            tags: BTreeMap::from([("note".to_string(), "acidic".to_string())]),
+            ..Default::default()
+        },
+    );
+    let annotated = Layer::Annotate {
+        select: SelectMany::Element(1),
+        tags: BTreeMap::from([("color".to_string(), "white".to_string())]),
+        remove: vec!["note".to_string()],
+    }
+    .filter(molecule)
+    .unwrap();
+    assert!(annotated.atoms.read_metadata(0).is_none());
+    let hydrogen = annotated.atoms.read_metadata(1).unwrap();
+    assert_eq!(hydrogen.label.as_deref(), Some("H1"));
+    assert_eq!(
+        hydrogen.tags,
+        BTreeMap::from([("color".to_string(), "white".to_string())])
+    );
+    let cleared = Layer::Annotate {
+        select: SelectMany::All,
+        tags: BTreeMap::new(),
+        remove: vec!["color".to_string()],
+    }
+    .filter(annotated)
+    .unwrap();
+    assert!(cleared.atoms.read_metadata(2).is_none());
+}
+
+#[test]
+fn align_to_reference() {
+    let atom = |element, x: f64, y: f64| Atom3D {
+        element,
+        position: Point3::new(x, y, 0.),
+        ..Default::default()
+    };
+    let reference = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(8, 0., 0.), atom(1, 1., 0.), atom(1, 0., 1.)]),
+        ..Default::default()
+    };
+    let moved = Layer::Isometry {
+        select: SelectMany::All,
+        isometry: Isometry3::new(Vector3::new(3., -2., 1.), Vector3::new(0.3, 1.2, -0.4)),
+    }
+    .filter(reference.clone())
+    .unwrap();
+    let distance = |molecule: &SparseMolecule, index| {
+        (molecule.atoms.read_atom(index).unwrap().position
+            - reference.atoms.read_atom(index).unwrap().position)
+            .norm()
+    };
+    let aligned = Layer::AlignTo {
+        reference: reference.clone(),
+        mapping: vec![],
+    }
+    .filter(moved.clone())
+    .unwrap();
+    assert!((0..3).all(|index| distance(&aligned, index) < 1e-8));
+    // Mapped by the oxygen and one hydrogen only
+    let mapped = Layer::AlignTo {
+        reference: reference.clone(),
+        mapping: vec![
+            (SelectOne::Index(0), SelectOne::Index(0)),
+            (SelectOne::Index(1), SelectOne::Index(1)),
+        ],
+    }
+    .filter(moved.clone())
+    .unwrap();
+    assert!(distance(&mapped, 0) < 1e-8 && distance(&mapped, 1) < 1e-8);
+    let missing = Layer::AlignTo {
+        reference,
+        mapping: vec![(SelectOne::Index(5), SelectOne::Index(0))],
+    };
+    assert!(missing.filter(moved).is_err());
+}
+
+#[test]
+fn perceive_bonds() {
+    let atom = |element, x: f64, y: f64| Atom3D {
+        element,
+        position: Point3::new(x, y, 0.),
+        ..Default::default()
+    };
+    // Water and a distant chloride, with an existing double bond kept
+    let mut structure = SparseMolecule {
+        atoms: SparseAtomList::from(vec![
+            atom(8, 0., 0.),
+            atom(1, 0.96, 0.),
+            atom(1, -0.24, 0.93),
+            atom(17, 5., 0.),
+        ]),
+        ..Default::default()
+    };
+    structure.bonds.set_bond(0, 1, Some(2.));
+    let layer = serde_yaml::from_str::<Layer>("type: PerceiveBonds").unwrap();
+    let perceived = layer.filter(structure.clone()).unwrap();
+    assert_eq!(perceived.bonds.read_bond(0, 1), Some(2.));
+    assert_eq!(perceived.bonds.read_bond(0, 2), Some(1.));
+    assert_eq!(perceived.bonds.read_bond(1, 2), None);
+    assert!(perceived.neighbors(3).is_empty());
+    let selected = Layer::PerceiveBonds {
+        select: SelectMany::Indexes(BTreeSet::from([SelectOne::Index(0), SelectOne::Index(1)])),
+        tolerance: 0.45,
+        orders: false,
+    }
+    .filter(structure)
+    .unwrap();
+    assert_eq!(selected.bonds.read_bond(0, 2), None);
+}
+
+#[test]
+fn center_of_mass_of_selection() {
+    let atom = |element, x: f64| Atom3D {
+        element,
+        position: Point3::new(x, 0., 0.),
+        ..Default::default()
+    };
+    // Hydrogen fluoride and a distant helium
+    let molecule = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(1, 0.), atom(9, 0.92), atom(2, 5.)]),
+        ..Default::default()
+    };
+    let select = SelectMany::Range(0..=1);
+    assert_eq!(select.centroid(&molecule), Some(Point3::new(0.46, 0., 0.)));
+    let center = select.center_of_mass(&molecule).unwrap();
+    assert!((center.x - 0.92 * 18.998 / (1.008 + 18.998)).abs() < 1e-9);
+    assert_eq!(SelectMany::Range(5..=6).centroid(&molecule), None);
+    assert_eq!(SelectMany::All.centroid(&molecule), molecule.centroid());
+    let moved = Layer::CenterOfMassAt {
+        select: select.clone(),
+        center: Point3::new(1., 2., 3.),
+    }
+    .filter(molecule)
+    .unwrap();
+    let center = select.center_of_mass(&moved).unwrap();
+    assert!((center - Point3::new(1., 2., 3.)).norm() < 1e-9);
+    assert_eq!(moved.atoms.read_atom(2).unwrap().position.x, 5.);
+}
+
+#[test]
+fn replace_whole_molecule() {
+    let mut current = SparseMolecule {
+        atoms: SparseAtomList::from(vec![Atom3D::default(); 3]),
+        ids: Some(BTreeMap::from([("a".to_string(), 2)])),
+        charge: Some(1),
+        ..Default::default()
+    };
+    current.atoms.set_metadata(
+        2,
+        AtomMetadata {
+            label: Some("a".to_string()),
+            ..Default::default()
+        },
+    );
+    let data = SparseMolecule {
+        atoms: SparseAtomList::from(vec![Atom3D::default()]),
+        ..Default::default()
+    };
+    let filled = Layer::Fill { data: data.clone() }
+        .filter(current.clone())
+        .unwrap();
+    assert_eq!(filled.charge, Some(1));
+    let replaced = Layer::Replace { data: data.clone() }
+        .filter(current)
+        .unwrap();
+    assert_eq!(replaced, data);
+}
+
+#[test]
+fn principal_axes_align() {
+    let atom = |element, x: f64, y: f64, z: f64| Atom3D {
+        element,
+        position: Point3::new(x, y, z),
+        ..Default::default()
+    };
+    // Formyl fluoride in the xy plane, without symmetry making the signs of the
+    // axes ambiguous
+    let molecule = SparseMolecule {
+        atoms: SparseAtomList::from(vec![
+            atom(6, 0., 0., 0.),
+            atom(8, 1.18, 0., 0.),
+            atom(9, -0.68, 1.13, 0.),
+            atom(1, -0.58, -0.93, 0.),
+        ]),
+        ..Default::default()
+    };
+    let rotation = UnitQuaternion::from_euler_angles(0.3, -1.2, 2.1);
+    let isometry = Isometry3::from_parts(Translation3::new(1., -2., 3.), rotation);
+    let mut moved = molecule.clone();
+    let selected = SelectMany::All.to_indexes(&molecule);
+    moved.atoms.isometry(isometry, &selected);
+    let aligned = [molecule, moved].map(|molecule| {
+        Layer::PrincipalAxesAlign {
+            select: SelectMany::All,
+        }
+        .filter(molecule)
+        .unwrap()
+    });
+    let (moments, axes) = SelectMany::All.principal_axes(&aligned[0]).unwrap();
+    assert!(moments[0] <= moments[1] && moments[1] <= moments[2]);
+    assert!((axes - Matrix3::identity()).norm() < 1e-9);
+    for index in 0..4 {
+        let a = aligned[0].atoms.read_atom(index).unwrap();
+        let b = aligned[1].atoms.read_atom(index).unwrap();
+        assert!((a.position - b.position).norm() < 1e-9);
+        // The molecular plane is normal to the axis of the largest moment
+        assert!(a.position.z.abs() < 1e-9);
+    }
+    let center = SelectMany::All.center_of_mass(&aligned[1]).unwrap();
+    assert!(center.coords.norm() < 1e-9);
+}
@@ -1,7 +1,15 @@
 pub mod chemistry;
+pub mod coordination;
 pub mod external;
 pub mod group_name;
 pub mod io;
 pub mod layer;
+pub mod migration;
+pub mod oniom;
+#[cfg(feature = "results")]
+pub mod results;
+pub mod smiles;
 pub mod sparse_molecule;
+pub mod stereo;
+pub mod structural_group;
 pub mod utils;
@@ -2,39 +2,68 @@ mod workflow;
 
 use std::{
     collections::{BTreeMap, BTreeSet},
-    default,
     fs::File,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Context;
-use nalgebra::storage;
+use anyhow::{anyhow, Context};
+use fancy_regex::Regex;
+#[cfg(feature = "results")]
+use lmers::results::{ResultsDatabase, RunRecord, StructureRecord};
+use lmers::{
+    layer::Layer,
+    sparse_molecule::SparseMolecule,
+    utils::{input::from_input_reader, process::prepend_path},
+};
 use rayon::prelude::*;
-use redb::Database;
+use schemars::schema_for;
 use workflow::{
+    condition::Condition,
+    doctor,
+    estimate::{estimate, report, Projection},
+    exit::{set_panic_hook, Exit, OrExit},
     input_data::WorkflowInput,
-    runner::{cached_read_stack, RunnerOutput},
-    step::{self, Step},
-    workflow_data::{LayerStorage, Window},
+    lineage::Lineage,
+    lock::lock_checkpoints,
+    runner::{cached_read_stack, failed_structures, Runner, RunnerOutput, SanitizeOptions},
+    step::{Step, StepRunner},
+    variable::{Capture, Variables},
+    workflow_data::{
+        checkpoint_windows, read_checkpoints, reference_counts, write_checkpoint, LayerStorage,
+        Window,
+    },
 };
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
 /// Start a LME modeling process
 #[derive(Parser, Debug)]
 struct Args {
     /// Specify the entrypoint file path.
     ///
-    /// The parent directory of the file will be the working directory
-    #[clap(short = 'i')]
-    input_file: String,
-    /// Specify the checkpoint name for restart.
+    /// The parent directory of the file will be the working directory. Files with
+    /// `.json` or `.toml` extension are read as JSON or TOML, others as YAML.
+    #[clap(short = 'i', required_unless_present = "schema")]
+    input_file: Option<String>,
+    /// Specify the checkpoint name or the step number for restart.
     ///
-    /// The LME will find the checkpoint file under `.checkpoint` folder
-    /// in the same directory of the entrypoint file, load the status and
+    /// The LME will find the checkpoint file under `.checkpoint` folder (see
+    /// `--isolate`) in the same directory of the entrypoint file, load the status and
     /// start from the step after the checkpoint in step sequence.
+    ///
+    /// A number which is not a step name restarts from the step of the number
+    /// (counted from 1), the same as `--from-step`.
     #[clap(short = 'c')]
     checkpoint: Option<String>,
+    /// Restart from the step of the number (counted from 1).
+    ///
+    /// The window is loaded from the checkpoint of the nearest named step
+    /// before it, and the steps between are executed again to reconstruct the
+    /// window, or the workflow starts over if no checkpoint is found.
+    #[clap(long, conflicts_with = "checkpoint")]
+    from_step: Option<usize>,
     /// Speicify the stop before a checkpoint/bookmark
     ///
     /// For a normal step without `load` property, the LME won't execute the step,
@@ -45,26 +74,98 @@ struct Args {
     /// Display details of the step before execute it.
     #[clap(long)]
     verbose: bool,
-    /// Remove unused layers in the on-disk database each time create a checkpoint.
+    /// Remove the layers unused by all checkpoints and the final window from the
+    /// on-disk database at the end of the run.
     #[clap(long)]
     clean: bool,
+    /// Print the JSON Schema of the given input file type and exit.
+    ///
+    /// The schema can be used by editors to validate and complete the input files.
+    #[clap(long, value_enum)]
+    schema: Option<SchemaTarget>,
+    /// Project the structure counts and calculation-hours of each step and exit.
+    ///
+    /// Nothing is executed, the fan-outs of the runners (e.g. substituent files
+    /// and stereoisomers) are multiplied from the start window. Calculation-hours
+    /// come from `estimated_hours` of the Calculation steps or the resource usage
+    /// of a previous run.
+    #[clap(long)]
+    estimate: bool,
+    /// Check the programs used by the steps, write permissions and free space of
+    /// the working directory and the layer database, print the problems found
+    /// and exit, with the status of validation failures if any error is found.
+    #[clap(long)]
+    doctor: bool,
+    /// Check and repair the layer database and the checkpoints before the run,
+    /// which is done anyway if the previous run in the directory was not
+    /// finished. Damaged checkpoints are renamed to `.damaged_<name>`, and the
+    /// partially written ones are removed.
+    #[clap(long)]
+    recover: bool,
+    /// Keep the checkpoints, the layer database and the other states of the
+    /// run in `.checkpoint_<name>` instead of `.checkpoint`.
+    ///
+    /// A checkpoint directory is locked by the run using it, so runs of the same
+    /// directory at the same time need different names. Outputs of the steps
+    /// are still shared unless step directories are enabled.
+    #[clap(long, value_name = "NAME")]
+    isolate: Option<String>,
+}
+
+/// Directory of the checkpoints, the layer database and the other states of
+/// the run, relative to the working directory, see `--isolate`.
+static CHECKPOINT_DIRECTORY: OnceLock<PathBuf> = OnceLock::new();
+
+fn checkpoint_directory() -> &'static Path {
+    CHECKPOINT_DIRECTORY.get_or_init(|| PathBuf::from(".checkpoint"))
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum SchemaTarget {
+    /// The entrypoint workflow file
+    Workflow,
+    /// Files contain a list of layers, e.g. input of merge_layers
+    Layers,
+    /// SparseMolecule files, e.g. base and substituents
+    Molecule,
 }
 
 fn main() {
     let args = Args::parse();
-    let entrypoint = PathBuf::from(args.input_file);
+    set_panic_hook();
+    if let Some(name) = &args.isolate {
+        let valid = |c: char| c.is_alphanumeric() || c == '-' || c == '_';
+        if name.is_empty() || !name.chars().all(valid) {
+            Exit::Validation.exit(format!(
+                "Invalid isolated run name {:?}, use letters, digits, - and _",
+                name
+            ));
+        }
+        CHECKPOINT_DIRECTORY
+            .set(PathBuf::from(format!(".checkpoint_{}", name)))
+            .unwrap();
+    }
+    if let Some(target) = args.schema {
+        let schema = match target {
+            SchemaTarget::Workflow => schema_for!(WorkflowInput),
+            SchemaTarget::Layers => schema_for!(Vec<Layer>),
+            SchemaTarget::Molecule => schema_for!(SparseMolecule),
+        };
+        println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+        return;
+    }
+    let entrypoint = PathBuf::from(args.input_file.expect("Entrypoint file is required"));
     let entrypoint = std::fs::canonicalize(entrypoint)
         .with_context(|| "Unable to get absolute path of the entrypoint file, does it exists?")
-        .unwrap();
+        .or_exit(Exit::Input);
     let working_directory = entrypoint.parent().expect("Invalid entrypoint file path");
-    std::env::set_current_dir(working_directory).expect(&format!(
-        "Unable to set {:?} as working directory",
-        working_directory
-    ));
+    std::env::set_current_dir(working_directory)
+        .with_context(|| format!("Unable to set {:?} as working directory", working_directory))
+        .or_exit(Exit::Input);
     let entrypoint_filename = entrypoint
         .file_name()
         .expect("Invalid entrypoint file path");
-    let input: WorkflowInput = serde_yaml::from_reader(
+    let input: WorkflowInput = from_input_reader(
         File::open(entrypoint_filename)
             .with_context(|| {
                 format!(
@@ -72,20 +173,69 @@ fn main() {
                     entrypoint_filename, working_directory
                 )
             })
-            .unwrap(),
+            .or_exit(Exit::Input),
+        entrypoint_filename.as_ref(),
     )
-    .unwrap();
+    .or_exit(Exit::Input);
+    #[cfg(not(feature = "results"))]
+    if input.results.is_some() {
+        Exit::Validation.exit("Archiving results needs lmers built with the results feature");
+    }
 
-    let checkpoint_list = input
+    let total_steps = input.steps.0.len();
+    let step_names = input
         .steps
         .0
         .iter()
-        .filter_map(|step| step.name.as_ref().map(|item| item.to_string()))
+        .map(|step| step.name.clone())
         .collect::<Vec<_>>();
+    set_path(input.binaries).or_exit(Exit::Validation);
 
-    set_path(input.binaries).unwrap();
+    if args.doctor {
+        let diagnostics = doctor::diagnose(&input.steps.0, checkpoint_directory());
+        if !doctor::report(&diagnostics) {
+            std::process::exit(Exit::Validation as i32);
+        }
+        return;
+    }
+
+    // Held until the process exits, estimating doesn't write the checkpoints
+    let _lock = (!args.estimate)
+        .then(|| lock_checkpoints(checkpoint_directory()).or_exit(Exit::Validation));
+
+    // Removed when the run is finished, the previous run was interrupted if it
+    // exists, e.g. killed or failed. Estimating without the lock never touches
+    // the layer database, which may be used by another run.
+    let running = checkpoint_directory().join(".running");
+    if !args.estimate && (running.exists() || args.recover && checkpoint_directory().is_dir()) {
+        recover_checkpoints();
+    }
+
+    let restart = args.checkpoint.is_some() || args.from_step.is_some();
+    let from_step = match &args.checkpoint {
+        Some(checkpoint)
+            if input
+                .steps
+                .0
+                .iter()
+                .all(|step| step.name.as_ref() != Some(checkpoint)) =>
+        {
+            checkpoint.parse::<usize>().ok()
+        }
+        _ => args.from_step,
+    };
+    let completed_steps = match from_step {
+        Some(0) => Exit::Validation.exit("Steps are counted from 1, no step 0 to start from"),
+        Some(step) => Some(step - 1),
+        None => None,
+    };
 
-    let (mut current_window, steps) = if let Some(checkpoint) = &args.checkpoint {
+    let (current_window, steps) = if let Some(completed) = completed_steps {
+        std::fs::create_dir_all(checkpoint_directory())
+            .with_context(|| "Unable to prepare checkpoint direcotry")
+            .or_exit(Exit::Internal);
+        resume_after_steps(input.steps.0, completed).or_exit(Exit::Validation)
+    } else if let Some(checkpoint) = &args.checkpoint {
         let num_of_steps = input.steps.0.len();
         let steps = input
             .steps
@@ -99,21 +249,24 @@ fn main() {
             checkpoint,
             num_of_steps - steps.len()
         );
-        let checkpoint = PathBuf::from(".checkpoint").join(checkpoint);
+        let checkpoint = checkpoint_directory().join(checkpoint);
         let checkpoint = File::open(&checkpoint)
             .with_context(|| format!("Unable to open the checkpoint file {:?}", checkpoint))
-            .unwrap();
+            .or_exit(Exit::Validation);
         let checkpoint: Window = serde_json::from_reader(checkpoint)
-            .with_context(|| format!("Failed to deserialize the file of given checkpoint"))
-            .unwrap();
+            .with_context(|| "Failed to deserialize the file of given checkpoint")
+            .or_exit(Exit::Validation);
         (checkpoint, steps)
     } else {
-        std::fs::create_dir_all(".checkpoint")
+        std::fs::create_dir_all(checkpoint_directory())
             .with_context(|| "Unable to prepare checkpoint direcotry")
-            .unwrap();
+            .or_exit(Exit::Internal);
         (BTreeMap::from([("LME".to_string(), vec![])]), input.steps.0)
     };
 
+    // Steps are numbered in the whole workflow for the step directories
+    let skipped_steps = total_steps - steps.len();
+
     let steps = if let Some(stop_at) = args.stop_at {
         let current_steps = steps.len();
         let steps = steps
@@ -132,87 +285,228 @@ fn main() {
         steps
     };
 
+    if args.estimate {
+        let estimates = estimate(&steps, Projection::Exact(current_window.len() as f64))
+            .with_context(|| "Failed to estimate the workflow")
+            .or_exit(Exit::Validation);
+        report(&estimates);
+        return;
+    }
+
     let num_of_steps = steps.len();
 
-    let layer_storage = LayerStorage::new(PathBuf::from(".checkpoint").join(".layers.db"));
+    let run_id = (input.step_directories.is_some() || input.results.is_some()).then(|| {
+        let run_id = input
+            .step_directories
+            .as_ref()
+            .and_then(|config| config.run_id.clone());
+        resolve_run_id(run_id, restart)
+    });
+    let step_root = input
+        .step_directories
+        .as_ref()
+        .and(run_id.as_ref())
+        .map(|run_id| PathBuf::from(format!("run_{}", run_id)));
 
-    for (idx, step) in steps.into_iter().enumerate() {
-        if let Some(from) = step.from.as_ref() {
-            let checkpoint = PathBuf::from(".checkpoint").join(from);
-            let checkpoint = File::open(&checkpoint)
-                .with_context(|| format!("Unable to open the checkpoint file {:?}", checkpoint))
-                .unwrap();
-            current_window = serde_json::from_reader(checkpoint)
-                .with_context(|| {
-                    format!("Failed to deserialize the checkpoint file for the {}", from)
-                })
-                .unwrap();
-        };
-        println!(
-            "Step {}/{}, input {} structures",
-            idx + 1,
-            num_of_steps,
-            current_window.len()
-        );
-        if args.verbose {
-            println!("{:#?}", step)
-        }
-        let result = step
-            .run
-            .execute(&input.base, &current_window, &layer_storage)
-            .unwrap();
+    let layer_storage = LayerStorage::new(checkpoint_directory().join(".layers.db"));
 
-        let cache_generated_stacks = |generated_stacks: &BTreeMap<String, Vec<u64>>| {
-            generated_stacks
-                .par_iter()
-                .map(|(_, stack_path)| cached_read_stack(&input.base, &layer_storage, &stack_path))
-                .collect::<Result<Vec<_>, _>>()
-        };
+    // Variables after each step, the ones of the steps executed again when
+    // restarting are dropped
+    let variables_path = checkpoint_directory().join(".variables.json");
+    let mut step_variables: BTreeMap<usize, Variables> = if restart {
+        File::open(&variables_path)
+            .ok()
+            .map(|file| {
+                serde_json::from_reader(file)
+                    .with_context(|| "Failed to deserialize the captured variables")
+                    .or_exit(Exit::Internal)
+            })
+            .unwrap_or_default()
+    } else {
+        BTreeMap::new()
+    };
+    step_variables.split_off(&(skipped_steps + 1));
+    let variables = step_variables
+        .values()
+        .next_back()
+        .cloned()
+        .unwrap_or_default();
 
-        match result {
-            RunnerOutput::None => {}
-            RunnerOutput::SingleWindow(window) => {
-                cache_generated_stacks(&window).unwrap();
-                current_window = window;
-            }
-            RunnerOutput::MultiWindow(windows) => {
-                if let Some(name) = step.name.as_ref() {
-                    for (window_name, window) in &windows {
-                        cache_generated_stacks(window).unwrap();
-                        let name = format!("{}_{}", name, window_name);
-                        let checkpoint = File::create(PathBuf::from(".checkpoint").join(&name))
-                            .with_context(|| format!("Failed to create checkpoint {}", name))
-                            .unwrap();
-                        serde_json::to_writer(checkpoint, &window)
-                            .with_context(|| {
-                                format!("Failed to serialize the checkpoint information")
-                            })
-                            .unwrap();
-                        println!("Checkpoint {} created", &name);
-                    }
-                }
-                current_window = BTreeMap::new();
-                for (_, window) in windows {
-                    current_window.extend(window);
-                }
-            }
+    let context = StepContext {
+        base: &input.base,
+        bases: &input.bases,
+        charge: input.charge,
+        multiplicity: input.multiplicity,
+        layer_storage: &layer_storage,
+        verbose: args.verbose,
+    };
+    let lineage_path = checkpoint_directory().join(".lineage.json");
+    let mut lineage = input.lineage.as_ref().map(|_| {
+        if restart {
+            let mut lineage = Lineage::load(&lineage_path).or_exit(Exit::Internal);
+            lineage.truncate(skipped_steps);
+            lineage
+        } else {
+            let mut lineage = Lineage::default();
+            lineage.record(0, "start", &current_window);
+            lineage
         }
-        if let Some(name) = step.name {
-            let checkpoint = File::create(PathBuf::from(".checkpoint").join(&name))
-                .with_context(|| format!("Failed to create checkpoint {}", name))
-                .unwrap();
-            serde_json::to_writer(checkpoint, &current_window)
-                .with_context(|| format!("Failed to serialize the checkpoint information"))
-                .unwrap();
-            println!("Checkpoint {} created", &name);
+    });
+    let mut state = State {
+        current_window,
+        variables,
+        checkpoints: skipped_checkpoints(&step_names[..skipped_steps]),
+    };
+    std::fs::write(&running, "")
+        .with_context(|| format!("Unable to create {:?}", running))
+        .or_exit(Exit::Internal);
+    for (idx, step) in steps.into_iter().enumerate() {
+        let number = skipped_steps + idx + 1;
+        let location = step_root
+            .as_ref()
+            .map(|root| (root.clone(), number.to_string()));
+        let step_label = step
+            .name
+            .clone()
+            .or(step.bookmark.clone())
+            .unwrap_or_else(|| format!("step {}", number));
+        run_step(
+            step,
+            &format!("Step {}/{}", idx + 1, num_of_steps),
+            location,
+            &context,
+            &mut state,
+        );
+        step_variables.insert(number, state.variables.clone());
+        let file = File::create(&variables_path)
+            .with_context(|| "Failed to create the file of captured variables")
+            .or_exit(Exit::Internal);
+        serde_json::to_writer(file, &step_variables)
+            .with_context(|| "Failed to serialize the captured variables")
+            .or_exit(Exit::Internal);
+        if let Some(lineage) = lineage.as_mut() {
+            lineage.record(number, &step_label, &state.current_window);
+            lineage.write(&lineage_path).or_exit(Exit::Internal);
         }
     }
+    if let (Some(lineage), Some(path)) = (&lineage, &input.lineage) {
+        lineage.write(path).or_exit(Exit::Internal);
+        println!(
+            "Lineage of {} structures written to {:?}",
+            lineage.nodes.len(),
+            path
+        );
+    }
+    #[cfg(feature = "results")]
+    if let Some(results) = &input.results {
+        let run_id = run_id.unwrap_or_default();
+        archive_results(results, &entrypoint, &run_id, &context, &state);
+    }
     if args.clean {
-        clean_unused_layers(&checkpoint_list, &layer_storage);
+        clean_unused_layers(&state.current_window, &layer_storage);
     }
+    std::fs::remove_file(&running)
+        .with_context(|| format!("Unable to remove {:?}", running))
+        .or_exit(Exit::Internal);
     println!("finished");
 }
 
+/// Existing checkpoints of the named steps skipped when restarting, including
+/// the windows `<name>_<window>` they saved.
+fn skipped_checkpoints(names: &[Option<String>]) -> BTreeSet<String> {
+    let Ok(entries) = std::fs::read_dir(checkpoint_directory()) else {
+        return BTreeSet::new();
+    };
+    let names = names.iter().flatten().collect::<Vec<_>>();
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|checkpoint| {
+            names.iter().any(|name| {
+                checkpoint == *name
+                    || checkpoint
+                        .strip_prefix(name.as_str())
+                        .is_some_and(|rest| rest.starts_with('_'))
+            })
+        })
+        .collect()
+}
+
+/// Check and repair the layer database and the checkpoints, see
+/// `LayerStorage::recover`.
+fn recover_checkpoints() {
+    println!("Checking the layer database and the checkpoints");
+    let mut storage = LayerStorage::new(checkpoint_directory().join(".layers.db"));
+    let recovery = storage
+        .recover(checkpoint_directory())
+        .with_context(|| "Failed to recover the layer database and the checkpoints")
+        .or_exit(Exit::Internal);
+    if recovery.repaired {
+        println!("The layer database is repaired");
+    }
+    if !recovery.damaged_layers.is_empty() {
+        println!(
+            "{} damaged layers removed from the layer database",
+            recovery.damaged_layers.len()
+        );
+    }
+    if !recovery.temporary_checkpoints.is_empty() {
+        println!(
+            "{} partially written checkpoints removed",
+            recovery.temporary_checkpoints.len()
+        );
+    }
+    for (name, hidden) in &recovery.damaged_checkpoints {
+        println!(
+            "Checkpoint {} is damaged or refers to damaged layers, renamed to {}",
+            name, hidden
+        );
+    }
+}
+
+/// Restart after the first `completed` steps. The window is loaded from the
+/// checkpoint of the nearest named step among them and the steps after it are
+/// replayed, or the steps start over from the start window if none of them is
+/// checkpointed.
+fn resume_after_steps(steps: Vec<Step>, completed: usize) -> anyhow::Result<(Window, Vec<Step>)> {
+    if completed > steps.len() {
+        Err(anyhow!(
+            "Unable to restart after step {}, the workflow has {} steps",
+            completed,
+            steps.len()
+        ))?
+    }
+    let checkpoint = steps[..completed]
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(index, step)| {
+            let name = step.name.as_ref()?;
+            let file = File::open(checkpoint_directory().join(name)).ok()?;
+            Some((index + 1, name, file))
+        });
+    let (skipped, window) = if let Some((skipped, name, file)) = checkpoint {
+        let window = serde_json::from_reader(file).with_context(|| {
+            format!("Failed to deserialize the checkpoint file for the {}", name)
+        })?;
+        println!("Try to start from checkpoint {} of step {}", name, skipped);
+        (skipped, window)
+    } else {
+        println!(
+            "No checkpoint found before step {}, start over",
+            completed + 1
+        );
+        (0, BTreeMap::from([("LME".to_string(), vec![])]))
+    };
+    if skipped < completed {
+        println!(
+            "Steps {} to {} will be executed again to reconstruct the window",
+            skipped + 1,
+            completed
+        );
+    }
+    Ok((window, steps.into_iter().skip(skipped).collect()))
+}
+
 fn set_path(user_specified_paths: Vec<PathBuf>) -> anyhow::Result<()> {
     let current_binary_directory = PathBuf::from(
         std::env::current_exe()?
@@ -220,39 +514,447 @@ fn set_path(user_specified_paths: Vec<PathBuf>) -> anyhow::Result<()> {
             .expect("Binary file must have a parent directory"),
     );
     let working_directory_bin = std::env::current_dir()?.join("bin");
-    let current_path_var = std::env::var_os("PATH").unwrap_or_default();
-    let current_path_var = std::env::split_paths(&current_path_var);
     let mut paths = user_specified_paths;
     paths.extend([working_directory_bin, current_binary_directory]);
-    paths.extend(current_path_var);
-    let paths = std::env::join_paths(paths)?;
-    std::env::set_var("PATH", paths);
-    Ok(())
+    prepend_path(paths)
 }
 
-fn clean_unused_layers(checkpoint_list: &Vec<String>, storage: &LayerStorage) {
-    let checkpoints = checkpoint_list
+/// Remove the layers referenced by none of the checkpoints on disk and the
+/// final window, see `reference_counts`.
+fn clean_unused_layers(current_window: &Window, storage: &LayerStorage) {
+    let mut windows = checkpoint_windows(checkpoint_directory())
+        .with_context(|| "Unable to scan the checkpoints, no layer removed")
+        .or_exit(Exit::Internal);
+    windows.insert(String::new(), current_window.clone());
+    let counts = reference_counts(windows.values());
+    let removed = storage.remove_unused_layers(&counts);
+    println!(
+        "{} unused layers removed, {} layers referenced by {} windows",
+        removed.len(),
+        counts.len(),
+        windows.len()
+    );
+}
+
+/// Workflow-level settings shared by all steps.
+struct StepContext<'a> {
+    base: &'a SparseMolecule,
+    bases: &'a BTreeMap<String, SparseMolecule>,
+    charge: Option<i32>,
+    multiplicity: Option<u32>,
+    layer_storage: &'a LayerStorage,
+    verbose: bool,
+}
+
+/// Status of the workflow changed by the steps.
+struct State {
+    current_window: Window,
+    variables: Variables,
+    /// Checkpoints created in this run, or by the steps skipped when restarting
+    checkpoints: BTreeSet<String>,
+}
+
+/// Identifier of the run for the step directories and the results database,
+/// it's saved in the checkpoint directory to be reused when restarting from a
+/// checkpoint.
+fn resolve_run_id(run_id: Option<String>, restart: bool) -> String {
+    let saved = checkpoint_directory().join(".run_id");
+    let run_id = run_id
+        .or_else(|| {
+            restart
+                .then(|| std::fs::read_to_string(&saved).ok())
+                .flatten()
+                .map(|run_id| run_id.trim().to_string())
+        })
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+                .to_string()
+        });
+    std::fs::write(&saved, &run_id)
+        .with_context(|| format!("Unable to save the run identifier to {:?}", saved))
+        .or_exit(Exit::Internal);
+    run_id
+}
+
+/// Append the structures of the final window to the results database, see
+/// `ResultsOptions`.
+#[cfg(feature = "results")]
+fn archive_results(
+    options: &workflow::input_data::ResultsOptions,
+    workflow: &Path,
+    run_id: &str,
+    context: &StepContext,
+    state: &State,
+) {
+    let mut properties: BTreeMap<String, BTreeMap<String, f64>> = BTreeMap::new();
+    for (name, file) in &options.properties {
+        let values = file
+            .read_window(&state.current_window)
+            .with_context(|| format!("Failed to read property {} of the results", name))
+            .or_exit(Exit::Internal);
+        for (title, value) in values {
+            if let Ok(value) = value {
+                properties
+                    .entry(title)
+                    .or_default()
+                    .insert(name.to_string(), value);
+            }
+        }
+    }
+    let structures = state
+        .current_window
         .iter()
-        .filter_map(|checkpoint_name| -> Option<Window> {
-            let checkpoint = PathBuf::from(".checkpoint").join(checkpoint_name);
-            let checkpoint = File::open(checkpoint).ok();
-            if let Some(checkpoint) = checkpoint {
-                Some(
-                    serde_json::from_reader(checkpoint)
-                        .with_context(|| {
-                            format!("checkpoint {} is unable to load", checkpoint_name)
-                        })
-                        .unwrap(),
-                )
-            } else {
-                None
+        .map(|(title, stack_path)| {
+            let molecule = cached_read_stack(context.base, context.layer_storage, stack_path)?;
+            let layers = stack_path
+                .iter()
+                .map(|id| {
+                    context
+                        .layer_storage
+                        .read_layer(*id)
+                        .with_context(|| format!("Layer {} of {} not found", id, title))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Ok(StructureRecord {
+                title: title.to_string(),
+                molecule,
+                layers,
+                properties: properties.remove(title).unwrap_or_default(),
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()
+        .with_context(|| "Failed to read the structures of the results")
+        .or_exit(Exit::Internal);
+    let run = RunRecord {
+        run_id: run_id.to_string(),
+        workflow: workflow.to_string_lossy().to_string(),
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        content: std::fs::read_to_string(workflow).unwrap_or_default(),
+        variables: state.variables.clone(),
+    };
+    ResultsDatabase::open(&options.database)
+        .and_then(|mut database| database.archive(&run, &structures))
+        .with_context(|| format!("Failed to archive results to {:?}", options.database))
+        .or_exit(Exit::Internal);
+    println!(
+        "{} structures archived to {:?} as run {}",
+        structures.len(),
+        options.database,
+        run_id
+    );
+}
+
+/// Execute a step, `location` is the parent directory and the index prefix of
+/// the step directory if step directories are enabled.
+fn run_step(
+    step: Step,
+    label: &str,
+    location: Option<(PathBuf, String)>,
+    context: &StepContext,
+    state: &mut State,
+) {
+    let directory = |runner_name: String| {
+        location.as_ref().map(|(parent, prefix)| {
+            let name = step
+                .name
+                .clone()
+                .or(step.bookmark.clone())
+                .unwrap_or(runner_name);
+            // step names may contain `/` or be `..`
+            let name = SanitizeOptions::default().name(&name);
+            parent.join(format!("{}_{}", prefix, name))
+        })
+    };
+    if let Some(name) = step.base.as_ref() {
+        state.current_window = base_window(name, context);
+        println!("{}, switched to base {}", label, name);
+    }
+    if let Some(from) = step.from.as_ref() {
+        state.current_window =
+            read_checkpoints(checkpoint_directory(), from).or_exit(Exit::Validation);
+    };
+    if let Some(when) = &step.when {
+        if !check_step_condition(when, state) {
+            println!("{}, skipped as condition {} is false", label, when.source());
+            return;
+        }
+    }
+    match step.run {
+        StepRunner::Loop {
+            steps,
+            until,
+            max_iterations,
+        } => run_loop(
+            &steps,
+            until.as_ref(),
+            max_iterations,
+            label,
+            directory("Loop".to_string()),
+            context,
+            state,
+        ),
+        run => {
+            let mut runner = run.resolve(&state.variables).or_exit(Exit::Validation);
+            runner.set_default_charge(context.charge, context.multiplicity);
+            if let Some(directory) = directory(runner.name()) {
+                runner.root_outputs(&directory);
             }
-        });
-    let mut retains = BTreeSet::new();
-    for checkpoint in checkpoints {
-        for structure in checkpoint.values() {
-            retains.extend(structure.iter().copied());
+            if let Runner::AlignTo { reference, mapping } = runner {
+                let reference = reference_structure(&reference, context)
+                    .with_context(|| format!("Failed to resolve the reference {}", reference))
+                    .or_exit(Exit::Validation);
+                let layers = vec![Layer::AlignTo { reference, mapping }];
+                runner = Runner::AppendLayers { layers };
+            }
+            run_runner(&runner, step.name.as_ref(), label, context, state);
         }
     }
-    storage.retain(&retains);
+    capture_variables(&step.capture, state);
+    if let Some(name) = step.name {
+        let window = state.current_window.clone();
+        save_checkpoint(&name, &window, state);
+    }
+}
+
+/// Window of the named base titled by its name, the structure is stored as a
+/// Replace layer on the workflow base so the stack paths share the same root,
+/// and nothing of the workflow base is left in it.
+fn base_window(name: &str, context: &StepContext) -> Window {
+    let base = context
+        .bases
+        .get(name)
+        .with_context(|| format!("No base named {} in the workflow", name))
+        .or_exit(Exit::Validation);
+    let layers = [Layer::Replace { data: base.clone() }];
+    let stack_path = context.layer_storage.create_layers(&layers).collect();
+    BTreeMap::from([(name.to_string(), stack_path)])
+}
+
+/// Structure of a named base, or of the only structure in the named checkpoint,
+/// as the reference of an `AlignTo` step.
+fn reference_structure(name: &str, context: &StepContext) -> anyhow::Result<SparseMolecule> {
+    if let Some(base) = context.bases.get(name) {
+        return Ok(base.clone());
+    }
+    let window = read_checkpoints(checkpoint_directory(), name)
+        .with_context(|| format!("No base or checkpoint named {}", name))?;
+    let mut stack_paths = window.values();
+    match (stack_paths.next(), stack_paths.next()) {
+        (Some(stack_path), None) => Ok(cached_read_stack(
+            context.base,
+            context.layer_storage,
+            stack_path,
+        )?),
+        _ => Err(anyhow!(
+            "Checkpoint {} contains {} structures instead of one",
+            name,
+            window.len()
+        )),
+    }
+}
+
+/// Evaluate the `when` condition of a step on the current window, see
+/// `StepLoader` for the variables and functions.
+fn check_step_condition(when: &Condition, state: &State) -> bool {
+    let lookup = |name: &str| match name {
+        "count" => Some(state.current_window.len() as f64),
+        name => state.variables.get(name).copied(),
+    };
+    when.evaluate_with(&lookup, &condition_functions(state))
+        .with_context(|| format!("Failed to evaluate step condition {}", when.source()))
+        .or_exit(Exit::Validation)
+}
+
+/// Functions of a text in the conditions of steps and loops: `matches` (number
+/// of titles matching the regex in the current window), `completed` (1 if the
+/// checkpoint of the named step is created in this run, or by the steps skipped
+/// when restarting) and `exists` (1 if the file exists, e.g. a sentinel file
+/// written by an external program).
+fn condition_functions(state: &State) -> impl Fn(&str, &str) -> anyhow::Result<f64> + '_ {
+    let boolean = |value: bool| if value { 1. } else { 0. };
+    move |name, text| match name {
+        "matches" => {
+            let regex =
+                Regex::new(text).with_context(|| format!("Invalid title regex {}", text))?;
+            let mut count = 0;
+            for title in state.current_window.keys() {
+                if regex
+                    .is_match(title)
+                    .with_context(|| format!("Unable to match title {} with {}", title, text))?
+                {
+                    count += 1;
+                }
+            }
+            Ok(count as f64)
+        }
+        "completed" => Ok(boolean(state.checkpoints.contains(text))),
+        "exists" => Ok(boolean(PathBuf::from(text).exists())),
+        name => Err(anyhow!("Unknown function {} of a text", name)),
+    }
+}
+
+/// Execute the runner of a step, and update the current window with its output.
+///
+/// Windows of a MultiWindow output are saved as checkpoints `<name>_<window>`
+/// if the step is named.
+fn run_runner(
+    runner: &Runner,
+    name: Option<&String>,
+    label: &str,
+    context: &StepContext,
+    state: &mut State,
+) {
+    println!("{}, input {} structures", label, state.current_window.len());
+    if context.verbose {
+        println!("{:#?}", runner)
+    }
+    let result = runner
+        .execute(context.base, &state.current_window, context.layer_storage)
+        .or_exit(Exit::Internal);
+
+    let cache_generated_stacks = |generated_stacks: &BTreeMap<String, Vec<u64>>| {
+        generated_stacks
+            .par_iter()
+            .map(|(_, stack_path)| {
+                cached_read_stack(context.base, context.layer_storage, stack_path)
+            })
+            .collect::<Result<Vec<_>, _>>()
+    };
+
+    match result {
+        RunnerOutput::None => {}
+        RunnerOutput::SingleWindow(window) => {
+            cache_generated_stacks(&window).or_exit(Exit::Internal);
+            state.current_window = window;
+        }
+        RunnerOutput::MultiWindow(windows) => {
+            for window in windows.values() {
+                cache_generated_stacks(window).or_exit(Exit::Internal);
+            }
+            save_windows(name, &windows, state);
+            state.current_window = BTreeMap::new();
+            for (_, window) in windows {
+                state.current_window.extend(window);
+            }
+        }
+        RunnerOutput::WithFailures { window, failures } => {
+            cache_generated_stacks(&window).or_exit(Exit::Internal);
+            // Saved as `<name>_failed` so the failed structures can be run again
+            let failed = failed_structures(&state.current_window, &failures);
+            save_windows(
+                name,
+                &BTreeMap::from([("failed".to_string(), failed)]),
+                state,
+            );
+            state.current_window = window;
+        }
+        RunnerOutput::Partition { mut windows, keep } => {
+            save_windows(name, &windows, state);
+            for (window_name, window) in &windows {
+                println!("Window {}: {} structures", window_name, window.len());
+            }
+            state.current_window = windows
+                .remove(&keep)
+                .with_context(|| format!("Kept window {} not found in the output", keep))
+                .or_exit(Exit::Internal);
+        }
+    }
+}
+
+/// Save each window as checkpoint `<name>_<window>` if the step is named.
+fn save_windows(name: Option<&String>, windows: &BTreeMap<String, Window>, state: &mut State) {
+    if let Some(name) = name {
+        for (window_name, window) in windows {
+            save_checkpoint(&format!("{}_{}", name, window_name), window, state);
+        }
+    }
+}
+
+/// Save the window as the named checkpoint, completed in this run.
+fn save_checkpoint(name: &str, window: &Window, state: &mut State) {
+    write_checkpoint(checkpoint_directory(), name, window).or_exit(Exit::Internal);
+    println!("Checkpoint {} created", name);
+    state.checkpoints.insert(name.to_string());
+}
+
+/// Repeat the steps until the condition is met or the max iterations reached.
+fn run_loop(
+    steps: &[Step],
+    until: Option<&Condition>,
+    max_iterations: usize,
+    label: &str,
+    directory: Option<PathBuf>,
+    context: &StepContext,
+    state: &mut State,
+) {
+    for iteration in 1..=max_iterations {
+        let previous = state.variables.clone();
+        let previous_window = state.current_window.clone();
+        state
+            .variables
+            .insert("iteration".to_string(), iteration as f64);
+        for (idx, step) in steps.iter().enumerate() {
+            run_step(
+                step.clone(),
+                &format!(
+                    "{}, iteration {}/{}, step {}/{}",
+                    label,
+                    iteration,
+                    max_iterations,
+                    idx + 1,
+                    steps.len()
+                ),
+                directory
+                    .as_ref()
+                    .map(|directory| (directory.clone(), format!("{}_{}", iteration, idx + 1))),
+                context,
+                state,
+            );
+        }
+        if let Some(until) = until {
+            let changed = state.current_window != previous_window;
+            let lookup = |name: &str| match name {
+                "count" => Some(state.current_window.len() as f64),
+                "changed" => Some(if changed { 1. } else { 0. }),
+                name => match name.strip_prefix("previous.") {
+                    Some(name) => previous.get(name).copied(),
+                    None => state.variables.get(name).copied(),
+                },
+            };
+            let converged = until
+                .evaluate_with(&lookup, &condition_functions(state))
+                .with_context(|| format!("Failed to evaluate loop condition {}", until.source()))
+                .or_exit(Exit::Validation);
+            if converged {
+                println!(
+                    "{}, loop condition {} met after {} iterations",
+                    label,
+                    until.source(),
+                    iteration
+                );
+                return;
+            }
+        }
+    }
+    println!(
+        "{}, loop stopped after max iterations {}",
+        label, max_iterations
+    );
+}
+
+fn capture_variables(capture: &BTreeMap<String, Capture>, state: &mut State) {
+    for (name, capture) in capture {
+        let value = capture
+            .evaluate(&state.current_window)
+            .with_context(|| format!("Failed to capture variable {}", name))
+            .or_exit(Exit::Internal);
+        println!("Variable {} captured: {}", name, value);
+        state.variables.insert(name.to_string(), value);
+    }
 }
@@ -0,0 +1,316 @@
+use std::{cell::Cell, collections::BTreeMap};
+
+use bincode::{Decode, Encode};
+use nalgebra::{Isometry3, Point3, Vector3};
+
+use crate::{
+    chemistry::Atom3D,
+    group_name::GroupName,
+    layer::{Layer, SelectMany, SelectOne, LAYER_FORMAT_VERSION},
+    sparse_molecule::{SparseAtomList, SparseBondMatrix, SparseMolecule},
+};
+
+thread_local! {
+    static LAYER_FORMAT: Cell<u8> = const { Cell::new(LAYER_FORMAT_VERSION) };
+}
+
+/// Binary layout version of the layers being encoded or decoded, types added
+/// to the layout after version 1 check it to read and write the old layouts.
+pub(crate) fn layer_format() -> u8 {
+    LAYER_FORMAT.with(Cell::get)
+}
+
+/// Encode or decode layers in an old format version inside `f`, which can be
+/// handled by the current types. The differences to version 1 are the atom
+/// metadata of SparseAtomList (version 2), and the charge and multiplicity of
+/// SparseMolecule (version 3).
+pub(crate) fn with_layer_format<T>(version: u8, f: impl FnOnce() -> T) -> T {
+    let previous = LAYER_FORMAT.with(|format| format.replace(version));
+    let result = f();
+    LAYER_FORMAT.with(|format| format.set(previous));
+    result
+}
+
+/// SparseMolecule as stored in layer databases before the version tag was
+/// introduced, with dense atom list and bond matrix.
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct SparseMoleculeV0 {
+    pub atoms: Vec<Option<Atom3D>>,
+    pub bonds: Vec<Vec<Option<f64>>>,
+    pub ids: Option<BTreeMap<String, usize>>,
+    pub groups: Option<GroupName>,
+}
+
+impl From<SparseMoleculeV0> for SparseMolecule {
+    fn from(value: SparseMoleculeV0) -> Self {
+        let mut bonds = SparseBondMatrix::new(value.bonds.len());
+        for (a, row) in value.bonds.into_iter().enumerate() {
+            for (b, bond) in row.into_iter().enumerate().skip(a) {
+                if bond.is_some() {
+                    bonds.set_bond(a, b, bond);
+                }
+            }
+        }
+        Self {
+            atoms: SparseAtomList::from(value.atoms),
+            bonds,
+            ids: value.ids,
+            groups: value.groups,
+            ..Default::default()
+        }
+    }
+}
+
+/// Layer as stored in layer databases before the version tag was introduced.
+///
+/// The variants must keep the order and fields of that format, new variants
+/// and fields only go to the current Layer.
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub enum LayerV0 {
+    Transparent,
+    Fill {
+        data: SparseMoleculeV0,
+    },
+    Insert {
+        offset: usize,
+        data: SparseMoleculeV0,
+    },
+    Append {
+        name: String,
+        data: SparseMoleculeV0,
+    },
+    SetAtom {
+        atoms: Vec<(SelectOne, Option<Atom3D>)>,
+    },
+    UpdateFormalCharge {
+        charges: Vec<(SelectOne, f64)>,
+    },
+    AppendAtoms {
+        atoms: Vec<Atom3D>,
+    },
+    SetBond {
+        bonds: Vec<(SelectOne, SelectOne, f64)>,
+    },
+    IdMap(BTreeMap<String, SelectOne>),
+    GroupMap {
+        groups: Vec<(String, SelectMany)>,
+    },
+    SetCenter {
+        select: SelectOne,
+        #[bincode(with_serde)]
+        center: Point3<f64>,
+    },
+    DirectionAlgin {
+        select: SelectOne,
+        #[bincode(with_serde)]
+        direction: Vector3<f64>,
+    },
+    XYAlign {
+        o: SelectOne,
+        x: SelectOne,
+        y: SelectOne,
+        select: SelectMany,
+    },
+    Translation {
+        select: SelectMany,
+        #[bincode(with_serde)]
+        vector: Vector3<f64>,
+    },
+    TranslationTo {
+        select: SelectMany,
+        target: SelectOne,
+        #[bincode(with_serde)]
+        position: Point3<f64>,
+    },
+    RotationTo {
+        a: SelectOne,
+        b: SelectOne,
+        select: SelectMany,
+        #[bincode(with_serde)]
+        direction: Vector3<f64>,
+    },
+    Rotation {
+        select: SelectMany,
+        #[bincode(with_serde)]
+        center: Point3<f64>,
+        #[bincode(with_serde)]
+        axis: Vector3<f64>,
+        angle: f64,
+        degree: bool,
+    },
+    Isometry {
+        select: SelectMany,
+        #[bincode(with_serde)]
+        isometry: Isometry3<f64>,
+    },
+    Mirror {
+        select: SelectMany,
+        #[bincode(with_serde)]
+        center: Point3<f64>,
+        #[bincode(with_serde)]
+        law_vector: Vector3<f64>,
+    },
+    RemoveAtoms {
+        select: SelectMany,
+    },
+    Hide {
+        select: SelectMany,
+    },
+    UnHide {
+        select: SelectMany,
+    },
+}
+
+impl From<LayerV0> for Layer {
+    fn from(value: LayerV0) -> Self {
+        match value {
+            LayerV0::Transparent => Self::Transparent,
+            LayerV0::Fill { data } => Self::Fill { data: data.into() },
+            LayerV0::Insert { offset, data } => Self::Insert {
+                offset,
+                data: data.into(),
+            },
+            LayerV0::Append { name, data } => Self::Append {
+                name,
+                data: data.into(),
+            },
+            LayerV0::SetAtom { atoms } => Self::SetAtom { atoms },
+            LayerV0::UpdateFormalCharge { charges } => Self::UpdateFormalCharge { charges },
+            LayerV0::AppendAtoms { atoms } => Self::AppendAtoms { atoms },
+            LayerV0::SetBond { bonds } => Self::SetBond { bonds },
+            LayerV0::IdMap(ids) => Self::IdMap(ids),
+            LayerV0::GroupMap { groups } => Self::GroupMap { groups },
+            LayerV0::SetCenter { select, center } => Self::SetCenter { select, center },
+            LayerV0::DirectionAlgin { select, direction } => {
+                Self::DirectionAlign { select, direction }
+            }
+            LayerV0::XYAlign { o, x, y, select } => Self::XYAlign { o, x, y, select },
+            LayerV0::Translation { select, vector } => Self::Translation { select, vector },
+            LayerV0::TranslationTo {
+                select,
+                target,
+                position,
+            } => Self::TranslationTo {
+                select,
+                target,
+                position,
+            },
+            LayerV0::RotationTo {
+                a,
+                b,
+                select,
+                direction,
+            } => Self::RotationTo {
+                a,
+                b,
+                select,
+                direction,
+            },
+            LayerV0::Rotation {
+                select,
+                center,
+                axis,
+                angle,
+                degree,
+            } => Self::Rotation {
+                select,
+                center,
+                axis,
+                angle,
+                degree,
+            },
+            LayerV0::Isometry { select, isometry } => Self::Isometry { select, isometry },
+            LayerV0::Mirror {
+                select,
+                center,
+                law_vector,
+            } => Self::Mirror {
+                select,
+                center,
+                law_vector,
+            },
+            LayerV0::RemoveAtoms { select } => Self::RemoveAtoms { select },
+            LayerV0::Hide { select } => Self::Hide { select },
+            LayerV0::UnHide { select } => Self::UnHide { select },
+        }
+    }
+}
+
+#[test]
+fn load_untagged_layer() {
+    use redb::Value;
+    let legacy = LayerV0::Fill {
+        data: SparseMoleculeV0 {
+            atoms: vec![
+                None,
+                Some(Atom3D {
+                    element: 6,
+                    position: Point3::new(0., 1., 2.),
+                    formal_charge: 0.,
+                }),
+            ],
+            bonds: vec![vec![None, Some(1.)], vec![Some(1.), None]],
+            ids: None,
+            groups: Some(GroupName::from_iter([("C".to_string(), 1)])),
+        },
+    };
+    let bytes = bincode::encode_to_vec(&legacy, bincode::config::standard()).unwrap();
+    let layer = Layer::from_bytes(&bytes);
+    assert_eq!(layer, Layer::from(legacy));
+    let renamed = LayerV0::DirectionAlgin {
+        select: SelectOne::Index(1),
+        direction: Vector3::x(),
+    };
+    let bytes = bincode::encode_to_vec(&renamed, bincode::config::standard()).unwrap();
+    assert!(matches!(
+        Layer::from_bytes(&bytes),
+        Layer::DirectionAlign { .. }
+    ));
+    let layer = Layer::Transparent;
+    assert_eq!(Layer::from_bytes(&Layer::as_bytes(&layer)), layer);
+}
+
+#[test]
+fn load_old_layer_formats() {
+    use redb::Value;
+    let layer = Layer::AppendAtoms {
+        atoms: vec![Atom3D::default()],
+    };
+    let fill = Layer::Fill {
+        data: SparseMolecule {
+            atoms: SparseAtomList::from(vec![Atom3D::default()]),
+            ..Default::default()
+        },
+    };
+    for layer in [layer, fill] {
+        for version in 1..LAYER_FORMAT_VERSION {
+            let payload = with_layer_format(version, || {
+                bincode::encode_to_vec(&layer, bincode::config::standard()).unwrap()
+            });
+            let bytes = [vec![250, version], payload].concat();
+            assert_eq!(Layer::from_bytes(&bytes), layer);
+        }
+    }
+    assert_eq!(layer_format(), LAYER_FORMAT_VERSION);
+}
+
+#[test]
+fn reject_damaged_capacity() {
+    use redb::Value;
+    let mut data = SparseMolecule::default();
+    data.extend_to(1000);
+    data.bonds.set_bond(10, 500, Some(1.));
+    let layer = Layer::Fill { data };
+    let bytes = Layer::as_bytes(&layer);
+    assert_eq!(Layer::decode(&bytes).unwrap(), layer);
+    // Variant index and the capacity of atoms (1000) after the version tag
+    assert_eq!(bytes[3], 251);
+    let capacity = [vec![253], (1_u64 << 40).to_le_bytes().to_vec()].concat();
+    let damaged = [&bytes[..3], &capacity, &bytes[6..]].concat();
+    assert!(Layer::decode(&damaged).is_err());
+    // A large capacity without atoms is not damaged
+    let mut data = SparseMolecule::default();
+    data.extend_to(1500);
+    let layer = Layer::Fill { data };
+    assert_eq!(Layer::decode(&Layer::as_bytes(&layer)).unwrap(), layer);
+}
@@ -0,0 +1,245 @@
+use bincode::{Decode, Encode};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeSet;
+
+use crate::{
+    chemistry::{validated_element_num, Atom3D},
+    sparse_molecule::SparseMolecule,
+    utils::hydrogens::hydrogen_bond_length,
+};
+
+/// Group of the capping hydrogens added by `cap_qm_region`.
+pub const LINK_ATOMS_GROUP: &str = "LINK_ATOMS";
+
+/// Layers of an ONIOM calculation, atoms are tagged by groups named after
+/// `group_name`.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    Encode,
+    Decode,
+    JsonSchema,
+)]
+pub enum OniomLevel {
+    High,
+    Medium,
+    Low,
+}
+
+impl OniomLevel {
+    pub const ALL: [Self; 3] = [Self::High, Self::Medium, Self::Low];
+
+    pub fn group_name(&self) -> &'static str {
+        match self {
+            Self::High => "ONIOM_HIGH",
+            Self::Medium => "ONIOM_MEDIUM",
+            Self::Low => "ONIOM_LOW",
+        }
+    }
+
+    /// Layer symbol of Gaussian molecule specification.
+    pub fn symbol(&self) -> char {
+        match self {
+            Self::High => 'H',
+            Self::Medium => 'M',
+            Self::Low => 'L',
+        }
+    }
+}
+
+/// Level of each atom in the order of output formats (empty slots skipped),
+/// untagged atoms are in the low level.
+pub fn oniom_levels(molecule: &SparseMolecule) -> Vec<OniomLevel> {
+    (0..molecule.atoms.len())
+        .filter(|index| {
+            molecule
+                .atoms
+                .read_atom(*index)
+                .map(|atom| validated_element_num(atom.element))
+                .unwrap_or_default()
+        })
+        .map(|index| {
+            OniomLevel::ALL
+                .into_iter()
+                .find(|level| {
+                    molecule
+                        .groups
+                        .as_ref()
+                        .map(|groups| {
+                            groups
+                                .get_right(&index)
+                                .any(|name| name == level.group_name())
+                        })
+                        .unwrap_or_default()
+                })
+                .unwrap_or(OniomLevel::Low)
+        })
+        .collect()
+}
+
+/// Link atoms of the boundary, as the atoms (continuous index) to be replaced
+/// and their hosts in a higher level.
+///
+/// When an atom is bonded to several atoms in higher levels, the one in the
+/// highest level with the smallest index is the host.
+pub fn link_atoms(levels: &[OniomLevel], bonds: &[(usize, usize, f64)]) -> Vec<(usize, usize)> {
+    let mut hosts: Vec<Option<usize>> = vec![None; levels.len()];
+    for (a, b, _) in bonds {
+        for (atom, host) in [(*a, *b), (*b, *a)] {
+            if levels[host] < levels[atom] {
+                let current = hosts[atom].get_or_insert(host);
+                if (levels[host], host) < (levels[*current], *current) {
+                    *current = host;
+                }
+            }
+        }
+    }
+    hosts
+        .into_iter()
+        .enumerate()
+        .filter_map(|(atom, host)| Some((atom, host?)))
+        .collect()
+}
+
+/// Append capping hydrogens for the bonds between the QM region and the other
+/// atoms, returns indexes of the link atoms.
+///
+/// The link atom lies on the cut bond, its distance to the QM atom is the cut
+/// bond length multiplied by `scale`, or the typical X-H bond length if
+/// `scale` is not given.
+pub fn cap_qm_region(
+    molecule: &mut SparseMolecule,
+    qm: &BTreeSet<usize>,
+    scale: Option<f64>,
+) -> Vec<usize> {
+    let mut links = vec![];
+    for index in qm {
+        let Some(atom) = molecule.atoms.read_atom(*index) else {
+            continue;
+        };
+        for neighbor in molecule.neighbors(*index) {
+            if qm.contains(&neighbor) {
+                continue;
+            }
+            let Some(other) = molecule.atoms.read_atom(neighbor) else {
+                continue;
+            };
+            let bond = other.position - atom.position;
+            let length = scale
+                .map(|scale| bond.norm() * scale)
+                .unwrap_or_else(|| hydrogen_bond_length(atom.element));
+            let link = molecule.len();
+            molecule.atoms.set_atoms(
+                link,
+                vec![Some(Atom3D {
+                    element: 1,
+                    position: atom.position + bond.normalize() * length,
+                    formal_charge: 0.,
+                })],
+            );
+            molecule.bonds.set_bond(*index, link, Some(1.));
+            links.push(link);
+        }
+    }
+    links
+}
+
+#[test]
+fn oniom_boundary() {
+    use crate::{
+        chemistry::Atom3D,
+        layer::{Layer, SelectMany},
+        sparse_molecule::SparseAtomList,
+    };
+    use nalgebra::Point3;
+    let carbon = |x| Atom3D {
+        element: 6,
+        position: Point3::new(x, 0., 0.),
+        formal_charge: 0.,
+    };
+    // a chain with an empty slot, C0-C1-C3-C4
+    let mut molecule = SparseMolecule {
+        atoms: SparseAtomList::from(vec![
+            carbon(0.),
+            carbon(1.5),
+            carbon(0.),
+            carbon(3.),
+            carbon(4.5),
+        ]),
+        ..Default::default()
+    };
+    molecule.atoms.set_atoms(2, vec![None]);
+    for (a, b) in [(0, 1), (1, 3), (3, 4)] {
+        molecule.bonds.set_bond(a, b, Some(1.));
+    }
+    let molecule = Layer::Oniom {
+        select: SelectMany::Range(0..=3),
+        level: OniomLevel::Medium,
+    }
+    .filter(molecule)
+    .unwrap();
+    let molecule = Layer::Oniom {
+        select: SelectMany::Range(0..=1),
+        level: OniomLevel::High,
+    }
+    .filter(molecule)
+    .unwrap();
+    let levels = oniom_levels(&molecule);
+    assert_eq!(
+        levels,
+        vec![
+            OniomLevel::High,
+            OniomLevel::High,
+            OniomLevel::Medium,
+            OniomLevel::Low
+        ]
+    );
+    let bonds = molecule.bonds.to_continuous_list(&molecule.atoms);
+    assert_eq!(link_atoms(&levels, &bonds), vec![(2, 1), (3, 2)]);
+}
+
+#[test]
+fn qm_subsystem_link_atoms() {
+    use crate::{
+        layer::{Layer, SelectMany},
+        sparse_molecule::SparseAtomList,
+    };
+    use nalgebra::Point3;
+    let atom = |element, x| Atom3D {
+        element,
+        position: Point3::new(x, 0., 0.),
+        formal_charge: 0.,
+    };
+    // O0-C1-C2-C3, the QM region is O0 and C1
+    let mut molecule = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(8, -1.4), atom(6, 0.), atom(6, 1.5), atom(6, 3.)]),
+        ..Default::default()
+    };
+    for (a, b) in [(0, 1), (1, 2), (2, 3)] {
+        molecule.bonds.set_bond(a, b, Some(1.));
+    }
+    let qm = Layer::QmSubsystem {
+        select: SelectMany::Range(0..=1),
+        scale: None,
+    }
+    .filter(molecule.clone())
+    .unwrap();
+    let atoms = Vec::<Atom3D>::from(qm.atoms.clone());
+    assert_eq!(atoms.len(), 3);
+    assert_eq!(atoms[2].element, 1);
+    assert!((atoms[2].position.x - 1.09).abs() < 1e-8);
+    assert_eq!(qm.neighbors(1), vec![0, 4]);
+    let links = cap_qm_region(&mut molecule, &BTreeSet::from([0, 1]), Some(0.7));
+    assert_eq!(links, vec![4]);
+    let link = molecule.atoms.read_atom(4).unwrap();
+    assert!((link.position.x - 1.05).abs() < 1e-8);
+}
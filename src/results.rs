@@ -0,0 +1,289 @@
+use std::{collections::BTreeMap, path::Path, str::FromStr};
+
+use anyhow::{anyhow, Context, Result};
+use rusqlite::{params, params_from_iter, Connection};
+
+use crate::{layer::Layer, sparse_molecule::SparseMolecule};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    run_id TEXT NOT NULL,
+    workflow TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    variables TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS structures (
+    id INTEGER PRIMARY KEY,
+    run INTEGER NOT NULL REFERENCES runs(id),
+    title TEXT NOT NULL,
+    molecule TEXT NOT NULL,
+    layers TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS properties (
+    structure INTEGER NOT NULL REFERENCES structures(id),
+    name TEXT NOT NULL,
+    value REAL NOT NULL,
+    PRIMARY KEY (structure, name)
+);
+";
+
+/// A workflow run archived in the results database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunRecord {
+    /// Identifier of the run, e.g. the `run_id` of the step directories
+    pub run_id: String,
+    /// Absolute path of the workflow file
+    pub workflow: String,
+    /// Seconds since the Unix epoch
+    pub created: u64,
+    /// Content of the workflow file
+    pub content: String,
+    /// Workflow variables captured by the steps
+    pub variables: BTreeMap<String, f64>,
+}
+
+/// A final structure of a run, with the layers building it from the base of
+/// the workflow as its provenance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructureRecord {
+    pub title: String,
+    pub molecule: SparseMolecule,
+    pub layers: Vec<Layer>,
+    pub properties: BTreeMap<String, f64>,
+}
+
+/// A structure read from the results database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchivedStructure {
+    pub run_id: String,
+    pub workflow: String,
+    pub created: u64,
+    pub title: String,
+    pub molecule: SparseMolecule,
+    pub properties: BTreeMap<String, f64>,
+}
+
+/// Comparison of a property with a value, e.g. `energy<-1000.5`, the
+/// operators are `<`, `<=`, `>`, `>=`, `=` and `!=`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PropertyFilter {
+    name: String,
+    operator: &'static str,
+    value: f64,
+}
+
+impl FromStr for PropertyFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let start = s
+            .find(['<', '>', '=', '!'])
+            .with_context(|| format!("No comparison operator in {}", s))?;
+        let (name, rest) = s.split_at(start);
+        let operator = ["<=", ">=", "!=", "<", ">", "="]
+            .into_iter()
+            .find(|operator| rest.starts_with(operator))
+            .with_context(|| format!("Invalid comparison operator in {}", s))?;
+        let value = rest[operator.len()..].trim();
+        Ok(Self {
+            name: name.trim().to_string(),
+            operator,
+            value: value
+                .parse()
+                .with_context(|| format!("Invalid number {} in {}", value, s))?,
+        })
+    }
+}
+
+/// Conditions of the archived structures, all given conditions must be met.
+/// `run_id`, `workflow` and `title` are glob patterns of SQLite, e.g.
+/// `*catalyst*`.
+#[derive(Debug, Clone, Default)]
+pub struct ResultsQuery {
+    pub run_id: Option<String>,
+    pub workflow: Option<String>,
+    pub title: Option<String>,
+    pub filters: Vec<PropertyFilter>,
+}
+
+/// Archive of the final structures of workflow runs in a SQLite database, so
+/// structures and properties of past runs can be searched together.
+pub struct ResultsDatabase {
+    connection: Connection,
+}
+
+impl ResultsDatabase {
+    /// Open the database, which is created if not exists.
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Unable to open results database at {:?}", path))?;
+        connection
+            .execute_batch(SCHEMA)
+            .with_context(|| format!("Unable to create tables in {:?}", path))?;
+        Ok(Self { connection })
+    }
+
+    /// Add a run with its structures in one transaction, returns the row id of
+    /// the run.
+    pub fn archive(&mut self, run: &RunRecord, structures: &[StructureRecord]) -> Result<i64> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO runs (run_id, workflow, created, content, variables)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                run.run_id,
+                run.workflow,
+                run.created,
+                run.content,
+                serde_json::to_string(&run.variables)?
+            ],
+        )?;
+        let run_row = transaction.last_insert_rowid();
+        for structure in structures {
+            transaction
+                .execute(
+                    "INSERT INTO structures (run, title, molecule, layers) VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        run_row,
+                        structure.title,
+                        serde_json::to_string(&structure.molecule)?,
+                        serde_json::to_string(&structure.layers)?
+                    ],
+                )
+                .with_context(|| format!("Unable to archive structure {}", structure.title))?;
+            let structure_row = transaction.last_insert_rowid();
+            for (name, value) in &structure.properties {
+                transaction.execute(
+                    "INSERT INTO properties (structure, name, value) VALUES (?1, ?2, ?3)",
+                    params![structure_row, name, value],
+                )?;
+            }
+        }
+        transaction.commit()?;
+        Ok(run_row)
+    }
+
+    /// Archived structures meeting the conditions, in the order of archiving.
+    pub fn query(&self, query: &ResultsQuery) -> Result<Vec<ArchivedStructure>> {
+        let mut conditions = vec![];
+        let mut parameters: Vec<rusqlite::types::Value> = vec![];
+        for (column, pattern) in [
+            ("runs.run_id", &query.run_id),
+            ("runs.workflow", &query.workflow),
+            ("structures.title", &query.title),
+        ] {
+            if let Some(pattern) = pattern {
+                conditions.push(format!("{} GLOB ?", column));
+                parameters.push(pattern.to_string().into());
+            }
+        }
+        for filter in &query.filters {
+            conditions.push(format!(
+                "EXISTS (SELECT 1 FROM properties WHERE properties.structure = structures.id
+                AND properties.name = ? AND properties.value {} ?)",
+                filter.operator
+            ));
+            parameters.push(filter.name.to_string().into());
+            parameters.push(filter.value.into());
+        }
+        let conditions = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT structures.id, runs.run_id, runs.workflow, runs.created, structures.title,
+            structures.molecule FROM structures JOIN runs ON structures.run = runs.id {}
+            ORDER BY structures.id",
+            conditions
+        ))?;
+        let rows = statement
+            .query_map(params_from_iter(parameters), |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, u64>(3)?,
+                    row.get::<_, String>(4)?,
+                    row.get::<_, String>(5)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut properties = self
+            .connection
+            .prepare("SELECT name, value FROM properties WHERE structure = ?1")?;
+        rows.into_iter()
+            .map(|(id, run_id, workflow, created, title, molecule)| {
+                let molecule = serde_json::from_str(&molecule).map_err(|err| {
+                    anyhow!("Invalid molecule of archived structure {}: {}", title, err)
+                })?;
+                let properties = properties
+                    .query_map([id], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect::<Result<_, _>>()?;
+                Ok(ArchivedStructure {
+                    run_id,
+                    workflow,
+                    created,
+                    title,
+                    molecule,
+                    properties,
+                })
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn archive_and_query_results() {
+    let directory = tempfile::tempdir().unwrap();
+    let mut database = ResultsDatabase::open(&directory.path().join("results.db")).unwrap();
+    let run = |run_id: &str| RunRecord {
+        run_id: run_id.to_string(),
+        workflow: "/campaign/workflow.yaml".to_string(),
+        created: 1700000000,
+        content: "steps: []".to_string(),
+        variables: BTreeMap::from([("best".to_string(), -1.5)]),
+    };
+    let structure = |title: &str, energy: f64| StructureRecord {
+        title: title.to_string(),
+        molecule: SparseMolecule::default(),
+        layers: vec![Layer::Transparent],
+        properties: BTreeMap::from([("energy".to_string(), energy)]),
+    };
+    database
+        .archive(
+            &run("1"),
+            &[structure("LME_Me", -1.5), structure("LME_Ph", -0.5)],
+        )
+        .unwrap();
+    database
+        .archive(&run("2"), &[structure("LME_Me", -2.5)])
+        .unwrap();
+    let titles = |query: &ResultsQuery| {
+        database
+            .query(query)
+            .unwrap()
+            .into_iter()
+            .map(|structure| format!("{}/{}", structure.run_id, structure.title))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(titles(&ResultsQuery::default()).len(), 3);
+    let query = ResultsQuery {
+        title: Some("*_Me".to_string()),
+        filters: vec!["energy <= -2".parse().unwrap()],
+        ..Default::default()
+    };
+    assert_eq!(titles(&query), ["2/LME_Me"]);
+    let query = ResultsQuery {
+        run_id: Some("1".to_string()),
+        filters: vec!["energy>-1".parse().unwrap()],
+        ..Default::default()
+    };
+    assert_eq!(titles(&query), ["1/LME_Ph"]);
+    let archived = database.query(&query).unwrap();
+    assert_eq!(archived[0].properties["energy"], -0.5);
+    assert!("energy".parse::<PropertyFilter>().is_err());
+    assert!("energy<high".parse::<PropertyFilter>().is_err());
+}
@@ -0,0 +1,395 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use anyhow::{anyhow, Context, Result};
+use nalgebra::{Point3, Rotation3, Unit, Vector3};
+
+use crate::{
+    chemistry::{element_symbol_to_num, Atom3D},
+    sparse_molecule::{SparseAtomList, SparseBondMatrix, SparseMolecule},
+    utils::geometric::ideal_directions,
+};
+
+struct SmilesAtom {
+    element: usize,
+    aromatic: bool,
+    charge: i32,
+    /// Hydrogen count of bracket atoms, `None` for atoms in the organic subset
+    hydrogens: Option<usize>,
+}
+
+/// Parse a SMILES string to a molecule with explicit hydrogens.
+///
+/// Aromatic bonds get bond order 1.5, implicit hydrogens of the organic subset
+/// are added by the lowest normal valence. Stereo marks (`@`, `/`, `\`),
+/// isotopes and atom classes are accepted but ignored. The coordinates are only
+/// a rough tree layout with ideal bond directions, ring closure bonds are not
+/// relaxed, so optimize the structure before using it in a calculation.
+pub fn parse_smiles(smiles: &str) -> Result<SparseMolecule> {
+    let (atoms, bonds) = parse(smiles).with_context(|| format!("Invalid SMILES {}", smiles))?;
+    let mut elements = atoms.iter().map(|atom| atom.element).collect::<Vec<_>>();
+    let mut charges = atoms.iter().map(|atom| atom.charge).collect::<Vec<_>>();
+    let mut bonds = bonds;
+    for (index, atom) in atoms.iter().enumerate() {
+        let hydrogens = match atom.hydrogens {
+            Some(hydrogens) => hydrogens,
+            None => implicit_hydrogens(index, atom, &bonds)?,
+        };
+        for _ in 0..hydrogens {
+            elements.push(1);
+            charges.push(0);
+            bonds.push((index, elements.len() - 1, 1.));
+        }
+    }
+    let positions = layout(&elements, &bonds);
+    let atoms = elements
+        .into_iter()
+        .zip(charges)
+        .zip(positions)
+        .map(|((element, charge), position)| Atom3D {
+            element,
+            position,
+            formal_charge: charge as f64,
+        })
+        .collect::<Vec<_>>();
+    let mut bond_matrix = SparseBondMatrix::new(atoms.len());
+    for (a, b, bond) in bonds {
+        bond_matrix.set_bond(a, b, Some(bond));
+    }
+    Ok(SparseMolecule {
+        atoms: SparseAtomList::from(atoms),
+        bonds: bond_matrix,
+        ids: None,
+        groups: None,
+        ..Default::default()
+    })
+}
+
+type ParsedSmiles = (Vec<SmilesAtom>, Vec<(usize, usize, f64)>);
+
+fn parse(smiles: &str) -> Result<ParsedSmiles> {
+    let mut atoms: Vec<SmilesAtom> = vec![];
+    let mut bonds = vec![];
+    let mut chars = smiles.trim().chars().peekable();
+    let mut previous: Option<usize> = None;
+    let mut branches = vec![];
+    let mut pending_bond: Option<f64> = None;
+    let mut rings: BTreeMap<usize, (usize, Option<f64>)> = BTreeMap::new();
+    let default_bond = |atoms: &[SmilesAtom], a: usize, b: usize| {
+        if atoms[a].aromatic && atoms[b].aromatic {
+            1.5
+        } else {
+            1.
+        }
+    };
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => branches.push(previous.ok_or_else(|| anyhow!("Branch without atom"))?),
+            ')' => {
+                previous = Some(branches.pop().ok_or_else(|| anyhow!("Unmatched `)`"))?);
+            }
+            '.' => previous = None,
+            '-' | '/' | '\\' => pending_bond = Some(1.),
+            '=' => pending_bond = Some(2.),
+            '#' => pending_bond = Some(3.),
+            '$' => pending_bond = Some(4.),
+            ':' => pending_bond = Some(1.5),
+            '0'..='9' | '%' => {
+                let number = if c == '%' {
+                    let digits = [chars.next(), chars.next()]
+                        .into_iter()
+                        .collect::<Option<String>>()
+                        .ok_or_else(|| anyhow!("Incomplete ring number after %"))?;
+                    digits
+                        .parse::<usize>()
+                        .with_context(|| format!("Invalid ring number %{}", digits))?
+                } else {
+                    c as usize - '0' as usize
+                };
+                let current = previous.ok_or_else(|| anyhow!("Ring number without atom"))?;
+                if let Some((opened, bond)) = rings.remove(&number) {
+                    let bond = pending_bond
+                        .or(bond)
+                        .unwrap_or_else(|| default_bond(&atoms, opened, current));
+                    bonds.push((opened, current, bond));
+                } else {
+                    rings.insert(number, (current, pending_bond));
+                }
+                pending_bond = None;
+            }
+            c => {
+                let atom = if c == '[' {
+                    let mut content = String::new();
+                    loop {
+                        match chars.next() {
+                            Some(']') => break,
+                            Some(c) => content.push(c),
+                            None => Err(anyhow!("Unclosed bracket atom [{}", content))?,
+                        }
+                    }
+                    parse_bracket_atom(&content)?
+                } else {
+                    let mut symbol = c.to_string();
+                    if let Some(next) =
+                        chars.next_if(|next| matches!((c, next), ('C', 'l') | ('B', 'r')))
+                    {
+                        symbol.push(next);
+                    }
+                    if !matches!(
+                        symbol.as_str(),
+                        "B" | "C"
+                            | "N"
+                            | "O"
+                            | "P"
+                            | "S"
+                            | "F"
+                            | "Cl"
+                            | "Br"
+                            | "I"
+                            | "b"
+                            | "c"
+                            | "n"
+                            | "o"
+                            | "p"
+                            | "s"
+                    ) {
+                        Err(anyhow!("Unexpected character {}", c))?
+                    }
+                    organic_atom(&symbol)?
+                };
+                atoms.push(atom);
+                let current = atoms.len() - 1;
+                if let Some(previous) = previous {
+                    let bond =
+                        pending_bond.unwrap_or_else(|| default_bond(&atoms, previous, current));
+                    bonds.push((previous, current, bond));
+                }
+                pending_bond = None;
+                previous = Some(current);
+            }
+        }
+    }
+    if !branches.is_empty() {
+        Err(anyhow!("Unclosed branch"))?
+    }
+    if let Some(number) = rings.keys().next() {
+        Err(anyhow!("Unclosed ring {}", number))?
+    }
+    Ok((atoms, bonds))
+}
+
+fn organic_atom(symbol: &str) -> Result<SmilesAtom> {
+    let aromatic = symbol.chars().next().is_some_and(|c| c.is_lowercase());
+    Ok(SmilesAtom {
+        element: element_symbol_to_num(symbol)
+            .ok_or_else(|| anyhow!("Unknown element {}", symbol))?,
+        aromatic,
+        charge: 0,
+        hydrogens: None,
+    })
+}
+
+/// Parse the content of a bracket atom, e.g. `13CH3+`, `nH`, `O-`, `Fe+2`.
+fn parse_bracket_atom(content: &str) -> Result<SmilesAtom> {
+    let mut chars = content.chars().peekable();
+    while chars.next_if(|c| c.is_ascii_digit()).is_some() {}
+    let first = chars
+        .next()
+        .ok_or_else(|| anyhow!("Empty bracket atom [{}]", content))?;
+    let mut symbol = first.to_string();
+    if first.is_uppercase() {
+        if let Some(second) = chars.peek().copied() {
+            let candidate = format!("{}{}", first, second);
+            if second.is_lowercase() && element_symbol_to_num(&candidate).is_some() {
+                symbol = candidate;
+                chars.next();
+            }
+        }
+    } else if first == 's' && chars.next_if_eq(&'e').is_some() {
+        symbol = "se".to_string();
+    } else if first == 'a' && chars.next_if_eq(&'s').is_some() {
+        symbol = "as".to_string();
+    }
+    let aromatic = first.is_lowercase();
+    let mut capitalized = symbol.clone();
+    capitalized[..1].make_ascii_uppercase();
+    let element = element_symbol_to_num(&capitalized)
+        .ok_or_else(|| anyhow!("Unknown element {} in [{}]", symbol, content))?;
+    // chirality, e.g. @, @@ and @TH1
+    if chars.next_if_eq(&'@').is_some() {
+        chars.next_if_eq(&'@');
+        let class = chars.clone().take(2).collect::<String>();
+        if ["TH", "AL", "SP", "TB", "OH"].contains(&class.as_str()) {
+            chars.nth(1);
+            while chars.next_if(|c| c.is_ascii_digit()).is_some() {}
+        }
+    }
+    let mut hydrogens = 0;
+    if chars.next_if_eq(&'H').is_some() {
+        hydrogens = 1;
+        let mut digits = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+            digits.push(c);
+        }
+        if !digits.is_empty() {
+            hydrogens = digits.parse()?;
+        }
+    }
+    let mut charge = 0;
+    while let Some(sign) = chars.next_if(|c| *c == '+' || *c == '-') {
+        let sign = if sign == '+' { 1 } else { -1 };
+        let mut digits = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+            digits.push(c);
+        }
+        charge += sign
+            * if digits.is_empty() {
+                1
+            } else {
+                digits.parse()?
+            };
+    }
+    if chars.next_if_eq(&':').is_some() {
+        while chars.next_if(|c| c.is_ascii_digit()).is_some() {}
+    }
+    if let Some(c) = chars.next() {
+        Err(anyhow!("Unexpected {} in bracket atom [{}]", c, content))?
+    }
+    Ok(SmilesAtom {
+        element,
+        aromatic,
+        charge,
+        hydrogens: Some(hydrogens),
+    })
+}
+
+/// Valences of the organic subset by the OpenSMILES specification.
+fn organic_subset_valences(element: usize) -> &'static [usize] {
+    match element {
+        5 => &[3],
+        6 => &[4],
+        7 | 15 => &[3, 5],
+        8 => &[2],
+        16 => &[2, 4, 6],
+        9 | 17 | 35 | 53 => &[1],
+        _ => &[],
+    }
+}
+
+fn implicit_hydrogens(
+    index: usize,
+    atom: &SmilesAtom,
+    bonds: &[(usize, usize, f64)],
+) -> Result<usize> {
+    let valences = organic_subset_valences(atom.element);
+    if valences.is_empty() {
+        Err(anyhow!(
+            "Element {} is not in the organic subset, write it in brackets",
+            atom.element
+        ))?
+    }
+    // aromatic bonds count as single bonds, and the aromatic atom has one more
+    let mut used = bonds
+        .iter()
+        .filter(|(a, b, _)| *a == index || *b == index)
+        .map(|(_, _, bond)| if *bond == 1.5 { 1 } else { *bond as usize })
+        .sum::<usize>();
+    if atom.aromatic {
+        used += 1;
+    }
+    Ok(valences
+        .iter()
+        .find(|valence| **valence >= used)
+        .map(|valence| valence - used)
+        .unwrap_or(0))
+}
+
+/// Place the atoms by breadth-first traversal, the bonds of each atom point to
+/// the ideal directions (linear, trigonal, tetrahedral...) of its neighbor count.
+fn layout(elements: &[usize], bonds: &[(usize, usize, f64)]) -> Vec<Point3<f64>> {
+    let mut neighbors = vec![vec![]; elements.len()];
+    for (a, b, bond) in bonds {
+        neighbors[*a].push((*b, *bond));
+        neighbors[*b].push((*a, *bond));
+    }
+    let mut positions: Vec<Option<Point3<f64>>> = vec![None; elements.len()];
+    let mut component_offset = 0.;
+    for root in 0..elements.len() {
+        if positions[root].is_some() {
+            continue;
+        }
+        positions[root] = Some(Point3::new(component_offset, 0., 0.));
+        let mut queue: VecDeque<(usize, Option<usize>)> = VecDeque::from([(root, None)]);
+        let mut max_x = component_offset;
+        while let Some((current, parent)) = queue.pop_front() {
+            let origin = positions[current].unwrap();
+            max_x = max_x.max(origin.x);
+            let mut directions = ideal_directions(neighbors[current].len().max(1));
+            if let Some(parent) = parent {
+                let to_parent: Vector3<f64> = positions[parent].unwrap() - origin;
+                let rotation = Rotation3::rotation_between(&directions[0], &to_parent)
+                    .unwrap_or_else(|| {
+                        Rotation3::from_axis_angle(
+                            &Unit::new_normalize(
+                                directions[0].cross(&Vector3::y()) + Vector3::z() * 1e-6,
+                            ),
+                            std::f64::consts::PI,
+                        )
+                    });
+                directions = directions
+                    .into_iter()
+                    .skip(1)
+                    .map(|d| rotation * d)
+                    .collect();
+            }
+            let mut directions = directions.into_iter();
+            for (neighbor, bond) in &neighbors[current] {
+                if positions[*neighbor].is_some() {
+                    continue;
+                }
+                let direction = directions.next().unwrap_or_else(Vector3::x);
+                let length = if elements[current] == 1 || elements[*neighbor] == 1 {
+                    1.09
+                } else if *bond >= 3. {
+                    1.20
+                } else if *bond >= 2. {
+                    1.34
+                } else if *bond > 1. {
+                    1.40
+                } else {
+                    1.50
+                };
+                positions[*neighbor] = Some(origin + direction * length);
+                queue.push_back((*neighbor, Some(current)));
+            }
+        }
+        component_offset = max_x + 5.;
+    }
+    positions.into_iter().map(Option::unwrap).collect()
+}
+
+#[test]
+fn parse_common_smiles() {
+    let benzene = parse_smiles("c1ccccc1").unwrap();
+    assert_eq!(benzene.atoms.len(), 12);
+    assert_eq!(benzene.bonds.read_bond(0, 5), Some(1.5));
+    let acetate = parse_smiles("CC(=O)[O-]").unwrap();
+    assert_eq!(acetate.atoms.len(), 7);
+    assert_eq!(acetate.bonds.read_bond(1, 2), Some(2.));
+    assert_eq!(acetate.atoms.data()[3].unwrap().formal_charge, -1.);
+    let ammonium = parse_smiles("[NH4+]").unwrap();
+    assert_eq!(ammonium.atoms.len(), 5);
+    let pyrrole = parse_smiles("c1cc[nH]c1").unwrap();
+    assert_eq!(pyrrole.atoms.len(), 10);
+    let chloroform = parse_smiles("ClC(Cl)Cl").unwrap();
+    assert_eq!(chloroform.atoms.len(), 5);
+    let position = |index: usize| benzene.atoms.data()[index].unwrap().position;
+    assert!(((position(0) - position(1)).norm() - 1.40).abs() < 1e-6);
+    assert!(parse_smiles("C1CC").is_err());
+    assert!(parse_smiles("C(C").is_err());
+    assert!(parse_smiles("CX").is_err());
+    assert!(parse_smiles("C[NH4+").is_err());
+    let chiral = parse_smiles("N[C@@H](C)C(=O)O").unwrap();
+    assert_eq!(chiral.atoms.len(), 13);
+    assert_eq!(parse_smiles("F[C@TH1H](Cl)Br").unwrap().atoms.len(), 5);
+    assert!(parse_smiles("[CX]").is_err());
+}
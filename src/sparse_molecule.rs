@@ -6,38 +6,187 @@ use std::{
 };
 
 use anyhow::Context;
-use bincode::{Decode, Encode};
-use nalgebra::Isometry3;
-use serde::{Deserialize, Serialize};
+use bincode::{
+    de::Decoder,
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+    impl_borrow_decode, Decode, Encode,
+};
+use nalgebra::{Isometry3, Point3};
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{ser::SerializeStruct, Deserialize, Serialize};
 
 use crate::{
-    chemistry::{validated_element_num, Atom3D},
+    chemistry::{
+        atomic_mass, element_num_to_symbol, isotope_mass, validated_element_num, Atom3D,
+        AtomMetadata,
+    },
     group_name::GroupName,
-    layer::{Layer, SelectMany},
+    layer::{Layer, SelectMany, SelectOne},
+    migration::layer_format,
 };
 
-#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize, Encode, Decode)]
-pub struct SparseAtomList(Vec<Option<Atom3D>>);
+/// Atoms of a molecule, with the optional metadata of atoms stored by index.
+///
+/// It's serialized as the plain list of atoms if no metadata is set, or as
+/// `{atoms, metadata}` otherwise.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(try_from = "SparseAtomListLoader")]
+pub struct SparseAtomList {
+    atoms: Vec<Option<Atom3D>>,
+    metadata: BTreeMap<usize, AtomMetadata>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum SparseAtomListLoader {
+    Atoms(Vec<Option<Atom3D>>),
+    WithMetadata {
+        atoms: Vec<Option<Atom3D>>,
+        #[serde(default)]
+        metadata: BTreeMap<MetadataIndex, AtomMetadata>,
+    },
+}
+
+/// Atom index of metadata, which is a string as the key of JSON objects.
+#[derive(Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(untagged)]
+enum MetadataIndex {
+    Index(usize),
+    Text(String),
+}
+
+impl TryFrom<SparseAtomListLoader> for SparseAtomList {
+    type Error = anyhow::Error;
+
+    fn try_from(value: SparseAtomListLoader) -> Result<Self, Self::Error> {
+        match value {
+            SparseAtomListLoader::Atoms(atoms) => Ok(Self::from(atoms)),
+            SparseAtomListLoader::WithMetadata { atoms, metadata } => {
+                let metadata = metadata
+                    .into_iter()
+                    .map(|(index, metadata)| {
+                        let index = match index {
+                            MetadataIndex::Index(index) => index,
+                            MetadataIndex::Text(index) => index.parse().with_context(|| {
+                                format!("Invalid atom index {:?} of metadata", index)
+                            })?,
+                        };
+                        Ok((index, metadata))
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(Self { atoms, metadata })
+            }
+        }
+    }
+}
+
+impl Serialize for SparseAtomList {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.metadata.is_empty() {
+            self.atoms.serialize(serializer)
+        } else {
+            let mut state = serializer.serialize_struct("SparseAtomList", 2)?;
+            state.serialize_field("atoms", &self.atoms)?;
+            state.serialize_field("metadata", &self.metadata)?;
+            state.end()
+        }
+    }
+}
+
+impl JsonSchema for SparseAtomList {
+    fn schema_name() -> String {
+        "SparseAtomList".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        SparseAtomListLoader::json_schema(gen)
+    }
+}
+
+/// The binary encoding only stores occupied entries as (index, atom) pairs
+/// after the capacity, as Fill layers created from calculation results are
+/// usually full of `None` in large systems. The metadata follows as (index,
+/// metadata) pairs, which is absent in layers of format version 1 (see
+/// `layer_format`).
+impl Encode for SparseAtomList {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        let occupied = self
+            .atoms
+            .iter()
+            .enumerate()
+            .filter_map(|(index, atom)| atom.map(|atom| (index, atom)))
+            .collect::<Vec<_>>();
+        self.len().encode(encoder)?;
+        occupied.encode(encoder)?;
+        if layer_format() >= 2 {
+            self.metadata.encode(encoder)?;
+        }
+        Ok(())
+    }
+}
+
+/// Largest capacity of SparseAtomList and SparseBondMatrix to decode. The
+/// empty slots are not stored but allocated up front (quadratically for
+/// bonds), so a damaged capacity would allocate unbounded memory without it.
+/// It's far beyond the structures the dense bond matrix can hold in memory.
+const MAX_DECODED_CAPACITY: usize = 1 << 16;
+
+/// Capacity of SparseAtomList or SparseBondMatrix, an error if it's larger
+/// than `MAX_DECODED_CAPACITY`.
+fn decode_capacity<D: Decoder>(decoder: &mut D) -> Result<usize, DecodeError> {
+    let capacity = usize::decode(decoder)?;
+    if capacity > MAX_DECODED_CAPACITY {
+        Err(DecodeError::Other(
+            "capacity of the sparse atoms or bonds is too large",
+        ))?
+    }
+    Ok(capacity)
+}
+
+impl Decode for SparseAtomList {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let capacity = decode_capacity(decoder)?;
+        let occupied = Vec::<(usize, Atom3D)>::decode(decoder)?;
+        let mut atoms = Self::new(capacity);
+        for (index, atom) in occupied {
+            let slot = atoms.atoms.get_mut(index).ok_or(DecodeError::Other(
+                "atom index out of the capacity of SparseAtomList",
+            ))?;
+            *slot = Some(atom);
+        }
+        if layer_format() >= 2 {
+            atoms.metadata = BTreeMap::decode(decoder)?;
+        }
+        Ok(atoms)
+    }
+}
+
+impl_borrow_decode!(SparseAtomList);
 
 impl From<Vec<Option<Atom3D>>> for SparseAtomList {
     fn from(value: Vec<Option<Atom3D>>) -> Self {
-        Self(value)
+        Self {
+            atoms: value,
+            metadata: BTreeMap::new(),
+        }
     }
 }
 
 impl From<Vec<Atom3D>> for SparseAtomList {
     fn from(value: Vec<Atom3D>) -> Self {
-        Self(value.into_iter().map(|atom| Some(atom)).collect())
+        Self::from(value.into_iter().map(Some).collect::<Vec<_>>())
     }
 }
 
-impl Into<Vec<Atom3D>> for SparseAtomList {
-    fn into(self) -> Vec<Atom3D> {
-        self.0
+impl From<SparseAtomList> for Vec<Atom3D> {
+    fn from(value: SparseAtomList) -> Self {
+        value
+            .atoms
             .into_iter()
             .filter_map(|atom| {
                 atom.and_then(|atom| {
-                    if validated_element_num(&atom.element) {
+                    if validated_element_num(atom.element) {
                         Some(atom)
                     } else {
                         None
@@ -48,9 +197,10 @@ impl Into<Vec<Atom3D>> for SparseAtomList {
     }
 }
 
-impl Into<BTreeMap<usize, usize>> for SparseAtomList {
-    fn into(self) -> BTreeMap<usize, usize> {
-        self.0
+impl From<SparseAtomList> for BTreeMap<usize, usize> {
+    fn from(value: SparseAtomList) -> Self {
+        value
+            .atoms
             .into_iter()
             .enumerate()
             .filter_map(|(index, atom)| {
@@ -70,39 +220,50 @@ impl Into<BTreeMap<usize, usize>> for SparseAtomList {
 
 impl SparseAtomList {
     pub fn new(capacity: usize) -> Self {
-        Self(vec![Default::default(); capacity])
+        Self::from(vec![None; capacity])
     }
 
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.atoms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.atoms.is_empty()
     }
 
     fn extend_to(&mut self, capacity: usize) {
         let current_capacity = self.len();
         if current_capacity < capacity {
-            self.0
+            self.atoms
                 .extend_from_slice(&vec![Default::default(); capacity - current_capacity]);
         }
     }
 
     pub fn offset(self, offset: usize) -> Self {
-        Self(vec![vec![Default::default(); offset], self.0].concat())
+        Self {
+            atoms: [vec![Default::default(); offset], self.atoms].concat(),
+            metadata: self
+                .metadata
+                .into_iter()
+                .map(|(index, metadata)| (index + offset, metadata))
+                .collect(),
+        }
     }
 
     pub fn read_atom(&self, index: usize) -> Option<Atom3D> {
-        self.0.get(index).copied().unwrap_or_default()
+        self.atoms.get(index).copied().unwrap_or_default()
     }
 
     pub fn set_atoms(&mut self, offset: usize, atoms: Vec<Option<Atom3D>>) {
-        let len_after_set = (offset + atoms.len() - 1).max(self.len());
+        let len_after_set = (offset + atoms.len()).max(self.len());
         self.extend_to(len_after_set);
         for (idx, atom) in atoms.into_iter().enumerate() {
-            self.0[idx + offset] = atom
+            self.atoms[idx + offset] = atom
         }
     }
 
     pub fn isometry(&mut self, isometry: Isometry3<f64>, select: &BTreeSet<usize>) {
-        self.0
+        self.atoms
             .iter_mut()
             .enumerate()
             .filter(|(idx, _)| select.contains(idx))
@@ -116,20 +277,48 @@ impl SparseAtomList {
     pub fn migrate(&mut self, other: Self) {
         let capacity = self.len().max(other.len());
         self.extend_to(capacity);
-        self.0
+        self.atoms
             .iter_mut()
             .enumerate()
-            .for_each(|(index, atom)| *atom = other.read_atom(index).or(*atom))
+            .for_each(|(index, atom)| *atom = other.read_atom(index).or(*atom));
+        for (index, metadata) in other.metadata {
+            self.set_metadata(index, metadata);
+        }
+    }
+
+    pub fn read_metadata(&self, index: usize) -> Option<&AtomMetadata> {
+        self.metadata.get(&index)
+    }
+
+    /// Put the metadata over the current one of the atom, see `AtomMetadata::overlay`.
+    pub fn set_metadata(&mut self, index: usize, metadata: AtomMetadata) {
+        self.metadata.entry(index).or_default().overlay(metadata);
+    }
+
+    /// Remove the tags of the keys from the metadata of the atom.
+    pub fn remove_tags(&mut self, index: usize, keys: &[String]) {
+        if let Some(metadata) = self.metadata.get_mut(&index) {
+            for key in keys {
+                metadata.tags.remove(key);
+            }
+            if metadata.is_empty() {
+                self.metadata.remove(&index);
+            }
+        }
+    }
+
+    pub fn metadata(&self) -> &BTreeMap<usize, AtomMetadata> {
+        &self.metadata
     }
 
     pub fn data(&self) -> &Vec<Option<Atom3D>> {
-        &self.0
+        &self.atoms
     }
 
-    pub fn update_from_continuous_list(&self, list: &Vec<Atom3D>) -> Option<Self> {
+    pub fn update_from_continuous_list(&self, list: &[Atom3D]) -> Option<Self> {
         let mut sparse_list = self.clone();
         let mut wait_to_update = list.iter();
-        for item in sparse_list.0.iter_mut() {
+        for item in sparse_list.atoms.iter_mut() {
             if item
                 .map(|atom| validated_element_num(atom.element))
                 .unwrap_or_default()
@@ -147,7 +336,7 @@ impl SparseAtomList {
             .unwrap_or_default()
         {
             Some(
-                self.0
+                self.atoms
                     .iter()
                     .take(index)
                     .filter(|item| {
@@ -162,7 +351,7 @@ impl SparseAtomList {
     }
 
     pub fn from_continuous_index(&self, index: usize) -> Option<usize> {
-        self.0
+        self.atoms
             .iter()
             .enumerate()
             .filter(|(_, atom)| {
@@ -175,9 +364,45 @@ impl SparseAtomList {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize, Encode, Decode)]
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize, JsonSchema)]
 pub struct SparseBondMatrix(Vec<Vec<Option<f64>>>);
 
+/// Like SparseAtomList, the binary encoding only stores the capacity and the
+/// occupied entries of the upper triangle as (a, b, bond) triplets.
+impl Encode for SparseBondMatrix {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        let mut occupied = vec![];
+        for a in 0..self.len() {
+            for b in a..self.len() {
+                if let Some(bond) = self.read_bond(a, b) {
+                    occupied.push((a, b, bond));
+                }
+            }
+        }
+        self.len().encode(encoder)?;
+        occupied.encode(encoder)
+    }
+}
+
+impl Decode for SparseBondMatrix {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let capacity = decode_capacity(decoder)?;
+        let occupied = Vec::<(usize, usize, f64)>::decode(decoder)?;
+        let mut bonds = Self::new(capacity);
+        for (a, b, bond) in occupied {
+            if a.max(b) >= capacity {
+                Err(DecodeError::Other(
+                    "bond index out of the capacity of SparseBondMatrix",
+                ))?
+            }
+            bonds.set_bond(a, b, Some(bond));
+        }
+        Ok(bonds)
+    }
+}
+
+impl_borrow_decode!(SparseBondMatrix);
+
 impl SparseBondMatrix {
     pub fn new(capacity: usize) -> Self {
         Self(vec![vec![None; capacity]; capacity])
@@ -191,6 +416,10 @@ impl SparseBondMatrix {
         self.0.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
     fn extend_to(&mut self, capacity: usize) {
         if self.len() < capacity {
             let current_capacity = self.len();
@@ -208,9 +437,9 @@ impl SparseBondMatrix {
         let current_rows = self
             .0
             .into_iter()
-            .map(|row| vec![vec![None; offset], row].concat())
+            .map(|row| [vec![None; offset], row].concat())
             .collect();
-        Self(vec![prepend_rows, current_rows].concat())
+        Self([prepend_rows, current_rows].concat())
     }
 
     pub fn read_bond(&self, a: usize, b: usize) -> Option<f64> {
@@ -242,17 +471,14 @@ impl SparseBondMatrix {
         let mut continuous_list = Vec::with_capacity(atom_list.len().pow(2).div(2));
         for row_idx in 0..self.len() {
             for col_idx in row_idx..self.len() {
-                match (
+                if let (Some(row_idx), Some(col_idx), Some(bond)) = (
                     atom_list.to_continuous_index(row_idx),
                     atom_list.to_continuous_index(col_idx),
                     self.read_bond(row_idx, col_idx),
                 ) {
-                    (Some(row_idx), Some(col_idx), Some(bond)) => {
-                        if bond != 0. {
-                            continuous_list.push((row_idx, col_idx, bond));
-                        }
+                    if bond != 0. {
+                        continuous_list.push((row_idx, col_idx, bond));
                     }
-                    _ => {}
                 }
             }
         }
@@ -275,20 +501,65 @@ impl<T: Clone + Iterator<Item = ((usize, usize), f64)>> From<T> for SparseBondMa
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize, Encode, Decode)]
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
 #[serde(try_from = "SparseMoleculeLoader")]
 pub struct SparseMolecule {
     pub atoms: SparseAtomList,
     pub bonds: SparseBondMatrix,
     pub ids: Option<BTreeMap<String, usize>>,
     pub groups: Option<GroupName>,
+    /// Total charge used by the structure writers, see `FormatOptions`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub charge: Option<i32>,
+    /// Spin multiplicity used by the structure writers, see `FormatOptions`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multiplicity: Option<u32>,
+}
+
+/// The charge and multiplicity are absent in layers before format version 3
+/// (see `layer_format`).
+impl Encode for SparseMolecule {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.atoms.encode(encoder)?;
+        self.bonds.encode(encoder)?;
+        self.ids.encode(encoder)?;
+        self.groups.encode(encoder)?;
+        if layer_format() >= 3 {
+            self.charge.encode(encoder)?;
+            self.multiplicity.encode(encoder)?;
+        }
+        Ok(())
+    }
 }
 
+impl Decode for SparseMolecule {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let mut molecule = Self {
+            atoms: Decode::decode(decoder)?,
+            bonds: Decode::decode(decoder)?,
+            ids: Decode::decode(decoder)?,
+            groups: Decode::decode(decoder)?,
+            ..Default::default()
+        };
+        if layer_format() >= 3 {
+            molecule.charge = Decode::decode(decoder)?;
+            molecule.multiplicity = Decode::decode(decoder)?;
+        }
+        Ok(molecule)
+    }
+}
+
+impl_borrow_decode!(SparseMolecule);
+
 impl SparseMolecule {
     pub fn len(&self) -> usize {
         self.atoms.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.atoms.is_empty()
+    }
+
     pub fn extend_to(&mut self, capacity: usize) {
         self.atoms.extend_to(capacity);
         self.bonds.extend_to(capacity);
@@ -309,6 +580,8 @@ impl SparseMolecule {
             }
             _ => self.groups = self.groups.clone().or(other.groups.clone()),
         }
+        self.charge = other.charge.or(self.charge);
+        self.multiplicity = other.multiplicity.or(self.multiplicity);
     }
 
     pub fn offset(self, offset: usize) -> Self {
@@ -331,11 +604,279 @@ impl SparseMolecule {
             bonds,
             ids,
             groups,
+            charge: self.charge,
+            multiplicity: self.multiplicity,
+        }
+    }
+
+    /// Generate the layers which turn `self` into `target` when applied on it.
+    ///
+    /// Changed atoms are collected in a SetAtom layer and changed bonds in a
+    /// SetBond layer (a removed bond is written as 0.0), changed atom metadata
+    /// in a SetMetadata layer, changed charge and multiplicity in a SetCharge
+    /// layer, new ids and group members are written with IdMap and GroupMap.
+    /// Layers without any content are omitted, so identical molecules produce an
+    /// empty list. Removal of ids, group members and metadata fields can't be
+    /// expressed by layers and is ignored.
+    pub fn diff(&self, target: &Self) -> Vec<Layer> {
+        let capacity = self.len().max(target.len());
+        let atoms = (0..capacity)
+            .filter_map(|index| {
+                let atom = target.atoms.read_atom(index);
+                if self.atoms.read_atom(index) != atom {
+                    Some((SelectOne::Index(index), atom))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        let capacity = self.bonds.len().max(target.bonds.len());
+        let mut bonds = vec![];
+        for a in 0..capacity {
+            for b in a..capacity {
+                let current = self.bonds.read_bond(a, b).unwrap_or_default();
+                let updated = target.bonds.read_bond(a, b).unwrap_or_default();
+                if current != updated {
+                    bonds.push((SelectOne::Index(a), SelectOne::Index(b), updated));
+                }
+            }
+        }
+        let ids = target
+            .ids
+            .iter()
+            .flatten()
+            .filter(|(id, index)| self.ids.as_ref().and_then(|ids| ids.get(*id)) != Some(*index))
+            .map(|(id, index)| (id.to_string(), SelectOne::Index(*index)))
+            .collect::<BTreeMap<_, _>>();
+        let mut groups: BTreeMap<String, BTreeSet<SelectOne>> = BTreeMap::new();
+        for (name, index) in target.groups.iter().flat_map(|groups| groups.data()) {
+            let existed = self
+                .groups
+                .as_ref()
+                .map(|groups| groups.data().contains(&(name.to_string(), *index)))
+                .unwrap_or_default();
+            if !existed {
+                groups
+                    .entry(name.to_string())
+                    .or_default()
+                    .insert(SelectOne::Index(*index));
+            }
+        }
+        let metadata = target
+            .atoms
+            .metadata()
+            .iter()
+            .filter(|(index, metadata)| self.atoms.read_metadata(**index) != Some(*metadata))
+            .map(|(index, metadata)| (SelectOne::Index(*index), metadata.clone()))
+            .collect::<Vec<_>>();
+        let mut layers = vec![];
+        if !atoms.is_empty() {
+            layers.push(Layer::SetAtom { atoms });
+        }
+        if !metadata.is_empty() {
+            layers.push(Layer::SetMetadata { atoms: metadata });
+        }
+        let charge = target.charge.filter(|_| target.charge != self.charge);
+        let multiplicity = target
+            .multiplicity
+            .filter(|_| target.multiplicity != self.multiplicity);
+        if charge.is_some() || multiplicity.is_some() {
+            layers.push(Layer::SetCharge {
+                charge,
+                multiplicity,
+            });
+        }
+        if !bonds.is_empty() {
+            layers.push(Layer::SetBond { bonds });
+        }
+        if !ids.is_empty() {
+            layers.push(Layer::IdMap(ids));
+        }
+        if !groups.is_empty() {
+            layers.push(Layer::GroupMap {
+                groups: groups
+                    .into_iter()
+                    .map(|(name, indexes)| (name, SelectMany::Indexes(indexes)))
+                    .collect(),
+            });
+        }
+        layers
+    }
+
+    /// Indexes of existing (not removed or hidden) atoms bonded to the atom.
+    pub fn neighbors(&self, index: usize) -> Vec<usize> {
+        self.bonds
+            .get_neighbors(index)
+            .map(|row| {
+                row.enumerate()
+                    .filter(|(neighbor, bond)| {
+                        bond.unwrap_or_default() != 0. && self.is_present(*neighbor)
+                    })
+                    .map(|(neighbor, _)| neighbor)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn is_present(&self, index: usize) -> bool {
+        self.atoms
+            .read_atom(index)
+            .map(|atom| validated_element_num(atom.element))
+            .unwrap_or_default()
+    }
+
+    fn is_heavy(&self, index: usize) -> bool {
+        self.is_present(index)
+            && self
+                .atoms
+                .read_atom(index)
+                .map(|atom| atom.element != 1)
+                .unwrap_or_default()
+    }
+
+    /// Atoms connected to `c` without passing the bond b-c, `c` included. If b-c
+    /// is in a ring, `b` is included too.
+    pub fn bond_side(&self, b: usize, c: usize) -> BTreeSet<usize> {
+        let mut visited = BTreeSet::from([c]);
+        let mut stack = vec![c];
+        while let Some(current) = stack.pop() {
+            for neighbor in self.neighbors(current) {
+                if (current, neighbor) != (c, b) && visited.insert(neighbor) {
+                    stack.push(neighbor);
+                }
+            }
+        }
+        visited
+    }
+
+    /// Rotatable bonds as `(b, c)` with `b < c`: single bonds not in a ring
+    /// between two heavy atoms, each of which has another heavy neighbor, so
+    /// rotation around them changes the heavy atom skeleton.
+    pub fn rotatable_bonds(&self) -> Vec<(usize, usize)> {
+        let mut rotatable = vec![];
+        for b in 0..self.len() {
+            if !self.is_heavy(b) {
+                continue;
+            }
+            for c in self.neighbors(b).into_iter().filter(|c| *c > b) {
+                if self.bonds.read_bond(b, c) == Some(1.)
+                    && self.is_heavy(c)
+                    && self.torsion_atoms(b, c).is_some()
+                    && !self.bond_side(b, c).contains(&b)
+                {
+                    rotatable.push((b, c));
+                }
+            }
+        }
+        rotatable
+    }
+
+    /// Existing atoms with their indexes.
+    fn present_atoms(&self) -> impl Iterator<Item = (usize, Atom3D)> + '_ {
+        (0..self.len())
+            .filter(|index| self.is_present(*index))
+            .filter_map(|index| Some((index, self.atoms.read_atom(index)?)))
+    }
+
+    /// Geometric center of the atoms, `None` if there is no atom.
+    pub fn centroid(&self) -> Option<Point3<f64>> {
+        SelectMany::All.centroid(self)
+    }
+
+    /// Center of mass of the atoms weighted by the atomic masses (see
+    /// `atomic_mass`), `None` if there is no atom.
+    pub fn center_of_mass(&self) -> Option<Point3<f64>> {
+        SelectMany::All.center_of_mass(self)
+    }
+
+    /// Count of the atoms of each element.
+    pub fn element_counts(&self) -> BTreeMap<usize, usize> {
+        let mut counts = BTreeMap::new();
+        for (_, atom) in self.present_atoms() {
+            *counts.entry(atom.element).or_default() += 1;
         }
+        counts
+    }
+
+    /// Molecular formula in the Hill system, carbon and hydrogen first if there
+    /// is carbon and then the other elements alphabetically, e.g. `C2H6O`.
+    pub fn formula(&self) -> String {
+        let counts = self.element_counts();
+        let mut symbols = counts
+            .iter()
+            .filter_map(|(element, count)| Some((element_num_to_symbol(element)?, *count)))
+            .collect::<Vec<_>>();
+        let hill_rank = |symbol: &str| match symbol {
+            "C" => 0,
+            "H" if counts.contains_key(&6) => 1,
+            _ => 2,
+        };
+        symbols.sort_by(|(a, _), (b, _)| hill_rank(a).cmp(&hill_rank(b)).then(a.cmp(b)));
+        symbols
+            .into_iter()
+            .map(|(symbol, count)| {
+                if count == 1 {
+                    symbol.to_string()
+                } else {
+                    format!("{}{}", symbol, count)
+                }
+            })
+            .collect()
+    }
+
+    /// Molecular weight (Dalton) by the atomic masses (see `atomic_mass`), or
+    /// the isotope masses of the atoms with an isotope in the metadata (see
+    /// `isotope_mass`).
+    pub fn molecular_weight(&self) -> f64 {
+        self.present_atoms()
+            .map(|(index, atom)| {
+                match self
+                    .atoms
+                    .read_metadata(index)
+                    .and_then(|metadata| metadata.isotope)
+                {
+                    Some(mass_number) => isotope_mass(atom.element, mass_number),
+                    None => atomic_mass(atom.element).unwrap_or_default(),
+                }
+            })
+            .sum()
+    }
+
+    /// Corners of the axis-aligned box enclosing the atoms, as the minimum and
+    /// the maximum coordinates, `None` if there is no atom.
+    pub fn bounding_box(&self) -> Option<(Point3<f64>, Point3<f64>)> {
+        self.present_atoms().fold(None, |corners, (_, atom)| {
+            let position = atom.position;
+            Some(match corners {
+                None => (position, position),
+                Some((min, max)) => (min.inf(&position), max.sup(&position)),
+            })
+        })
+    }
+
+    /// Atoms `[a, b, c, d]` defining the torsion of the bond b-c, `a` and `d`
+    /// are the heavy neighbors with the lowest indexes.
+    pub fn torsion_atoms(&self, b: usize, c: usize) -> Option<[usize; 4]> {
+        let substituent = |center: usize, other: usize| {
+            self.neighbors(center)
+                .into_iter()
+                .find(|neighbor| *neighbor != other && self.is_heavy(*neighbor))
+        };
+        Some([substituent(b, c)?, b, c, substituent(c, b)?])
+    }
+}
+
+impl JsonSchema for SparseMolecule {
+    fn schema_name() -> String {
+        "SparseMolecule".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        SparseMoleculeLoader::json_schema(gen)
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 #[serde(untagged)]
 enum SparseMoleculeLoader {
     FilePath(PathBuf),
@@ -346,11 +887,15 @@ enum SparseMoleculeLoader {
         ids: Option<BTreeMap<String, usize>>,
         #[serde(default)]
         groups: Option<GroupName>,
+        #[serde(default)]
+        charge: Option<i32>,
+        #[serde(default)]
+        multiplicity: Option<u32>,
     },
     Component(Vec<SparseMoleculeComponent>),
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 struct SparseMoleculeComponent {
     name: String,
     #[serde(default)]
@@ -386,11 +931,15 @@ impl TryFrom<SparseMoleculeLoader> for SparseMolecule {
                 bonds,
                 ids,
                 groups,
+                charge,
+                multiplicity,
             } => Ok(Self {
                 atoms,
                 bonds,
                 ids,
                 groups,
+                charge,
+                multiplicity,
             }),
             SparseMoleculeLoader::FilePath(path) => {
                 let file = File::open(&path).with_context(|| {
@@ -409,3 +958,221 @@ impl TryFrom<SparseMoleculeLoader> for SparseMolecule {
         }
     }
 }
+
+#[test]
+fn diff_layers_rebuild_target() {
+    use nalgebra::Point3;
+    let carbon = |x: f64| Atom3D {
+        element: 6,
+        position: Point3::new(x, 0., 0.),
+        formal_charge: 0.,
+    };
+    let mut current = SparseMolecule::default();
+    current.atoms.set_atoms(
+        0,
+        vec![Some(carbon(0.)), Some(carbon(1.5)), Some(carbon(3.))],
+    );
+    current.bonds.set_bond(0, 1, Some(1.));
+    current.bonds.set_bond(1, 2, Some(1.));
+    let mut target = current.clone();
+    target
+        .atoms
+        .set_atoms(1, vec![Some(carbon(1.4)), None, Some(carbon(4.))]);
+    target.bonds.set_bond(0, 1, Some(2.));
+    target.bonds.set_bond(1, 2, None);
+    target.ids = Some(BTreeMap::from([("C1".to_string(), 1)]));
+    let layers = current.diff(&target);
+    assert_eq!(layers.len(), 3);
+    let mut rebuilt = current.clone();
+    for layer in layers {
+        rebuilt = layer.filter(rebuilt).unwrap();
+    }
+    assert_eq!(rebuilt.atoms.data(), target.atoms.data());
+    assert_eq!(
+        rebuilt.bonds.to_continuous_list(&rebuilt.atoms),
+        target.bonds.to_continuous_list(&target.atoms)
+    );
+    assert_eq!(rebuilt.ids, target.ids);
+    assert!(target.diff(&target).is_empty());
+}
+
+#[test]
+fn detect_rotatable_bonds() {
+    use nalgebra::Point3;
+    let atom = |element| {
+        Some(Atom3D {
+            element,
+            position: Point3::origin(),
+            formal_charge: 0.,
+        })
+    };
+    // butane with a hydrogen on C0, and a cyclopropyl on C3
+    let mut molecule = SparseMolecule::default();
+    molecule.atoms.set_atoms(
+        0,
+        vec![
+            atom(6),
+            atom(6),
+            atom(6),
+            atom(6),
+            atom(1),
+            atom(6),
+            atom(6),
+            atom(6),
+        ],
+    );
+    for (a, b) in [
+        (0, 1),
+        (1, 2),
+        (2, 3),
+        (0, 4),
+        (3, 5),
+        (5, 6),
+        (6, 7),
+        (7, 5),
+    ] {
+        molecule.bonds.set_bond(a, b, Some(1.));
+    }
+    assert_eq!(molecule.rotatable_bonds(), vec![(1, 2), (2, 3), (3, 5)]);
+    assert_eq!(molecule.torsion_atoms(1, 2), Some([0, 1, 2, 3]));
+    assert_eq!(molecule.bond_side(1, 2), BTreeSet::from([2, 3, 5, 6, 7]));
+    assert!(molecule.bond_side(5, 6).contains(&5));
+}
+
+#[test]
+fn compact_binary_encoding() {
+    use nalgebra::Point3;
+    let mut molecule = SparseMolecule::default();
+    molecule.extend_to(1000);
+    molecule.atoms.set_atoms(
+        10,
+        vec![Some(Atom3D {
+            element: 8,
+            position: Point3::new(1., 2., 3.),
+            formal_charge: -1.,
+        })],
+    );
+    molecule.bonds.set_bond(10, 500, Some(1.));
+    molecule.bonds.set_bond(3, 3, Some(0.));
+    molecule.charge = Some(-1);
+    let encoded = bincode::encode_to_vec(&molecule, bincode::config::standard()).unwrap();
+    assert!(encoded.len() < 100);
+    let (decoded, _): (SparseMolecule, _) =
+        bincode::decode_from_slice(&encoded, bincode::config::standard()).unwrap();
+    assert_eq!(decoded, molecule);
+}
+
+#[test]
+fn overlay_atom_metadata() {
+    use nalgebra::Point3;
+    let mut molecule = SparseMolecule::default();
+    molecule.atoms.set_atoms(
+        0,
+        vec![Some(Atom3D {
+            element: 6,
+            position: Point3::origin(),
+            formal_charge: 0.,
+        })],
+    );
+    assert!(serde_json::to_value(&molecule.atoms).unwrap().is_array());
+    molecule.atoms.set_metadata(
+        0,
+        AtomMetadata {
+            label: Some("C1".to_string()),
+            tags: BTreeMap::from([("role".to_string(), "core".to_string())]),
+            ..Default::default()
+        },
+    );
+    let mut upper = SparseAtomList::new(1);
+    upper.set_metadata(
+        0,
+        AtomMetadata {
+            partial_charge: Some(-0.2),
+            tags: BTreeMap::from([("site".to_string(), "A".to_string())]),
+            ..Default::default()
+        },
+    );
+    let mut target = molecule.clone();
+    target.atoms.migrate(upper);
+    let metadata = target.atoms.read_metadata(0).unwrap();
+    assert_eq!(metadata.label.as_deref(), Some("C1"));
+    assert_eq!(metadata.partial_charge, Some(-0.2));
+    assert_eq!(metadata.tags.len(), 2);
+    let layers = molecule.diff(&target);
+    assert!(matches!(layers.as_slice(), [Layer::SetMetadata { .. }]));
+    assert_eq!(layers[0].filter(molecule).unwrap(), target);
+    let json = serde_json::to_string(&target.atoms).unwrap();
+    assert_eq!(
+        serde_json::from_str::<SparseAtomList>(&json).unwrap(),
+        target.atoms
+    );
+    let yaml = "atoms: [null]\nmetadata:\n  0: {isotope: 13}\n";
+    let atoms: SparseAtomList = serde_yaml::from_str(yaml).unwrap();
+    assert_eq!(atoms.read_metadata(0).unwrap().isotope, Some(13));
+    let encoded = bincode::encode_to_vec(&target, bincode::config::standard()).unwrap();
+    let (decoded, _): (SparseMolecule, _) =
+        bincode::decode_from_slice(&encoded, bincode::config::standard()).unwrap();
+    assert_eq!(decoded, target);
+    assert_eq!(
+        target
+            .offset(2)
+            .atoms
+            .read_metadata(2)
+            .unwrap()
+            .label
+            .as_deref(),
+        Some("C1")
+    );
+}
+
+#[test]
+fn centers_and_bounding_box() {
+    let atom = |element, x| {
+        Some(Atom3D {
+            element,
+            position: Point3::new(x, 1., -x),
+            formal_charge: 0.,
+        })
+    };
+    // carbon monoxide with a removed atom
+    let mut molecule = SparseMolecule::default();
+    molecule
+        .atoms
+        .set_atoms(0, vec![atom(6, 0.), None, atom(8, 1.128)]);
+    assert_eq!(molecule.centroid(), Some(Point3::new(0.564, 1., -0.564)));
+    let center = molecule.center_of_mass().unwrap();
+    assert!((center.x - 1.128 * 15.999 / (12.011 + 15.999)).abs() < 1e-9);
+    assert_eq!(
+        molecule.bounding_box(),
+        Some((Point3::new(0., 1., -1.128), Point3::new(1.128, 1., 0.)))
+    );
+    assert_eq!(SparseMolecule::default().center_of_mass(), None);
+    let centered = Layer::CenterOfMassToOrigin.filter(molecule).unwrap();
+    assert!(centered.center_of_mass().unwrap().coords.norm() < 1e-9);
+}
+
+#[test]
+fn formula_and_molecular_weight() {
+    use crate::smiles::parse_smiles;
+    let ethanol = parse_smiles("CCO").unwrap();
+    assert_eq!(ethanol.formula(), "C2H6O");
+    assert_eq!(
+        ethanol.element_counts(),
+        BTreeMap::from([(1, 6), (6, 2), (8, 1)])
+    );
+    assert!((ethanol.molecular_weight() - 46.069).abs() < 1e-3);
+    assert_eq!(parse_smiles("O").unwrap().formula(), "H2O");
+    assert_eq!(parse_smiles("[Na+].[Cl-]").unwrap().formula(), "ClNa");
+    // Deuterated methane
+    let mut methane = parse_smiles("C").unwrap();
+    for index in 1..5 {
+        methane.atoms.set_metadata(
+            index,
+            AtomMetadata {
+                isotope: Some(2),
+                ..Default::default()
+            },
+        );
+    }
+    assert!((methane.molecular_weight() - (12.011 + 4. * 2.014102)).abs() < 1e-9);
+}
@@ -0,0 +1,334 @@
+use std::{cmp::Ordering, collections::BTreeSet, f64::consts::PI, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    layer::{Layer, SelectMany, SelectOne},
+    sparse_molecule::SparseMolecule,
+    utils::geometric::dihedral_angle,
+};
+
+/// Configuration of a double bond by the highest priority substituents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DoubleBondGeometry {
+    E,
+    Z,
+}
+
+impl Display for DoubleBondGeometry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::E => write!(f, "E"),
+            Self::Z => write!(f, "Z"),
+        }
+    }
+}
+
+/// Spheres explored from a stereocenter when comparing its substituents.
+const CIP_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Chirality {
+    R,
+    S,
+}
+
+impl Display for Chirality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::R => write!(f, "R"),
+            Self::S => write!(f, "S"),
+        }
+    }
+}
+
+/// Atomic numbers of each sphere of the substituent starting at `start`,
+/// sorted in descending order.
+///
+/// Multiple bonds add duplicated atoms, and atoms already on the path from the
+/// center (ring closures) are duplicated without further exploration. Aromatic
+/// bonds are treated as single bonds.
+fn substituent_spheres(molecule: &SparseMolecule, center: usize, start: usize) -> Vec<Vec<usize>> {
+    let element = |index: usize| {
+        molecule
+            .atoms
+            .read_atom(index)
+            .map(|atom| atom.element)
+            .unwrap_or_default()
+    };
+    let duplicates = |a: usize, b: usize| {
+        let bond = molecule.bonds.read_bond(a, b).unwrap_or_default();
+        if bond >= 2. {
+            bond.round() as usize - 1
+        } else {
+            0
+        }
+    };
+    // (atom, path from the center), duplicated atoms are not explored
+    let mut current = vec![(start, vec![center])];
+    let mut spheres = vec![vec![element(start)]];
+    for _ in 1..CIP_DEPTH {
+        let mut sphere = vec![];
+        let mut next = vec![];
+        for (atom, path) in current {
+            let parent = *path.last().unwrap();
+            sphere.extend(vec![element(parent); duplicates(atom, parent)]);
+            for neighbor in molecule.neighbors(atom) {
+                if neighbor == parent {
+                    continue;
+                }
+                sphere.extend(vec![element(neighbor); duplicates(atom, neighbor) + 1]);
+                if !path.contains(&neighbor) {
+                    let mut path = path.clone();
+                    path.push(atom);
+                    next.push((neighbor, path));
+                }
+            }
+        }
+        if sphere.is_empty() {
+            break;
+        }
+        sphere.sort_by(|a, b| b.cmp(a));
+        spheres.push(sphere);
+        current = next;
+    }
+    spheres
+}
+
+/// Neighbors of the atom ordered by CIP priority from high to low, `None` if
+/// the atom doesn't have 4 neighbors or any two of them can't be distinguished.
+///
+/// Substituents are compared sphere by sphere with the sorted atomic numbers
+/// of each sphere, which is an approximation of the hierarchical digraph rules
+/// and doesn't consider isotopes or stereo descriptors.
+pub fn cip_priorities(molecule: &SparseMolecule, center: usize) -> Option<[usize; 4]> {
+    let mut neighbors = molecule
+        .neighbors(center)
+        .into_iter()
+        .map(|neighbor| (neighbor, substituent_spheres(molecule, center, neighbor)))
+        .collect::<Vec<_>>();
+    if neighbors.len() != 4 {
+        return None;
+    }
+    neighbors.sort_by(|(_, a), (_, b)| b.cmp(a));
+    if neighbors
+        .windows(2)
+        .any(|pair| pair[0].1.cmp(&pair[1].1) == Ordering::Equal)
+    {
+        return None;
+    }
+    Some([0, 1, 2, 3].map(|index| neighbors[index].0))
+}
+
+/// R/S descriptor of the stereocenter from the 3D coordinates.
+pub fn chirality(molecule: &SparseMolecule, center: usize) -> Option<Chirality> {
+    let [a, b, c, _] = cip_priorities(molecule, center)?;
+    let origin = molecule.atoms.read_atom(center)?.position;
+    let [a, b, c] = [a, b, c].map(|index| {
+        molecule
+            .atoms
+            .read_atom(index)
+            .map(|atom| atom.position - origin)
+    });
+    let volume = a?.dot(&b?.cross(&c?));
+    // clockwise from the highest priority when the lowest points away
+    if volume < 0. {
+        Some(Chirality::R)
+    } else {
+        Some(Chirality::S)
+    }
+}
+
+/// Stereocenters (atoms with 4 distinguishable neighbors) and their descriptors.
+///
+/// Hydrogen atoms must be explicit, atoms with implicit hydrogens or lone
+/// pairs as the fourth substituent are not perceived.
+pub fn stereocenters(molecule: &SparseMolecule) -> Vec<(usize, Chirality)> {
+    (0..molecule.len())
+        .filter_map(|center| Some((center, chirality(molecule, center)?)))
+        .collect()
+}
+
+/// A layer inverting the stereocenter by swapping two of its substituents.
+///
+/// The two smallest substituents not in a ring with the center are rotated
+/// together by 180 degrees around the bisector of their bonds, so the
+/// configuration of other stereocenters in them is kept. `None` if there are
+/// less than 2 such substituents.
+pub fn inversion(molecule: &SparseMolecule, center: usize) -> Option<Layer> {
+    let origin = molecule.atoms.read_atom(center)?.position;
+    let mut substituents = molecule
+        .neighbors(center)
+        .into_iter()
+        .map(|neighbor| (neighbor, molecule.bond_side(center, neighbor)))
+        .filter(|(_, side)| !side.contains(&center))
+        .collect::<Vec<_>>();
+    if molecule.neighbors(center).len() != 4 || substituents.len() < 2 {
+        return None;
+    }
+    substituents.sort_by_key(|(_, side)| side.len());
+    let (a, a_side) = &substituents[0];
+    let (b, b_side) = &substituents[1];
+    let direction = |index: usize| {
+        molecule
+            .atoms
+            .read_atom(index)
+            .map(|atom| (atom.position - origin).normalize())
+    };
+    let axis = (direction(*a)? + direction(*b)?).normalize();
+    Some(Layer::Rotation {
+        select: SelectMany::Indexes(
+            a_side
+                .union(b_side)
+                .map(|index| SelectOne::Index(*index))
+                .collect::<BTreeSet<_>>(),
+        ),
+        center: origin,
+        axis,
+        angle: PI,
+        degree: false,
+    })
+}
+
+/// The substituent of `b` with the highest priority other than `c`, `None` if
+/// `b` has no other neighbor or two of them can't be distinguished.
+fn highest_substituent(molecule: &SparseMolecule, b: usize, c: usize) -> Option<usize> {
+    let mut substituents = molecule
+        .neighbors(b)
+        .into_iter()
+        .filter(|neighbor| *neighbor != c)
+        .map(|neighbor| (neighbor, substituent_spheres(molecule, b, neighbor)))
+        .collect::<Vec<_>>();
+    substituents.sort_by(|(_, x), (_, y)| y.cmp(x));
+    match substituents.as_slice() {
+        [(highest, _)] => Some(*highest),
+        [(highest, x), (_, y), ..] if x != y => Some(*highest),
+        _ => None,
+    }
+}
+
+/// E/Z descriptor of the double bond b=c from the 3D coordinates, `None` if it
+/// is not a double bond or any end has two identical substituents.
+pub fn double_bond_geometry(
+    molecule: &SparseMolecule,
+    b: usize,
+    c: usize,
+) -> Option<DoubleBondGeometry> {
+    if molecule.bonds.read_bond(b, c) != Some(2.) {
+        return None;
+    }
+    let a = highest_substituent(molecule, b, c)?;
+    let d = highest_substituent(molecule, c, b)?;
+    let [a, b, c, d] = [a, b, c, d].map(|index| molecule.atoms.read_atom(index));
+    let angle = dihedral_angle(&a?.position, &b?.position, &c?.position, &d?.position);
+    if angle.abs() < 90. {
+        Some(DoubleBondGeometry::Z)
+    } else {
+        Some(DoubleBondGeometry::E)
+    }
+}
+
+/// Double bonds not in a ring with E/Z isomerism, as `(b, c)` with `b < c`.
+pub fn stereo_double_bonds(molecule: &SparseMolecule) -> Vec<((usize, usize), DoubleBondGeometry)> {
+    let mut double_bonds = vec![];
+    for b in 0..molecule.len() {
+        for c in molecule.neighbors(b).into_iter().filter(|c| *c > b) {
+            if let Some(geometry) = double_bond_geometry(molecule, b, c) {
+                if !molecule.bond_side(b, c).contains(&b) {
+                    double_bonds.push(((b, c), geometry));
+                }
+            }
+        }
+    }
+    double_bonds
+}
+
+/// A layer switching E/Z geometry of the double bond b=c by rotating the `c`
+/// side by 180 degrees around the bond, `None` if the bond is in a ring.
+pub fn flip_double_bond(molecule: &SparseMolecule, b: usize, c: usize) -> Option<Layer> {
+    let side = molecule.bond_side(b, c);
+    if side.contains(&b) {
+        return None;
+    }
+    let b_position = molecule.atoms.read_atom(b)?.position;
+    let c_position = molecule.atoms.read_atom(c)?.position;
+    Some(Layer::Rotation {
+        select: SelectMany::Indexes(side.into_iter().map(SelectOne::Index).collect()),
+        center: b_position,
+        axis: (c_position - b_position).normalize(),
+        angle: PI,
+        degree: false,
+    })
+}
+
+#[test]
+fn perceive_and_invert() {
+    use crate::{chemistry::Atom3D, sparse_molecule::SparseAtomList};
+    use nalgebra::Point3;
+    let atom = |element, x, y, z| Atom3D {
+        element,
+        position: Point3::new(x, y, z),
+        formal_charge: 0.,
+    };
+    // CHFClBr, the priorities are Br > Cl > F > H
+    let mut molecule = SparseMolecule {
+        atoms: SparseAtomList::from(vec![
+            atom(6, 0., 0., 0.),
+            atom(35, 0., 1., 0.3),
+            atom(17, 1., 0., 0.3),
+            atom(9, -1., -1., 0.3),
+            atom(1, 0., 0., -1.),
+        ]),
+        ..Default::default()
+    };
+    for neighbor in 1..5 {
+        molecule.bonds.set_bond(0, neighbor, Some(1.));
+    }
+    assert_eq!(cip_priorities(&molecule, 0), Some([1, 2, 3, 4]));
+    assert_eq!(stereocenters(&molecule), vec![(0, Chirality::R)]);
+    let inverted = inversion(&molecule, 0).unwrap().filter(molecule).unwrap();
+    assert_eq!(chirality(&inverted, 0), Some(Chirality::S));
+    // CH2FCl is not a stereocenter
+    let mut achiral = inverted.clone();
+    achiral.atoms.set_atoms(1, vec![Some(atom(1, 0., 1., 0.3))]);
+    assert!(stereocenters(&achiral).is_empty());
+}
+
+#[test]
+fn perceive_and_flip_double_bond() {
+    use crate::{chemistry::Atom3D, sparse_molecule::SparseAtomList};
+    use nalgebra::Point3;
+    let atom = |element, x, y| Atom3D {
+        element,
+        position: Point3::new(x, y, 0.),
+        formal_charge: 0.,
+    };
+    // 2-butene in cis geometry, C1 and C4 have no hydrogens for brevity
+    let mut butene = SparseMolecule {
+        atoms: SparseAtomList::from(vec![
+            atom(6, -1., 1.),
+            atom(6, 0., 0.),
+            atom(6, 1.3, 0.),
+            atom(6, 2.3, 1.),
+            atom(1, 0., -1.),
+            atom(1, 1.3, -1.),
+        ]),
+        ..Default::default()
+    };
+    for (a, b) in [(0, 1), (2, 3), (1, 4), (2, 5)] {
+        butene.bonds.set_bond(a, b, Some(1.));
+    }
+    butene.bonds.set_bond(1, 2, Some(2.));
+    assert_eq!(
+        stereo_double_bonds(&butene),
+        vec![((1, 2), DoubleBondGeometry::Z)]
+    );
+    let flipped = flip_double_bond(&butene, 1, 2)
+        .unwrap()
+        .filter(butene)
+        .unwrap();
+    assert_eq!(
+        double_bond_geometry(&flipped, 1, 2),
+        Some(DoubleBondGeometry::E)
+    );
+}
@@ -0,0 +1,294 @@
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet, VecDeque};
+
+use bincode::{Decode, Encode};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    layer::{SelectMany, SelectOne},
+    sparse_molecule::SparseMolecule,
+    utils::geometric::dihedral_angle,
+};
+
+/// Position on a ring relative to an anchor atom of the ring.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Encode, Decode, JsonSchema)]
+pub enum RingPosition {
+    /// 1 bond away along the ring
+    Ortho,
+    /// 2 bonds away along the ring
+    Meta,
+    /// 3 bonds away along the ring
+    Para,
+}
+
+impl RingPosition {
+    fn bonds(&self) -> usize {
+        match self {
+            Self::Ortho => 1,
+            Self::Meta => 2,
+            Self::Para => 3,
+        }
+    }
+}
+
+/// Structural criteria selecting the atoms of a group, evaluated on the
+/// structure when the layer is applied.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode, JsonSchema)]
+#[serde(tag = "rule", deny_unknown_fields)]
+pub enum StructuralGroup {
+    /// Atoms at least `min_bonds` and at most `max_bonds` bonds away from any
+    /// atom of `from`, e.g. the first coordination sphere of a metal is
+    /// `{rule: BondDistance, from: 26, min_bonds: 1, max_bonds: 1}`.
+    BondDistance {
+        from: SelectMany,
+        #[serde(default)]
+        min_bonds: usize,
+        max_bonds: usize,
+    },
+    /// Atoms at the position of the smallest ring through each anchor atom,
+    /// e.g. the ortho carbons of the aryl rings bound to a metal with the ipso
+    /// carbons as anchors. Anchors not in a ring are ignored.
+    Ring {
+        anchor: SelectMany,
+        position: RingPosition,
+    },
+    /// Atoms `x` of `candidates` with the angle `a`-`vertex`-`x` in the range
+    /// (degrees), e.g. the ligand atoms trans to `a` around a metal.
+    Angle {
+        a: SelectOne,
+        vertex: SelectOne,
+        #[serde(default)]
+        candidates: SelectMany,
+        range: (f64, f64),
+    },
+    /// Atoms `x` of `candidates` with the dihedral `a`-`b`-`c`-`x` in the range
+    /// (degrees in -180 to 180), compared by the absolute value if `absolute`
+    /// is set, e.g. the anti substituents of a bond are in `[150, 180]`.
+    Dihedral {
+        a: SelectOne,
+        b: SelectOne,
+        c: SelectOne,
+        #[serde(default)]
+        candidates: SelectMany,
+        range: (f64, f64),
+        #[serde(default)]
+        absolute: bool,
+    },
+}
+
+impl StructuralGroup {
+    /// Indexes of the existing atoms matching the criteria, the referenced atom
+    /// is the error if it is not found.
+    pub fn to_indexes(&self, molecule: &SparseMolecule) -> Result<BTreeSet<usize>, SelectOne> {
+        let position = |select: &SelectOne| {
+            select
+                .get_atom(molecule)
+                .map(|atom| atom.position)
+                .ok_or(select.clone())
+        };
+        let in_range = |value: f64, (min, max): (f64, f64)| min <= value && value <= max;
+        let candidates = |select: &SelectMany, excluded: &[Option<usize>]| {
+            select
+                .to_indexes(molecule)
+                .into_iter()
+                .filter(|index| !excluded.contains(&Some(*index)))
+                .filter_map(|index| Some((index, molecule.atoms.read_atom(index)?.position)))
+                .collect::<Vec<_>>()
+        };
+        Ok(match self {
+            Self::BondDistance {
+                from,
+                min_bonds,
+                max_bonds,
+            } => bond_distances(molecule, from.to_indexes(molecule), *max_bonds)
+                .into_iter()
+                .filter(|(_, distance)| distance >= min_bonds)
+                .map(|(index, _)| index)
+                .collect(),
+            Self::Ring { anchor, position } => anchor
+                .to_indexes(molecule)
+                .into_iter()
+                .filter_map(|anchor| smallest_ring(molecule, anchor))
+                .flat_map(|ring| {
+                    let bonds = position.bonds();
+                    if ring.len() < 2 * bonds {
+                        vec![]
+                    } else {
+                        vec![ring[bonds], ring[ring.len() - bonds]]
+                    }
+                })
+                .collect(),
+            Self::Angle {
+                a,
+                vertex,
+                candidates: selected,
+                range,
+            } => {
+                let (a_position, vertex_position) = (position(a)?, position(vertex)?);
+                let excluded = [a.to_index(molecule), vertex.to_index(molecule)];
+                candidates(selected, &excluded)
+                    .into_iter()
+                    .filter(|(_, x)| {
+                        let angle = (a_position - vertex_position)
+                            .angle(&(x - vertex_position))
+                            .to_degrees();
+                        in_range(angle, *range)
+                    })
+                    .map(|(index, _)| index)
+                    .collect()
+            }
+            Self::Dihedral {
+                a,
+                b,
+                c,
+                candidates: selected,
+                range,
+                absolute,
+            } => {
+                let (a_position, b_position, c_position) =
+                    (position(a)?, position(b)?, position(c)?);
+                let excluded = [
+                    a.to_index(molecule),
+                    b.to_index(molecule),
+                    c.to_index(molecule),
+                ];
+                candidates(selected, &excluded)
+                    .into_iter()
+                    .filter(|(_, x)| {
+                        let dihedral = dihedral_angle(&a_position, &b_position, &c_position, x);
+                        let dihedral = if *absolute { dihedral.abs() } else { dihedral };
+                        in_range(dihedral, *range)
+                    })
+                    .map(|(index, _)| index)
+                    .collect()
+            }
+        })
+    }
+}
+
+/// Shortest count of bonds from the sources to the atoms within `max_bonds`.
+fn bond_distances(
+    molecule: &SparseMolecule,
+    sources: BTreeSet<usize>,
+    max_bonds: usize,
+) -> BTreeMap<usize, usize> {
+    let mut distances = sources
+        .into_iter()
+        .filter(|index| molecule.atoms.read_atom(*index).is_some())
+        .map(|index| (index, 0))
+        .collect::<BTreeMap<_, _>>();
+    let mut queue = distances.keys().copied().collect::<VecDeque<_>>();
+    while let Some(current) = queue.pop_front() {
+        let distance = distances[&current];
+        if distance == max_bonds {
+            continue;
+        }
+        for neighbor in molecule.neighbors(current) {
+            if let Entry::Vacant(entry) = distances.entry(neighbor) {
+                entry.insert(distance + 1);
+                queue.push_back(neighbor);
+            }
+        }
+    }
+    distances
+}
+
+/// Atoms of the smallest ring through the anchor in the order along the ring,
+/// starting from the anchor.
+fn smallest_ring(molecule: &SparseMolecule, anchor: usize) -> Option<Vec<usize>> {
+    molecule
+        .neighbors(anchor)
+        .into_iter()
+        .filter_map(|start| {
+            // Shortest path from the neighbor back to the anchor without the
+            // bond between them
+            let mut previous = BTreeMap::from([(start, start)]);
+            let mut queue = VecDeque::from([start]);
+            while let Some(current) = queue.pop_front() {
+                for neighbor in molecule.neighbors(current) {
+                    if current == start && neighbor == anchor {
+                        continue;
+                    }
+                    if neighbor == anchor {
+                        let mut ring = vec![anchor, current];
+                        let mut atom = current;
+                        while atom != start {
+                            atom = previous[&atom];
+                            ring.push(atom);
+                        }
+                        return Some(ring);
+                    }
+                    if let Entry::Vacant(entry) = previous.entry(neighbor) {
+                        entry.insert(current);
+                        queue.push_back(neighbor);
+                    }
+                }
+            }
+            None
+        })
+        .min_by_key(Vec::len)
+}
+
+#[test]
+fn structural_groups() {
+    use crate::layer::Layer;
+    // Toluene: ring carbons 0-5, methyl carbon 6 on carbon 5
+    let molecule = Layer::FromSmiles {
+        smiles: "c1ccccc1C".to_string(),
+    }
+    .filter(SparseMolecule::default())
+    .unwrap();
+    let heavy = |indexes: BTreeSet<usize>| {
+        indexes
+            .into_iter()
+            .filter(|index| molecule.atoms.read_atom(*index).unwrap().element != 1)
+            .collect::<Vec<_>>()
+    };
+    let methyl = SelectMany::Indexes(BTreeSet::from([SelectOne::Index(6)]));
+    let group = StructuralGroup::BondDistance {
+        from: methyl.clone(),
+        min_bonds: 1,
+        max_bonds: 2,
+    };
+    assert_eq!(heavy(group.to_indexes(&molecule).unwrap()), [0, 4, 5]);
+    let ring = |position| StructuralGroup::Ring {
+        anchor: SelectMany::Indexes(BTreeSet::from([SelectOne::Index(5)])),
+        position,
+    };
+    assert_eq!(
+        ring(RingPosition::Ortho).to_indexes(&molecule).unwrap(),
+        BTreeSet::from([0, 4])
+    );
+    assert_eq!(
+        ring(RingPosition::Para).to_indexes(&molecule).unwrap(),
+        BTreeSet::from([2])
+    );
+    let non_ring = StructuralGroup::Ring {
+        anchor: methyl,
+        position: RingPosition::Ortho,
+    };
+    assert!(non_ring.to_indexes(&molecule).unwrap().is_empty());
+    let angle = StructuralGroup::Angle {
+        a: SelectOne::Index(0),
+        vertex: SelectOne::Index(5),
+        candidates: SelectMany::All,
+        range: (100., 140.),
+    };
+    assert_eq!(heavy(angle.to_indexes(&molecule).unwrap()), [4, 6]);
+    let missing = StructuralGroup::Angle {
+        a: SelectOne::IdName("missing".to_string()),
+        vertex: SelectOne::Index(5),
+        candidates: SelectMany::All,
+        range: (0., 180.),
+    };
+    assert!(missing.to_indexes(&molecule).is_err());
+    let grouped = Layer::StructuralGroups {
+        groups: vec![("ortho".to_string(), ring(RingPosition::Ortho))],
+    }
+    .filter(molecule.clone())
+    .unwrap();
+    assert_eq!(
+        SelectMany::GroupName("ortho".to_string()).to_indexes(&grouped),
+        BTreeSet::from([0, 4])
+    );
+}
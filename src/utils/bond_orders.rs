@@ -0,0 +1,220 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    chemistry::covalent_radius, sparse_molecule::SparseMolecule,
+    utils::hydrogens::missing_hydrogens,
+};
+
+/// Bonds shorter than this ratio to the sum of covalent radii may be multiple.
+const MULTIPLE_BOND_RATIO: f64 = 0.95;
+/// Bonds shorter than this ratio to the sum of covalent radii may be triple.
+const TRIPLE_BOND_RATIO: f64 = 0.83;
+
+/// Assign double and triple orders to the single bonds between the selected
+/// atoms from their lengths and the valences of the atoms, e.g. after the
+/// bonds are perceived from the distances.
+///
+/// The free valences of the atoms are their missing hydrogens (see
+/// `missing_hydrogens`), so the hydrogens must be present. The short bonds
+/// between atoms with free valences are raised one at a time, starting from
+/// the atoms with the fewest candidates (ends of conjugated chains) and the
+/// shortest bonds. Six-membered rings of atoms each with a double bond in the
+/// same ring are then set aromatic (order 1.5).
+pub fn assign_bond_orders(molecule: &mut SparseMolecule, selected: &BTreeSet<usize>) {
+    let ratio = |molecule: &SparseMolecule, a: usize, b: usize| {
+        let (a, b) = (molecule.atoms.read_atom(a)?, molecule.atoms.read_atom(b)?);
+        let radii = covalent_radius(a.element)? + covalent_radius(b.element)?;
+        Some((a.position - b.position).norm() / radii)
+    };
+    let mut free = selected
+        .iter()
+        .map(|index| (*index, missing_hydrogens(molecule, *index)))
+        .filter(|(_, free)| *free > 0)
+        .collect::<BTreeMap<_, _>>();
+    let mut candidates = free
+        .keys()
+        .flat_map(|a| {
+            molecule
+                .neighbors(*a)
+                .into_iter()
+                .filter(|b| b > a && free.contains_key(b))
+                .filter(|b| molecule.bonds.read_bond(*a, *b) == Some(1.))
+                .filter_map(|b| Some((*a, b, ratio(molecule, *a, b)?)))
+                .filter(|(_, _, ratio)| *ratio < MULTIPLE_BOND_RATIO)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    while !candidates.is_empty() {
+        let mut counts = BTreeMap::<usize, usize>::new();
+        for (a, b, _) in &candidates {
+            *counts.entry(*a).or_default() += 1;
+            *counts.entry(*b).or_default() += 1;
+        }
+        let (position, &(a, b, ratio)) = candidates
+            .iter()
+            .enumerate()
+            .min_by(|(_, x), (_, y)| {
+                let fewest = |(a, b, _): &&(usize, usize, f64)| counts[a].min(counts[b]);
+                fewest(x).cmp(&fewest(y)).then(x.2.total_cmp(&y.2))
+            })
+            .unwrap();
+        candidates.swap_remove(position);
+        let order = if ratio < TRIPLE_BOND_RATIO && free[&a] >= 2 && free[&b] >= 2 {
+            3
+        } else {
+            2
+        };
+        molecule.bonds.set_bond(a, b, Some(order as f64));
+        *free.get_mut(&a).unwrap() -= order - 1;
+        *free.get_mut(&b).unwrap() -= order - 1;
+        candidates.retain(|(a, b, _)| free[a] > 0 && free[b] > 0);
+    }
+    let aromatic = six_membered_rings(molecule, selected)
+        .into_iter()
+        .filter(|ring| {
+            ring.iter().all(|a| {
+                molecule
+                    .neighbors(*a)
+                    .into_iter()
+                    .filter(|b| molecule.bonds.read_bond(*a, *b) == Some(2.))
+                    .filter(|b| ring.contains(b))
+                    .count()
+                    == 1
+            })
+        })
+        .collect::<Vec<_>>();
+    for ring in aromatic {
+        for (position, a) in ring.iter().enumerate() {
+            let b = ring[(position + 1) % ring.len()];
+            molecule.bonds.set_bond(*a, b, Some(1.5));
+        }
+    }
+}
+
+/// Rings of six selected atoms, each in the order along the ring from its
+/// lowest index.
+fn six_membered_rings(molecule: &SparseMolecule, selected: &BTreeSet<usize>) -> Vec<Vec<usize>> {
+    fn extend(
+        molecule: &SparseMolecule,
+        selected: &BTreeSet<usize>,
+        path: &mut Vec<usize>,
+        rings: &mut BTreeMap<BTreeSet<usize>, Vec<usize>>,
+    ) {
+        let last = *path.last().unwrap();
+        for next in molecule.neighbors(last) {
+            if path.len() == 6 {
+                if next == path[0] {
+                    rings
+                        .entry(path.iter().copied().collect())
+                        .or_insert_with(|| path.clone());
+                }
+            } else if next > path[0] && selected.contains(&next) && !path.contains(&next) {
+                path.push(next);
+                extend(molecule, selected, path, rings);
+                path.pop();
+            }
+        }
+    }
+    let mut rings = BTreeMap::new();
+    for start in selected {
+        extend(molecule, selected, &mut vec![*start], &mut rings);
+    }
+    rings.into_values().collect()
+}
+
+#[test]
+fn bond_orders_from_geometry() {
+    use crate::{
+        chemistry::Atom3D,
+        layer::{Layer, SelectMany},
+        sparse_molecule::SparseAtomList,
+    };
+    use nalgebra::Point3;
+    let atom = |element, x: f64, y: f64, z: f64| Atom3D {
+        element,
+        position: Point3::new(x, y, z),
+        ..Default::default()
+    };
+    // Benzene, with acetylene, formaldehyde and ethane away from it
+    let mut atoms = (0..6)
+        .flat_map(|k| {
+            let angle = k as f64 * std::f64::consts::PI / 3.;
+            let (x, y) = (angle.cos(), angle.sin());
+            [
+                atom(6, 1.39 * x, 1.39 * y, 0.),
+                atom(1, 2.47 * x, 2.47 * y, 0.),
+            ]
+        })
+        .collect::<Vec<_>>();
+    atoms.extend([
+        atom(6, 0., 0., 10.),
+        atom(6, 1.2, 0., 10.),
+        atom(1, -1.06, 0., 10.),
+        atom(1, 2.26, 0., 10.),
+        atom(6, 0., 0., 20.),
+        atom(8, 1.21, 0., 20.),
+        atom(1, -0.55, 0.94, 20.),
+        atom(1, -0.55, -0.94, 20.),
+        atom(6, 0., 0., 30.),
+        atom(6, 1.54, 0., 30.),
+    ]);
+    let structure = SparseMolecule {
+        atoms: SparseAtomList::from(atoms),
+        ..Default::default()
+    };
+    let structure = Layer::PerceiveBonds {
+        select: SelectMany::All,
+        tolerance: 0.45,
+        orders: true,
+    }
+    .filter(structure)
+    .unwrap();
+    let bond = |a, b| structure.bonds.read_bond(a, b);
+    assert!((0..6).all(|k| bond(2 * k, (2 * k + 2) % 12) == Some(1.5)));
+    assert_eq!(bond(0, 1), Some(1.));
+    assert_eq!(bond(12, 13), Some(3.));
+    assert_eq!(bond(16, 17), Some(2.));
+    assert_eq!(bond(16, 18), Some(1.));
+    // Not raised by the free valences of the bare carbons, as it is long
+    assert_eq!(bond(20, 21), Some(1.));
+}
+
+#[test]
+fn aromatic_within_ring() {
+    use crate::{chemistry::Atom3D, sparse_molecule::SparseAtomList};
+    use nalgebra::Point3;
+    // Two rings linked by double bonds 0=6 and 5=7, far apart to keep the orders
+    let mut molecule = SparseMolecule {
+        atoms: SparseAtomList::from(
+            (0..12)
+                .map(|index| Atom3D {
+                    element: 6,
+                    position: Point3::new(index as f64 * 10., 0., 0.),
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>(),
+        ),
+        ..Default::default()
+    };
+    for (a, b, order) in [
+        (0, 1, 1.),
+        (1, 2, 2.),
+        (2, 3, 1.),
+        (3, 4, 2.),
+        (4, 5, 1.),
+        (5, 0, 1.),
+        (6, 7, 1.),
+        (7, 8, 1.),
+        (8, 9, 2.),
+        (9, 10, 1.),
+        (10, 11, 2.),
+        (11, 6, 1.),
+        (0, 6, 2.),
+        (5, 7, 2.),
+    ] {
+        molecule.bonds.set_bond(a, b, Some(order));
+    }
+    assign_bond_orders(&mut molecule, &(0..12).collect());
+    assert_eq!(molecule.bonds.read_bond(1, 2), Some(2.));
+    assert_eq!(molecule.bonds.read_bond(8, 9), Some(2.));
+}
@@ -0,0 +1,131 @@
+use std::collections::BTreeMap;
+
+use crate::sparse_molecule::SparseMolecule;
+
+/// Hybridization of the atom by its bond orders, 1 for sp (a triple bond or
+/// two double bonds), 2 for sp2 (a double or aromatic bond) and 3 for sp3.
+fn hybridization(molecule: &SparseMolecule, index: usize) -> usize {
+    let orders = molecule
+        .neighbors(index)
+        .into_iter()
+        .filter_map(|neighbor| molecule.bonds.read_bond(index, neighbor))
+        .collect::<Vec<_>>();
+    let multiple = orders.iter().filter(|order| **order >= 1.5).count();
+    if orders.iter().any(|order| *order >= 2.5) || multiple >= 2 && orders.len() <= 2 {
+        1
+    } else if multiple > 0 {
+        2
+    } else {
+        3
+    }
+}
+
+/// Parameters (a, b, c) of the orbital electronegativity `a + b q + c q^2` of
+/// the element and hybridization, from Gasteiger and Marsili (1980).
+fn electronegativity_parameters(element: usize, hybridization: usize) -> Option<[f64; 3]> {
+    Some(match (element, hybridization) {
+        (1, _) => [7.17, 6.24, -0.56],
+        (6, 1) => [10.39, 9.45, 0.73],
+        (6, 2) => [8.79, 9.32, 1.51],
+        (6, _) => [7.98, 9.18, 1.88],
+        (7, 1) => [15.68, 11.70, -0.27],
+        (7, 2) => [12.87, 11.15, 0.85],
+        (7, _) => [11.54, 10.82, 1.36],
+        (8, 3) => [14.18, 12.92, 1.39],
+        (8, _) => [17.07, 13.79, 0.47],
+        (9, _) => [14.66, 13.85, 2.31],
+        (15, _) => [8.90, 8.24, 0.96],
+        (16, _) => [10.14, 9.13, 1.38],
+        (17, _) => [11.00, 9.69, 1.35],
+        (35, _) => [10.08, 8.47, 1.16],
+        (53, _) => [9.90, 7.96, 0.96],
+        _ => None?,
+    })
+}
+
+/// Partial charges of the atoms by the Gasteiger-Marsili partial equalization
+/// of orbital electronegativity, starting from the formal charges.
+///
+/// In each iteration charge flows along the bonds to the more electronegative
+/// atom, by the difference of electronegativities divided by the cationic
+/// electronegativity of the donor, damped by half every iteration (6
+/// iterations is the common setting). Atoms of elements without parameters
+/// (e.g. metals) keep their formal charges and their bonds are skipped.
+pub fn gasteiger_charges(molecule: &SparseMolecule, iterations: usize) -> BTreeMap<usize, f64> {
+    let atoms = (0..molecule.len())
+        .filter_map(|index| Some((index, molecule.atoms.read_atom(index)?)))
+        .collect::<Vec<_>>();
+    let mut charges = atoms
+        .iter()
+        .map(|(index, atom)| (*index, atom.formal_charge))
+        .collect::<BTreeMap<_, _>>();
+    let parameters = atoms
+        .iter()
+        .filter_map(|(index, atom)| {
+            let parameters =
+                electronegativity_parameters(atom.element, hybridization(molecule, *index))?;
+            Some((*index, parameters))
+        })
+        .collect::<BTreeMap<_, _>>();
+    // Electronegativity of the cation (q = 1), 20.02 for hydrogen
+    let cationic = parameters
+        .iter()
+        .map(|(index, [a, b, c])| {
+            let hydrogen = molecule.atoms.read_atom(*index).map(|atom| atom.element) == Some(1);
+            (*index, if hydrogen { 20.02 } else { a + b + c })
+        })
+        .collect::<BTreeMap<_, _>>();
+    let bonds = parameters
+        .keys()
+        .flat_map(|a| {
+            molecule
+                .neighbors(*a)
+                .into_iter()
+                .filter(|b| b > a && parameters.contains_key(b))
+                .map(|b| (*a, b))
+        })
+        .collect::<Vec<_>>();
+    let mut damping = 1.;
+    for _ in 0..iterations {
+        damping *= 0.5;
+        let electronegativity = parameters
+            .iter()
+            .map(|(index, [a, b, c])| {
+                let charge = charges[index];
+                (*index, a + b * charge + c * charge * charge)
+            })
+            .collect::<BTreeMap<_, _>>();
+        let mut transfers = BTreeMap::<usize, f64>::new();
+        for (a, b) in &bonds {
+            // Electrons move from the donor to the acceptor
+            let (donor, acceptor) = if electronegativity[b] > electronegativity[a] {
+                (a, b)
+            } else {
+                (b, a)
+            };
+            let transfer =
+                (electronegativity[acceptor] - electronegativity[donor]) / cationic[donor];
+            *transfers.entry(*donor).or_default() += transfer;
+            *transfers.entry(*acceptor).or_default() -= transfer;
+        }
+        for (index, transfer) in transfers {
+            *charges.get_mut(&index).unwrap() += damping * transfer;
+        }
+    }
+    charges
+}
+
+#[test]
+fn gasteiger_water_and_acetate() {
+    use crate::smiles::parse_smiles;
+    let water = parse_smiles("O").unwrap();
+    let charges = gasteiger_charges(&water, 6);
+    assert!((charges[&0] + 0.411).abs() < 0.005);
+    assert!((charges[&1] - charges[&2]).abs() < 1e-12);
+    assert!(charges.values().sum::<f64>().abs() < 1e-12);
+    // The total charge is kept
+    let acetate = parse_smiles("CC(=O)[O-]").unwrap();
+    let charges = gasteiger_charges(&acetate, 6);
+    assert!((charges.values().sum::<f64>() + 1.).abs() < 1e-12);
+    assert!(charges[&2] < 0. && charges[&3] < 0. && charges[&1] > 0.);
+}
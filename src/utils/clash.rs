@@ -0,0 +1,79 @@
+use std::collections::BTreeSet;
+
+use crate::{chemistry::vdw_radius, sparse_molecule::SparseMolecule, utils::neighbors::KdTree};
+
+/// Pairs of atoms closer than `scale` times the sum of their van der Waals
+/// radii (see `vdw_radius`), with the ratios of their distances to the sums of
+/// radii, the closest first.
+///
+/// Atoms bonded to each other or to a common atom are not checked, and the
+/// elements without radius use `default_radius`.
+pub fn vdw_clashes(
+    molecule: &SparseMolecule,
+    scale: f64,
+    default_radius: f64,
+) -> Vec<(usize, usize, f64)> {
+    let (atoms, radii): (Vec<_>, Vec<_>) = (0..molecule.len())
+        .filter_map(|index| {
+            let atom = molecule.atoms.read_atom(index)?;
+            let radius = vdw_radius(atom.element).unwrap_or(default_radius);
+            Some(((index, atom.position), radius))
+        })
+        .unzip();
+    let mut excluded = BTreeSet::new();
+    for (a, _) in &atoms {
+        for neighbor in molecule.neighbors(*a) {
+            excluded.insert((*a.min(&neighbor), *a.max(&neighbor)));
+            for b in molecule.neighbors(neighbor).into_iter().filter(|b| b != a) {
+                excluded.insert((*a.min(&b), *a.max(&b)));
+            }
+        }
+    }
+    let r_max = radii.iter().copied().fold(0., f64::max);
+    let positions = atoms
+        .iter()
+        .map(|(_, position)| *position)
+        .collect::<Vec<_>>();
+    let mut clashes = KdTree::new(&positions)
+        .pairs_within(scale * 2. * r_max)
+        .into_iter()
+        .filter(|(a, b)| !excluded.contains(&(atoms[*a].0, atoms[*b].0)))
+        .map(|(a, b)| {
+            let ratio = (positions[a] - positions[b]).norm() / (radii[a] + radii[b]);
+            (atoms[a].0, atoms[b].0, ratio)
+        })
+        .filter(|(_, _, ratio)| *ratio < scale)
+        .collect::<Vec<_>>();
+    clashes.sort_by(|a, b| a.2.total_cmp(&b.2));
+    clashes
+}
+
+#[test]
+fn clashes_of_non_bonded_atoms() {
+    use crate::{chemistry::Atom3D, sparse_molecule::SparseAtomList};
+    use nalgebra::Point3;
+    let atom = |element, x| Atom3D {
+        element,
+        position: Point3::new(x, 0., 0.),
+        ..Default::default()
+    };
+    // Bonded C-C, a hydrogen 1-3 to the first carbon, and a close oxygen
+    let mut molecule = SparseMolecule {
+        atoms: SparseAtomList::from(vec![
+            atom(6, 0.),
+            atom(6, 1.5),
+            atom(1, 2.5),
+            atom(8, 3.5),
+            atom(118, 10.),
+        ]),
+        ..Default::default()
+    };
+    molecule.bonds.set_bond(0, 1, Some(1.));
+    molecule.bonds.set_bond(1, 2, Some(1.));
+    let clashes = vdw_clashes(&molecule, 0.6, 2.);
+    assert_eq!(clashes.len(), 1);
+    let (a, b, ratio) = clashes[0];
+    assert_eq!((a, b), (2, 3));
+    assert!((ratio - 1. / 2.72).abs() < 1e-9);
+    assert!(vdw_clashes(&molecule, 0.3, 2.).is_empty());
+}
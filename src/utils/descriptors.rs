@@ -0,0 +1,110 @@
+use std::f64::consts::PI;
+
+use nalgebra::DMatrix;
+
+use crate::chemistry::Atom3D;
+
+/// Eigenvalues of the Coulomb matrix sorted by absolute value in descending
+/// order, truncated or padded with zeros to `size`.
+///
+/// The diagonal elements are `0.5 * Z^2.4` and the others are `Zi * Zj / Rij`
+/// with distances in angstrom, so the vector is invariant to translation,
+/// rotation and atom order.
+pub fn coulomb_eigenvalues(atoms: &[Atom3D], size: usize) -> Vec<f64> {
+    let matrix = DMatrix::from_fn(atoms.len(), atoms.len(), |i, j| {
+        let (a, b) = (&atoms[i], &atoms[j]);
+        if i == j {
+            0.5 * (a.element as f64).powf(2.4)
+        } else {
+            (a.element * b.element) as f64 / (a.position - b.position).norm()
+        }
+    });
+    let mut eigenvalues = matrix
+        .symmetric_eigenvalues()
+        .iter()
+        .copied()
+        .collect::<Vec<_>>();
+    eigenvalues.sort_by(|a, b| b.abs().total_cmp(&a.abs()));
+    eigenvalues.resize(size, 0.);
+    eigenvalues
+}
+
+/// Smoothed radial distribution of each pair of elements in `species`.
+///
+/// Each atom pair within `cutoff` adds a gaussian of width `sigma` centered at
+/// its distance, damped by a cosine cutoff function, and the distribution is
+/// sampled at `bins` points evenly distributed in `[0, cutoff)`. Pairs are
+/// ordered as `(species[i], species[j])` with `i <= j`, the vector has
+/// `bins * n * (n + 1) / 2` values for `n` species.
+pub fn radial_distribution(
+    atoms: &[Atom3D],
+    species: &[usize],
+    cutoff: f64,
+    bins: usize,
+    sigma: f64,
+) -> Vec<f64> {
+    let pairs = species
+        .iter()
+        .enumerate()
+        .flat_map(|(i, a)| species.iter().skip(i).map(move |b| (*a, *b)))
+        .collect::<Vec<_>>();
+    let mut distribution = vec![0.; pairs.len() * bins];
+    for (i, a) in atoms.iter().enumerate() {
+        for b in atoms.iter().skip(i + 1) {
+            let distance = (a.position - b.position).norm();
+            if distance >= cutoff {
+                continue;
+            }
+            let Some(pair) = pairs.iter().position(|pair| {
+                *pair == (a.element, b.element) || *pair == (b.element, a.element)
+            }) else {
+                continue;
+            };
+            let damping = 0.5 * ((PI * distance / cutoff).cos() + 1.);
+            for bin in 0..bins {
+                let center = cutoff * bin as f64 / bins as f64;
+                distribution[pair * bins + bin] +=
+                    damping * (-(distance - center).powi(2) / (2. * sigma.powi(2))).exp();
+            }
+        }
+    }
+    distribution
+}
+
+#[test]
+fn invariant_descriptors() {
+    use nalgebra::{Isometry3, Point3, Vector3};
+    let atom = |element, x, y, z| Atom3D {
+        element,
+        position: Point3::new(x, y, z),
+        formal_charge: 0.,
+    };
+    let water = vec![
+        atom(8, 0., 0., 0.),
+        atom(1, 0.96, 0., 0.),
+        atom(1, -0.24, 0.93, 0.),
+    ];
+    let isometry = Isometry3::new(Vector3::new(1., 2., 3.), Vector3::new(0.3, -0.2, 0.5));
+    let moved = water
+        .iter()
+        .rev()
+        .map(|atom| Atom3D {
+            position: isometry * atom.position,
+            ..*atom
+        })
+        .collect::<Vec<_>>();
+    let coulomb = coulomb_eigenvalues(&water, 4);
+    assert_eq!(coulomb.len(), 4);
+    assert_eq!(coulomb[3], 0.);
+    for (a, b) in coulomb.iter().zip(coulomb_eigenvalues(&moved, 4)) {
+        assert!((a - b).abs() < 1e-8);
+    }
+    let distribution = radial_distribution(&water, &[1, 8], 4., 10, 0.2);
+    assert_eq!(distribution.len(), 30);
+    for (a, b) in distribution
+        .iter()
+        .zip(radial_distribution(&moved, &[1, 8], 4., 10, 0.2))
+    {
+        assert!((a - b).abs() < 1e-8);
+    }
+}
@@ -1,23 +1,217 @@
-use std::path::Path;
+use std::{fs::File, path::Path};
+
+use anyhow::Context;
+use glob::glob;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+/// How the files of a skeleton directory are put into the target directory,
+/// directories are always created. Files that can't be linked (e.g. hard links
+/// across file systems, or symbolic links on platforms without them) are
+/// copied instead. Copies are copy-on-write clones sharing the blocks with
+/// the skeleton on file systems supporting them (e.g. Btrfs and XFS on Linux),
+/// which are as cheap as links but safe to be written.
+///
+/// Programs writing into a hard-linked file modify the skeleton and all the
+/// other links, so the link modes are for files only read by the programs
+/// (e.g. basis sets, force field parameters or initial guesses).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema)]
+pub enum SkeletonMode {
+    #[default]
+    Copy,
+    Symlink,
+    Hardlink,
+}
 
 pub fn copy_skeleton<P: AsRef<Path>>(skeleton: P, target: P) -> anyhow::Result<()> {
-    std::fs::create_dir_all(&target)?;
+    link_skeleton(skeleton.as_ref(), target.as_ref(), SkeletonMode::Copy)
+}
+
+/// Put the files of the skeleton directory into the target directory
+/// recursively, see `SkeletonMode`.
+pub fn link_skeleton(skeleton: &Path, target: &Path, mode: SkeletonMode) -> anyhow::Result<()> {
+    std::fs::create_dir_all(target)?;
     let items = std::fs::read_dir(skeleton)?;
     for item in items {
         let item = item?;
         let path = item.path();
+        let destination = target.join(item.file_name());
         if path.is_dir() {
-            let new_folder_path = target.as_ref().join(item.file_name());
-            copy_skeleton(&path, &new_folder_path)?;
+            link_skeleton(&path, &destination, mode)?;
         }
         if path.is_file() {
-            std::fs::copy(path, target.as_ref().join(item.file_name()))?;
+            link_file(&path, &destination, mode)?;
         }
     }
     Ok(())
 }
 
+fn link_file(source: &Path, destination: &Path, mode: SkeletonMode) -> anyhow::Result<()> {
+    // An existing file may be a link, which must not be written through to
+    // the skeleton
+    if destination
+        .symlink_metadata()
+        .is_ok_and(|metadata| !metadata.is_dir())
+    {
+        std::fs::remove_file(destination)?;
+    }
+    let linked = match mode {
+        SkeletonMode::Copy => false,
+        #[cfg(unix)]
+        SkeletonMode::Symlink => std::fs::canonicalize(source)
+            .and_then(|source| std::os::unix::fs::symlink(source, destination))
+            .is_ok(),
+        #[cfg(not(unix))]
+        SkeletonMode::Symlink => false,
+        SkeletonMode::Hardlink => std::fs::hard_link(source, destination).is_ok(),
+    };
+    if !linked && !reflink(source, destination) {
+        std::fs::copy(source, destination)
+            .with_context(|| format!("Unable to copy {:?} to {:?}", source, destination))?;
+    }
+    Ok(())
+}
+
+/// Clone the file as a copy-on-write copy, false if the file system doesn't
+/// support it.
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, destination: &Path) -> bool {
+    use std::os::fd::AsRawFd;
+    let (Ok(source_file), Ok(destination_file)) =
+        (File::open(source), File::create_new(destination))
+    else {
+        return false;
+    };
+    let cloned = unsafe {
+        libc::ioctl(
+            destination_file.as_raw_fd(),
+            libc::FICLONE,
+            source_file.as_raw_fd(),
+        )
+    } == 0;
+    let cloned = cloned
+        && source_file
+            .metadata()
+            .and_then(|metadata| destination_file.set_permissions(metadata.permissions()))
+            .is_ok();
+    if !cloned {
+        drop(destination_file);
+        std::fs::remove_file(destination).ok();
+    }
+    cloned
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &Path, _destination: &Path) -> bool {
+    false
+}
+
+/// Copy the files and directories matched by the glob pattern into the target
+/// directory by their names, or create symbolic links to them if `symlink` is
+/// set (copied on platforms without symbolic links), e.g. for large basis set
+/// files. Returns the count of matched items.
+pub fn stage_files(pattern: &str, target: &Path, symlink: bool) -> anyhow::Result<usize> {
+    std::fs::create_dir_all(target)?;
+    let mut count = 0;
+    for path in glob(pattern).with_context(|| format!("Invalid glob pattern {}", pattern))? {
+        let path = path?;
+        let name = path
+            .file_name()
+            .with_context(|| format!("Unable to get file name of {:?}", path))?;
+        let destination = target.join(name);
+        if symlink && cfg!(unix) {
+            let source = std::fs::canonicalize(&path)?;
+            match destination.symlink_metadata() {
+                Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(&destination)?,
+                Ok(_) => std::fs::remove_file(&destination)?,
+                Err(_) => {}
+            }
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(source, &destination)?;
+        } else if path.is_dir() {
+            copy_skeleton(path.as_path(), destination.as_path())?;
+        } else {
+            std::fs::copy(&path, &destination)?;
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
 #[test]
 fn copy_target_dir() {
     copy_skeleton("./target", "./target2").unwrap();
 }
+
+#[test]
+fn stage_matched_files() {
+    let directory = tempfile::tempdir().unwrap();
+    let source = directory.path().join("source");
+    std::fs::create_dir_all(source.join("basis")).unwrap();
+    std::fs::write(source.join("a.chk"), "a").unwrap();
+    std::fs::write(source.join("b.chk"), "b").unwrap();
+    std::fs::write(source.join("c.log"), "c").unwrap();
+    std::fs::write(source.join("basis").join("def2.gbs"), "basis").unwrap();
+    let target = directory.path().join("target");
+    let pattern = source.join("*.chk").to_string_lossy().to_string();
+    assert_eq!(stage_files(&pattern, &target, false).unwrap(), 2);
+    assert!(target.join("b.chk").is_file());
+    assert!(!target.join("c.log").exists());
+    let pattern = source.join("basis").to_string_lossy().to_string();
+    assert_eq!(stage_files(&pattern, &target, true).unwrap(), 1);
+    assert_eq!(
+        std::fs::read_to_string(target.join("basis").join("def2.gbs")).unwrap(),
+        "basis"
+    );
+    // A copied directory is replaced by the link
+    let copied = directory.path().join("copied");
+    assert_eq!(stage_files(&pattern, &copied, false).unwrap(), 1);
+    assert_eq!(stage_files(&pattern, &copied, true).unwrap(), 1);
+    #[cfg(unix)]
+    assert!(copied
+        .join("basis")
+        .symlink_metadata()
+        .unwrap()
+        .is_symlink());
+}
+
+#[test]
+fn link_skeleton_files() {
+    let directory = tempfile::tempdir().unwrap();
+    let skeleton = directory.path().join("skeleton");
+    std::fs::create_dir_all(skeleton.join("basis")).unwrap();
+    std::fs::write(skeleton.join("basis").join("def2.gbs"), "basis").unwrap();
+    for mode in [
+        SkeletonMode::Copy,
+        SkeletonMode::Symlink,
+        SkeletonMode::Hardlink,
+    ] {
+        let target = directory.path().join(format!("{:?}", mode));
+        // linking twice replaces the existing links
+        link_skeleton(&skeleton, &target, mode).unwrap();
+        link_skeleton(&skeleton, &target, mode).unwrap();
+        let file = target.join("basis").join("def2.gbs");
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "basis");
+        assert_eq!(
+            file.symlink_metadata().unwrap().is_symlink(),
+            cfg!(unix) && mode == SkeletonMode::Symlink
+        );
+    }
+    // copying over a hard link doesn't write into the skeleton
+    let target = directory.path().join("Hardlink");
+    copy_skeleton(&skeleton, &target).unwrap();
+    std::fs::write(target.join("basis").join("def2.gbs"), "local").unwrap();
+    assert_eq!(
+        std::fs::read_to_string(skeleton.join("basis").join("def2.gbs")).unwrap(),
+        "basis"
+    );
+    // copying over a symbolic link doesn't write into the skeleton
+    let target = directory.path().join("Symlink");
+    std::fs::write(skeleton.join("basis").join("def2.gbs"), "updated").unwrap();
+    copy_skeleton(&skeleton, &target).unwrap();
+    std::fs::write(target.join("basis").join("def2.gbs"), "local").unwrap();
+    assert_eq!(
+        std::fs::read_to_string(skeleton.join("basis").join("def2.gbs")).unwrap(),
+        "updated"
+    );
+}
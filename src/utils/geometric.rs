@@ -1,4 +1,6 @@
-use nalgebra::{Unit, Vector3};
+use nalgebra::{
+    Isometry3, Matrix3, Point3, Rotation3, Translation3, Unit, UnitQuaternion, Vector3,
+};
 
 pub fn axis_angle_for_b2a(a: Vector3<f64>, b: Vector3<f64>) -> (Unit<Vector3<f64>>, f64) {
     let axis = b.cross(&a);
@@ -16,6 +18,125 @@ pub fn axis_angle_for_b2a(a: Vector3<f64>, b: Vector3<f64>) -> (Unit<Vector3<f64
     (axis, angle)
 }
 
+/// Signed dihedral angle a-b-c-d in degrees, in range (-180, 180], positive if
+/// a is rotated clockwise to eclipse d when viewed along b to c.
+pub fn dihedral_angle(a: &Point3<f64>, b: &Point3<f64>, c: &Point3<f64>, d: &Point3<f64>) -> f64 {
+    let (b1, b2, b3) = (b - a, c - b, d - c);
+    let n2 = b2.cross(&b3);
+    (b2.norm() * b1.dot(&n2))
+        .atan2(b1.cross(&b2).dot(&n2))
+        .to_degrees()
+}
+
+/// Unit vectors of ideal bond directions around an atom with `count` neighbors:
+/// linear, trigonal planar, tetrahedral, or evenly distributed on a sphere for
+/// more neighbors. The first direction is always the x axis.
+pub fn ideal_directions(count: usize) -> Vec<Vector3<f64>> {
+    let (c, s) = (-0.5, 3f64.sqrt() / 2.);
+    match count {
+        1 => vec![Vector3::x()],
+        2 => vec![Vector3::x(), -Vector3::x()],
+        3 => vec![
+            Vector3::x(),
+            Vector3::new(c, s, 0.),
+            Vector3::new(c, -s, 0.),
+        ],
+        4 => [
+            Vector3::new(1., 1., 1.),
+            Vector3::new(1., -1., -1.),
+            Vector3::new(-1., 1., -1.),
+            Vector3::new(-1., -1., 1.),
+        ]
+        .into_iter()
+        .map(|direction| direction.normalize())
+        .collect(),
+        count => {
+            // points evenly distributed on a sphere
+            let golden_angle = std::f64::consts::PI * (3. - 5f64.sqrt());
+            (0..count)
+                .map(|index| {
+                    let x = 1. - 2. * (index as f64 + 0.5) / count as f64;
+                    let radius = (1. - x * x).sqrt();
+                    let theta = golden_angle * index as f64;
+                    Vector3::new(x, radius * theta.cos(), radius * theta.sin())
+                })
+                .collect()
+        }
+    }
+}
+
+/// The rigid motion that best maps `from` onto `to` in the least-squares sense
+/// (Kabsch algorithm), points are paired by order.
+pub fn kabsch(from: &[Point3<f64>], to: &[Point3<f64>]) -> Isometry3<f64> {
+    let centroid = |points: &[Point3<f64>]| {
+        points
+            .iter()
+            .map(|point| point.coords)
+            .sum::<Vector3<f64>>()
+            / points.len().max(1) as f64
+    };
+    let (from_center, to_center) = (centroid(from), centroid(to));
+    let covariance = from
+        .iter()
+        .zip(to)
+        .map(|(a, b)| (a.coords - from_center) * (b.coords - to_center).transpose())
+        .sum::<Matrix3<f64>>();
+    let svd = covariance.svd(true, true);
+    let (u, v_t) = (svd.u.unwrap(), svd.v_t.unwrap());
+    // avoid reflection
+    let sign = (v_t.transpose() * u.transpose()).determinant().signum();
+    let correction = Matrix3::from_diagonal(&Vector3::new(1., 1., sign));
+    let rotation = Rotation3::from_matrix_unchecked(v_t.transpose() * correction * u.transpose());
+    let translation = to_center - rotation * from_center;
+    Isometry3::from_parts(
+        Translation3::from(translation),
+        UnitQuaternion::from_rotation_matrix(&rotation),
+    )
+}
+
+/// Root mean square deviation of the points after the optimal superposition of
+/// `from` onto `to` (see `kabsch`), the points are paired by their order.
+pub fn aligned_rmsd(from: &[Point3<f64>], to: &[Point3<f64>]) -> f64 {
+    let isometry = kabsch(from, to);
+    let sum = from
+        .iter()
+        .zip(to)
+        .map(|(a, b)| (isometry * a - b).norm_squared())
+        .sum::<f64>();
+    (sum / from.len().max(1) as f64).sqrt()
+}
+
+#[test]
+fn kabsch_recovers_motion() {
+    let isometry = Isometry3::new(Vector3::new(1., -2., 0.5), Vector3::new(0.4, 0.1, -0.7));
+    let from = [
+        Point3::new(0., 0., 0.),
+        Point3::new(1.5, 0., 0.),
+        Point3::new(0., 1., 0.3),
+        Point3::new(0.2, -0.4, 1.1),
+    ];
+    let to = from.map(|point| isometry * point);
+    let fitted = kabsch(&from, &to);
+    for (a, b) in from.iter().zip(&to) {
+        assert!((fitted * a - b).norm() < 1e-8);
+    }
+    assert!(aligned_rmsd(&from, &to) < 1e-8);
+    let mut moved = to;
+    moved[0].x += 0.4;
+    assert!((aligned_rmsd(&from, &moved) - 0.2).abs() < 0.1);
+}
+
+#[test]
+fn dihedral_of_points() {
+    let angle = dihedral_angle(
+        &Point3::new(1., 0., 0.),
+        &Point3::origin(),
+        &Point3::new(0., 0., 1.),
+        &Point3::new(0., 1., 1.),
+    );
+    assert!((angle - 90.).abs() < 1e-8);
+}
+
 #[test]
 fn reverse_vectors() {
     println!(
@@ -0,0 +1,267 @@
+use std::collections::BTreeSet;
+
+use nalgebra::{Rotation3, Unit, Vector3};
+
+use crate::{
+    chemistry::{default_valences, Atom3D},
+    sparse_molecule::SparseMolecule,
+    utils::geometric::ideal_directions,
+};
+
+/// Length of the bond between hydrogen and the element in angstrom.
+pub(crate) fn hydrogen_bond_length(element: usize) -> f64 {
+    match element {
+        5 => 1.19,
+        6 => 1.09,
+        7 => 1.01,
+        8 => 0.96,
+        9 => 0.92,
+        14 => 1.48,
+        15 => 1.42,
+        16 => 1.34,
+        17 => 1.27,
+        35 => 1.41,
+        53 => 1.61,
+        _ => 1.0,
+    }
+}
+
+/// Count of hydrogens missing on the atom, by the lowest normal valence of the
+/// element not less than the sum of its bond orders.
+///
+/// Positive formal charges raise the valences of group 15-17 elements (e.g.
+/// ammonium) and lower the others (e.g. carbocation), negative charges do the
+/// opposite for group 13 and 15-17 elements (e.g. borohydride) and lower the
+/// others (carbanion).
+/// Hydrogen atoms and elements without normal valences get no hydrogen.
+///
+/// Aromatic bonds (order 1.5) count as single bonds, plus one for the double
+/// bond of the Kekulé structure unless the single bonds already reach the
+/// lowest valence, e.g. the nitrogen of pyrrole bonded to a hydrogen, or the
+/// oxygen of furan. Whether an aromatic nitrogen without hydrogen belongs to a
+/// pyridine or a pyrrole can't be told locally, so it gets no hydrogen.
+pub fn missing_hydrogens(molecule: &SparseMolecule, index: usize) -> usize {
+    let Some(atom) = molecule.atoms.read_atom(index) else {
+        return 0;
+    };
+    if atom.element == 1 {
+        return 0;
+    }
+    let (aromatic, others) = molecule
+        .neighbors(index)
+        .into_iter()
+        .map(|neighbor| {
+            molecule
+                .bonds
+                .read_bond(index, neighbor)
+                .unwrap_or_default()
+        })
+        .fold((0, 0.), |(aromatic, others), bond| {
+            if bond == 1.5 {
+                (aromatic + 1, others)
+            } else {
+                (aromatic, others + bond)
+            }
+        });
+    let charge = atom.formal_charge.round() as i64;
+    let electron_rich = matches!(atom.element, 7 | 8 | 9 | 15 | 16 | 17 | 33 | 34 | 35 | 53);
+    let electron_poor = matches!(atom.element, 5 | 13);
+    let valences = default_valences(atom.element)
+        .iter()
+        .map(|valence| {
+            if electron_rich {
+                *valence as i64 + charge
+            } else if electron_poor {
+                *valence as i64 - charge
+            } else {
+                *valence as i64 - charge.abs()
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut used = others.round() as i64 + aromatic;
+    if aromatic > 0 && valences.first().is_some_and(|lowest| used < *lowest) {
+        used += 1;
+    }
+    valences
+        .into_iter()
+        .find(|valence| *valence >= used)
+        .map(|valence| (valence - used) as usize)
+        .unwrap_or(0)
+}
+
+/// Directions of `count` new bonds on the atom, completing an ideal geometry
+/// (linear, trigonal or tetrahedral) with the existing bonds.
+///
+/// The new bonds of a terminal atom are staggered against the substituents of
+/// its neighbor.
+fn new_bond_directions(
+    molecule: &SparseMolecule,
+    index: usize,
+    count: usize,
+) -> Option<Vec<Vector3<f64>>> {
+    let direction = |from: usize, to: usize| {
+        Some(
+            (molecule.atoms.read_atom(to)?.position - molecule.atoms.read_atom(from)?.position)
+                .normalize(),
+        )
+    };
+    let neighbors = molecule.neighbors(index);
+    let existing = neighbors
+        .iter()
+        .map(|neighbor| direction(index, *neighbor))
+        .collect::<Option<Vec<_>>>()?;
+    let ideal = ideal_directions(existing.len() + count);
+    let mut rotation = Rotation3::identity();
+    if let Some(first) = existing.first() {
+        rotation = Rotation3::rotation_between(&ideal[0], first).unwrap_or_else(|| {
+            Rotation3::from_axis_angle(&Unit::new_normalize(Vector3::y()), std::f64::consts::PI)
+        });
+        let reference = if let Some(second) = existing.get(1) {
+            Some(*second)
+        } else {
+            molecule
+                .neighbors(neighbors[0])
+                .into_iter()
+                .find(|other| *other != index)
+                .and_then(|other| direction(neighbors[0], other))
+                .map(|direction| -direction)
+        };
+        if let (Some(reference), Some(second)) = (reference, ideal.get(1)) {
+            let project = |vector: Vector3<f64>| vector - first * first.dot(&vector);
+            let (u, v) = (project(rotation * second), project(reference));
+            if u.norm() > 1e-6 && v.norm() > 1e-6 {
+                let angle = first.dot(&u.cross(&v)).atan2(u.dot(&v));
+                rotation =
+                    Rotation3::from_axis_angle(&Unit::new_normalize(*first), angle) * rotation;
+            }
+        }
+    }
+    let mut candidates = ideal
+        .into_iter()
+        .map(|direction| rotation * direction)
+        .collect::<Vec<_>>();
+    for bond in &existing {
+        let nearest = (0..candidates.len()).max_by(|a, b| {
+            candidates[*a]
+                .dot(bond)
+                .total_cmp(&candidates[*b].dot(bond))
+        })?;
+        candidates.remove(nearest);
+    }
+    Some(candidates)
+}
+
+/// Append the missing hydrogens (see `missing_hydrogens`) of the selected atoms,
+/// placed at ideal directions with typical X-H bond lengths.
+pub fn add_hydrogens(molecule: &mut SparseMolecule, select: &BTreeSet<usize>) {
+    for index in select {
+        let count = missing_hydrogens(molecule, *index);
+        if count == 0 {
+            continue;
+        }
+        let Some(atom) = molecule.atoms.read_atom(*index) else {
+            continue;
+        };
+        let Some(directions) = new_bond_directions(molecule, *index, count) else {
+            continue;
+        };
+        let length = hydrogen_bond_length(atom.element);
+        for direction in directions {
+            let hydrogen = molecule.len();
+            molecule.atoms.set_atoms(
+                hydrogen,
+                vec![Some(Atom3D {
+                    element: 1,
+                    position: atom.position + direction * length,
+                    formal_charge: 0.,
+                })],
+            );
+            molecule.bonds.set_bond(*index, hydrogen, Some(1.));
+        }
+    }
+}
+
+/// Hydrogen atoms bonded to the selected atoms.
+pub fn bonded_hydrogens(molecule: &SparseMolecule, select: &BTreeSet<usize>) -> BTreeSet<usize> {
+    select
+        .iter()
+        .flat_map(|index| molecule.neighbors(*index))
+        .filter(|neighbor| {
+            molecule
+                .atoms
+                .read_atom(*neighbor)
+                .map(|atom| atom.element == 1)
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+#[test]
+fn protonate_fragments() {
+    use crate::sparse_molecule::SparseAtomList;
+    use nalgebra::Point3;
+    let atom = |element, x, formal_charge| Atom3D {
+        element,
+        position: Point3::new(x, 0., 0.),
+        formal_charge,
+    };
+    // ethane skeleton, and a separated ammonium
+    let mut molecule = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(6, 0., 0.), atom(6, 1.54, 0.), atom(7, 5., 1.)]),
+        ..Default::default()
+    };
+    molecule.bonds.set_bond(0, 1, Some(1.));
+    assert_eq!(missing_hydrogens(&molecule, 0), 3);
+    assert_eq!(missing_hydrogens(&molecule, 2), 4);
+    add_hydrogens(&mut molecule, &BTreeSet::from([0, 1, 2]));
+    assert_eq!(molecule.len(), 13);
+    for index in 0..3 {
+        assert_eq!(missing_hydrogens(&molecule, index), 0);
+    }
+    // tetrahedral angles around the carbon
+    let position = |index| molecule.atoms.read_atom(index).unwrap().position;
+    for hydrogen in molecule
+        .neighbors(0)
+        .into_iter()
+        .filter(|index| *index != 1)
+    {
+        let angle = (position(1) - position(0))
+            .angle(&(position(hydrogen) - position(0)))
+            .to_degrees();
+        assert!((angle - 109.47).abs() < 0.1);
+        assert!(((position(hydrogen) - position(0)).norm() - 1.09).abs() < 1e-8);
+    }
+    assert_eq!(bonded_hydrogens(&molecule, &BTreeSet::from([0])).len(), 3);
+    assert_eq!(
+        bonded_hydrogens(&molecule, &BTreeSet::from([0, 1, 2])).len(),
+        10
+    );
+    // borohydride, and a separated borenium
+    let molecule = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(5, 0., -1.), atom(5, 5., 1.)]),
+        ..Default::default()
+    };
+    assert_eq!(missing_hydrogens(&molecule, 0), 4);
+    assert_eq!(missing_hydrogens(&molecule, 1), 2);
+}
+
+#[test]
+fn complete_aromatic_and_hypervalent() {
+    use crate::smiles::parse_smiles;
+    // pyrrole, furan and chlorine dioxide are complete as parsed
+    for smiles in ["c1cc[nH]c1", "c1ccoc1", "O=Cl=O"] {
+        let molecule = parse_smiles(smiles).unwrap();
+        for index in 0..molecule.len() {
+            assert_eq!(missing_hydrogens(&molecule, index), 0, "{}", smiles);
+        }
+    }
+    // the aromatic carbons of a bare benzene ring miss one hydrogen each
+    let mut benzene = parse_smiles("c1ccccc1").unwrap();
+    for hydrogen in 6..12 {
+        benzene.atoms.set_atoms(hydrogen, vec![None]);
+        for carbon in 0..6 {
+            benzene.bonds.set_bond(carbon, hydrogen, None);
+        }
+    }
+    assert!((0..6).all(|carbon| missing_hydrogens(&benzene, carbon) == 1));
+}
@@ -0,0 +1,154 @@
+use std::{io::Read, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
+
+lazy_static! {
+    static ref UNKNOWN_NAME_RE: Regex =
+        Regex::new(r"unknown (?:variant|field) `([^`]*)`, expected (.*?)(?: at line \d+|$)")
+            .unwrap();
+    static ref EXPECTED_NAME_RE: Regex = Regex::new(r"`([^`]*)`").unwrap();
+}
+
+/// Deserialize YAML content, the error message contains the source name, the
+/// position reported by serde_yaml and a suggestion for misspelled runner, layer
+/// or field names.
+pub fn from_yaml_str<T: DeserializeOwned>(content: &str, source: &str) -> Result<T> {
+    serde_yaml::from_str(content).map_err(|err| parse_error(source, &err.to_string()))
+}
+
+/// Deserialize a YAML value, errors are reported like `from_yaml_str`.
+pub fn from_yaml_value<T: DeserializeOwned>(value: serde_yaml::Value, source: &str) -> Result<T> {
+    serde_yaml::from_value(value).map_err(|err| parse_error(source, &err.to_string()))
+}
+
+pub fn from_yaml_reader<T: DeserializeOwned, R: Read>(reader: R, source: &str) -> Result<T> {
+    from_yaml_str(&read_content(reader, source)?, source)
+}
+
+/// Deserialize an input file with the format decided by the extension of the
+/// path: `.json` for JSON, `.toml` for TOML and YAML for all the others.
+pub fn from_input_reader<T: DeserializeOwned, R: Read>(reader: R, path: &Path) -> Result<T> {
+    let source = format!("{:?}", path);
+    let content = read_content(reader, &source)?;
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => {
+            serde_json::from_str(&content).map_err(|err| parse_error(&source, &err.to_string()))
+        }
+        Some("toml") => {
+            toml::from_str(&content).map_err(|err| parse_error(&source, &err.to_string()))
+        }
+        _ => from_yaml_str(&content, &source),
+    }
+}
+
+/// Deserialize a list from an input file like `from_input_reader`. TOML has no
+/// top-level array, so a `.toml` file puts the list under `key` of the table,
+/// e.g. `layers = [...]`.
+pub fn from_input_list_reader<T: DeserializeOwned, R: Read>(
+    reader: R,
+    path: &Path,
+    key: &str,
+) -> Result<T> {
+    if path.extension().and_then(|extension| extension.to_str()) != Some("toml") {
+        return from_input_reader(reader, path);
+    }
+    let source = format!("{:?}", path);
+    let content = read_content(reader, &source)?;
+    let mut table: toml::Table =
+        toml::from_str(&content).map_err(|err| parse_error(&source, &err.to_string()))?;
+    let list = table.remove(key).with_context(|| {
+        format!(
+            "Failed to parse {}: the list must be written as `{} = [...]` in TOML",
+            source, key
+        )
+    })?;
+    list.try_into()
+        .map_err(|err: toml::de::Error| parse_error(&source, &err.to_string()))
+}
+
+fn read_content<R: Read>(mut reader: R, source: &str) -> Result<String> {
+    let mut content = String::new();
+    reader
+        .read_to_string(&mut content)
+        .with_context(|| format!("Failed to read {}", source))?;
+    Ok(content)
+}
+
+fn parse_error(source: &str, message: &str) -> anyhow::Error {
+    if let Some(suggestion) = did_you_mean(message) {
+        anyhow!(
+            "Failed to parse {}: {}, did you mean `{}`?",
+            source,
+            message.trim_end(),
+            suggestion
+        )
+    } else {
+        anyhow!("Failed to parse {}: {}", source, message.trim_end())
+    }
+}
+
+/// Find the most similar expected name for an unknown variant or field in the
+/// error message of serde.
+fn did_you_mean(message: &str) -> Option<String> {
+    let captures = UNKNOWN_NAME_RE.captures(message).ok()??;
+    let unknown = captures.get(1)?.as_str();
+    let expected = captures.get(2)?.as_str();
+    EXPECTED_NAME_RE
+        .captures_iter(expected)
+        .filter_map(|captures| Some(captures.ok()?.get(1)?.as_str().to_string()))
+        .map(|name| (strsim::damerau_levenshtein(unknown, &name), name))
+        .filter(|(distance, name)| *distance <= name.len().max(unknown.len()) / 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name)
+}
+
+#[test]
+fn suggest_similar_name() {
+    assert_eq!(
+        did_you_mean("steps[0].run.with: unknown variant `Calculaton`, expected one of `Rename`, `Calculation`, `CheckPoint` at line 3 column 13"),
+        Some("Calculation".to_string())
+    );
+    assert_eq!(
+        did_you_mean("steps[0]: unknown field `runn`, expected one of `from`, `name`, `run` at line 2 column 5"),
+        Some("run".to_string())
+    );
+    assert_eq!(
+        did_you_mean("steps[0]: unknown field `zzzzzz`, expected `x` or `y`"),
+        None
+    );
+}
+
+#[test]
+fn input_format_by_extension() {
+    use std::collections::BTreeMap;
+    let json: BTreeMap<String, usize> =
+        from_input_reader(r#"{"a": 1}"#.as_bytes(), Path::new("input.json")).unwrap();
+    let toml: BTreeMap<String, usize> =
+        from_input_reader("a = 1".as_bytes(), Path::new("input.toml")).unwrap();
+    let yaml: BTreeMap<String, usize> =
+        from_input_reader("a: 1".as_bytes(), Path::new("input.yaml")).unwrap();
+    assert_eq!(json, toml);
+    assert_eq!(toml, yaml);
+}
+
+#[test]
+fn list_in_toml_table() {
+    let toml: Vec<usize> = from_input_list_reader(
+        "layers = [1, 2]".as_bytes(),
+        Path::new("layers.toml"),
+        "layers",
+    )
+    .unwrap();
+    let yaml: Vec<usize> =
+        from_input_list_reader("[1, 2]".as_bytes(), Path::new("layers.yaml"), "layers").unwrap();
+    assert_eq!(toml, yaml);
+    let bare = from_input_list_reader::<Vec<usize>, _>(
+        "steps = [1]".as_bytes(),
+        Path::new("layers.toml"),
+        "layers",
+    );
+    assert!(bare.unwrap_err().to_string().contains("`layers = [...]`"));
+}
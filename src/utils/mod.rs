@@ -1,3 +1,12 @@
+pub mod bond_orders;
+pub mod charges;
+pub mod clash;
+pub mod descriptors;
 pub mod fs;
 pub mod geometric;
+pub mod hydrogens;
+pub mod input;
+pub mod neighbors;
+pub mod process;
 pub mod sterimol;
+pub mod symmetry;
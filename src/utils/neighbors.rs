@@ -0,0 +1,176 @@
+use nalgebra::{DMatrix, Point3};
+
+/// A k-d tree over points for neighbor searches in O(log n) per query, instead
+/// of comparing all pairs of atoms in large systems.
+///
+/// The points are indexed by their positions in the slice the tree is built
+/// from, and the tree is balanced by splitting at the median of the x, y and z
+/// coordinates in turn.
+#[derive(Debug, Clone)]
+pub struct KdTree {
+    points: Vec<Point3<f64>>,
+    /// Indexes of the points, the median of each range is the node splitting it
+    order: Vec<usize>,
+}
+
+impl KdTree {
+    pub fn new(points: &[Point3<f64>]) -> Self {
+        let mut order = (0..points.len()).collect::<Vec<_>>();
+        build(points, &mut order, 0);
+        Self {
+            points: points.to_vec(),
+            order,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Indexes of the points within the radius of the center (inclusive), in
+    /// ascending order.
+    pub fn within(&self, center: &Point3<f64>, radius: f64) -> Vec<usize> {
+        let mut found = vec![];
+        self.search(&self.order, 0, center, radius, &mut found);
+        found.sort_unstable();
+        found
+    }
+
+    fn search(
+        &self,
+        order: &[usize],
+        depth: usize,
+        center: &Point3<f64>,
+        radius: f64,
+        found: &mut Vec<usize>,
+    ) {
+        if order.is_empty() {
+            return;
+        }
+        let (axis, mid) = (depth % 3, order.len() / 2);
+        let node = &self.points[order[mid]];
+        if (node - center).norm() <= radius {
+            found.push(order[mid]);
+        }
+        let offset = center[axis] - node[axis];
+        if offset <= radius {
+            self.search(&order[..mid], depth + 1, center, radius, found);
+        }
+        if offset >= -radius {
+            self.search(&order[mid + 1..], depth + 1, center, radius, found);
+        }
+    }
+
+    /// Index and distance of the point nearest to the position, `None` if the
+    /// tree is empty.
+    pub fn nearest(&self, position: &Point3<f64>) -> Option<(usize, f64)> {
+        let mut best = None;
+        self.nearest_in(&self.order, 0, position, &mut best);
+        best
+    }
+
+    fn nearest_in(
+        &self,
+        order: &[usize],
+        depth: usize,
+        position: &Point3<f64>,
+        best: &mut Option<(usize, f64)>,
+    ) {
+        if order.is_empty() {
+            return;
+        }
+        let (axis, mid) = (depth % 3, order.len() / 2);
+        let node = &self.points[order[mid]];
+        let distance = (node - position).norm();
+        if best.map(|(_, best)| distance < best).unwrap_or(true) {
+            *best = Some((order[mid], distance));
+        }
+        let offset = position[axis] - node[axis];
+        let (near, far) = if offset <= 0. {
+            (&order[..mid], &order[mid + 1..])
+        } else {
+            (&order[mid + 1..], &order[..mid])
+        };
+        self.nearest_in(near, depth + 1, position, best);
+        if best.map(|(_, best)| offset.abs() < best).unwrap_or(true) {
+            self.nearest_in(far, depth + 1, position, best);
+        }
+    }
+
+    /// Pairs `(a, b)` with `a < b` of the points within the distance of each
+    /// other, sorted.
+    pub fn pairs_within(&self, distance: f64) -> Vec<(usize, usize)> {
+        let mut pairs = vec![];
+        for (a, point) in self.points.iter().enumerate() {
+            pairs.extend(
+                self.within(point, distance)
+                    .into_iter()
+                    .filter(|b| *b > a)
+                    .map(|b| (a, b)),
+            );
+        }
+        pairs
+    }
+}
+
+fn build(points: &[Point3<f64>], order: &mut [usize], depth: usize) {
+    if order.len() <= 1 {
+        return;
+    }
+    let (axis, mid) = (depth % 3, order.len() / 2);
+    order.select_nth_unstable_by(mid, |a, b| points[*a][axis].total_cmp(&points[*b][axis]));
+    let (lower, upper) = order.split_at_mut(mid);
+    build(points, lower, depth + 1);
+    build(points, &mut upper[1..], depth + 1);
+}
+
+/// Symmetric matrix of the distances between the points.
+pub fn distance_matrix(points: &[Point3<f64>]) -> DMatrix<f64> {
+    DMatrix::from_fn(points.len(), points.len(), |a, b| {
+        (points[a] - points[b]).norm()
+    })
+}
+
+#[test]
+fn neighbor_search() {
+    // A 5x5x5 grid of points 1 apart, shuffled by a fixed permutation
+    let points = (0..125)
+        .map(|index| (index * 38) % 125)
+        .map(|index| {
+            Point3::new(
+                (index % 5) as f64,
+                (index / 5 % 5) as f64,
+                (index / 25) as f64,
+            )
+        })
+        .collect::<Vec<_>>();
+    let tree = KdTree::new(&points);
+    let brute_force = |center: &Point3<f64>, radius: f64| {
+        (0..points.len())
+            .filter(|index| (points[*index] - center).norm() <= radius)
+            .collect::<Vec<_>>()
+    };
+    for (center, radius) in [
+        (Point3::new(2., 2., 2.), 1.),
+        (Point3::new(0.2, 4.1, 1.7), 1.5),
+        (Point3::new(-3., 0., 0.), 2.),
+        (Point3::new(2., 2., 2.), 10.),
+    ] {
+        assert_eq!(tree.within(&center, radius), brute_force(&center, radius));
+    }
+    let (nearest, distance) = tree.nearest(&Point3::new(3.1, 0.9, 4.2)).unwrap();
+    assert_eq!(points[nearest], Point3::new(3., 1., 4.));
+    assert!((distance - 0.06_f64.sqrt()).abs() < 1e-9);
+    // Each point has 6 neighbors at 1 except those on the faces
+    let pairs = tree.pairs_within(1.);
+    assert_eq!(pairs.len(), 3 * 4 * 25);
+    assert!(pairs.iter().all(|(a, b)| a < b));
+    assert_eq!(KdTree::new(&[]).nearest(&Point3::origin()), None);
+    let matrix = distance_matrix(&points[..3]);
+    assert_eq!(matrix, matrix.transpose());
+    assert_eq!(matrix[(1, 1)], 0.);
+}
@@ -0,0 +1,343 @@
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Interval of checking a child process with a timeout.
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Executable extensions searched on Windows if PATHEXT is not set.
+pub const DEFAULT_PATHEXT: &str = ".COM;.EXE;.BAT;.CMD";
+
+/// Create a command running the program, searched in PATH like a shell does.
+///
+/// On Windows, a program without extension is searched with the extensions in
+/// PATHEXT (e.g. `orca` is resolved to `orca.exe`, or a `.bat` or `.cmd`
+/// script, whose arguments are escaped for `cmd.exe` by the standard library).
+/// On other platforms the program is passed to the system as is.
+pub fn new_command(program: impl AsRef<OsStr>) -> Command {
+    #[cfg(windows)]
+    {
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let extensions = std::env::var("PATHEXT").unwrap_or_else(|_| DEFAULT_PATHEXT.to_string());
+        if let Some(resolved) = resolve_program(program.as_ref(), &path, &extensions) {
+            return Command::new(resolved);
+        }
+    }
+    Command::new(program)
+}
+
+/// Find the file of the program in the directories of the PATH value, trying
+/// the `;` separated extensions (PATHEXT on Windows) if the program has none.
+/// Programs given with directories (e.g. `./bin/run`) are not searched in PATH.
+pub fn resolve_program(program: &OsStr, path: &OsStr, extensions: &str) -> Option<PathBuf> {
+    let program = Path::new(program);
+    let directories = if program.components().count() > 1 {
+        vec![PathBuf::new()]
+    } else {
+        std::env::split_paths(path).collect()
+    };
+    let extensions = extensions
+        .split(';')
+        .filter(|extension| !extension.is_empty())
+        .collect::<Vec<_>>();
+    directories.into_iter().find_map(|directory| {
+        let base = directory.join(program);
+        if program.extension().is_some() && base.is_file() {
+            return Some(base);
+        }
+        extensions.iter().find_map(|extension| {
+            let mut candidate = OsString::from(base.as_os_str());
+            candidate.push(extension);
+            let candidate = PathBuf::from(candidate);
+            candidate.is_file().then_some(candidate)
+        })
+    })
+}
+
+/// Shell script loading the environment modules before running the program,
+/// the module system is initialized from `$MODULESHOME/init/sh` (set by both
+/// Environment Modules and Lmod) or `/etc/profile.d/modules.sh` if `module` is
+/// not defined in the shell.
+const MODULE_SCRIPT: &str = r#"if ! command -v module >/dev/null 2>&1; then
+    if [ -n "$MODULESHOME" ] && [ -f "$MODULESHOME/init/sh" ]; then
+        . "$MODULESHOME/init/sh"
+    elif [ -f /etc/profile.d/modules.sh ]; then
+        . /etc/profile.d/modules.sh
+    fi
+fi
+"#;
+
+/// Quote the value as a single word of POSIX shell.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Wrap the command to run in a shell with the environment modules loaded, e.g.
+/// `gaussian/16`, keeping its arguments, environment variables and current
+/// directory. The shell exits with 127 if any module is failed to load.
+///
+/// Only available on platforms with a POSIX shell `sh`.
+pub fn with_modules(command: &Command, modules: &[String]) -> Command {
+    let mut script = MODULE_SCRIPT.to_string();
+    for module in modules {
+        script.push_str(&format!(
+            "module load {} || exit 127\n",
+            shell_quote(module)
+        ));
+    }
+    script.push_str("exec \"$0\" \"$@\"\n");
+    let mut wrapped = new_command("sh");
+    wrapped
+        .arg("-c")
+        .arg(script)
+        .arg(command.get_program())
+        .args(command.get_args());
+    for (key, value) in command.get_envs() {
+        match value {
+            Some(value) => wrapped.env(key, value),
+            None => wrapped.env_remove(key),
+        };
+    }
+    if let Some(directory) = command.get_current_dir() {
+        wrapped.current_dir(directory);
+    }
+    wrapped
+}
+
+/// Put the directories before the current PATH of this process, which is
+/// inherited by the external programs.
+pub fn prepend_path(directories: impl IntoIterator<Item = PathBuf>) -> Result<()> {
+    let current = std::env::var_os("PATH").unwrap_or_default();
+    let mut paths = directories.into_iter().collect::<Vec<_>>();
+    paths.extend(std::env::split_paths(&current));
+    std::env::set_var("PATH", std::env::join_paths(paths)?);
+    Ok(())
+}
+
+/// Error of an external program unable to start or exiting with failure, so
+/// the failures of programs can be told apart from the others by downcasting,
+/// e.g. `error.downcast_ref::<ProgramFailure>()`, through the contexts.
+#[derive(Debug)]
+pub struct ProgramFailure(pub String);
+
+impl std::fmt::Display for ProgramFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ProgramFailure {}
+
+/// Resources used by a finished external process.
+///
+/// CPU time and peak resident set size are only available on unix platforms.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    /// Wall time in seconds
+    pub wall_time: f64,
+    /// CPU time in user mode in seconds
+    pub user_time: Option<f64>,
+    /// CPU time in kernel mode in seconds
+    pub system_time: Option<f64>,
+    /// Peak resident set size in KiB
+    pub max_rss: Option<u64>,
+}
+
+impl ResourceUsage {
+    /// The available values as structure properties, named like the fields.
+    pub fn properties(&self) -> BTreeMap<String, String> {
+        let mut properties = BTreeMap::new();
+        properties.insert("wall_time".to_string(), self.wall_time.to_string());
+        if let Some(user_time) = self.user_time {
+            properties.insert("user_time".to_string(), user_time.to_string());
+        }
+        if let Some(system_time) = self.system_time {
+            properties.insert("system_time".to_string(), system_time.to_string());
+        }
+        if let Some(max_rss) = self.max_rss {
+            properties.insert("max_rss".to_string(), max_rss.to_string());
+        }
+        properties
+    }
+}
+
+/// Wait the child process to exit and collect the resource it used.
+///
+/// The `started` is the moment the child was spawned, used to compute the wall time.
+pub fn wait_with_usage(child: &mut Child, started: Instant) -> Result<(ExitStatus, ResourceUsage)> {
+    let (status, usage) = wait_with_timeout(child, started, None)?;
+    Ok((
+        status.expect("Child without timeout is never killed"),
+        usage,
+    ))
+}
+
+/// Wait the child process like `wait_with_usage`, and kill it if it runs longer
+/// than the timeout, the status is None if it's killed.
+///
+/// On Unix, the process group of the child is killed too, so the programs it
+/// started are stopped if it's spawned as a group leader (see
+/// `CommandExt::process_group`).
+#[cfg(unix)]
+pub fn wait_with_timeout(
+    child: &mut Child,
+    started: Instant,
+    timeout: Option<Duration>,
+) -> Result<(Option<ExitStatus>, ResourceUsage)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let pid = child.id() as libc::pid_t;
+    let mut status: libc::c_int = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let mut killed = false;
+    loop {
+        let flags = if timeout.is_some() && !killed {
+            libc::WNOHANG
+        } else {
+            0
+        };
+        let result = unsafe { libc::wait4(pid, &mut status, flags, &mut usage) };
+        if result == pid {
+            break;
+        }
+        if result == 0 {
+            if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+                unsafe {
+                    libc::kill(-pid, libc::SIGKILL);
+                    libc::kill(pid, libc::SIGKILL);
+                }
+                killed = true;
+            } else {
+                std::thread::sleep(TIMEOUT_POLL_INTERVAL);
+            }
+            continue;
+        }
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::Interrupted {
+            Err(error)?
+        }
+    }
+    let wall_time = started.elapsed().as_secs_f64();
+    let seconds = |time: libc::timeval| time.tv_sec as f64 + time.tv_usec as f64 / 1e6;
+    // ru_maxrss is in bytes on macOS and in KiB on the other platforms
+    let max_rss = if cfg!(target_os = "macos") {
+        usage.ru_maxrss as u64 / 1024
+    } else {
+        usage.ru_maxrss as u64
+    };
+    Ok((
+        (!killed).then(|| ExitStatus::from_raw(status)),
+        ResourceUsage {
+            wall_time,
+            user_time: Some(seconds(usage.ru_utime)),
+            system_time: Some(seconds(usage.ru_stime)),
+            max_rss: Some(max_rss),
+        },
+    ))
+}
+
+#[cfg(not(unix))]
+pub fn wait_with_timeout(
+    child: &mut Child,
+    started: Instant,
+    timeout: Option<Duration>,
+) -> Result<(Option<ExitStatus>, ResourceUsage)> {
+    let status = match timeout {
+        None => Some(child.wait()?),
+        Some(timeout) => loop {
+            if let Some(status) = child.try_wait()? {
+                break Some(status);
+            }
+            if started.elapsed() >= timeout {
+                child.kill()?;
+                child.wait()?;
+                break None;
+            }
+            std::thread::sleep(TIMEOUT_POLL_INTERVAL);
+        },
+    };
+    Ok((
+        status,
+        ResourceUsage {
+            wall_time: started.elapsed().as_secs_f64(),
+            ..Default::default()
+        },
+    ))
+}
+
+#[cfg(unix)]
+#[test]
+fn usage_of_sleep() {
+    let started = Instant::now();
+    let mut child = std::process::Command::new("sleep")
+        .arg("0.2")
+        .spawn()
+        .unwrap();
+    let (status, usage) = wait_with_usage(&mut child, started).unwrap();
+    assert!(status.success());
+    assert!(usage.wall_time >= 0.2);
+    assert!(usage.max_rss.is_some());
+    let started = Instant::now();
+    let mut child = std::process::Command::new("sleep")
+        .arg("10")
+        .spawn()
+        .unwrap();
+    let (status, usage) =
+        wait_with_timeout(&mut child, started, Some(Duration::from_millis(200))).unwrap();
+    assert!(status.is_none());
+    assert!(usage.wall_time < 5.);
+}
+
+#[test]
+fn resolve_program_with_extensions() {
+    let directory = tempfile::tempdir().unwrap();
+    for name in ["orca.EXE", "g16.CMD", "notes.txt"] {
+        std::fs::write(directory.path().join(name), "").unwrap();
+    }
+    let path = std::env::join_paths([Path::new("/nonexistent"), directory.path()]).unwrap();
+    let resolve = |program: &str| resolve_program(program.as_ref(), &path, DEFAULT_PATHEXT);
+    assert_eq!(resolve("orca"), Some(directory.path().join("orca.EXE")));
+    assert_eq!(resolve("g16"), Some(directory.path().join("g16.CMD")));
+    assert_eq!(resolve("orca.EXE"), Some(directory.path().join("orca.EXE")));
+    assert_eq!(resolve("notes"), None);
+    let relative = directory.path().join("orca");
+    assert_eq!(
+        resolve(relative.to_str().unwrap()),
+        Some(directory.path().join("orca.EXE"))
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn load_modules_before_program() {
+    let directory = tempfile::tempdir().unwrap();
+    let init = directory.path().join("init");
+    std::fs::create_dir_all(&init).unwrap();
+    std::fs::write(
+        init.join("sh"),
+        "module() { [ \"$2\" != missing ] && echo \"$2\" >> modules.txt; }\n",
+    )
+    .unwrap();
+    let mut command = Command::new("sh");
+    command
+        .args(["-c", "echo \"$1\" > done.txt", "-", "it's done"])
+        .current_dir(directory.path())
+        .env("MODULESHOME", directory.path());
+    let modules = ["gaussian/16".to_string(), "openmpi/4".to_string()];
+    let status = with_modules(&command, &modules).status().unwrap();
+    assert!(status.success());
+    let read = |name: &str| std::fs::read_to_string(directory.path().join(name)).unwrap();
+    assert_eq!(read("modules.txt"), "gaussian/16\nopenmpi/4\n");
+    assert_eq!(read("done.txt"), "it's done\n");
+    let status = with_modules(&command, &["missing".to_string()])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(127));
+}
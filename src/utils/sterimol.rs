@@ -1,43 +1,42 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use nalgebra::{Point3, Vector3};
 use petgraph::{csr::IndexType, prelude::StableUnGraph};
 use serde::Deserialize;
 
-use crate::chemistry::Atom3D;
+use crate::{chemistry::Atom3D, utils::neighbors::KdTree};
 
 #[derive(Deserialize)]
 pub struct RadiisItem {
-    symbol: String,
-    value: f64,
+    pub symbol: String,
+    pub value: f64,
 }
 
 pub fn auto_connect_bonds(
-    atoms: &Vec<Atom3D>,
+    atoms: &[Atom3D],
     r_cov_table: &RadiisTable,
 ) -> Result<Vec<(usize, usize, f64)>> {
-    let mut bonds = vec![];
-    for (a_idx, atom) in atoms.iter().enumerate() {
-        let r_a = r_cov_table
-            .get(atom.element)
-            .with_context(|| {
-                format!(
-                    "Failed to found the radiis for the second atom element {}",
-                    atom.element
-                )
-            })?
-            .value;
-        let p_a = atom.position;
-        for (b_idx, atom) in atoms.iter().enumerate().skip(a_idx + 1) {
-            let r_b = r_cov_table
+    let radii = atoms
+        .iter()
+        .map(|atom| {
+            r_cov_table
                 .get(atom.element)
+                .map(|item| item.value)
                 .with_context(|| {
                     format!(
-                        "Failed to found the radiis for the second atom element {}",
+                        "Failed to found the radiis for the atom element {}",
                         atom.element
                     )
-                })?
-                .value;
-            let distance = (atom.position - p_a).norm();
-            if distance <= r_a + r_b {
+                })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let r_max = radii.iter().copied().fold(0., f64::max);
+    let tree = KdTree::new(&atoms.iter().map(|atom| atom.position).collect::<Vec<_>>());
+    let mut bonds = vec![];
+    for (a_idx, atom) in atoms.iter().enumerate() {
+        let p_a = atom.position;
+        for b_idx in tree.within(&p_a, radii[a_idx] + r_max) {
+            let distance = (atoms[b_idx].position - p_a).norm();
+            if b_idx > a_idx && distance <= radii[a_idx] + radii[b_idx] {
                 bonds.push((a_idx, b_idx, 1.0))
             }
         }
@@ -59,7 +58,7 @@ pub fn molecular_graph_walk(
         .neighbors(entry.into())
         .filter(|neighbor| !excludes.contains(&neighbor.index()))
         .collect::<Vec<_>>();
-    if current_depth == limit_depth || neighbors.len() == 0 {
+    if current_depth == limit_depth || neighbors.is_empty() {
         Ok(vec![(entry, *current_position)])
     } else {
         let sub_find_results = neighbors
@@ -70,7 +69,7 @@ pub fn molecular_graph_walk(
                     index.index(),
                     current_depth + 1,
                     limit_depth,
-                    vec![vec![entry.index()], excludes.clone()].concat(),
+                    [vec![entry.index()], excludes.clone()].concat(),
                 )
             })
             .collect::<Result<Vec<_>>>()?;
@@ -80,10 +79,7 @@ pub fn molecular_graph_walk(
 
 type MolecularGraph = StableUnGraph<Atom3D, f64, usize>;
 
-pub fn get_molecular_graph(
-    atoms: &Vec<Atom3D>,
-    bonds: &Vec<(usize, usize, f64)>,
-) -> MolecularGraph {
+pub fn get_molecular_graph(atoms: &[Atom3D], bonds: &[(usize, usize, f64)]) -> MolecularGraph {
     let mut molecular_graph: StableUnGraph<Atom3D, f64, usize> = StableUnGraph::default();
     for atom in atoms {
         molecular_graph.add_node(*atom);
@@ -123,11 +119,11 @@ pub fn sterimol(molecular_graph: &MolecularGraph, table: &RadiisTable) -> Result
         .into_iter()
         .reduce(|acc, next| if acc > next { acc } else { next })
         .unwrap_or(ab.norm() + b_radii);
-    let branches = molecular_graph_walk(&molecular_graph, 1, 0, 1, vec![0])?
+    let branches = molecular_graph_walk(molecular_graph, 1, 0, 1, vec![0])?
         .into_iter()
         .map(|(idx, _)| {
             Ok(
-                molecular_graph_walk(&molecular_graph, idx, 1, 0, vec![0, 1])?
+                molecular_graph_walk(molecular_graph, idx, 1, 0, vec![0, 1])?
                     .into_iter()
                     .map(|(_, atom)| atom)
                     .map(|atom| {
@@ -201,3 +197,84 @@ pub fn tolman_cone_angle(molecular_graph: &MolecularGraph) -> Result<f64> {
         Ok(tolman_angle / (branches as f64) * 2.)
     }
 }
+
+/// Percent buried volume (%Vbur) of a sphere around the center, e.g. the metal
+/// of a ligand, by the atoms with the radii from the table.
+///
+/// The sphere is sampled on a cubic grid of `mesh` spacing, a point is buried
+/// if it is inside any of the atoms. The common settings are a sphere of 3.5
+/// Angstrom, a mesh of 0.1 Angstrom and the Bondi radii scaled by 1.17. The
+/// atom at the center should not be included in the atoms.
+pub fn buried_volume(
+    atoms: &[Atom3D],
+    center: &Point3<f64>,
+    table: &RadiisTable,
+    sphere_radius: f64,
+    mesh: f64,
+) -> Result<f64> {
+    if !(sphere_radius > 0. && mesh > 0.) {
+        Err(anyhow!(
+            "Invalid sphere radius {} or mesh {} for buried volume",
+            sphere_radius,
+            mesh
+        ))?
+    }
+    let radii = atoms
+        .iter()
+        .map(|atom| {
+            table
+                .get(atom.element)
+                .map(|item| item.value)
+                .with_context(|| format!("Failed to read radiis of element {}", atom.element))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let r_max = radii.iter().copied().fold(0., f64::max);
+    let tree = KdTree::new(&atoms.iter().map(|atom| atom.position).collect::<Vec<_>>());
+    let steps = (sphere_radius / mesh).floor() as i64;
+    let (mut total, mut buried) = (0_usize, 0_usize);
+    for x in -steps..=steps {
+        for y in -steps..=steps {
+            for z in -steps..=steps {
+                let offset = Vector3::new(x as f64, y as f64, z as f64) * mesh;
+                if offset.norm() > sphere_radius {
+                    continue;
+                }
+                total += 1;
+                let point = center + offset;
+                if tree
+                    .within(&point, r_max)
+                    .into_iter()
+                    .any(|index| (atoms[index].position - point).norm() <= radii[index])
+                {
+                    buried += 1;
+                }
+            }
+        }
+    }
+    Ok(buried as f64 / total as f64 * 100.)
+}
+
+#[test]
+fn buried_volume_of_spheres() {
+    let table = (0..=6)
+        .map(|element| RadiisItem {
+            symbol: String::new(),
+            value: if element == 1 { 1.75 } else { 10. },
+        })
+        .collect::<Vec<_>>();
+    let atom = |element, x| Atom3D {
+        element,
+        position: Point3::new(x, 0., 0.),
+        formal_charge: 0.,
+    };
+    let center = Point3::origin();
+    // Half of the radius buries 1/8 of the sphere
+    let half = buried_volume(&[atom(1, 0.)], &center, &table, 3.5, 0.1).unwrap();
+    assert!((half - 12.5).abs() < 0.5);
+    let full = buried_volume(&[atom(6, 2.)], &center, &table, 3.5, 0.2).unwrap();
+    assert_eq!(full, 100.);
+    let none = buried_volume(&[atom(1, 10.)], &center, &table, 3.5, 0.2).unwrap();
+    assert_eq!(none, 0.);
+    assert!(buried_volume(&[atom(7, 1.)], &center, &table, 3.5, 0.2).is_err());
+    assert!(buried_volume(&[], &center, &table, 3.5, 0.).is_err());
+}
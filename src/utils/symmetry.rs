@@ -0,0 +1,313 @@
+use std::f64::consts::PI;
+
+use nalgebra::{Matrix3, Point3, Rotation3, SymmetricEigen, Unit, Vector3};
+
+use crate::{sparse_molecule::SparseMolecule, utils::neighbors::KdTree};
+
+/// Highest order of the rotation axes searched.
+const MAX_ORDER: usize = 8;
+/// Max |cos| of the angle between two axes taken as perpendicular, and min
+/// |cos| of two axes taken as the same.
+const AXIS_TOLERANCE: f64 = 0.02;
+
+/// Atoms of a molecule centered at the centroid, with the symmetry operations
+/// checked by mapping each atom onto an atom of the same element.
+struct Symmetry {
+    elements: Vec<usize>,
+    positions: Vec<Point3<f64>>,
+    tree: KdTree,
+    tolerance: f64,
+}
+
+impl Symmetry {
+    fn has(&self, operation: &Matrix3<f64>) -> bool {
+        self.positions
+            .iter()
+            .zip(&self.elements)
+            .all(|(position, element)| {
+                let image = Point3::from(operation * position.coords);
+                self.tree
+                    .within(&image, self.tolerance)
+                    .into_iter()
+                    .any(|index| self.elements[index] == *element)
+            })
+    }
+
+    /// Highest order of the proper rotation axis, 1 if it's not an axis.
+    fn rotation_order(&self, axis: &Unit<Vector3<f64>>) -> usize {
+        (2..=MAX_ORDER)
+            .rev()
+            .find(|order| self.has(&rotation(axis, *order)))
+            .unwrap_or(1)
+    }
+
+    fn mirror(&self, normal: &Unit<Vector3<f64>>) -> bool {
+        self.has(&reflection(normal))
+    }
+}
+
+fn rotation(axis: &Unit<Vector3<f64>>, order: usize) -> Matrix3<f64> {
+    Rotation3::from_axis_angle(axis, 2. * PI / order as f64).into_inner()
+}
+
+fn reflection(normal: &Unit<Vector3<f64>>) -> Matrix3<f64> {
+    Matrix3::identity() - 2. * normal.into_inner() * normal.transpose()
+}
+
+/// Schoenflies symbol of the point group of the molecule, e.g. `C2v` for
+/// water, `Td` for methane and `D6h` for benzene. Linear molecules are
+/// `Cinfv` or `Dinfh` and a single atom is `Kh`.
+///
+/// An operation is a symmetry if it moves every atom within `tolerance`
+/// (Angstrom) of an atom of the same element. The rotation axes (up to order 8)
+/// and the mirror planes are searched among the principal axes, the directions
+/// of the atoms, and the sums, differences and normals of the positions of each
+/// pair of atoms of the same element, relative to the centroid. Groups with
+/// more than one axis of order 3 or more are cubic (T, Td, Th, O, Oh) or
+/// icosahedral (I, Ih), the latter found only if a 5-fold axis is among the
+/// searched directions.
+pub fn point_group(molecule: &SparseMolecule, tolerance: f64) -> String {
+    let atoms = (0..molecule.len())
+        .filter_map(|index| molecule.atoms.read_atom(index))
+        .collect::<Vec<_>>();
+    match atoms.len() {
+        0 => return "C1".to_string(),
+        1 => return "Kh".to_string(),
+        _ => {}
+    }
+    let centroid = atoms
+        .iter()
+        .fold(Vector3::zeros(), |sum, atom| sum + atom.position.coords)
+        / atoms.len() as f64;
+    let positions = atoms
+        .iter()
+        .map(|atom| atom.position - centroid)
+        .collect::<Vec<_>>();
+    let symmetry = Symmetry {
+        elements: atoms.iter().map(|atom| atom.element).collect(),
+        tree: KdTree::new(&positions),
+        positions,
+        tolerance,
+    };
+    let inversion = symmetry.has(&-Matrix3::identity());
+    let covariance = symmetry
+        .positions
+        .iter()
+        .fold(Matrix3::zeros(), |sum, position| {
+            sum + position.coords * position.coords.transpose()
+        });
+    let eigen = SymmetricEigen::new(covariance);
+    let line = Unit::new_normalize(
+        eigen
+            .eigenvectors
+            .column(eigen.eigenvalues.imax())
+            .into_owned(),
+    );
+    let linear = symmetry
+        .positions
+        .iter()
+        .all(|position| position.coords.cross(&line).norm() < tolerance);
+    if linear {
+        return if inversion { "Dinfh" } else { "Cinfv" }.to_string();
+    }
+
+    let mut candidates: Vec<Unit<Vector3<f64>>> = vec![];
+    let mut add = |direction: Vector3<f64>| {
+        if direction.norm() > tolerance {
+            let direction = Unit::new_normalize(direction);
+            if candidates
+                .iter()
+                .all(|axis| axis.dot(&direction).abs() < 1. - AXIS_TOLERANCE)
+            {
+                candidates.push(direction);
+            }
+        }
+    };
+    for axis in eigen.eigenvectors.column_iter() {
+        add(axis.into_owned());
+    }
+    let (elements, positions) = (&symmetry.elements, &symmetry.positions);
+    for a in 0..positions.len() {
+        add(positions[a].coords);
+        for b in (a + 1)..positions.len() {
+            if elements[a] == elements[b] {
+                let (p, q) = (positions[a].coords, positions[b].coords);
+                add(p + q);
+                add(p - q);
+                // Normal of the plane of the pair and the centroid, scaled to
+                // be comparable with the tolerance
+                add(p.cross(&q) / p.norm().max(q.norm()));
+            }
+        }
+    }
+
+    let rotations = candidates
+        .iter()
+        .map(|axis| (*axis, symmetry.rotation_order(axis)))
+        .filter(|(_, order)| *order >= 2)
+        .collect::<Vec<_>>();
+    let mirror = || candidates.iter().any(|normal| symmetry.mirror(normal));
+    let highest = rotations.iter().map(|(_, order)| *order).max().unwrap_or(1);
+    if rotations.iter().filter(|(_, order)| *order >= 3).count() >= 2 {
+        return match (highest, inversion) {
+            (5, true) => "Ih",
+            (5, false) => "I",
+            (4, true) => "Oh",
+            (4, false) => "O",
+            (_, true) => "Th",
+            (_, false) if mirror() => "Td",
+            _ => "T",
+        }
+        .to_string();
+    }
+    if highest == 1 {
+        return if mirror() {
+            "Cs"
+        } else if inversion {
+            "Ci"
+        } else {
+            "C1"
+        }
+        .to_string();
+    }
+    // Any axis of the highest order may be the principal one, e.g. the S4
+    // axis among the three C2 axes of D2d, the largest group is taken
+    rotations
+        .iter()
+        .filter(|(_, order)| *order == highest)
+        .map(|(axis, order)| {
+            let perpendicular = |other: &Unit<Vector3<f64>>| axis.dot(other).abs() < AXIS_TOLERANCE;
+            let dihedral = rotations
+                .iter()
+                .any(|(other, order)| order % 2 == 0 && perpendicular(other));
+            let horizontal = symmetry.mirror(axis);
+            let vertical = candidates
+                .iter()
+                .any(|normal| perpendicular(normal) && symmetry.mirror(normal));
+            let n = *order;
+            match (dihedral, horizontal, vertical) {
+                (true, true, _) => (format!("D{}h", n), 4 * n),
+                (true, false, true) => (format!("D{}d", n), 4 * n),
+                (true, false, false) => (format!("D{}", n), 2 * n),
+                (false, true, _) => (format!("C{}h", n), 2 * n),
+                (false, false, true) => (format!("C{}v", n), 2 * n),
+                _ if symmetry.has(&(reflection(axis) * rotation(axis, 2 * n))) => {
+                    (format!("S{}", 2 * n), 2 * n)
+                }
+                _ => (format!("C{}", n), n),
+            }
+        })
+        .max_by_key(|(_, order)| *order)
+        .map(|(name, _)| name)
+        .unwrap()
+}
+
+#[test]
+fn point_groups() {
+    use crate::{chemistry::Atom3D, sparse_molecule::SparseAtomList};
+    let molecule = |atoms: &[(usize, [f64; 3])]| SparseMolecule {
+        atoms: SparseAtomList::from(
+            atoms
+                .iter()
+                .map(|(element, [x, y, z])| Atom3D {
+                    element: *element,
+                    position: Point3::new(*x, *y, *z),
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>(),
+        ),
+        ..Default::default()
+    };
+    let group = |atoms: &[(usize, [f64; 3])]| point_group(&molecule(atoms), 0.1);
+    let hexagon = |element, radius: f64, z: f64| {
+        (0..6).map(move |k| {
+            let angle = k as f64 * PI / 3.;
+            (element, [radius * angle.cos(), radius * angle.sin(), z])
+        })
+    };
+    assert_eq!(group(&[(1, [0., 0., 0.])]), "Kh");
+    assert_eq!(
+        group(&[(8, [0., 0., 0.]), (6, [0., 0., 1.16]), (8, [0., 0., 2.32])]),
+        "Dinfh"
+    );
+    assert_eq!(
+        group(&[(1, [0., 0., -1.06]), (6, [0., 0., 0.]), (7, [0., 0., 1.16])]),
+        "Cinfv"
+    );
+    let water = [
+        (8, [0., 0., 0.12]),
+        (1, [0.76, 0., -0.47]),
+        (1, [-0.76, 0., -0.47]),
+    ];
+    assert_eq!(group(&water), "C2v");
+    let ammonia = [
+        (7, [0., 0., 0.]),
+        (1, [0.94, 0., -0.38]),
+        (1, [-0.47, 0.81, -0.38]),
+        (1, [-0.47, -0.81, -0.38]),
+    ];
+    assert_eq!(group(&ammonia), "C3v");
+    let methane = [
+        (6, [0., 0., 0.]),
+        (1, [0.63, 0.63, 0.63]),
+        (1, [-0.63, -0.63, 0.63]),
+        (1, [-0.63, 0.63, -0.63]),
+        (1, [0.63, -0.63, -0.63]),
+    ];
+    assert_eq!(group(&methane), "Td");
+    let mut hexafluoride = vec![(16, [0., 0., 0.])];
+    for axis in 0..3 {
+        for sign in [1., -1.] {
+            let mut position = [0.; 3];
+            position[axis] = 1.56 * sign;
+            hexafluoride.push((9, position));
+        }
+    }
+    assert_eq!(group(&hexafluoride), "Oh");
+    let benzene = hexagon(6, 1.39, 0.)
+        .chain(hexagon(1, 2.47, 0.))
+        .collect::<Vec<_>>();
+    assert_eq!(group(&benzene), "D6h");
+    // Allene, the CH2 planes are perpendicular
+    let allene = [
+        (6, [0., 0., 0.]),
+        (6, [0., 0., 1.31]),
+        (6, [0., 0., -1.31]),
+        (1, [0.93, 0., 1.87]),
+        (1, [-0.93, 0., 1.87]),
+        (1, [0., 0.93, -1.87]),
+        (1, [0., -0.93, -1.87]),
+    ];
+    assert_eq!(group(&allene), "D2d");
+    // trans-1,2-dichloroethylene
+    let dichloroethylene = [
+        (6, [0.67, 0., 0.]),
+        (6, [-0.67, 0., 0.]),
+        (17, [1.5, 1.45, 0.]),
+        (17, [-1.5, -1.45, 0.]),
+        (1, [1.25, -0.92, 0.]),
+        (1, [-1.25, 0.92, 0.]),
+    ];
+    assert_eq!(group(&dichloroethylene), "C2h");
+    let hypochlorous_acid = [
+        (8, [0., 0., 0.]),
+        (17, [1.69, 0., 0.]),
+        (1, [-0.25, 0.94, 0.]),
+    ];
+    assert_eq!(group(&hypochlorous_acid), "Cs");
+    let bromochlorofluoromethane = [
+        (6, [0., 0., 0.]),
+        (1, [0.63, 0.63, 0.63]),
+        (9, [-0.8, -0.8, 0.8]),
+        (17, [-1., 1., -1.]),
+        (35, [1.1, -1.1, -1.1]),
+    ];
+    assert_eq!(group(&bromochlorofluoromethane), "C1");
+    // Slightly distorted water is still C2v within the tolerance
+    let distorted = [
+        (8, [0., 0.01, 0.12]),
+        (1, [0.77, 0., -0.47]),
+        (1, [-0.76, 0., -0.46]),
+    ];
+    assert_eq!(group(&distorted), "C2v");
+}
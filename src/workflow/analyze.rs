@@ -0,0 +1,133 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use lmers::{sparse_molecule::SparseMolecule, utils::symmetry::point_group};
+use rayon::prelude::*;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{
+    features::quote_field,
+    runner::{cached_read_stack, rooted, RunnerOutput},
+    workflow_data::{LayerStorage, Window},
+};
+
+const COLUMNS: [&str; 4] = ["title", "point_group", "formula", "molecular_weight"];
+
+/// Find the point group of each structure (see
+/// `lmers::utils::symmetry::point_group`), with atoms matched within
+/// `tolerance` (0.1 Angstrom by default), e.g.
+/// `{with: Analyze, path: symmetry.csv}`.
+///
+/// All structures are passed to the next step, and the windows of each point
+/// group are saved as checkpoints `<name>_<point group>` if the step is named,
+/// so later steps can start `from` the structures of a point group (e.g.
+/// `analyze_C2v`) or check them in conditions (e.g. `completed("analyze_Td")`).
+/// The point groups, formulas and molecular weights are written to the table
+/// at `path` if given, as CSV, or tab separated if the path ends with `.tsv`.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AnalyzeOptions {
+    #[serde(default)]
+    path: Option<PathBuf>,
+    #[serde(default = "AnalyzeOptions::default_tolerance")]
+    tolerance: f64,
+}
+
+impl AnalyzeOptions {
+    fn default_tolerance() -> f64 {
+        0.1
+    }
+
+    pub fn root_outputs(&mut self, directory: &Path) {
+        if let Some(path) = self.path.as_mut() {
+            *path = rooted(directory, path);
+        }
+    }
+
+    pub fn execute(
+        &self,
+        base: &SparseMolecule,
+        current_window: &Window,
+        layer_storage: &LayerStorage,
+    ) -> Result<RunnerOutput> {
+        let delimiter = match self
+            .path
+            .as_ref()
+            .and_then(|path| path.extension())
+            .and_then(|ext| ext.to_str())
+        {
+            Some("tsv") => "\t",
+            Some("csv") | None => ",",
+            Some(ext) => Err(anyhow!(
+                "Unsupported analysis table format {}, use csv or tsv",
+                ext
+            ))?,
+        };
+        let rows = current_window
+            .par_iter()
+            .map(|(title, stack_path)| {
+                let structure = cached_read_stack(base, layer_storage, stack_path)?;
+                Ok((
+                    title.as_str(),
+                    [
+                        point_group(&structure, self.tolerance),
+                        structure.formula(),
+                        structure.molecular_weight().to_string(),
+                    ],
+                ))
+            })
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        let mut windows: BTreeMap<String, Window> = BTreeMap::new();
+        for (title, [group, ..]) in &rows {
+            windows
+                .entry(group.to_string())
+                .or_default()
+                .insert(title.to_string(), current_window[*title].clone());
+        }
+        for (group, window) in &windows {
+            println!("Point group {}: {} structures", group, window.len());
+        }
+        if let Some(path) = &self.path {
+            let mut content = COLUMNS
+                .map(|column| quote_field(column, delimiter))
+                .join(delimiter);
+            content.push('\n');
+            for (title, values) in rows {
+                let mut fields = vec![quote_field(title, delimiter)];
+                fields.extend(values.iter().map(|value| quote_field(value, delimiter)));
+                content.push_str(&fields.join(delimiter));
+                content.push('\n');
+            }
+            File::create(path)
+                .with_context(|| format!("Unable to create analysis table at {:?}", path))?
+                .write_all(content.as_bytes())
+                .with_context(|| format!("Unable to write analysis table at {:?}", path))?;
+        }
+        Ok(RunnerOutput::MultiWindow(windows))
+    }
+}
+
+#[test]
+fn analyze_empty_window() {
+    let options = serde_yaml::from_str::<AnalyzeOptions>("{}").unwrap();
+    assert_eq!(options.tolerance, 0.1);
+    assert!(options.path.is_none());
+    // Stack paths are cached by their layers only, so only the empty window
+    // is executed here, the point groups are tested with `point_group`
+    let directory = tempfile::tempdir().unwrap();
+    let storage = LayerStorage::new(directory.path().join(".layers.db"));
+    let mut options = serde_yaml::from_str::<AnalyzeOptions>("{path: symmetry.tsv}").unwrap();
+    options.root_outputs(directory.path());
+    let output = options
+        .execute(&SparseMolecule::default(), &Window::new(), &storage)
+        .unwrap();
+    assert!(matches!(output, RunnerOutput::MultiWindow(windows) if windows.is_empty()));
+    let table = std::fs::read_to_string(directory.path().join("symmetry.tsv")).unwrap();
+    assert_eq!(table, "title\tpoint_group\tformula\tmolecular_weight\n");
+}
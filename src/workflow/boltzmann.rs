@@ -0,0 +1,181 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{
+    features::quote_field,
+    runner::{rooted, RunnerOutput},
+    unit::EnergyUnit::KcalPerMol,
+    variable::ScalarFile,
+    workflow_data::Window,
+};
+
+/// Boltzmann constant in Hartree/K
+const BOLTZMANN: f64 = 3.166811563e-6;
+
+/// Boltzmann factors of the energies (Hartree) relative to the lowest one at
+/// the temperature.
+fn boltzmann_factors(energies: &[f64], temperature: f64) -> Vec<f64> {
+    let minimum = energies.iter().copied().fold(f64::INFINITY, f64::min);
+    energies
+        .iter()
+        .map(|energy| (-(energy - minimum) / (BOLTZMANN * temperature)).exp())
+        .collect()
+}
+
+/// Weight the structures of an ensemble (e.g. conformers) by the Boltzmann
+/// populations of their energies at `temperature` (K).
+///
+/// The energies are read by `energy` (see `ScalarFile`) in Hartree, or in a
+/// declared unit, and can be read from the table of PropertyExtract with the
+/// title in the pattern. The populations with the relative energies (kcal/mol)
+/// are written to `path` as CSV, and the ensemble summary (the weighted
+/// energy, and the free energy correction `-RT ln(sum of the Boltzmann
+/// factors)` to the lowest energy) is printed.
+///
+/// The window is partitioned into `populated` (with populations not below
+/// `min_population`, all by default), `minor` and `unscored` (structures with
+/// the energy failed to read), and `populated` is kept.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BoltzmannOptions {
+    energy: ScalarFile,
+    #[serde(default = "BoltzmannOptions::default_temperature")]
+    temperature: f64,
+    path: PathBuf,
+    #[serde(default)]
+    min_population: Option<f64>,
+}
+
+impl BoltzmannOptions {
+    fn default_temperature() -> f64 {
+        298.15
+    }
+
+    pub fn root_outputs(&mut self, directory: &Path) {
+        self.path = rooted(directory, &self.path);
+    }
+
+    pub fn execute(&self, current_window: &Window) -> Result<RunnerOutput> {
+        if self.temperature <= 0. {
+            Err(anyhow!("Invalid temperature {} K", self.temperature))?
+        }
+        let mut windows = BTreeMap::from([
+            ("populated".to_string(), Window::new()),
+            ("minor".to_string(), Window::new()),
+        ]);
+        let mut scored = vec![];
+        for (title, value) in self.energy.read_window(current_window)? {
+            match value {
+                Ok(energy) if energy.is_finite() => scored.push((title, energy)),
+                value => {
+                    match value {
+                        Ok(value) => println!("Invalid energy {} of {}", value, title),
+                        Err(err) => println!("Unable to read energy of {}: {:#}", title, err),
+                    }
+                    let stack_path = current_window[&title].clone();
+                    windows
+                        .entry("unscored".to_string())
+                        .or_default()
+                        .insert(title, stack_path);
+                }
+            }
+        }
+        let energies = scored.iter().map(|(_, energy)| *energy).collect::<Vec<_>>();
+        let factors = boltzmann_factors(&energies, self.temperature);
+        let partition = factors.iter().sum::<f64>();
+        let populations = factors
+            .iter()
+            .map(|factor| factor / partition)
+            .collect::<Vec<_>>();
+        let minimum = energies.iter().copied().fold(f64::INFINITY, f64::min);
+        let mut content = ["title", "energy", "relative_energy", "population"].join(",");
+        content.push('\n');
+        for ((title, energy), population) in scored.iter().zip(&populations) {
+            content.push_str(&format!(
+                "{},{},{:.4},{:.6}\n",
+                quote_field(title, ","),
+                energy,
+                KcalPerMol.convert_hartree(energy - minimum),
+                population
+            ));
+            let kept = self
+                .min_population
+                .map(|min_population| *population >= min_population)
+                .unwrap_or(true);
+            windows
+                .get_mut(if kept { "populated" } else { "minor" })
+                .unwrap()
+                .insert(title.to_string(), current_window[title].clone());
+        }
+        File::create(&self.path)
+            .with_context(|| format!("Unable to create Boltzmann table at {:?}", self.path))?
+            .write_all(content.as_bytes())
+            .with_context(|| format!("Unable to write Boltzmann table at {:?}", self.path))?;
+        if !scored.is_empty() {
+            let weighted = energies
+                .iter()
+                .zip(&populations)
+                .map(|(energy, population)| energy * population)
+                .sum::<f64>();
+            let correction = -BOLTZMANN * self.temperature * partition.ln();
+            println!(
+                "Ensemble of {} structures at {} K: lowest energy {}, weighted energy {} ({:.4} kcal/mol above), free energy correction {:.4} kcal/mol, written to {:?}",
+                scored.len(),
+                self.temperature,
+                minimum,
+                weighted,
+                KcalPerMol.convert_hartree(weighted - minimum),
+                KcalPerMol.convert_hartree(correction),
+                self.path
+            );
+        }
+        Ok(RunnerOutput::Partition {
+            windows,
+            keep: "populated".to_string(),
+        })
+    }
+}
+
+#[test]
+fn boltzmann_populations() {
+    // 1 kcal/mol apart at 298.15 K: 84.4% and 15.6%
+    let gap = KcalPerMol.to_hartree(1.);
+    let factors = boltzmann_factors(&[-1., -1. + gap], 298.15);
+    assert_eq!(factors[0], 1.);
+    assert!((factors[0] / (factors[0] + factors[1]) - 0.8437).abs() < 1e-3);
+    let directory = tempfile::tempdir().unwrap();
+    std::fs::write(
+        directory.path().join("results.csv"),
+        format!("title,energy\na,-1\nb,{}\nc,-0.99\n", -1. + gap),
+    )
+    .unwrap();
+    let window = Window::from_iter(
+        ["a", "b", "c", "d"]
+            .into_iter()
+            .enumerate()
+            .map(|(index, title)| (title.to_string(), vec![index as u64])),
+    );
+    let mut options = serde_yaml::from_str::<BoltzmannOptions>(&format!(
+        "energy: {{path: '{}/results.csv', pattern: '(?m)^{{title}},(\\S+)$'}}\npath: boltzmann.csv\nmin_population: 0.1",
+        directory.path().display()
+    ))
+    .unwrap();
+    options.root_outputs(directory.path());
+    let RunnerOutput::Partition { windows, keep } = options.execute(&window).unwrap() else {
+        panic!("Partition expected")
+    };
+    assert_eq!(keep, "populated");
+    assert_eq!(windows["populated"].keys().collect::<Vec<_>>(), ["a", "b"]);
+    assert_eq!(windows["minor"].keys().collect::<Vec<_>>(), ["c"]);
+    assert_eq!(windows["unscored"].keys().collect::<Vec<_>>(), ["d"]);
+    let table = std::fs::read_to_string(directory.path().join("boltzmann.csv")).unwrap();
+    assert!(table.contains("b,") && table.contains(",1.0000,"));
+}
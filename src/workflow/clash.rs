@@ -0,0 +1,121 @@
+use anyhow::{anyhow, Result};
+use lmers::{sparse_molecule::SparseMolecule, utils::clash::vdw_clashes};
+use rayon::prelude::*;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{
+    runner::{cached_read_stack, RunnerOutput},
+    workflow_data::{LayerStorage, Window},
+};
+
+/// Check the structures for atoms closer than `scale` (0.6 by default) times
+/// the sum of their van der Waals radii, e.g. after placing substituents, see
+/// `lmers::utils::clash::vdw_clashes`. Elements without a radius use
+/// `default_radius` (2.0 Angstrom by default).
+///
+/// The clashed structures are reported and kept by default, removed from the
+/// window if `remove` is set, or renamed with the `suffix` appended to the
+/// titles.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ClashOptions {
+    #[serde(default = "ClashOptions::default_scale")]
+    scale: f64,
+    #[serde(default = "ClashOptions::default_radius")]
+    default_radius: f64,
+    #[serde(default)]
+    remove: bool,
+    #[serde(default)]
+    suffix: Option<String>,
+}
+
+impl ClashOptions {
+    fn default_scale() -> f64 {
+        0.6
+    }
+
+    fn default_radius() -> f64 {
+        2.
+    }
+
+    /// Whether structures may be removed from the window.
+    pub fn removes(&self) -> bool {
+        self.remove
+    }
+
+    pub fn execute(
+        &self,
+        base: &SparseMolecule,
+        current_window: &Window,
+        layer_storage: &LayerStorage,
+    ) -> Result<RunnerOutput> {
+        let checked = current_window
+            .par_iter()
+            .map(|(title, stack_path)| {
+                let structure = cached_read_stack(base, layer_storage, stack_path)?;
+                let clashes = vdw_clashes(&structure, self.scale, self.default_radius);
+                if let Some((a, b, ratio)) = clashes.first() {
+                    println!(
+                        "{} has {} clashes, the closest atoms {} and {} at {:.2} of the vdW radii",
+                        title,
+                        clashes.len(),
+                        a,
+                        b,
+                        ratio
+                    );
+                }
+                Ok((title.as_str(), stack_path.as_slice(), !clashes.is_empty()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let clashed = checked.iter().filter(|(_, _, clashed)| *clashed).count();
+        println!(
+            "{} of {} structures have clashes",
+            clashed,
+            current_window.len()
+        );
+        Ok(RunnerOutput::SingleWindow(self.apply(checked)?))
+    }
+
+    /// Window of the checked structures, with the clashed ones removed or
+    /// renamed. A renamed title already used by another structure is an error
+    /// instead of replacing it.
+    fn apply(&self, checked: Vec<(&str, &[u64], bool)>) -> Result<Window> {
+        let mut window = Window::new();
+        for (title, stack_path, clashed) in checked {
+            if self.remove && clashed {
+                continue;
+            }
+            let title = match &self.suffix {
+                Some(suffix) if clashed => format!("{}{}", title, suffix),
+                _ => title.to_string(),
+            };
+            if window.insert(title.clone(), stack_path.to_vec()).is_some() {
+                Err(anyhow!(
+                    "Title {} of a clashed structure is used by another structure",
+                    title
+                ))?
+            }
+        }
+        Ok(window)
+    }
+}
+
+#[test]
+fn apply_clash_check() {
+    let checked = vec![("a", [1].as_slice(), false), ("b", [2].as_slice(), true)];
+    let options = serde_yaml::from_str::<ClashOptions>("{}").unwrap();
+    assert_eq!(options.apply(checked.clone()).unwrap().len(), 2);
+    let options = serde_yaml::from_str::<ClashOptions>("remove: true").unwrap();
+    assert_eq!(
+        options.apply(checked.clone()).unwrap(),
+        Window::from([("a".to_string(), vec![1])])
+    );
+    let options = serde_yaml::from_str::<ClashOptions>("suffix: _clash").unwrap();
+    let window = options.apply(checked.clone()).unwrap();
+    assert_eq!(window["b_clash"], vec![2]);
+    assert!(window.contains_key("a"));
+    let mut collided = checked;
+    collided.push(("b_clash", [3].as_slice(), false));
+    assert!(options.apply(collided).is_err());
+}
@@ -0,0 +1,463 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context, Result};
+use lmers::{
+    chemistry::validated_element_num,
+    layer::SelectOne,
+    sparse_molecule::SparseMolecule,
+    utils::geometric::{aligned_rmsd, dihedral_angle},
+};
+use nalgebra::Point3;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{
+    runner::{cached_read_stack, RunnerOutput},
+    variable::ScalarFile,
+    workflow_data::{LayerStorage, Window},
+};
+
+/// Clustering algorithm over the torsion fingerprints.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(tag = "algorithm", deny_unknown_fields)]
+pub enum ClusterMethod {
+    /// Partition into `k` clusters around medoids, initialized by k-medoids++
+    /// with the random `seed`
+    KMedoids {
+        k: usize,
+        #[serde(default)]
+        seed: u64,
+        #[serde(default = "ClusterMethod::default_max_iterations")]
+        max_iterations: usize,
+    },
+    /// Density-based clustering, structures within `eps` degrees are neighbors
+    /// and a cluster grows from structures with at least `min_points`
+    /// neighbors (itself included). Structures in no cluster are noise.
+    Dbscan { eps: f64, min_points: usize },
+}
+
+impl ClusterMethod {
+    fn default_max_iterations() -> usize {
+        100
+    }
+}
+
+/// Cluster conformers by the dihedral angles of the selected torsions, which is
+/// much cheaper than pairwise RMSD for very large ensembles.
+///
+/// The distance of two structures is the root mean square of the differences of
+/// their torsions in degrees, with the periodicity considered. The medoid of each
+/// cluster goes to the `representatives` window which is kept, and the members
+/// of clusters are saved as windows `cluster_<index>` (and `noise` for DBSCAN).
+/// The torsions of all rotatable bonds of the first structure are used if
+/// `torsions` is not given.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TorsionClusterOptions {
+    #[serde(default)]
+    torsions: Vec<[SelectOne; 4]>,
+    method: ClusterMethod,
+}
+
+impl TorsionClusterOptions {
+    pub fn execute(
+        &self,
+        base: &SparseMolecule,
+        current_window: &Window,
+        layer_storage: &LayerStorage,
+    ) -> Result<RunnerOutput> {
+        let entries = current_window.iter().collect::<Vec<_>>();
+        let torsions = match entries.first() {
+            Some((_, stack_path)) if self.torsions.is_empty() => {
+                let structure = cached_read_stack(base, layer_storage, stack_path)?;
+                structure
+                    .rotatable_bonds()
+                    .into_iter()
+                    .filter_map(|(b, c)| structure.torsion_atoms(b, c))
+                    .map(|torsion| torsion.map(SelectOne::Index))
+                    .collect()
+            }
+            _ => self.torsions.clone(),
+        };
+        if torsions.is_empty() && !entries.is_empty() {
+            Err(anyhow!(
+                "No torsion given or rotatable bond found for clustering"
+            ))?
+        }
+        let fingerprints = entries
+            .par_iter()
+            .map(|(title, stack_path)| {
+                let structure = cached_read_stack(base, layer_storage, stack_path)?;
+                fingerprint(&torsions, &structure)
+                    .with_context(|| format!("Unable to compute torsions of {}", title))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let labels = match &self.method {
+            ClusterMethod::KMedoids {
+                k,
+                seed,
+                max_iterations,
+            } => k_medoids(&fingerprints, *k, *seed, *max_iterations)
+                .into_iter()
+                .map(Some)
+                .collect(),
+            ClusterMethod::Dbscan { eps, min_points } => dbscan(&fingerprints, *eps, *min_points),
+        };
+        let mut clusters: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        let mut windows = BTreeMap::from([("representatives".to_string(), Window::new())]);
+        for (index, label) in labels.into_iter().enumerate() {
+            let (title, stack_path) = entries[index];
+            if let Some(label) = label {
+                clusters.entry(label).or_default().push(index);
+            } else {
+                windows
+                    .entry("noise".to_string())
+                    .or_default()
+                    .insert(title.to_string(), stack_path.clone());
+            }
+        }
+        for (cluster_index, members) in clusters.values().enumerate() {
+            let Some(medoid) = medoid(&fingerprints, members) else {
+                continue;
+            };
+            let (title, stack_path) = entries[medoid];
+            windows
+                .get_mut("representatives")
+                .unwrap()
+                .insert(title.to_string(), stack_path.clone());
+            windows.insert(
+                format!("cluster_{}", cluster_index),
+                members
+                    .iter()
+                    .map(|member| {
+                        let (title, stack_path) = entries[*member];
+                        (title.to_string(), stack_path.clone())
+                    })
+                    .collect(),
+            );
+        }
+        println!(
+            "{} structures clustered into {} clusters",
+            entries.len(),
+            clusters.len()
+        );
+        Ok(RunnerOutput::Partition {
+            windows,
+            keep: "representatives".to_string(),
+        })
+    }
+}
+
+/// Remove the duplicated conformers, whose RMSD of heavy atoms (or all atoms if
+/// `hydrogens` is set) after the optimal superposition is less than the
+/// `threshold` in angstrom.
+///
+/// Atoms are paired by their indices without considering the symmetry, and
+/// structures of different atoms are never duplicates. The structures are
+/// visited in the order of titles, or of the `score` (e.g. the energies in the
+/// output logs, structures with the score failed to read are visited last) so
+/// the representative of duplicates is the one with the least score. The
+/// representatives go to the `unique` window which is kept, and the others to
+/// `duplicates`.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DeduplicateOptions {
+    threshold: f64,
+    #[serde(default)]
+    hydrogens: bool,
+    #[serde(default)]
+    score: Option<ScalarFile>,
+}
+
+impl DeduplicateOptions {
+    pub fn execute(
+        &self,
+        base: &SparseMolecule,
+        current_window: &Window,
+        layer_storage: &LayerStorage,
+    ) -> Result<RunnerOutput> {
+        let mut entries = current_window.iter().collect::<Vec<_>>();
+        if let Some(score) = &self.score {
+            let scores = score.read_window(current_window)?;
+            let score = |title: &String| scores[title].as_ref().ok().copied();
+            entries.sort_by(|(a, _), (b, _)| match (score(a), score(b)) {
+                (Some(a), Some(b)) => a.total_cmp(&b),
+                (a, b) => b.is_some().cmp(&a.is_some()),
+            });
+        }
+        let conformers = entries
+            .par_iter()
+            .map(|(_, stack_path)| {
+                let structure = cached_read_stack(base, layer_storage, stack_path)?;
+                Ok(self.conformer(&structure))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let unique = deduplicate(&conformers, self.threshold);
+        let mut windows = BTreeMap::from([
+            ("unique".to_string(), Window::new()),
+            ("duplicates".to_string(), Window::new()),
+        ]);
+        for ((title, stack_path), unique) in entries.iter().zip(&unique) {
+            let name = if *unique { "unique" } else { "duplicates" };
+            windows
+                .get_mut(name)
+                .unwrap()
+                .insert(title.to_string(), stack_path.to_vec());
+        }
+        println!(
+            "{} unique structures in {} structures",
+            windows["unique"].len(),
+            entries.len()
+        );
+        Ok(RunnerOutput::Partition {
+            windows,
+            keep: "unique".to_string(),
+        })
+    }
+
+    /// Elements and positions of the compared atoms in the order of indices.
+    fn conformer(&self, structure: &SparseMolecule) -> (Vec<usize>, Vec<Point3<f64>>) {
+        structure
+            .atoms
+            .data()
+            .iter()
+            .flatten()
+            .filter(|atom| validated_element_num(atom.element))
+            .filter(|atom| self.hydrogens || atom.element != 1)
+            .map(|atom| (atom.element, atom.position))
+            .unzip()
+    }
+}
+
+/// Check if each conformer is not a duplicate of a previous unique one.
+fn deduplicate(conformers: &[(Vec<usize>, Vec<Point3<f64>>)], threshold: f64) -> Vec<bool> {
+    let mut representatives: Vec<usize> = vec![];
+    for (index, (elements, points)) in conformers.iter().enumerate() {
+        let duplicated = representatives.par_iter().any(|representative| {
+            let (representative_elements, representative_points) = &conformers[*representative];
+            representative_elements == elements
+                && aligned_rmsd(points, representative_points) < threshold
+        });
+        if !duplicated {
+            representatives.push(index);
+        }
+    }
+    let mut unique = vec![false; conformers.len()];
+    for representative in representatives {
+        unique[representative] = true;
+    }
+    unique
+}
+
+/// Dihedral angles of the torsions in degrees.
+fn fingerprint(torsions: &[[SelectOne; 4]], structure: &SparseMolecule) -> Result<Vec<f64>> {
+    torsions
+        .iter()
+        .map(|torsion| {
+            let [a, b, c, d] = torsion
+                .iter()
+                .map(|select| {
+                    select
+                        .get_atom(structure)
+                        .map(|atom| atom.position)
+                        .with_context(|| format!("Atom {:?} not found", select))
+                })
+                .collect::<Result<Vec<_>>>()?
+                .try_into()
+                .unwrap();
+            Ok(dihedral_angle(&a, &b, &c, &d))
+        })
+        .collect()
+}
+
+/// Root mean square of the periodic differences of torsions in degrees.
+fn torsion_distance(a: &[f64], b: &[f64]) -> f64 {
+    let sum = a
+        .iter()
+        .zip(b)
+        .map(|(a, b)| {
+            let diff = (a - b).rem_euclid(360.);
+            diff.min(360. - diff).powi(2)
+        })
+        .sum::<f64>();
+    (sum / a.len() as f64).sqrt()
+}
+
+/// The member with the least sum of distances to the other members, `None`
+/// for an empty cluster.
+fn medoid(points: &[Vec<f64>], members: &[usize]) -> Option<usize> {
+    members
+        .iter()
+        .min_by(|a, b| {
+            let cost = |center: usize| {
+                members
+                    .iter()
+                    .map(|member| torsion_distance(&points[center], &points[*member]))
+                    .sum::<f64>()
+            };
+            cost(**a).total_cmp(&cost(**b))
+        })
+        .copied()
+}
+
+fn nearest(points: &[Vec<f64>], point: usize, medoids: &[usize]) -> (usize, f64) {
+    medoids
+        .iter()
+        .enumerate()
+        .map(|(label, medoid)| (label, torsion_distance(&points[point], &points[*medoid])))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap()
+}
+
+/// Cluster labels of k-medoids by alternating assignment and medoid update.
+fn k_medoids(points: &[Vec<f64>], k: usize, seed: u64, max_iterations: usize) -> Vec<usize> {
+    let k = k.min(points.len());
+    if k == 0 {
+        return vec![0; points.len()];
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut medoids = vec![rng.gen_range(0..points.len())];
+    while medoids.len() < k {
+        let weights = (0..points.len())
+            .map(|point| nearest(points, point, &medoids).1.powi(2))
+            .collect::<Vec<_>>();
+        let total = weights.iter().sum::<f64>();
+        if total == 0. {
+            // all remaining points coincide with a medoid
+            let next = (0..points.len())
+                .find(|point| !medoids.contains(point))
+                .unwrap();
+            medoids.push(next);
+            continue;
+        }
+        let mut threshold = rng.gen_range(0. ..total);
+        let next = weights
+            .iter()
+            .position(|weight| {
+                threshold -= weight;
+                threshold < 0.
+            })
+            .unwrap_or(points.len() - 1);
+        medoids.push(next);
+    }
+    let mut labels = vec![];
+    for _ in 0..max_iterations {
+        labels = (0..points.len())
+            .map(|point| nearest(points, point, &medoids).0)
+            .collect::<Vec<_>>();
+        // Coinciding medoids leave a cluster empty, which keeps its medoid
+        let updated = (0..k)
+            .map(|label| {
+                let members = (0..points.len())
+                    .filter(|point| labels[*point] == label)
+                    .collect::<Vec<_>>();
+                medoid(points, &members).unwrap_or(medoids[label])
+            })
+            .collect::<Vec<_>>();
+        if updated == medoids {
+            break;
+        }
+        medoids = updated;
+    }
+    labels
+}
+
+/// Cluster labels of DBSCAN, `None` for noise.
+fn dbscan(points: &[Vec<f64>], eps: f64, min_points: usize) -> Vec<Option<usize>> {
+    let neighbors = (0..points.len())
+        .map(|a| {
+            (0..points.len())
+                .filter(|b| torsion_distance(&points[a], &points[*b]) <= eps)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let mut labels = vec![None; points.len()];
+    let mut cluster = 0;
+    for point in 0..points.len() {
+        if labels[point].is_some() || neighbors[point].len() < min_points {
+            continue;
+        }
+        labels[point] = Some(cluster);
+        let mut queue = neighbors[point].clone();
+        while let Some(next) = queue.pop() {
+            if labels[next].is_some() {
+                continue;
+            }
+            labels[next] = Some(cluster);
+            if neighbors[next].len() >= min_points {
+                queue.extend(&neighbors[next]);
+            }
+        }
+        cluster += 1;
+    }
+    labels
+}
+
+#[test]
+fn cluster_torsions() {
+    let points = [
+        [179.],
+        [-178.],
+        [175.],
+        [60.],
+        [65.],
+        [58.],
+        [-60.],
+        [-62.],
+        [0.],
+    ]
+    .map(|point| point.to_vec());
+    let labels = k_medoids(&points, 3, 0, 100);
+    assert_eq!(labels[0], labels[1]);
+    assert_eq!(labels[0], labels[2]);
+    assert_eq!(labels[3], labels[5]);
+    assert_ne!(labels[0], labels[3]);
+    assert_ne!(labels[3], labels[6]);
+    let labels = dbscan(&points, 10., 2);
+    assert_eq!(labels[0], labels[1]);
+    assert_ne!(labels[0], labels[3]);
+    assert_eq!(labels[6], labels[7]);
+    assert_eq!(labels[8], None);
+    assert_eq!(medoid(&points, &[3, 4, 5]), Some(3));
+    assert_eq!(medoid(&points, &[]), None);
+}
+
+#[test]
+fn cluster_identical_conformers() {
+    let points = vec![vec![60., -60.]; 4];
+    for k in 1..=4 {
+        assert_eq!(k_medoids(&points, k, 0, 100).len(), 4);
+    }
+    let mut points = points;
+    points.push(vec![180., 180.]);
+    let labels = k_medoids(&points, 3, 1, 100);
+    assert!(labels[..4].iter().all(|label| *label == labels[0]));
+    assert_ne!(labels[0], labels[4]);
+}
+
+#[test]
+fn deduplicate_conformers() {
+    use nalgebra::{Isometry3, Vector3};
+    let points = vec![
+        Point3::new(0., 0., 0.),
+        Point3::new(1.5, 0., 0.),
+        Point3::new(2., 1.2, 0.),
+        Point3::new(3.4, 1.3, 0.5),
+    ];
+    let moved = Isometry3::new(Vector3::new(3., 1., -2.), Vector3::new(0.3, -1., 0.2));
+    let mut rotamer = points.clone();
+    rotamer[3] = Point3::new(3.4, 1.3, -1.5);
+    let conformers = [
+        (vec![6, 6, 6, 8], points.clone()),
+        (
+            vec![6, 6, 6, 8],
+            points.iter().map(|point| moved * point).collect(),
+        ),
+        (vec![6, 6, 6, 8], rotamer),
+        (vec![6, 6, 6, 7], points),
+    ];
+    assert_eq!(deduplicate(&conformers, 0.1), [true, false, true, true]);
+    assert_eq!(deduplicate(&conformers[..3], 5.), [true, false, false]);
+}
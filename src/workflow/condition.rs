@@ -0,0 +1,354 @@
+use std::{iter::Peekable, str::Chars};
+
+use anyhow::{anyhow, Result};
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::Deserialize;
+
+/// An expression over workflow variables, e.g. `previous.best - best < 0.01 || iteration >= 5`.
+///
+/// Supports numbers, variable names (letters, digits, `_` and `.`), arithmetic
+/// operators `+ - * /`, comparisons `< <= > >= == !=`, logical operators
+/// `&& || !`, parentheses and functions `abs`, `min`, `max`. Comparisons and
+/// logical operators give 1 for true and 0 for false, non-zero is true.
+///
+/// Quoted texts (e.g. `'^Me_'`) can only be the argument of the functions
+/// given by the caller, see `Condition::evaluate_with`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Condition {
+    source: String,
+    expression: Expression,
+}
+
+impl JsonSchema for Condition {
+    fn schema_name() -> String {
+        "Condition".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        String::json_schema(gen)
+    }
+}
+
+impl TryFrom<String> for Condition {
+    type Error = anyhow::Error;
+
+    fn try_from(source: String) -> Result<Self> {
+        let mut parser = Parser {
+            chars: source.chars().peekable(),
+        };
+        let expression = parser.parse_or()?;
+        parser.skip_whitespaces();
+        if let Some(c) = parser.chars.peek() {
+            Err(anyhow!("Unexpected {:?} in condition {}", c, source))?
+        }
+        Ok(Self { source, expression })
+    }
+}
+
+impl Condition {
+    pub fn evaluate(&self, lookup: &dyn Fn(&str) -> Option<f64>) -> Result<bool> {
+        self.evaluate_with(lookup, &|name, _| {
+            Err(anyhow!("Unknown function {} of a text", name))
+        })
+    }
+
+    /// Evaluate with the functions of a text argument, `functions` is called
+    /// with the function name and the text, e.g. `matches` and `^Me_` for
+    /// `matches('^Me_')`.
+    pub fn evaluate_with(
+        &self,
+        lookup: &dyn Fn(&str) -> Option<f64>,
+        functions: &dyn Fn(&str, &str) -> Result<f64>,
+    ) -> Result<bool> {
+        Ok(self.expression.evaluate(lookup, functions)? != 0.)
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Expression {
+    Number(f64),
+    Text(String),
+    Variable(String),
+    Not(Box<Expression>),
+    Negative(Box<Expression>),
+    Binary(Box<Expression>, Operator, Box<Expression>),
+    Function(String, Vec<Expression>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operator {
+    Or,
+    And,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+impl Expression {
+    fn evaluate(
+        &self,
+        lookup: &dyn Fn(&str) -> Option<f64>,
+        functions: &dyn Fn(&str, &str) -> Result<f64>,
+    ) -> Result<f64> {
+        let boolean = |value: bool| if value { 1. } else { 0. };
+        Ok(match self {
+            Self::Number(value) => *value,
+            Self::Text(text) => Err(anyhow!(
+                "Text {:?} can only be the argument of a function",
+                text
+            ))?,
+            Self::Variable(name) => {
+                lookup(name).ok_or_else(|| anyhow!("Variable {} is not defined", name))?
+            }
+            Self::Not(inner) => boolean(inner.evaluate(lookup, functions)? == 0.),
+            Self::Negative(inner) => -inner.evaluate(lookup, functions)?,
+            Self::Binary(lhs, operator, rhs) => {
+                let lhs = lhs.evaluate(lookup, functions)?;
+                match operator {
+                    Operator::Or if lhs != 0. => 1.,
+                    Operator::And if lhs == 0. => 0.,
+                    Operator::Or | Operator::And => boolean(rhs.evaluate(lookup, functions)? != 0.),
+                    operator => {
+                        let rhs = rhs.evaluate(lookup, functions)?;
+                        match operator {
+                            Operator::Less => boolean(lhs < rhs),
+                            Operator::LessEqual => boolean(lhs <= rhs),
+                            Operator::Greater => boolean(lhs > rhs),
+                            Operator::GreaterEqual => boolean(lhs >= rhs),
+                            Operator::Equal => boolean(lhs == rhs),
+                            Operator::NotEqual => boolean(lhs != rhs),
+                            Operator::Add => lhs + rhs,
+                            Operator::Subtract => lhs - rhs,
+                            Operator::Multiply => lhs * rhs,
+                            Operator::Divide => lhs / rhs,
+                            Operator::Or | Operator::And => unreachable!(),
+                        }
+                    }
+                }
+            }
+            Self::Function(name, arguments) => {
+                if let [Self::Text(text)] = arguments.as_slice() {
+                    return functions(name, text);
+                }
+                let arguments = arguments
+                    .iter()
+                    .map(|argument| argument.evaluate(lookup, functions))
+                    .collect::<Result<Vec<_>>>()?;
+                match (name.as_str(), arguments.as_slice()) {
+                    ("abs", [value]) => value.abs(),
+                    ("min", [first, rest @ ..]) => rest.iter().copied().fold(*first, f64::min),
+                    ("max", [first, rest @ ..]) => rest.iter().copied().fold(*first, f64::max),
+                    (name, arguments) => Err(anyhow!(
+                        "Unknown function {} with {} arguments",
+                        name,
+                        arguments.len()
+                    ))?,
+                }
+            }
+        })
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespaces(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    /// Consume the token if the following characters match it.
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespaces();
+        let mut lookahead = self.chars.clone();
+        for expected in token.chars() {
+            if lookahead.next() != Some(expected) {
+                return false;
+            }
+        }
+        // `<` must not consume the `<` of `<=`, the same for the others
+        if token.len() == 1 && "<>=!".contains(token) && lookahead.peek() == Some(&'=') {
+            return false;
+        }
+        self.chars = lookahead;
+        true
+    }
+
+    fn parse_binary(
+        &mut self,
+        operators: &[(&str, Operator)],
+        next: fn(&mut Self) -> Result<Expression>,
+    ) -> Result<Expression> {
+        let mut lhs = next(self)?;
+        'outer: loop {
+            for (token, operator) in operators {
+                if self.eat(token) {
+                    lhs = Expression::Binary(Box::new(lhs), *operator, Box::new(next(self)?));
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expression> {
+        self.parse_binary(&[("||", Operator::Or)], Self::parse_and)
+    }
+
+    fn parse_and(&mut self) -> Result<Expression> {
+        self.parse_binary(&[("&&", Operator::And)], Self::parse_comparison)
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expression> {
+        self.parse_binary(
+            &[
+                ("<=", Operator::LessEqual),
+                (">=", Operator::GreaterEqual),
+                ("==", Operator::Equal),
+                ("!=", Operator::NotEqual),
+                ("<", Operator::Less),
+                (">", Operator::Greater),
+            ],
+            Self::parse_sum,
+        )
+    }
+
+    fn parse_sum(&mut self) -> Result<Expression> {
+        self.parse_binary(
+            &[("+", Operator::Add), ("-", Operator::Subtract)],
+            Self::parse_product,
+        )
+    }
+
+    fn parse_product(&mut self) -> Result<Expression> {
+        self.parse_binary(
+            &[("*", Operator::Multiply), ("/", Operator::Divide)],
+            Self::parse_unary,
+        )
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression> {
+        if self.eat("!") {
+            Ok(Expression::Not(Box::new(self.parse_unary()?)))
+        } else if self.eat("-") {
+            Ok(Expression::Negative(Box::new(self.parse_unary()?)))
+        } else {
+            self.parse_atom()
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<Expression> {
+        self.skip_whitespaces();
+        if self.eat("(") {
+            let inner = self.parse_or()?;
+            if !self.eat(")") {
+                Err(anyhow!("Missing `)` in condition"))?
+            }
+            return Ok(inner);
+        }
+        if let Some(quote) = self.chars.next_if(|c| *c == '\'' || *c == '"') {
+            let mut text = String::new();
+            loop {
+                match self.chars.next() {
+                    Some(c) if c == quote => return Ok(Expression::Text(text)),
+                    Some(c) => text.push(c),
+                    None => Err(anyhow!("Missing closing {} of text in condition", quote))?,
+                }
+            }
+        }
+        let mut token = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '.')
+        {
+            token.push(c);
+        }
+        match token.chars().next() {
+            None => Err(anyhow!(
+                "Expect a number, variable or `(` in condition, found {:?}",
+                self.chars.peek()
+            )),
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                // exponent of scientific notation, e.g. 1e-3
+                if token.ends_with(['e', 'E']) {
+                    if let Some(sign) = self.chars.next_if(|c| *c == '-' || *c == '+') {
+                        token.push(sign);
+                        while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit()) {
+                            token.push(c);
+                        }
+                    }
+                }
+                Ok(Expression::Number(token.parse().map_err(|_| {
+                    anyhow!("Invalid number {} in condition", token)
+                })?))
+            }
+            Some(_) => {
+                if self.eat("(") {
+                    let mut arguments = vec![];
+                    if !self.eat(")") {
+                        loop {
+                            arguments.push(self.parse_or()?);
+                            if self.eat(")") {
+                                break;
+                            }
+                            if !self.eat(",") {
+                                Err(anyhow!("Expect `,` or `)` after argument of {}", token))?
+                            }
+                        }
+                    }
+                    Ok(Expression::Function(token, arguments))
+                } else {
+                    Ok(Expression::Variable(token))
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn evaluate_condition() {
+    let lookup = |name: &str| match name {
+        "best" => Some(-10.5),
+        "previous.best" => Some(-10.495),
+        "iteration" => Some(3.),
+        _ => None,
+    };
+    let condition = |source: &str| Condition::try_from(source.to_string()).unwrap();
+    assert!(condition("abs(previous.best - best) < 1e-2")
+        .evaluate(&lookup)
+        .unwrap());
+    assert!(!condition("iteration >= 5 || !(best < -10)")
+        .evaluate(&lookup)
+        .unwrap());
+    assert!(condition("1 + 2 * 3 == 7 && min(iteration, 2) <= 2")
+        .evaluate(&lookup)
+        .unwrap());
+    assert!(condition("missing > 0").evaluate(&lookup).is_err());
+    assert!(Condition::try_from("best <".to_string()).is_err());
+    assert!(Condition::try_from("best ) 1".to_string()).is_err());
+    let functions = |name: &str, text: &str| match name {
+        "length" => Ok(text.len() as f64),
+        _ => Err(anyhow!("Unknown function {}", name)),
+    };
+    assert!(condition("length('Me_(1)') == 6 && length(\"\") == 0")
+        .evaluate_with(&lookup, &functions)
+        .unwrap());
+    assert!(condition("length('Me') > 0").evaluate(&lookup).is_err());
+    assert!(condition("'Me' > 0")
+        .evaluate_with(&lookup, &functions)
+        .is_err());
+    assert!(Condition::try_from("length('Me) > 0".to_string()).is_err());
+}
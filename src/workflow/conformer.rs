@@ -0,0 +1,277 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{anyhow, Context, Result};
+use lmers::{
+    chemistry::covalent_radius,
+    layer::{Layer, SelectMany, SelectOne},
+    sparse_molecule::SparseMolecule,
+    utils::{geometric::dihedral_angle, neighbors::KdTree},
+};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{
+    runner::{cached_read_stack, RunnerOutput},
+    workflow_data::{LayerStorage, Window},
+};
+
+/// Generate conformers by systematic enumeration of the torsions on a grid of
+/// `step` degrees from the current angles, over the rotatable bonds (see
+/// `SparseMolecule::rotatable_bonds`) or the given `bonds`.
+///
+/// Conformers with two atoms closer than `clash_scale` times the sum of their
+/// covalent radii are dropped, atoms bonded to each other or to a common atom
+/// are not checked. The combinations are enumerated in order from the input
+/// geometry, and at most `max_conformers` of each structure are kept if set.
+/// A structure with more than `max_combinations` (100000 by default)
+/// combinations of torsions fails, select fewer `bonds` or a larger `step`.
+/// The conformers are titled `<title>_<index>`, and those of each structure
+/// are a window named by its title.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ConformerOptions {
+    #[serde(default)]
+    bonds: Vec<(SelectOne, SelectOne)>,
+    #[serde(default = "ConformerOptions::default_step")]
+    step: f64,
+    #[serde(default = "ConformerOptions::default_clash_scale")]
+    clash_scale: f64,
+    #[serde(default)]
+    max_conformers: Option<usize>,
+    #[serde(default = "ConformerOptions::default_max_combinations")]
+    max_combinations: usize,
+}
+
+impl ConformerOptions {
+    fn default_step() -> f64 {
+        120.
+    }
+
+    fn default_clash_scale() -> f64 {
+        0.7
+    }
+
+    fn default_max_combinations() -> usize {
+        100000
+    }
+
+    /// Torsion angles of each bond on the grid.
+    fn grid(&self) -> Result<usize> {
+        if !(self.step > 0. && self.step <= 360.) {
+            Err(anyhow!("Invalid torsion step {} degrees", self.step))?
+        }
+        Ok(((360. / self.step).round() as usize).max(1))
+    }
+
+    /// Most conformers of each structure, unknown if the bonds are perceived.
+    pub fn max_per_structure(&self) -> Option<f64> {
+        let grid = self.grid().ok()? as f64;
+        let combinations = (!self.bonds.is_empty()).then(|| {
+            grid.powi(self.bonds.len() as i32)
+                .min(self.max_combinations as f64)
+        });
+        match (self.max_conformers, combinations) {
+            (Some(max), Some(combinations)) => Some(combinations.min(max as f64)),
+            (Some(max), None) => Some(max as f64),
+            (None, combinations) => combinations,
+        }
+    }
+
+    pub fn execute(
+        &self,
+        base: &SparseMolecule,
+        current_window: &Window,
+        layer_storage: &LayerStorage,
+    ) -> Result<RunnerOutput> {
+        let grid = self.grid()?;
+        let mut windows = BTreeMap::new();
+        for (title, stack_path) in current_window {
+            let structure = cached_read_stack(base, layer_storage, stack_path)?;
+            let conformers = self
+                .conformers(&structure, grid)
+                .with_context(|| format!("Unable to generate conformers of {}", title))?;
+            let mut window = Window::new();
+            for (index, layers) in conformers.into_iter().enumerate() {
+                let mut stack_path = stack_path.clone();
+                stack_path.extend(layer_storage.create_layers(&layers));
+                window.insert(format!("{}_{}", title, index), stack_path);
+            }
+            println!("{} conformers of {} generated", window.len(), title);
+            windows.insert(title.to_string(), window);
+        }
+        Ok(RunnerOutput::MultiWindow(windows))
+    }
+
+    /// Layers of each conformer without clashes.
+    fn conformers(&self, structure: &SparseMolecule, grid: usize) -> Result<Vec<Vec<Layer>>> {
+        let bonds = if self.bonds.is_empty() {
+            structure.rotatable_bonds()
+        } else {
+            self.bonds
+                .iter()
+                .map(|(b, c)| {
+                    b.to_index(structure)
+                        .zip(c.to_index(structure))
+                        .with_context(|| format!("Bond {:?}-{:?} not found", b, c))
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+        let torsions = bonds
+            .into_iter()
+            .map(|(b, c)| {
+                let atoms = structure
+                    .torsion_atoms(b, c)
+                    .with_context(|| format!("No torsion defined around bond {}-{}", b, c))?;
+                let side = structure.bond_side(b, c);
+                if side.contains(&b) {
+                    Err(anyhow!("Bond {}-{} is in a ring", b, c))?
+                }
+                let [a, b, c, d] = atoms.map(|index| structure.atoms.read_atom(index).unwrap());
+                let angle = dihedral_angle(&a.position, &b.position, &c.position, &d.position);
+                Ok((atoms, side, angle))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let combinations = u32::try_from(torsions.len())
+            .ok()
+            .and_then(|count| grid.checked_pow(count))
+            .filter(|combinations| *combinations <= self.max_combinations)
+            .with_context(|| {
+                format!(
+                    "Too many torsion combinations of {} bonds, more than max_combinations {}",
+                    torsions.len(),
+                    self.max_combinations
+                )
+            })?;
+        let clash = ClashCheck::new(structure, self.clash_scale);
+        let mut conformers = vec![];
+        for combination in 0..combinations {
+            if Some(conformers.len()) == self.max_conformers {
+                break;
+            }
+            let mut layers = vec![];
+            let mut digits = combination;
+            for ([a, b, c, d], side, angle) in &torsions {
+                let offset = digits % grid;
+                digits /= grid;
+                if offset != 0 {
+                    layers.push(Layer::SetDihedral {
+                        a: SelectOne::Index(*a),
+                        b: SelectOne::Index(*b),
+                        c: SelectOne::Index(*c),
+                        d: SelectOne::Index(*d),
+                        select: SelectMany::Indexes(
+                            side.iter().copied().map(SelectOne::Index).collect(),
+                        ),
+                        angle: angle + offset as f64 * self.step,
+                        degree: true,
+                    });
+                }
+            }
+            let mut conformer = structure.clone();
+            for layer in &layers {
+                conformer = layer.filter(conformer)?;
+            }
+            if !clash.has_clash(&conformer) {
+                conformers.push(layers);
+            }
+        }
+        Ok(conformers)
+    }
+}
+
+/// Clash check of the conformers of a structure, by the covalent radii of the
+/// atoms not bonded to each other or to a common atom.
+struct ClashCheck {
+    radii: BTreeMap<usize, f64>,
+    excluded: BTreeSet<(usize, usize)>,
+    clash_scale: f64,
+}
+
+impl ClashCheck {
+    fn new(structure: &SparseMolecule, clash_scale: f64) -> Self {
+        let radii = (0..structure.len())
+            .filter_map(|index| {
+                let radius = covalent_radius(structure.atoms.read_atom(index)?.element)?;
+                Some((index, radius))
+            })
+            .collect::<BTreeMap<_, _>>();
+        let mut excluded = BTreeSet::new();
+        for a in radii.keys().copied() {
+            for neighbor in structure.neighbors(a) {
+                excluded.insert((a.min(neighbor), a.max(neighbor)));
+                for b in structure.neighbors(neighbor) {
+                    excluded.insert((a.min(b), a.max(b)));
+                }
+            }
+        }
+        Self {
+            radii,
+            excluded,
+            clash_scale,
+        }
+    }
+
+    fn has_clash(&self, structure: &SparseMolecule) -> bool {
+        let (indexes, positions): (Vec<_>, Vec<_>) = self
+            .radii
+            .keys()
+            .filter_map(|index| Some((*index, structure.atoms.read_atom(*index)?.position)))
+            .unzip();
+        let max_radius = self.radii.values().copied().fold(0., f64::max);
+        let tree = KdTree::new(&positions);
+        tree.pairs_within(self.clash_scale * 2. * max_radius)
+            .into_iter()
+            .map(|(a, b)| (indexes[a], indexes[b]))
+            .filter(|pair| !self.excluded.contains(pair))
+            .any(|(a, b)| {
+                let distance = (structure.atoms.read_atom(a).unwrap().position
+                    - structure.atoms.read_atom(b).unwrap().position)
+                    .norm();
+                distance < self.clash_scale * (self.radii[&a] + self.radii[&b])
+            })
+    }
+}
+
+#[test]
+fn systematic_conformers() {
+    use lmers::smiles::parse_smiles;
+    // Butane, a single rotatable bond C1-C2
+    let butane = parse_smiles("CCCC").unwrap();
+    let options = serde_yaml::from_str::<ConformerOptions>("step: 120").unwrap();
+    let conformers = options
+        .conformers(&butane, options.grid().unwrap())
+        .unwrap();
+    assert_eq!(conformers.len(), 3);
+    let torsion = |layers: &[Layer]| {
+        let mut conformer = butane.clone();
+        for layer in layers {
+            conformer = layer.filter(conformer).unwrap();
+        }
+        let [a, b, c, d] = [0, 1, 2, 3].map(|index| conformer.atoms.read_atom(index).unwrap());
+        dihedral_angle(&a.position, &b.position, &c.position, &d.position)
+    };
+    let initial = torsion(&conformers[0]);
+    let second = torsion(&conformers[1]);
+    assert!(((second - initial).rem_euclid(360.) - 120.).abs() < 1e-6);
+    // Eclipsed end atoms clash with a strict threshold
+    let options = serde_yaml::from_str::<ConformerOptions>(
+        "{step: 60, clash_scale: 1.7, max_conformers: 10}",
+    )
+    .unwrap();
+    let conformers = options
+        .conformers(&butane, options.grid().unwrap())
+        .unwrap();
+    assert!(conformers.len() < 6);
+    assert_eq!(options.max_per_structure(), Some(10.));
+    let options = serde_yaml::from_str::<ConformerOptions>("bonds: [[1, 2]]\nstep: 90").unwrap();
+    assert_eq!(options.max_per_structure(), Some(4.));
+    let options = serde_yaml::from_str::<ConformerOptions>("bonds: [[0, 1]]").unwrap();
+    assert!(options.conformers(&butane, 3).is_err());
+    // Hexane has 3 rotatable bonds, 27 combinations on the 120 degree grid
+    let hexane = parse_smiles("CCCCCC").unwrap();
+    let options = serde_yaml::from_str::<ConformerOptions>("max_combinations: 26").unwrap();
+    let err = options.conformers(&hexane, 3).unwrap_err().to_string();
+    assert!(err.contains("max_combinations"));
+    let options = serde_yaml::from_str::<ConformerOptions>("max_combinations: 27").unwrap();
+    assert!(options.conformers(&hexane, 3).is_ok());
+}
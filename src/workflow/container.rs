@@ -0,0 +1,291 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    process::Command,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{anyhow, Context, Result};
+use lmers::utils::process::new_command;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+/// Container engine running the programs of Calculation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema)]
+pub enum ContainerEngine {
+    #[default]
+    Docker,
+    Podman,
+    Apptainer,
+    Singularity,
+}
+
+impl ContainerEngine {
+    fn executable(&self) -> &'static str {
+        match self {
+            Self::Docker => "docker",
+            Self::Podman => "podman",
+            Self::Apptainer => "apptainer",
+            Self::Singularity => "singularity",
+        }
+    }
+}
+
+/// A host directory mounted in the container, in the form of
+/// `host_path[:container_path[:options]]`, or `{host, container, options}` for
+/// host paths containing `:`. A drive letter (e.g. `C:\data:/data`) is a part
+/// of the host path on Windows.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum Bind {
+    Text(String),
+    Mount {
+        host: String,
+        #[serde(default)]
+        container: Option<String>,
+        #[serde(default)]
+        options: Option<String>,
+    },
+}
+
+impl Bind {
+    /// Host path, container path and options of the bind.
+    fn parts(&self) -> (&str, Option<&str>, Option<&str>) {
+        match self {
+            Self::Text(bind) => {
+                let drive = matches!(
+                    bind.as_bytes(),
+                    [letter, b':', b'\\' | b'/', ..] if letter.is_ascii_alphabetic()
+                );
+                let drive = if cfg!(windows) && drive { 2 } else { 0 };
+                let Some(index) = bind[drive..].find(':').map(|index| index + drive) else {
+                    return (bind, None, None);
+                };
+                let (host, rest) = (&bind[..index], &bind[index + 1..]);
+                match rest.split_once(':') {
+                    Some((container, options)) => (host, Some(container), Some(options)),
+                    None => (host, Some(rest), None),
+                }
+            }
+            Self::Mount {
+                host,
+                container,
+                options,
+            } => (host, container.as_deref(), options.as_deref()),
+        }
+    }
+}
+
+/// Run the program of each structure inside a container image, e.g. a Docker
+/// image `orca:6.0` or an Apptainer image file `images/xtb.sif`.
+///
+/// The working directory of the structure is bind-mounted at the same absolute
+/// path inside the container and used as the current directory, so the
+/// arguments and the input/output files are the same as running on the host.
+/// The `envs` of the Calculation are passed into the container. Other host
+/// directories (e.g. basis sets or scratch) can be mounted by `binds` in the
+/// form of `host_path[:container_path[:options]]` (see `Bind`), the host path
+/// is relative to the workflow directory and mounted at the same absolute path
+/// if no container path given. `options` are added to the engine command line before the
+/// image, e.g. `--gpus=all`. Docker runs the program as the current user, so
+/// the output files are not owned by root.
+///
+/// Docker and Podman containers are named by the run, and killed by the
+/// engine when the program is killed after a timeout, since killing the engine
+/// client leaves the container running.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ContainerOptions {
+    #[serde(default)]
+    engine: ContainerEngine,
+    image: String,
+    #[serde(default)]
+    binds: Vec<Bind>,
+    #[serde(default)]
+    options: Vec<String>,
+}
+
+impl ContainerOptions {
+    /// Program of the container engine, e.g. `docker`.
+    pub fn engine_executable(&self) -> &'static str {
+        self.engine.executable()
+    }
+
+    /// Unique name of a container run by this process.
+    pub fn container_name() -> String {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        format!(
+            "lmers-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+
+    /// Command running the program with arguments in the container named by
+    /// `name` (see `container_name`).
+    pub fn command(
+        &self,
+        name: &str,
+        working_directory: &Path,
+        program: &str,
+        args: &[String],
+        envs: &BTreeMap<String, String>,
+    ) -> Result<Command> {
+        let directory = std::fs::canonicalize(working_directory)
+            .with_context(|| format!("Unable to get absolute path of {:?}", working_directory))?;
+        let directory = directory.to_string_lossy();
+        let mut command = new_command(self.engine.executable());
+        match self.engine {
+            ContainerEngine::Docker | ContainerEngine::Podman => {
+                command.args(["run", "--rm", "-i", "--name", name]);
+                command.args(["-v", &format!("{}:{}", directory, directory)]);
+                command.args(["-w", &directory]);
+                #[cfg(unix)]
+                if self.engine == ContainerEngine::Docker {
+                    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+                    command.args(["--user", &format!("{}:{}", uid, gid)]);
+                }
+                for bind in self.binds()? {
+                    command.args(["-v", &bind]);
+                }
+                for (key, value) in envs {
+                    command.args(["-e", &format!("{}={}", key, value)]);
+                }
+            }
+            ContainerEngine::Apptainer | ContainerEngine::Singularity => {
+                command.args(["exec", "--bind", &directory, "--pwd", &directory]);
+                for bind in self.binds()? {
+                    command.args(["--bind", &bind]);
+                }
+                for (key, value) in envs {
+                    command.args(["--env", &format!("{}={}", key, value)]);
+                }
+            }
+        }
+        command
+            .args(&self.options)
+            .arg(&self.image)
+            .arg(program)
+            .args(args);
+        Ok(command)
+    }
+
+    /// Binds with the absolute host paths, mounted at the same paths if no
+    /// container path given.
+    fn binds(&self) -> Result<Vec<String>> {
+        self.binds
+            .iter()
+            .map(|bind| {
+                let (host, container, options) = bind.parts();
+                let host = std::fs::canonicalize(host)
+                    .with_context(|| format!("Unable to get absolute path of bind {}", host))?;
+                let host = host.to_string_lossy();
+                let container = container.filter(|container| !container.is_empty());
+                let bind = format!("{}:{}", host, container.unwrap_or(&host));
+                Ok(match options {
+                    Some(options) => format!("{}:{}", bind, options),
+                    None => bind,
+                })
+            })
+            .collect()
+    }
+
+    /// Kill the container named by `name` if it's still running, the programs
+    /// in Apptainer and Singularity containers are children of the engine
+    /// process and killed with it.
+    pub fn kill(&self, name: &str) -> Result<()> {
+        if let ContainerEngine::Docker | ContainerEngine::Podman = self.engine {
+            let output = new_command(self.engine.executable())
+                .args(["kill", name])
+                .output()
+                .with_context(|| format!("Unable to kill container {}", name))?;
+            // The container may have stopped after the engine client is killed
+            if !output.status.success() && self.running(name) {
+                Err(anyhow!(
+                    "Unable to kill container {}: {}",
+                    name,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ))?
+            }
+        }
+        Ok(())
+    }
+
+    fn running(&self, name: &str) -> bool {
+        new_command(self.engine.executable())
+            .args(["container", "inspect", name])
+            .output()
+            .is_ok_and(|output| output.status.success())
+    }
+}
+
+#[test]
+fn container_command_line() {
+    let directory = tempfile::tempdir().unwrap();
+    let absolute = std::fs::canonicalize(directory.path()).unwrap();
+    let absolute = absolute.to_string_lossy();
+    let options: ContainerOptions = serde_yaml::from_str(
+        "engine: Apptainer\nimage: xtb.sif\nbinds: [src, 'src:/data:ro', {host: src, options: ro}]\noptions: [--nv]",
+    )
+    .unwrap();
+    let envs = BTreeMap::from([("OMP_NUM_THREADS".to_string(), "4".to_string())]);
+    let command = options
+        .command(
+            "job",
+            directory.path(),
+            "xtb",
+            &["input.xyz".to_string()],
+            &envs,
+        )
+        .unwrap();
+    assert_eq!(command.get_program(), "apptainer");
+    let args = command
+        .get_args()
+        .map(|arg| arg.to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    let source = std::fs::canonicalize("src").unwrap();
+    let source = source.to_string_lossy();
+    let expected = [
+        "exec",
+        "--bind",
+        &absolute,
+        "--pwd",
+        &absolute,
+        "--bind",
+        &format!("{}:{}", source, source),
+        "--bind",
+        &format!("{}:/data:ro", source),
+        "--bind",
+        &format!("{}:{}:ro", source, source),
+        "--env",
+        "OMP_NUM_THREADS=4",
+        "--nv",
+        "xtb.sif",
+        "xtb",
+        "input.xyz",
+    ];
+    assert_eq!(args, expected);
+    let windows = Bind::Text(r"C:\data:/data:ro".to_string());
+    if cfg!(windows) {
+        assert_eq!(windows.parts(), (r"C:\data", Some("/data"), Some("ro")));
+    } else {
+        assert_eq!(windows.parts(), ("C", Some(r"\data"), Some("/data:ro")));
+    }
+    let options: ContainerOptions = serde_yaml::from_str("image: orca:6.0").unwrap();
+    let command = options
+        .command("job", directory.path(), "orca", &[], &BTreeMap::new())
+        .unwrap();
+    let args = command
+        .get_args()
+        .map(|arg| arg.to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(command.get_program(), "docker");
+    assert_eq!(args[..5], ["run", "--rm", "-i", "--name", "job"]);
+    #[cfg(unix)]
+    assert!(args.contains(&"--user".to_string()));
+    assert_ne!(
+        ContainerOptions::container_name(),
+        ContainerOptions::container_name()
+    );
+    assert_eq!(args[args.len() - 2..], ["orca:6.0", "orca"]);
+}
@@ -0,0 +1,376 @@
+use std::{
+    ffi::OsStr,
+    fmt::Display,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use lmers::utils::process::resolve_program;
+use redb::{Database, ReadableTable};
+
+use super::{
+    mock::MOCK_PROGRAM,
+    runner::Runner,
+    step::{Step, StepRunner},
+    workflow_data::LAYER_TABLE,
+};
+
+/// Free space of the working directory below which a warning is reported.
+const MIN_FREE_BYTES: u64 = 1 << 30;
+/// Leading bytes of the redb database files.
+const REDB_MAGIC: [u8; 9] = [b'r', b'e', b'd', b'b', 0x1a, 0x0a, 0xa9, 0x0d, 0x0a];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+/// Result of a check of the environment, with the action to fix it.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub subject: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn new(severity: Severity, subject: impl Display, message: impl Display) -> Self {
+        Self {
+            severity,
+            subject: subject.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = match self.severity {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "[{}] {}: {}", label, self.subject, self.message)
+    }
+}
+
+/// Check the environment of the workflow in the working directory: openbabel,
+/// the programs of the steps (Calculation and Plugin runners, container
+/// engines and batch schedulers), write permissions and free space of the
+/// working directory, and the layer database of the checkpoints.
+///
+/// Programs are searched in PATH after the `binaries` of the workflow are
+/// prepended. Runners referring to variables are checked when executed.
+pub fn diagnose(steps: &[Step], checkpoint_directory: &Path) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![match find_program("obabel") {
+        Some(path) => Diagnostic::new(Severity::Ok, "obabel", format!("found at {:?}", path)),
+        None => Diagnostic::new(
+            Severity::Warning,
+            "obabel",
+            "not found in PATH, required by obabelme and the formats converted by openbabel",
+        ),
+    }];
+    let mut programs = vec![];
+    collect_programs(steps, &mut programs, &mut diagnostics);
+    programs.sort();
+    programs.dedup();
+    for (program, severity, usage) in programs {
+        diagnostics.push(match find_program(&program) {
+            Some(path) => Diagnostic::new(Severity::Ok, &program, format!("found at {:?}", path)),
+            None => Diagnostic::new(
+                severity,
+                &program,
+                format!(
+                    "not found in PATH but {}, install it or add its directory to `binaries` of the workflow",
+                    usage
+                ),
+            ),
+        });
+    }
+    diagnostics.push(check_writable(Path::new(".")));
+    if let Some(diagnostic) = check_free_space(Path::new(".")) {
+        diagnostics.push(diagnostic);
+    }
+    diagnostics.push(check_layer_database(
+        &checkpoint_directory.join(".layers.db"),
+    ));
+    diagnostics
+}
+
+fn find_program(program: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let extensions = if cfg!(windows) {
+        std::env::var("PATHEXT").unwrap_or_else(|_| lmers::utils::process::DEFAULT_PATHEXT.into())
+    } else {
+        String::new()
+    };
+    resolve_program(OsStr::new(program), &path, &extensions)
+}
+
+/// Programs used by the steps with the severity if missing and the usage.
+fn collect_programs(
+    steps: &[Step],
+    programs: &mut Vec<(String, Severity, String)>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    for (index, step) in steps.iter().enumerate() {
+        let label = step
+            .name
+            .clone()
+            .or(step.bookmark.clone())
+            .unwrap_or_else(|| format!("step {}", index + 1));
+        match &step.run {
+            StepRunner::Loop { steps, .. } => collect_programs(steps, programs, diagnostics),
+            StepRunner::Deferred(_) => diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                &label,
+                "the runner refers to variables, its programs are not checked",
+            )),
+            StepRunner::Ready(runner) => {
+                collect_runner_programs(runner, &label, programs, diagnostics)
+            }
+        }
+    }
+}
+
+/// Programs used by the runner and the runners nested in it.
+fn collect_runner_programs(
+    runner: &Runner,
+    label: &str,
+    programs: &mut Vec<(String, Severity, String)>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    match runner {
+        Runner::Plugin { command, .. } => programs.push((
+            command.clone(),
+            Severity::Error,
+            format!("used by {}", label),
+        )),
+        Runner::Calculation {
+            program,
+            container,
+            scheduler,
+            ..
+        } => {
+            if let Some(container) = container {
+                let engine = container.engine_executable().to_string();
+                programs.push((engine, Severity::Error, format!("used by {}", label)));
+            }
+            if let Some(submit) = scheduler
+                .as_ref()
+                .and_then(|scheduler| scheduler.submit_program())
+            {
+                programs.push((
+                    submit.to_string(),
+                    Severity::Error,
+                    format!("used by {}", label),
+                ));
+            }
+            match program {
+                Some(program) if program == MOCK_PROGRAM => {}
+                // The program is inside the image
+                Some(_) if container.is_some() => {}
+                // The program may be only available on the compute nodes
+                Some(program) if scheduler.is_some() => programs.push((
+                    program.clone(),
+                    Severity::Warning,
+                    format!("submitted by {} to the scheduler", label),
+                )),
+                Some(program) => programs.push((
+                    program.clone(),
+                    Severity::Error,
+                    format!("used by {}", label),
+                )),
+                None => {}
+            }
+        }
+        Runner::Output {
+            render: Some(render),
+            ..
+        } => programs.push((
+            render.program().to_string(),
+            Severity::Error,
+            format!("rendering images of {}", label),
+        )),
+        Runner::GeneticOptimize(options) => {
+            let (_, _, evaluate) = options.estimate();
+            collect_runner_programs(evaluate, label, programs, diagnostics)
+        }
+        Runner::ForEach(options) => match options.runners() {
+            Ok(runners) => {
+                for (name, runner) in runners {
+                    let label = format!("{} ({})", label, name);
+                    collect_runner_programs(&runner, &label, programs, diagnostics)
+                }
+            }
+            Err(err) => diagnostics.push(Diagnostic::new(
+                Severity::Error,
+                label,
+                format!("invalid runners of the matrix: {:#}", err),
+            )),
+        },
+        _ => {}
+    }
+}
+
+fn check_writable(directory: &Path) -> Diagnostic {
+    match tempfile::tempfile_in(directory) {
+        Ok(_) => Diagnostic::new(Severity::Ok, "working directory", "writable"),
+        Err(err) => Diagnostic::new(
+            Severity::Error,
+            "working directory",
+            format!(
+                "unable to create files ({}), check the permissions of {:?}",
+                err,
+                std::fs::canonicalize(directory).unwrap_or(directory.to_path_buf())
+            ),
+        ),
+    }
+}
+
+#[cfg(unix)]
+fn check_free_space(directory: &Path) -> Option<Diagnostic> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(directory.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let free = stat.f_bavail as u64 * stat.f_frsize as u64;
+    let gigabytes = free as f64 / (1u64 << 30) as f64;
+    Some(if free < MIN_FREE_BYTES {
+        Diagnostic::new(
+            Severity::Warning,
+            "free space",
+            format!(
+                "only {:.2} GiB left in the working directory, free some space or use another scratch directory",
+                gigabytes
+            ),
+        )
+    } else {
+        Diagnostic::new(Severity::Ok, "free space", format!("{:.1} GiB", gigabytes))
+    })
+}
+
+#[cfg(not(unix))]
+fn check_free_space(_: &Path) -> Option<Diagnostic> {
+    None
+}
+
+/// Open the layer database and read all the layers in it.
+fn check_layer_database(path: &Path) -> Diagnostic {
+    if !path.exists() {
+        return Diagnostic::new(Severity::Ok, "layer database", "not created yet");
+    }
+    // redb initializes files without its header on open, which must not
+    // happen to a damaged database here
+    let mut magic = [0; REDB_MAGIC.len()];
+    let result = File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .map_err(|err| err.to_string())
+        .and_then(|_| match magic == REDB_MAGIC {
+            true => Ok(()),
+            false => Err("not a redb database".to_string()),
+        })
+        .and_then(|_| Database::open(path).map_err(|err| err.to_string()))
+        .and_then(|database| {
+            let read_txn = database.begin_read().map_err(|err| err.to_string())?;
+            let table = match read_txn.open_table(LAYER_TABLE) {
+                Ok(table) => table,
+                Err(redb::TableError::TableDoesNotExist(_)) => return Ok(0),
+                Err(err) => Err(err.to_string())?,
+            };
+            // The layers are not decoded, which panics on damaged values
+            let mut count = 0;
+            for entry in table.iter().map_err(|err| err.to_string())? {
+                entry.map_err(|err| err.to_string())?;
+                count += 1;
+            }
+            Ok(count)
+        });
+    match result {
+        Ok(count) => Diagnostic::new(
+            Severity::Ok,
+            "layer database",
+            format!("{} layers in {:?}", count, path),
+        ),
+        Err(err) => Diagnostic::new(
+            Severity::Error,
+            "layer database",
+            format!(
+                "unable to read {:?} ({}), it may be opened by another run or damaged, remove the checkpoint directory to start over",
+                path, err
+            ),
+        ),
+    }
+}
+
+/// Print the diagnostics, returns whether no error is found.
+pub fn report(diagnostics: &[Diagnostic]) -> bool {
+    for diagnostic in diagnostics {
+        println!("{}", diagnostic);
+    }
+    let count = |severity| {
+        diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == severity)
+            .count()
+    };
+    let (errors, warnings) = (count(Severity::Error), count(Severity::Warning));
+    println!("{} errors, {} warnings", errors, warnings);
+    errors == 0
+}
+
+#[test]
+fn diagnose_programs() {
+    let steps: super::step::Steps = serde_yaml::from_str(
+        "
+- run: {with: Plugin, command: lme-missing-plugin, arguments: []}
+- loop:
+    max_iterations: 1
+    steps:
+      - run: {with: Calculation, working_directory: calc, pre_format: {format: xyz}, pre_filename: a.xyz, program: lme-missing-program, scheduler: {type: Slurm}}
+- run: {with: Calculation, working_directory: calc, pre_format: {format: xyz}, pre_filename: a.xyz, program: lme-mock}
+- run:
+    with: ForEach
+    matrix: {method: [a, b]}
+    run: {with: Calculation, working_directory: 'calc_${method}', pre_format: {format: xyz}, pre_filename: a.xyz, program: 'lme-${method}'}
+- run: {with: Output, path: 'out/{title}.xyz', format: {format: xyz}, render: {program: lme-render, directory: images}}
+",
+    )
+    .unwrap();
+    let mut programs = vec![];
+    let mut diagnostics = vec![];
+    collect_programs(&steps.0, &mut programs, &mut diagnostics);
+    assert!(diagnostics.is_empty());
+    assert_eq!(
+        programs
+            .iter()
+            .map(|(program, severity, _)| (program.as_str(), *severity))
+            .collect::<Vec<_>>(),
+        [
+            ("lme-missing-plugin", Severity::Error),
+            ("sbatch", Severity::Error),
+            ("lme-missing-program", Severity::Warning),
+            ("lme-a", Severity::Error),
+            ("lme-b", Severity::Error),
+            ("lme-render", Severity::Error),
+        ]
+    );
+    let directory = tempfile::tempdir().unwrap();
+    assert_eq!(check_writable(directory.path()).severity, Severity::Ok);
+    let database = directory.path().join(".layers.db");
+    assert_eq!(check_layer_database(&database).severity, Severity::Ok);
+    let storage = super::workflow_data::LayerStorage::new(database.clone());
+    storage.create_layers(&[lmers::layer::Layer::CenterOfMassToOrigin]);
+    drop(storage);
+    let diagnostic = check_layer_database(&database);
+    assert_eq!(diagnostic.severity, Severity::Ok);
+    assert!(diagnostic.message.starts_with("1 layers"));
+    std::fs::write(&database, [0xffu8; 4096]).unwrap();
+    assert_eq!(check_layer_database(&database).severity, Severity::Error);
+    assert_eq!(std::fs::read(&database).unwrap(), [0xffu8; 4096]);
+}
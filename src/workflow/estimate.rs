@@ -0,0 +1,337 @@
+use std::{fmt::Display, fs::File, path::Path};
+
+use anyhow::Result;
+use lmers::utils::process::ResourceUsage;
+
+use super::{
+    runner::{load_substituents, Runner},
+    step::{Step, StepRunner},
+};
+
+/// Projected count of structures, which may be an upper bound (e.g. after a
+/// filter) or unknown (e.g. depends on the structures or external programs).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    Exact(f64),
+    AtMost(f64),
+    Unknown,
+}
+
+impl Projection {
+    fn value(&self) -> Option<f64> {
+        match self {
+            Self::Exact(value) | Self::AtMost(value) => Some(*value),
+            Self::Unknown => None,
+        }
+    }
+
+    fn map(self, f: impl Fn(f64) -> f64) -> Self {
+        match self {
+            Self::Exact(value) => Self::Exact(f(value)),
+            Self::AtMost(value) => Self::AtMost(f(value)),
+            Self::Unknown => Self::Unknown,
+        }
+    }
+
+    fn add(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::Exact(a), Self::Exact(b)) => Self::Exact(a + b),
+            (Self::Unknown, _) | (_, Self::Unknown) => Self::Unknown,
+            (a, b) => Self::AtMost(a.value().unwrap() + b.value().unwrap()),
+        }
+    }
+
+    fn at_most(self) -> Self {
+        match self {
+            Self::Exact(value) => Self::AtMost(value),
+            other => other,
+        }
+    }
+}
+
+impl Display for Projection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exact(value) => write!(f, "{}", value),
+            Self::AtMost(value) => write!(f, "<= {}", value),
+            Self::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Projection of a step, the calculation-hours are None if unknown.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepEstimate {
+    pub label: String,
+    pub runner: String,
+    pub input: Projection,
+    pub output: Projection,
+    pub hours: Option<f64>,
+}
+
+/// Walk the steps and project the count of structures and calculation-hours
+/// of each step from `input` structures, without executing anything.
+///
+/// Fan-outs of the runners are multiplied, e.g. the count of substituent files
+/// or the isomers of the given stereocenters, while filters only give an upper
+/// bound. Steps of a loop are projected once with the hours multiplied by the
+/// max iterations. Runners with variables are unknown as they are resolved at
+/// runtime. Conditional steps are projected as executed, and the count after
+/// them is unknown if the step changes it.
+pub fn estimate(steps: &[Step], input: Projection) -> Result<Vec<StepEstimate>> {
+    let mut estimates = vec![];
+    let mut current = input;
+    for (index, step) in steps.iter().enumerate() {
+        let label = format!("Step {}/{}", index + 1, steps.len());
+        if step.base.is_some() {
+            current = Projection::Exact(1.);
+        }
+        if step.from.is_some() {
+            current = Projection::Unknown;
+        }
+        let before = current;
+        match &step.run {
+            StepRunner::Loop {
+                steps,
+                max_iterations,
+                ..
+            } => {
+                for mut inner in estimate(steps, current)? {
+                    inner.label = format!("{}, loop {}", label, inner.label);
+                    inner.hours = inner.hours.map(|hours| hours * *max_iterations as f64);
+                    current = inner.output;
+                    estimates.push(inner);
+                }
+            }
+            StepRunner::Deferred(_) => {
+                estimates.push(StepEstimate {
+                    label,
+                    runner: "runner with variables".to_string(),
+                    input: current,
+                    output: Projection::Unknown,
+                    hours: None,
+                });
+                current = Projection::Unknown;
+            }
+            StepRunner::Ready(runner) => {
+                let (output, hours) = project(runner, current)?;
+                estimates.push(StepEstimate {
+                    label,
+                    runner: runner.name(),
+                    input: current,
+                    output,
+                    hours,
+                });
+                current = output;
+            }
+        }
+        if step.when.is_some() && current != before {
+            current = Projection::Unknown;
+        }
+    }
+    Ok(estimates)
+}
+
+/// Print the estimates as a table, with the total calculation-hours.
+pub fn report(estimates: &[StepEstimate]) {
+    println!(
+        "{:<32} {:<20} {:>12} {:>12} {:>12}",
+        "step", "runner", "input", "output", "hours"
+    );
+    for estimate in estimates {
+        println!(
+            "{:<32} {:<20} {:>12} {:>12} {:>12}",
+            estimate.label,
+            estimate.runner,
+            estimate.input.to_string(),
+            estimate.output.to_string(),
+            estimate
+                .hours
+                .map(|hours| format!("{:.1}", hours))
+                .unwrap_or("unknown".to_string())
+        );
+    }
+    let known = estimates
+        .iter()
+        .filter_map(|estimate| estimate.hours)
+        .sum::<f64>();
+    let unknown = estimates
+        .iter()
+        .filter(|estimate| estimate.hours.is_none())
+        .count();
+    println!(
+        "Total {:.1} calculation-hours, {} steps with unknown cost",
+        known, unknown
+    );
+}
+
+/// Output count and calculation-hours of a runner.
+fn project(runner: &Runner, input: Projection) -> Result<(Projection, Option<f64>)> {
+    let hours = |per_structure: Option<f64>| match (input.value(), per_structure) {
+        (_, Some(0.)) => Some(0.),
+        (Some(count), Some(hours)) => Some(count * hours),
+        _ => None,
+    };
+    Ok(match runner {
+        Runner::DistributeLayers(layers) => {
+            (input.map(|count| count * layers.len() as f64), Some(0.))
+        }
+        Runner::Substituent { file_pattern, .. } => {
+            let substituents = load_substituents(file_pattern)?.len() as f64;
+            (input.map(|count| count * substituents), Some(0.))
+        }
+        Runner::Stereoisomers { centers } if !centers.is_empty() => (
+            input.map(|count| count * 2_f64.powi(centers.len() as i32)),
+            Some(0.),
+        ),
+        Runner::DoubleBondIsomers { bonds } if !bonds.is_empty() => (
+            input.map(|count| count * 2_f64.powi(bonds.len() as i32)),
+            Some(0.),
+        ),
+        Runner::Stereoisomers { .. } | Runner::DoubleBondIsomers { .. } => {
+            (Projection::Unknown, Some(0.))
+        }
+        Runner::Conformers(options) => match options.max_per_structure() {
+            Some(max) => (input.map(|count| count * max).at_most(), Some(0.)),
+            None => (Projection::Unknown, Some(0.)),
+        },
+        Runner::ClashCheck(options) if !options.removes() => (input, Some(0.)),
+        Runner::Retain { .. }
+        | Runner::TorsionCluster(_)
+        | Runner::ClashCheck(_)
+        | Runner::FrequencyFilter(_)
+        | Runner::Pareto { .. }
+        | Runner::Filter(_)
+        | Runner::DeduplicateByRMSD(_)
+        | Runner::Boltzmann(_) => (input.at_most(), Some(0.)),
+        Runner::Sort(options) => {
+            let output = match (input.value(), options.limit()) {
+                (Some(value), Some(limit)) => Projection::AtMost(value.min(limit as f64)),
+                (None, Some(limit)) => Projection::AtMost(limit as f64),
+                (_, None) => input.at_most(),
+            };
+            (output, Some(0.))
+        }
+        Runner::Calculation {
+            working_directory,
+            post_frames,
+            estimated_hours,
+            ..
+        } => {
+            let output = if *post_frames {
+                Projection::Unknown
+            } else {
+                input
+            };
+            let per_structure = estimated_hours.or_else(|| average_hours(working_directory));
+            (output, hours(per_structure))
+        }
+        Runner::GeneticOptimize(options) => {
+            let (evaluations, keep, evaluate) = options.estimate();
+            let (_, evaluate_hours) = project(evaluate, Projection::Exact(1.))?;
+            // Evaluated and kept for each input structure
+            let output = input.map(|count| count * keep as f64).at_most();
+            (
+                output,
+                hours(evaluate_hours.map(|hours| hours * evaluations as f64)),
+            )
+        }
+        Runner::ForEach(options) => {
+            let mut output = Projection::Exact(0.);
+            let mut total = Some(0.);
+            for (_, runner) in options.runners()? {
+                let (count, hours) = project(&runner, input)?;
+                output = output.add(count);
+                total = total.zip(hours).map(|(total, hours)| total + hours);
+            }
+            (output, total)
+        }
+        Runner::Plugin { .. } => (Projection::Unknown, None),
+        Runner::ManualBreak { .. }
+        | Runner::CountBreak { .. }
+        | Runner::AppendLayers { .. }
+        | Runner::AlignTo { .. }
+        | Runner::Rename(_)
+        | Runner::Output { .. }
+        | Runner::Features(_)
+        | Runner::Thermochemistry(_)
+        | Runner::Report(_)
+        | Runner::PropertyExtract(_)
+        | Runner::Measure(_)
+        | Runner::StericDescriptors(_)
+        | Runner::Analyze(_)
+        | Runner::CheckPoint => (input, Some(0.)),
+    })
+}
+
+/// Average wall hours of the `resources.json` files written by a previous run.
+fn average_hours(working_directory: &Path) -> Option<f64> {
+    let wall_times = std::fs::read_dir(working_directory)
+        .ok()?
+        .filter_map(|entry| {
+            let file = File::open(entry.ok()?.path().join("resources.json")).ok()?;
+            let usage: ResourceUsage = serde_json::from_reader(file).ok()?;
+            Some(usage.wall_time)
+        })
+        .collect::<Vec<_>>();
+    if wall_times.is_empty() {
+        None
+    } else {
+        Some(wall_times.iter().sum::<f64>() / wall_times.len() as f64 / 3600.)
+    }
+}
+
+#[test]
+fn project_fan_outs() {
+    let runner = |yaml: &str| -> Runner { serde_yaml::from_str(yaml).unwrap() };
+    let step = |run| Step {
+        base: None,
+        from: None,
+        name: None,
+        bookmark: None,
+        when: None,
+        run,
+        capture: Default::default(),
+    };
+    let steps = vec![
+        step(StepRunner::Ready(runner(
+            "with: DistributeLayers\na: {type: Transparent}\nb: {type: Transparent}",
+        ))),
+        step(StepRunner::Ready(runner(
+            "with: Stereoisomers\ncenters: [1, 2]",
+        ))),
+        step(StepRunner::Loop {
+            steps: vec![
+                step(StepRunner::Ready(runner(
+                    "with: Calculation\nworking_directory: not_exists\npre_format: {format: xyz}\npre_filename: input.xyz\nestimated_hours: 0.5",
+                ))),
+                step(StepRunner::Ready(runner("with: Retain\npattern: a"))),
+            ],
+            until: None,
+            max_iterations: 3,
+        }),
+        step(StepRunner::Ready(runner("with: Plugin\ncommand: x\narguments: []"))),
+    ];
+    let estimates = estimate(&steps, Projection::Exact(3.)).unwrap();
+    let outputs = estimates
+        .iter()
+        .map(|estimate| estimate.output)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        outputs,
+        vec![
+            Projection::Exact(6.),
+            Projection::Exact(24.),
+            Projection::Exact(24.),
+            Projection::AtMost(24.),
+            Projection::Unknown
+        ]
+    );
+    assert_eq!(estimates[2].label, "Step 3/4, loop Step 1/2");
+    assert_eq!(estimates[2].hours, Some(36.));
+    assert_eq!(estimates[4].hours, None);
+    let mut switched = step(StepRunner::Ready(runner("with: CheckPoint")));
+    switched.base = Some("scaffold".to_string());
+    let estimates = estimate(&[switched], Projection::Unknown).unwrap();
+    assert_eq!(estimates[0].input, Projection::Exact(1.));
+}
@@ -0,0 +1,76 @@
+use std::fmt::Display;
+
+use lmers::utils::process::ProgramFailure;
+
+/// Exit status of the workflow when it fails, so wrapper scripts and
+/// schedulers can tell the failures apart, e.g. resubmit a job after a
+/// failure of an external program, but not after an invalid input.
+///
+/// Errors of the command line options are reported by clap with status 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// The input file is unable to be read or parsed.
+    Input = 3,
+    /// The workflow refers to something missing or invalid, e.g. a checkpoint,
+    /// a base, a variable, or a problem found by `--doctor`.
+    Validation = 4,
+    /// An external program is unable to start or failed, see `ProgramFailure`.
+    Program = 5,
+    /// Any other error, including bugs of the LME.
+    Internal = 6,
+}
+
+impl Exit {
+    /// Status of the error, an error caused by an external program is always
+    /// a program failure.
+    pub fn of(self, error: &anyhow::Error) -> Self {
+        if error.downcast_ref::<ProgramFailure>().is_some() {
+            Self::Program
+        } else {
+            self
+        }
+    }
+
+    /// Print the message and exit the process with the status.
+    pub fn exit(self, message: impl Display) -> ! {
+        eprintln!("Error: {}", message);
+        std::process::exit(self as i32)
+    }
+}
+
+/// Exit the process with the status on error instead of panicking.
+pub trait OrExit<T> {
+    fn or_exit(self, exit: Exit) -> T;
+}
+
+impl<T, E: Into<anyhow::Error>> OrExit<T> for Result<T, E> {
+    fn or_exit(self, exit: Exit) -> T {
+        self.unwrap_or_else(|error| {
+            let error = error.into();
+            exit.of(&error).exit(format!("{:#}", error))
+        })
+    }
+}
+
+/// Report a panic as an internal error, as the panics are bugs or unexpected
+/// states rather than failures of the input or the programs.
+pub fn set_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        Exit::Internal.exit(format!("internal error, {}", info));
+    }));
+}
+
+#[test]
+fn exit_of_program_failures() {
+    use anyhow::Context;
+    let failed: anyhow::Result<()> = Err(ProgramFailure("obabel failed".to_string()))
+        .with_context(|| "Failed to convert the structure")
+        .with_context(|| "Failed to execute step");
+    assert_eq!(Exit::Internal.of(&failed.unwrap_err()), Exit::Program);
+    let missing: anyhow::Result<()> = Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+        .with_context(|| ProgramFailure("Failed to start g16".to_string()))
+        .with_context(|| "Failed to execute step");
+    assert_eq!(Exit::Internal.of(&missing.unwrap_err()), Exit::Program);
+    let invalid = anyhow::anyhow!("No base named ligand in the workflow");
+    assert_eq!(Exit::Validation.of(&invalid), Exit::Validation);
+}
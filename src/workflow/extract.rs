@@ -0,0 +1,213 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use lmers::io::BasicIOMolecule;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    features::quote_field,
+    runner::{rooted, SanitizeOptions},
+    variable::{matched_path, ScalarFile},
+    workflow_data::Window,
+};
+
+/// How a value of each structure is extracted from its calculation outputs.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(tag = "from", deny_unknown_fields)]
+pub enum Extractor {
+    /// A number matched by a regular expression in a file, see `ScalarFile`.
+    File(ScalarFile),
+    /// A property parsed from the output log (`g16log` or `orcaout`), e.g.
+    /// `energy`, `enthalpy` or `free_energy` in Hartree. `{title}` in the path
+    /// is replaced like `ScalarFile`.
+    Log {
+        path: String,
+        format: String,
+        property: String,
+        #[serde(default)]
+        sanitize: SanitizeOptions,
+    },
+}
+
+impl Extractor {
+    fn read_window(&self, window: &Window) -> Result<BTreeMap<String, Result<f64>>> {
+        match self {
+            Self::File(file) => file.read_window(window),
+            Self::Log {
+                path,
+                format,
+                property,
+                sanitize,
+            } => {
+                let read = |name: &str| -> Result<f64> {
+                    let path = matched_path(path, name)?;
+                    let file =
+                        File::open(&path).with_context(|| format!("Unable to open {:?}", path))?;
+                    let log = BasicIOMolecule::input(format, file)?;
+                    let value = log
+                        .properties
+                        .get(property)
+                        .with_context(|| format!("No property {} in {:?}", property, path))?;
+                    value
+                        .parse()
+                        .with_context(|| format!("Invalid {} {:?} in {:?}", property, value, path))
+                };
+                Ok(window
+                    .keys()
+                    .map(|title| (title.to_string(), read(&sanitize.name(title))))
+                    .collect())
+            }
+        }
+    }
+}
+
+/// A row of the results table.
+#[derive(Debug, Clone, Serialize)]
+struct ResultRow<'a> {
+    title: &'a str,
+    values: BTreeMap<&'a str, Option<f64>>,
+    stack_path: &'a [u64],
+}
+
+/// Extract values from the calculation outputs of each structure into a
+/// results table with the title, the values and the stack path.
+///
+/// The table is written as CSV, tab separated if the path ends with `.tsv`,
+/// or a JSON array of `{title, values, stack_path}` if it ends with `.json`.
+/// The layers of a stack path are separated by `/` in the CSV. Values failed
+/// to extract are left empty (null in JSON).
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ExtractOptions {
+    path: PathBuf,
+    columns: BTreeMap<String, Extractor>,
+}
+
+impl ExtractOptions {
+    pub fn root_outputs(&mut self, directory: &Path) {
+        self.path = rooted(directory, &self.path);
+    }
+
+    pub fn execute(&self, current_window: &Window) -> Result<()> {
+        let delimiter = match self.path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => None,
+            Some("tsv") => Some("\t"),
+            Some("csv") | None => Some(","),
+            Some(ext) => Err(anyhow!(
+                "Unsupported results table format {}, use csv, tsv or json",
+                ext
+            ))?,
+        };
+        let mut rows = current_window
+            .iter()
+            .map(|(title, stack_path)| {
+                let row = ResultRow {
+                    title,
+                    values: BTreeMap::new(),
+                    stack_path,
+                };
+                (title.as_str(), row)
+            })
+            .collect::<BTreeMap<_, _>>();
+        for (name, extractor) in &self.columns {
+            for (title, value) in extractor.read_window(current_window)? {
+                let value = value
+                    .map_err(|err| println!("Unable to extract {} of {}: {:#}", name, title, err))
+                    .ok();
+                rows.get_mut(title.as_str())
+                    .unwrap()
+                    .values
+                    .insert(name, value);
+            }
+        }
+        let content = if let Some(delimiter) = delimiter {
+            let mut header = vec!["title"];
+            header.extend(self.columns.keys().map(String::as_str));
+            header.push("stack_path");
+            let mut content = header
+                .iter()
+                .map(|column| quote_field(column, delimiter))
+                .collect::<Vec<_>>()
+                .join(delimiter);
+            content.push('\n');
+            for row in rows.values() {
+                let mut fields = vec![quote_field(row.title, delimiter)];
+                fields.extend(
+                    row.values
+                        .values()
+                        .map(|value| value.map(|value| value.to_string()).unwrap_or_default()),
+                );
+                fields.push(
+                    row.stack_path
+                        .iter()
+                        .map(u64::to_string)
+                        .collect::<Vec<_>>()
+                        .join("/"),
+                );
+                content.push_str(&fields.join(delimiter));
+                content.push('\n');
+            }
+            content
+        } else {
+            serde_json::to_string_pretty(&rows.values().collect::<Vec<_>>())?
+        };
+        File::create(&self.path)
+            .with_context(|| format!("Unable to create results table at {:?}", self.path))?
+            .write_all(content.as_bytes())
+            .with_context(|| format!("Unable to write results table at {:?}", self.path))?;
+        println!(
+            "Results of {} structures with {} columns written to {:?}",
+            rows.len(),
+            self.columns.len(),
+            self.path
+        );
+        Ok(())
+    }
+}
+
+#[test]
+fn extract_results_table() {
+    let directory = tempfile::tempdir().unwrap();
+    for (title, energy) in [("a", -1.25), ("b", -1.5)] {
+        let calc = directory.path().join(title);
+        std::fs::create_dir_all(&calc).unwrap();
+        std::fs::write(
+            calc.join("opt.out"),
+            format!("E = {}\nGap: 3.5 eV\n", energy),
+        )
+        .unwrap();
+    }
+    let window = Window::from([
+        ("a".to_string(), vec![0, 1]),
+        ("b".to_string(), vec![0, 2]),
+        ("c".to_string(), vec![0, 3]),
+    ]);
+    let extract = |path: &str| {
+        let mut options = serde_yaml::from_str::<ExtractOptions>(&format!(
+            "path: {}
+columns:
+  energy: {{from: File, path: '{1}/{{title}}/*.out', pattern: 'E = (\\S+)'}}
+  gap: {{from: File, path: '{1}/{{title}}/opt.out', pattern: 'Gap: (\\S+)'}}",
+            path,
+            directory.path().display()
+        ))
+        .unwrap();
+        options.root_outputs(directory.path());
+        options.execute(&window).unwrap();
+        std::fs::read_to_string(directory.path().join(path)).unwrap()
+    };
+    assert_eq!(
+        extract("results.csv"),
+        "title,energy,gap,stack_path\na,-1.25,3.5,0/1\nb,-1.5,3.5,0/2\nc,,,0/3\n"
+    );
+    let rows = serde_json::from_str::<serde_json::Value>(&extract("results.json")).unwrap();
+    assert_eq!(rows[1]["values"]["energy"], -1.5);
+    assert_eq!(rows[2]["values"]["gap"], serde_json::Value::Null);
+    assert_eq!(rows[2]["stack_path"], serde_json::json!([0, 3]));
+}
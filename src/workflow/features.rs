@@ -0,0 +1,279 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use lmers::{
+    chemistry::{element_num_to_symbol, element_symbol_to_num, Atom3D},
+    sparse_molecule::SparseMolecule,
+    utils::descriptors::{coulomb_eigenvalues, radial_distribution},
+};
+// shared by the tables of the other runners
+pub(super) use lmers::io::quote_field;
+use rayon::prelude::*;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{
+    runner::{cached_read_stack, rooted},
+    variable::ScalarFile,
+    workflow_data::{LayerStorage, Window},
+};
+
+/// A group of columns in the feature table.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(tag = "type", deny_unknown_fields)]
+pub enum Feature {
+    /// Counts of atoms, heavy atoms and bonds, the total formal charge, and the
+    /// count of each element in `elements`
+    Composition {
+        #[serde(default)]
+        elements: Vec<String>,
+    },
+    /// Sorted eigenvalues of the Coulomb matrix, padded to `size` columns
+    CoulombMatrix { size: usize },
+    /// Smoothed radial distribution of each pair of elements in `species`,
+    /// a light-weight alternative of SOAP vectors
+    RadialDistribution {
+        species: Vec<String>,
+        #[serde(default = "Feature::default_cutoff")]
+        cutoff: f64,
+        #[serde(default = "Feature::default_bins")]
+        bins: usize,
+        #[serde(default = "Feature::default_sigma")]
+        sigma: f64,
+    },
+    /// A number read from a file of each structure
+    Property { name: String, file: ScalarFile },
+}
+
+impl Feature {
+    fn default_cutoff() -> f64 {
+        6.
+    }
+
+    fn default_bins() -> usize {
+        20
+    }
+
+    fn default_sigma() -> f64 {
+        0.2
+    }
+
+    fn columns(&self) -> Result<Vec<String>> {
+        Ok(match self {
+            Self::Composition { elements } => ["atoms", "heavy_atoms", "bonds", "charge"]
+                .into_iter()
+                .map(String::from)
+                .chain(elements.iter().map(|element| format!("count_{}", element)))
+                .collect(),
+            Self::CoulombMatrix { size } => (0..*size).map(|i| format!("cm_{}", i)).collect(),
+            Self::RadialDistribution { species, bins, .. } => {
+                let species = element_numbers(species)?;
+                let mut columns = vec![];
+                for (i, a) in species.iter().enumerate() {
+                    for b in species.iter().skip(i) {
+                        let (a, b) = (
+                            element_num_to_symbol(a).unwrap(),
+                            element_num_to_symbol(b).unwrap(),
+                        );
+                        columns.extend((0..*bins).map(|bin| format!("rdf_{}_{}_{}", a, b, bin)));
+                    }
+                }
+                columns
+            }
+            Self::Property { name, .. } => vec![name.to_string()],
+        })
+    }
+
+    /// Values of the structure, the properties are filled in afterwards.
+    fn values(&self, structure: &SparseMolecule) -> Result<Vec<Option<f64>>> {
+        let atoms: Vec<Atom3D> = structure.atoms.clone().into();
+        Ok(match self {
+            Self::Composition { elements } => {
+                let bonds = structure.bonds.to_continuous_list(&structure.atoms).len();
+                let charge = atoms.iter().map(|atom| atom.formal_charge).sum::<f64>();
+                let mut values = vec![
+                    atoms.len() as f64,
+                    atoms.iter().filter(|atom| atom.element != 1).count() as f64,
+                    bonds as f64,
+                    charge,
+                ];
+                for element in element_numbers(elements)? {
+                    values.push(atoms.iter().filter(|atom| atom.element == element).count() as f64)
+                }
+                values.into_iter().map(Some).collect()
+            }
+            Self::CoulombMatrix { size } => coulomb_eigenvalues(&atoms, *size)
+                .into_iter()
+                .map(Some)
+                .collect(),
+            Self::RadialDistribution {
+                species,
+                cutoff,
+                bins,
+                sigma,
+            } => radial_distribution(&atoms, &element_numbers(species)?, *cutoff, *bins, *sigma)
+                .into_iter()
+                .map(Some)
+                .collect(),
+            Self::Property { .. } => vec![None],
+        })
+    }
+}
+
+fn element_numbers(symbols: &[String]) -> Result<Vec<usize>> {
+    symbols
+        .iter()
+        .map(|symbol| {
+            element_symbol_to_num(symbol).with_context(|| format!("Unknown element {}", symbol))
+        })
+        .collect()
+}
+
+/// Write a feature table of the window for machine learning.
+///
+/// Each row is a structure with its title in the first column, followed by the
+/// columns of `features` and the `target` column if set. The table is written
+/// as CSV, or tab separated if the path ends with `.tsv`. Values failed to be
+/// read are left empty, which are loaded as NaN by pandas. Parquet is not
+/// written directly, convert the table if needed, e.g. with
+/// `pandas.read_csv("features.csv").to_parquet("features.parquet")`.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct FeatureOptions {
+    path: PathBuf,
+    features: Vec<Feature>,
+    #[serde(default)]
+    target: Option<ScalarFile>,
+}
+
+impl FeatureOptions {
+    pub fn root_outputs(&mut self, directory: &Path) {
+        self.path = rooted(directory, &self.path);
+    }
+
+    pub fn execute(
+        &self,
+        base: &SparseMolecule,
+        current_window: &Window,
+        layer_storage: &LayerStorage,
+    ) -> Result<()> {
+        let delimiter = match self.path.extension().and_then(|ext| ext.to_str()) {
+            Some("tsv") => "\t",
+            Some("csv") | None => ",",
+            Some(ext) => Err(anyhow!(
+                "Unsupported feature table format {}, use csv or tsv and convert it",
+                ext
+            ))?,
+        };
+        let mut header = vec!["title".to_string()];
+        for feature in &self.features {
+            header.extend(feature.columns()?);
+        }
+        if self.target.is_some() {
+            header.push("target".to_string());
+        }
+        let mut rows = current_window
+            .par_iter()
+            .map(|(title, stack_path)| {
+                let structure = cached_read_stack(base, layer_storage, stack_path)?;
+                let values = self
+                    .features
+                    .iter()
+                    .map(|feature| feature.values(&structure))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok((title.to_string(), values))
+            })
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        for (index, feature) in self.features.iter().enumerate() {
+            if let Feature::Property { name, file } = feature {
+                for (title, value) in file.read_window(current_window)? {
+                    let value = value
+                        .map_err(|err| println!("Unable to read {} of {}: {:#}", name, title, err))
+                        .ok();
+                    rows.get_mut(&title).unwrap()[index] = vec![value];
+                }
+            }
+        }
+        let mut targets = self
+            .target
+            .as_ref()
+            .map(|target| target.read_window(current_window))
+            .transpose()?;
+        let mut content = header
+            .iter()
+            .map(|column| quote_field(column, delimiter))
+            .collect::<Vec<_>>()
+            .join(delimiter);
+        content.push('\n');
+        for (title, values) in rows {
+            let mut fields = vec![quote_field(&title, delimiter)];
+            let target = targets.as_mut().map(|targets| {
+                targets
+                    .remove(&title)
+                    .unwrap()
+                    .map_err(|err| println!("Unable to read target of {}: {:#}", title, err))
+                    .ok()
+            });
+            for value in values.into_iter().flatten().chain(target) {
+                fields.push(value.map(|value| value.to_string()).unwrap_or_default());
+            }
+            content.push_str(&fields.join(delimiter));
+            content.push('\n');
+        }
+        File::create(&self.path)
+            .with_context(|| format!("Unable to create feature table at {:?}", self.path))?
+            .write_all(content.as_bytes())
+            .with_context(|| format!("Unable to write feature table at {:?}", self.path))?;
+        println!(
+            "Feature table of {} structures with {} columns written to {:?}",
+            current_window.len(),
+            header.len(),
+            self.path
+        );
+        Ok(())
+    }
+}
+
+#[test]
+fn feature_table() {
+    use lmers::{layer::Layer, smiles::parse_smiles};
+    let directory = tempfile::tempdir().unwrap();
+    let storage = LayerStorage::new(directory.path().join(".layers.db"));
+    let base = parse_smiles("CO").unwrap();
+    // Stack paths are cached by the layer ids only, a path not used by other
+    // tests keeps the cached structure the base of this test
+    let stack_path = storage
+        .create_layers(&vec![Layer::Transparent; 7])
+        .rev()
+        .collect::<Vec<_>>();
+    let window = Window::from([("methanol".to_string(), stack_path)]);
+    std::fs::write(directory.path().join("methanol.out"), "E = -115.5\n").unwrap();
+    let options = format!(
+        "path: features.tsv
+features:
+  - {{type: Composition, elements: [C, O]}}
+  - {{type: Property, name: energy, file: {{path: '{0}/{{title}}.out', pattern: 'E = (\\S+)'}}}}
+target: {{path: '{0}/{{title}}.target'}}",
+        directory.path().to_string_lossy()
+    );
+    let mut options = serde_yaml::from_str::<FeatureOptions>(&options).unwrap();
+    options.root_outputs(directory.path());
+    options.execute(&base, &window, &storage).unwrap();
+    let table = std::fs::read_to_string(directory.path().join("features.tsv")).unwrap();
+    let lines = table.lines().collect::<Vec<_>>();
+    assert_eq!(
+        lines[0],
+        "title\tatoms\theavy_atoms\tbonds\tcharge\tcount_C\tcount_O\tenergy\ttarget"
+    );
+    let row = lines[1].split('\t').collect::<Vec<_>>();
+    assert_eq!(row[0], "methanol");
+    assert_eq!(row[2], "2");
+    assert_eq!(row[5..], ["1", "1", "-115.5", ""]);
+    options.path = directory.path().join("features.parquet");
+    assert!(options.execute(&base, &window, &storage).is_err());
+}
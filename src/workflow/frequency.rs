@@ -0,0 +1,227 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use lmers::{
+    io::BasicIOMolecule,
+    layer::{Layer, SelectOne},
+    sparse_molecule::SparseMolecule,
+};
+use nalgebra::Vector3;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{
+    runner::{cached_read_stack, RunnerOutput, SanitizeOptions},
+    workflow_data::{LayerStorage, Window},
+};
+
+/// Kind of stationary point expected from the optimization.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema)]
+pub enum StationaryPoint {
+    /// No imaginary frequency
+    #[default]
+    Minimum,
+    /// Exactly one imaginary frequency
+    TransitionState,
+}
+
+/// Check the imaginary frequencies in the output log (`g16log` or `orcaout`)
+/// of each structure.
+///
+/// Structures with the expected count of imaginary frequencies go to the
+/// `passed` window which is kept, the others go to `rejected`, and the ones
+/// without frequencies in the log go to `failed`. Imaginary frequencies with
+/// absolute values smaller than `threshold` (cm^-1) are ignored as numerical
+/// noise. If `displace` is set, the rejected structures are displaced along
+/// the lowest imaginary mode with the largest atomic displacement of the given
+/// length in angstrom, and saved as the `displaced` window to restart the
+/// optimization.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct FrequencyFilterOptions {
+    /// Path of the output log, `{title}` is replaced like the working
+    /// directories of Calculation, e.g. `calc/{title}/input.log`
+    path: String,
+    format: String,
+    #[serde(default)]
+    expect: StationaryPoint,
+    #[serde(default)]
+    threshold: f64,
+    #[serde(default)]
+    displace: Option<f64>,
+    #[serde(default)]
+    sanitize: SanitizeOptions,
+}
+
+impl FrequencyFilterOptions {
+    pub fn execute(
+        &self,
+        base: &SparseMolecule,
+        current_window: &Window,
+        layer_storage: &LayerStorage,
+    ) -> Result<RunnerOutput> {
+        let mut windows = BTreeMap::from([
+            ("passed".to_string(), Window::new()),
+            ("rejected".to_string(), Window::new()),
+        ]);
+        for (title, stack_path) in current_window {
+            let path = PathBuf::from(self.path.replace("{title}", &self.sanitize.name(title)));
+            let log = match self.read_log(&path) {
+                Ok(log) => log,
+                Err(err) => {
+                    println!("Unable to read frequencies of {}: {:#}", title, err);
+                    windows
+                        .entry("failed".to_string())
+                        .or_default()
+                        .insert(title.to_string(), stack_path.clone());
+                    continue;
+                }
+            };
+            let imaginary = imaginary_count(&log, self.threshold)?;
+            let expected = match self.expect {
+                StationaryPoint::Minimum => 0,
+                StationaryPoint::TransitionState => 1,
+            };
+            if imaginary == expected {
+                windows
+                    .get_mut("passed")
+                    .unwrap()
+                    .insert(title.to_string(), stack_path.clone());
+                continue;
+            }
+            windows
+                .get_mut("rejected")
+                .unwrap()
+                .insert(title.to_string(), stack_path.clone());
+            if let (Some(amplitude), true) = (self.displace, imaginary > 0) {
+                let structure = cached_read_stack(base, layer_storage, stack_path)?;
+                let layer = displacement(&structure, &log, amplitude)
+                    .with_context(|| format!("Unable to displace structure {}", title))?;
+                let mut stack_path = stack_path.clone();
+                stack_path.extend(layer_storage.create_layers(&[layer]));
+                windows
+                    .entry("displaced".to_string())
+                    .or_default()
+                    .insert(title.to_string(), stack_path);
+            }
+        }
+        println!(
+            "{} of {} structures have the expected imaginary frequencies",
+            windows["passed"].len(),
+            current_window.len()
+        );
+        Ok(RunnerOutput::Partition {
+            windows,
+            keep: "passed".to_string(),
+        })
+    }
+
+    fn read_log(&self, path: &Path) -> Result<BasicIOMolecule> {
+        if !matches!(self.format.as_str(), "g16log" | "orcaout") {
+            Err(anyhow!(
+                "Frequencies can only be read from g16log or orcaout, found {}",
+                self.format
+            ))?
+        }
+        let file = File::open(path).with_context(|| format!("Unable to open {:?}", path))?;
+        let log = BasicIOMolecule::input(&self.format, file)?;
+        if !log.properties.contains_key("frequencies") {
+            Err(anyhow!("No frequency found in {:?}", path))?
+        }
+        Ok(log)
+    }
+}
+
+/// Count of imaginary (negative) frequencies beyond the threshold.
+fn imaginary_count(log: &BasicIOMolecule, threshold: f64) -> Result<usize> {
+    let frequencies = log
+        .properties
+        .get("frequencies")
+        .map(String::as_str)
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(|value| {
+            value
+                .parse::<f64>()
+                .with_context(|| format!("Invalid frequency {:?}", value))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(frequencies
+        .into_iter()
+        .filter(|frequency| *frequency < -threshold.abs())
+        .count())
+}
+
+/// A layer moving the atoms along the imaginary mode, scaled so the largest
+/// atomic displacement is `amplitude`.
+fn displacement(
+    structure: &SparseMolecule,
+    log: &BasicIOMolecule,
+    amplitude: f64,
+) -> Result<Layer> {
+    let mode = log
+        .properties
+        .get("imaginary_mode")
+        .context("No imaginary mode found in the output log")?
+        .split_whitespace()
+        .map(|value| {
+            value
+                .parse::<f64>()
+                .with_context(|| format!("Invalid displacement {:?}", value))
+        })
+        .collect::<Result<Vec<_>>>()?
+        .chunks(3)
+        .map(Vector3::from_column_slice)
+        .collect::<Vec<_>>();
+    let largest = mode.iter().map(|vector| vector.norm()).fold(0., f64::max);
+    if largest == 0. {
+        Err(anyhow!("The imaginary mode has no displacement"))?
+    }
+    let atoms = mode
+        .into_iter()
+        .enumerate()
+        .map(|(continuous, vector)| {
+            let index = structure
+                .atoms
+                .from_continuous_index(continuous)
+                .with_context(|| format!("Atom {} of the mode not found", continuous))?;
+            let mut atom = structure.atoms.read_atom(index).unwrap();
+            atom.position += vector * amplitude / largest;
+            Ok((SelectOne::Index(index), Some(atom)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Layer::SetAtom { atoms })
+}
+
+#[test]
+fn count_and_displace_imaginary_modes() {
+    use lmers::{chemistry::Atom3D, sparse_molecule::SparseAtomList};
+    use nalgebra::Point3;
+    let atom = |element, y| Atom3D {
+        element,
+        position: Point3::new(0., y, 0.),
+        formal_charge: 0.,
+    };
+    let structure = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(8, 0.), atom(1, 1.)]),
+        ..Default::default()
+    };
+    let mut log = BasicIOMolecule::new(String::new(), vec![atom(8, 0.), atom(1, 1.)], vec![]);
+    log.properties
+        .insert("frequencies".to_string(), "-350.2 -12.5 3650.1".to_string());
+    log.properties
+        .insert("imaginary_mode".to_string(), "0 0.1 0 0 -0.5 0".to_string());
+    assert_eq!(imaginary_count(&log, 0.).unwrap(), 2);
+    assert_eq!(imaginary_count(&log, 50.).unwrap(), 1);
+    let displaced = displacement(&structure, &log, 0.2)
+        .unwrap()
+        .filter(structure)
+        .unwrap();
+    let y = |index| displaced.atoms.read_atom(index).unwrap().position.y;
+    assert!((y(0) - 0.04).abs() < 1e-8);
+    assert!((y(1) - 0.8).abs() < 1e-8);
+}
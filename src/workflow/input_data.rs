@@ -2,20 +2,80 @@ use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 use lmers::sparse_molecule::SparseMolecule;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use super::step::Steps;
+use super::variable::ScalarFile;
 use super::workflow_data::{LayerStorageConfig, Window};
 
-#[derive(Deserialize, Default, Debug)]
+#[derive(Deserialize, Default, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct WorkflowInput {
     #[serde(default)]
     pub binaries: Vec<PathBuf>,
     #[serde(default)]
     pub base: SparseMolecule,
+    /// Named base structures, e.g. several scaffolds compared in one workflow.
+    /// A step switches to one of them by its `base` field, and the structures
+    /// of the named bases are stored as layers on `base`.
+    #[serde(default)]
+    pub bases: BTreeMap<String, SparseMolecule>,
+    /// Default charge for `gjf` and `orca` structure writers of all steps
+    #[serde(default)]
+    pub charge: Option<i32>,
+    /// Default multiplicity for `gjf` and `orca` structure writers of all steps
+    #[serde(default)]
+    pub multiplicity: Option<u32>,
+    /// Put the outputs of each step under its own directory, see `StepDirectories`
+    #[serde(default)]
+    pub step_directories: Option<StepDirectories>,
+    /// Archive the final structures into a results database, see `ResultsOptions`
+    #[serde(default)]
+    pub results: Option<ResultsOptions>,
+    /// Export the parent to child relationships of the structures across the
+    /// steps to the file (JSON, or Graphviz with `.dot` extension), see `Lineage`
+    #[serde(default)]
+    pub lineage: Option<PathBuf>,
     pub steps: Steps,
 }
 
+/// Root the outputs of each step (working directories of Calculation, files
+/// of Output, Features and Thermochemistry) under
+/// `run_<id>/<step index>_<step name>/`, so the steps don't need to specify
+/// non-colliding paths. Unnamed steps use the runner name, and the steps in a
+/// loop are under `<iteration>_<index>_<name>/` in the directory of the loop.
+/// Absolute paths and paths read by the steps are not changed, e.g. the log
+/// path of FrequencyFilter must include the directory of the calculation step.
+#[derive(Deserialize, Default, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct StepDirectories {
+    /// Identifier of the run, the start time (seconds since the Unix epoch) is
+    /// used if not given, and the identifier of the previous run is reused when
+    /// restarting from a checkpoint.
+    #[serde(default)]
+    pub run_id: Option<String>,
+}
+
+/// Append the structures of the final window to a SQLite database when the
+/// workflow finishes, with the values read by `properties` (see `ScalarFile`),
+/// e.g. `energy: {path: 'calc/{title}/properties.json', pattern: '"energy":
+/// "(.+)"'}`. The workflow file, captured variables, run identifier (of the
+/// step directories, or the start time) and the layers building each structure
+/// are kept as the provenance. Query the database with `lme-query`.
+///
+/// Structures without a property value are archived without it. Needs the
+/// `results` feature, which is enabled by default.
+#[cfg_attr(not(feature = "results"), allow(dead_code))]
+#[derive(Deserialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ResultsOptions {
+    pub database: PathBuf,
+    #[serde(default)]
+    pub properties: BTreeMap<String, ScalarFile>,
+}
+
+#[allow(dead_code)]
 #[derive(Deserialize, Serialize)]
 pub struct WorkflowCheckPoint {
     pub skip: usize,
@@ -0,0 +1,167 @@
+use std::{collections::BTreeMap, fs::File, io::Write, path::Path};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::workflow_data::Window;
+
+/// A structure in the window after a step.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LineageNode {
+    /// Number of the step in the workflow, 0 for the first window
+    #[serde(default)]
+    pub index: usize,
+    /// Name (or bookmark, or `step <index>`) of the step producing the
+    /// structure, `start` for the first window
+    pub step: String,
+    pub title: String,
+    pub stack_path: Vec<u64>,
+}
+
+/// Parent to child relationships of the structures across the steps, e.g. the
+/// substituted structure to its conformers and their optimized structures.
+///
+/// The parent of a structure is the latest recorded structure with the longest
+/// prefix of its stack path, since the runners build the outputs by appending
+/// layers to the inputs. A structure passed through a step unchanged (same
+/// title and stack path) is kept as the same node. Exported as JSON of the
+/// `nodes` and `edges` (pairs of the node indexes), or Graphviz if the path
+/// ends with `.dot`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Lineage {
+    pub nodes: Vec<LineageNode>,
+    pub edges: Vec<(usize, usize)>,
+    /// Latest node of each stack path
+    #[serde(skip)]
+    latest: BTreeMap<Vec<u64>, usize>,
+}
+
+impl Lineage {
+    /// Load the lineage recorded before, or start a new one if not exists.
+    pub fn load(path: &Path) -> Result<Self> {
+        let Ok(file) = File::open(path) else {
+            return Ok(Self::default());
+        };
+        let mut lineage: Self = serde_json::from_reader(file)
+            .with_context(|| format!("Unable to read the lineage at {:?}", path))?;
+        lineage.index_nodes();
+        Ok(lineage)
+    }
+
+    fn index_nodes(&mut self) {
+        self.latest.clear();
+        for (index, node) in self.nodes.iter().enumerate() {
+            self.latest.insert(node.stack_path.clone(), index);
+        }
+    }
+
+    /// Drop the structures recorded after the first `steps` steps of the
+    /// workflow, e.g. the steps executed again when restarting.
+    pub fn truncate(&mut self, steps: usize) {
+        let length = self
+            .nodes
+            .iter()
+            .position(|node| node.index > steps)
+            .unwrap_or(self.nodes.len());
+        self.nodes.truncate(length);
+        self.edges.retain(|(_, child)| *child < length);
+        self.index_nodes();
+    }
+
+    /// Add the structures of the window after the `index`th step.
+    pub fn record(&mut self, index: usize, step: &str, window: &Window) {
+        for (title, stack_path) in window {
+            let same = self
+                .latest
+                .get(stack_path)
+                .copied()
+                .filter(|index| self.nodes[*index].title == *title);
+            if same.is_some() {
+                continue;
+            }
+            let parent = (0..=stack_path.len())
+                .rev()
+                .find_map(|length| self.latest.get(&stack_path[..length]).copied());
+            let node = self.nodes.len();
+            self.nodes.push(LineageNode {
+                index,
+                step: step.to_string(),
+                title: title.to_string(),
+                stack_path: stack_path.clone(),
+            });
+            self.latest.insert(stack_path.clone(), node);
+            if let Some(parent) = parent {
+                self.edges.push((parent, node));
+            }
+        }
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut content = "digraph lineage {\n    rankdir=LR;\n    node [shape=box];\n".to_string();
+        for (index, node) in self.nodes.iter().enumerate() {
+            let label = format!("{}\\n{}", node.title, node.step).replace('"', "\\\"");
+            content.push_str(&format!("    n{} [label=\"{}\"];\n", index, label));
+        }
+        for (parent, child) in &self.edges {
+            content.push_str(&format!("    n{} -> n{};\n", parent, child));
+        }
+        content.push_str("}\n");
+        content
+    }
+
+    /// Write the lineage as JSON, or Graphviz if the path ends with `.dot`.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let content = if path.extension().and_then(|ext| ext.to_str()) == Some("dot") {
+            self.to_dot()
+        } else {
+            serde_json::to_string(self)?
+        };
+        File::create(path)
+            .with_context(|| format!("Unable to create lineage file at {:?}", path))?
+            .write_all(content.as_bytes())
+            .with_context(|| format!("Unable to write lineage file at {:?}", path))
+    }
+}
+
+#[test]
+fn lineage_of_structures() {
+    let mut lineage = Lineage::default();
+    lineage.record(0, "start", &Window::from([("LME".to_string(), vec![])]));
+    lineage.record(
+        1,
+        "substitute",
+        &Window::from([
+            ("LME_Me".to_string(), vec![1]),
+            ("LME_Ph".to_string(), vec![2]),
+        ]),
+    );
+    // Filtered without changes, then conformers of LME_Ph
+    lineage.record(
+        2,
+        "filter",
+        &Window::from([("LME_Ph".to_string(), vec![2])]),
+    );
+    lineage.record(
+        3,
+        "conformers",
+        &Window::from([
+            ("LME_Ph_0".to_string(), vec![2, 3]),
+            ("LME_Ph_1".to_string(), vec![2, 4, 5]),
+        ]),
+    );
+    assert_eq!(lineage.nodes.len(), 5);
+    assert_eq!(lineage.edges, [(0, 1), (0, 2), (2, 3), (2, 4)]);
+    assert!(lineage.to_dot().contains("n2 -> n4;"));
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("lineage.json");
+    lineage.write(&path).unwrap();
+    let loaded = Lineage::load(&path).unwrap();
+    assert_eq!(loaded.nodes, lineage.nodes);
+    assert_eq!(loaded.latest, lineage.latest);
+    // Restarting after the substitution
+    let mut restarted = loaded;
+    restarted.truncate(2);
+    assert_eq!(restarted.nodes.len(), 3);
+    assert_eq!(restarted.edges, [(0, 1), (0, 2)]);
+    assert_eq!(restarted.latest.get(&vec![2, 3]), None);
+}
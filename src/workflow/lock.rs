@@ -0,0 +1,79 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+/// Advisory lock of a checkpoint directory held by a run, released when it's
+/// dropped or the process exits, even if the process is killed.
+#[derive(Debug)]
+pub struct CheckpointLock {
+    _file: File,
+}
+
+/// Lock the checkpoint directory for the run, creating it if missing. The
+/// lock file `.lock` records the process and the start time of the run
+/// holding it, and a second run in the same directory fails with them instead
+/// of corrupting the checkpoints and the layer database.
+pub fn lock_checkpoints(directory: &Path) -> Result<CheckpointLock> {
+    std::fs::create_dir_all(directory)
+        .with_context(|| format!("Unable to prepare checkpoint directory {:?}", directory))?;
+    let path = directory.join(".lock");
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("Unable to open lock file {:?}", path))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut holder = String::new();
+            file.read_to_string(&mut holder).ok();
+            if holder.trim().is_empty() {
+                holder = "unknown process".to_string();
+            }
+            Err(anyhow!(
+                "Another run ({}) is using {:?}, wait for it to finish or use --isolate to run in another checkpoint directory",
+                holder.trim(),
+                directory
+            ))?
+        }
+        Err(TryLockError::Error(err)) => {
+            Err(err).with_context(|| format!("Unable to lock {:?}", path))?
+        }
+    }
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    file.set_len(0)
+        .and_then(|_| file.rewind())
+        .and_then(|_| {
+            write!(
+                file,
+                "process {} started at {}",
+                std::process::id(),
+                started
+            )
+        })
+        .with_context(|| format!("Unable to write lock file {:?}", path))?;
+    Ok(CheckpointLock { _file: file })
+}
+
+#[test]
+fn lock_checkpoint_directory() {
+    let directory = tempfile::tempdir().unwrap();
+    let checkpoints = directory.path().join(".checkpoint");
+    let lock = lock_checkpoints(&checkpoints).unwrap();
+    let err = lock_checkpoints(&checkpoints).unwrap_err().to_string();
+    assert!(err.contains(&format!("process {}", std::process::id())));
+    // Another directory is not locked
+    lock_checkpoints(&directory.path().join(".checkpoint_isolated")).unwrap();
+    drop(lock);
+    lock_checkpoints(&checkpoints).unwrap();
+}
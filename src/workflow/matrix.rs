@@ -0,0 +1,169 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use lmers::{sparse_molecule::SparseMolecule, utils::input::from_yaml_value};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_yaml::Value;
+
+use super::{
+    runner::{Runner, RunnerOutput},
+    variable::{scalar_string, substitute},
+    workflow_data::{LayerStorage, Window},
+};
+
+/// Run the template runner once for each combination of the parameter lists,
+/// e.g. `matrix: {substituents: [alkyl, aryl], temperature: [298, 373]}` runs
+/// it 4 times.
+///
+/// `${name}` in the strings of `run` is replaced by the value of the
+/// parameter like the workflow variables, a string of only the reference keeps
+/// the type of the value (e.g. a number), and referring to a name not in the
+/// matrix is an error. Each combination is named by its values joined by `_` in
+/// the order of parameter names, e.g. `alkyl_298`, and the output window of
+/// each combination (the kept window of a partition, or the input window if
+/// the runner outputs nothing) is saved as a window of the name, with titles
+/// suffixed by it like `{title}_alkyl_298`. Outputs of the runners (e.g. the
+/// working directory of Calculation) should use the parameters to avoid
+/// overwriting each other, or they are put under directories of the
+/// combinations if the workflow has step directories.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ForEachOptions {
+    #[schemars(with = "BTreeMap<String, Vec<serde_json::Value>>")]
+    matrix: BTreeMap<String, Vec<Value>>,
+    #[schemars(with = "Runner")]
+    run: Value,
+    #[serde(skip)]
+    directory: Option<PathBuf>,
+    #[serde(skip)]
+    default_charge: (Option<i32>, Option<u32>),
+}
+
+impl ForEachOptions {
+    pub fn root_outputs(&mut self, directory: &Path) {
+        self.directory = Some(directory.to_path_buf());
+    }
+
+    pub fn set_default_charge(&mut self, charge: Option<i32>, multiplicity: Option<u32>) {
+        self.default_charge = (charge, multiplicity);
+    }
+
+    /// Names and runners of the combinations.
+    pub fn runners(&self) -> Result<Vec<(String, Runner)>> {
+        let mut combinations = vec![(vec![], BTreeMap::new())];
+        for (name, values) in &self.matrix {
+            if values.is_empty() {
+                Err(anyhow!("No value given for parameter {}", name))?
+            }
+            let mut expanded = vec![];
+            for (labels, parameters) in &combinations {
+                for value in values {
+                    let label = scalar_string(value)
+                        .with_context(|| format!("Invalid value of parameter {}", name))?;
+                    let mut labels: Vec<String> = labels.clone();
+                    labels.push(label);
+                    let mut parameters: BTreeMap<String, Value> = parameters.clone();
+                    parameters.insert(name.to_string(), value.clone());
+                    expanded.push((labels, parameters));
+                }
+            }
+            combinations = expanded;
+        }
+        combinations
+            .into_iter()
+            .map(|(labels, parameters)| {
+                let name = labels.join("_");
+                let value = substitute_parameters(self.run.clone(), &parameters)?;
+                let mut runner: Runner = from_yaml_value(value, &format!("runner of {}", name))?;
+                let (charge, multiplicity) = self.default_charge;
+                runner.set_default_charge(charge, multiplicity);
+                if let Some(directory) = &self.directory {
+                    runner.root_outputs(&directory.join(&name));
+                }
+                Ok((name, runner))
+            })
+            .collect()
+    }
+
+    pub fn execute(
+        &self,
+        base: &SparseMolecule,
+        current_window: &Window,
+        layer_storage: &LayerStorage,
+    ) -> Result<RunnerOutput> {
+        let mut windows = BTreeMap::new();
+        for (name, runner) in self.runners()? {
+            println!("Running {} for combination {}", runner.name(), name);
+            let window = match runner
+                .execute(base, current_window, layer_storage)
+                .with_context(|| format!("Failed to run combination {}", name))?
+            {
+                RunnerOutput::SingleWindow(window) => window,
+                RunnerOutput::MultiWindow(windows) => windows.into_values().flatten().collect(),
+                RunnerOutput::Partition { mut windows, keep } => windows
+                    .remove(&keep)
+                    .with_context(|| format!("Kept window {} not found in the output", keep))?,
+                RunnerOutput::WithFailures { window, .. } => window,
+                RunnerOutput::None => current_window.clone(),
+            };
+            let window = window
+                .into_iter()
+                .map(|(title, stack_path)| (format!("{}_{}", title, name), stack_path))
+                .collect();
+            windows.insert(name, window);
+        }
+        Ok(RunnerOutput::MultiWindow(windows))
+    }
+}
+
+/// Replace `${name}` in the strings of the value by the parameters.
+fn substitute_parameters(value: Value, parameters: &BTreeMap<String, Value>) -> Result<Value> {
+    substitute(value, &mut |name| {
+        parameters
+            .get(name)
+            .cloned()
+            .map(Some)
+            .with_context(|| format!("Parameter {} is not in the matrix", name))
+    })
+}
+
+#[test]
+fn expand_parameter_matrix() {
+    let mut options: ForEachOptions = serde_yaml::from_str(
+        "matrix: {group: [Me, Ph], scale: [1, 1.5]}
+run:
+  with: Calculation
+  working_directory: 'calc_${group}'
+  pre_format: {format: xyz}
+  pre_filename: input.xyz
+  program: lme-mock
+  args: ['scale=${ scale }']
+  estimated_hours: '${scale}'",
+    )
+    .unwrap();
+    options.root_outputs(Path::new("run_1/2_scan"));
+    let runners = options.runners().unwrap();
+    let names = runners
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["Me_1", "Me_1.5", "Ph_1", "Ph_1.5"]);
+    let Runner::Calculation {
+        working_directory,
+        args,
+        estimated_hours,
+        ..
+    } = &runners[3].1
+    else {
+        panic!("Calculation expected")
+    };
+    assert_eq!(working_directory, Path::new("run_1/2_scan/Ph_1.5/calc_Ph"));
+    assert_eq!(args, &["scale=1.5"]);
+    assert_eq!(*estimated_hours, Some(1.5));
+    let value = serde_yaml::from_str("with: Retain\npattern: '${missing}'").unwrap();
+    assert!(substitute_parameters(value, &BTreeMap::new()).is_err());
+}
@@ -0,0 +1,178 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use lmers::{layer::SelectOne, sparse_molecule::SparseMolecule, utils::geometric::dihedral_angle};
+use nalgebra::Point3;
+use rayon::prelude::*;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{
+    features::quote_field,
+    runner::{cached_read_stack, rooted},
+    workflow_data::{LayerStorage, Window},
+};
+
+/// A distance (Angstrom), angle or dihedral angle (degrees) of the atoms.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Measurement {
+    Distance(SelectOne, SelectOne),
+    Angle(SelectOne, SelectOne, SelectOne),
+    Dihedral(SelectOne, SelectOne, SelectOne, SelectOne),
+}
+
+impl Measurement {
+    fn measure(&self, structure: &SparseMolecule) -> Result<f64> {
+        let position = |select: &SelectOne| -> Result<Point3<f64>> {
+            select
+                .get_atom(structure)
+                .map(|atom| atom.position)
+                .with_context(|| format!("Atom {:?} not found", select))
+        };
+        Ok(match self {
+            Self::Distance(a, b) => (position(a)? - position(b)?).norm(),
+            Self::Angle(a, b, c) => {
+                let b = position(b)?;
+                (position(a)? - b).angle(&(position(c)? - b)).to_degrees()
+            }
+            Self::Dihedral(a, b, c, d) => {
+                dihedral_angle(&position(a)?, &position(b)?, &position(c)?, &position(d)?)
+            }
+        })
+    }
+}
+
+/// Write the named measurements of each structure as a table, e.g.
+/// `{path: geometry.csv, measurements: {d_CO: [C1, O1], a_OCO: [O1, C1, O2]}}`,
+/// two atoms for a distance, three for an angle and four for a dihedral angle.
+///
+/// The table is written as CSV with the title in the first column, or tab
+/// separated if the path ends with `.tsv`. Measurements of missing atoms are
+/// left empty.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct MeasureOptions {
+    path: PathBuf,
+    measurements: BTreeMap<String, Measurement>,
+}
+
+impl MeasureOptions {
+    pub fn root_outputs(&mut self, directory: &Path) {
+        self.path = rooted(directory, &self.path);
+    }
+
+    pub fn execute(
+        &self,
+        base: &SparseMolecule,
+        current_window: &Window,
+        layer_storage: &LayerStorage,
+    ) -> Result<()> {
+        let delimiter = match self.path.extension().and_then(|ext| ext.to_str()) {
+            Some("tsv") => "\t",
+            Some("csv") | None => ",",
+            Some(ext) => Err(anyhow!(
+                "Unsupported measurement table format {}, use csv or tsv",
+                ext
+            ))?,
+        };
+        let rows = current_window
+            .par_iter()
+            .map(|(title, stack_path)| {
+                let structure = cached_read_stack(base, layer_storage, stack_path)?;
+                let values = self
+                    .measurements
+                    .iter()
+                    .map(|(name, measurement)| {
+                        measurement
+                            .measure(&structure)
+                            .map_err(|err| {
+                                println!("Unable to measure {} of {}: {:#}", name, title, err)
+                            })
+                            .ok()
+                    })
+                    .collect::<Vec<_>>();
+                Ok((title.as_str(), values))
+            })
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        let mut content = ["title"]
+            .into_iter()
+            .chain(self.measurements.keys().map(String::as_str))
+            .map(|column| quote_field(column, delimiter))
+            .collect::<Vec<_>>()
+            .join(delimiter);
+        content.push('\n');
+        for (title, values) in rows {
+            let mut fields = vec![quote_field(title, delimiter)];
+            fields.extend(
+                values
+                    .into_iter()
+                    .map(|value| value.map(|value| value.to_string()).unwrap_or_default()),
+            );
+            content.push_str(&fields.join(delimiter));
+            content.push('\n');
+        }
+        File::create(&self.path)
+            .with_context(|| format!("Unable to create measurement table at {:?}", self.path))?
+            .write_all(content.as_bytes())
+            .with_context(|| format!("Unable to write measurement table at {:?}", self.path))?;
+        println!(
+            "Measurements of {} structures written to {:?}",
+            current_window.len(),
+            self.path
+        );
+        Ok(())
+    }
+}
+
+#[test]
+fn measure_geometry() {
+    use lmers::{chemistry::Atom3D, sparse_molecule::SparseAtomList};
+    let atom = |x, y, z| Atom3D {
+        element: 6,
+        position: Point3::new(x, y, z),
+        formal_charge: 0.,
+    };
+    let structure = SparseMolecule {
+        atoms: SparseAtomList::from(vec![
+            atom(1., 0., 0.),
+            atom(0., 0., 0.),
+            atom(0., 1., 0.),
+            atom(0., 1., 1.),
+        ]),
+        ..Default::default()
+    };
+    let options = serde_yaml::from_str::<MeasureOptions>(
+        "path: geometry.csv
+measurements:
+  d: [0, 1]
+  a: [0, 1, 2]
+  t: [0, 1, 2, 3]
+  missing: [0, 9]",
+    )
+    .unwrap();
+    let measure = |name: &str| options.measurements[name].measure(&structure);
+    assert!(matches!(
+        options.measurements["t"],
+        Measurement::Dihedral(..)
+    ));
+    assert_eq!(measure("d").unwrap(), 1.);
+    assert!((measure("a").unwrap() - 90.).abs() < 1e-9);
+    assert!((measure("t").unwrap().abs() - 90.).abs() < 1e-9);
+    assert!(measure("missing").is_err());
+    // Header only for an empty window
+    let directory = tempfile::tempdir().unwrap();
+    let storage = LayerStorage::new(directory.path().join(".layers.db"));
+    let mut options = options.clone();
+    options.root_outputs(directory.path());
+    options
+        .execute(&structure, &Window::new(), &storage)
+        .unwrap();
+    let table = std::fs::read_to_string(directory.path().join("geometry.csv")).unwrap();
+    assert_eq!(table, "title,a,d,missing,t\n");
+}
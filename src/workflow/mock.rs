@@ -0,0 +1,193 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use lmers::{
+    chemistry::element_num_to_symbol, io::BasicIOMolecule, sparse_molecule::SparseMolecule,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::runner::fnv1a;
+
+/// Program name of the built-in mock backend of Calculation.
+pub const MOCK_PROGRAM: &str = "lme-mock";
+
+/// Run the mock backend instead of an external program, which writes the
+/// structure to the post-calculation file directly, so the logic of a workflow
+/// can be checked in seconds before using real programs.
+///
+/// The arguments are `jitter=<angstrom>` to move each coordinate randomly within
+/// the amplitude, and `seed=<integer>` for the randomness (combined with the
+/// title). A fake energy (in Hartree) from a simple pair potential is written
+/// with the structure, as the `SCF Done` line of a `g16log` file, the `FINAL
+/// SINGLE POINT ENERGY` line of an `orcaout` file, or the `energy` property of
+/// other formats (kept by `sdf`).
+pub fn run_mock(
+    structure: &SparseMolecule,
+    title: &str,
+    args: &[String],
+    post_file: Option<&(String, String)>,
+    working_directory: &Path,
+) -> Result<()> {
+    let mut jitter = 0.;
+    let mut seed = 0_u64;
+    for arg in args {
+        match arg.split_once('=') {
+            Some(("jitter", value)) => {
+                jitter = value
+                    .parse()
+                    .with_context(|| format!("Invalid jitter amplitude {:?}", value))?
+            }
+            Some(("seed", value)) => {
+                seed = value
+                    .parse()
+                    .with_context(|| format!("Invalid random seed {:?}", value))?
+            }
+            _ => Err(anyhow!(
+                "Unknown argument {:?} of {}, expect jitter=<angstrom> or seed=<integer>",
+                arg,
+                MOCK_PROGRAM
+            ))?,
+        }
+    }
+    let Some((format, filename)) = post_file else {
+        return Ok(());
+    };
+    let mut rng = StdRng::seed_from_u64(seed ^ fnv1a(title));
+    let mut molecule = BasicIOMolecule::from((structure.clone(), title.to_string()));
+    if jitter > 0. {
+        for atom in molecule.atoms.iter_mut() {
+            for coordinate in atom.position.iter_mut() {
+                *coordinate += rng.gen_range(-jitter..=jitter);
+            }
+        }
+    }
+    let energy = fake_energy(&molecule);
+    let content = match format.as_str() {
+        "g16log" => fake_gaussian_log(&molecule, energy)?,
+        "orcaout" => fake_orca_output(&molecule, energy)?,
+        format => {
+            molecule
+                .properties
+                .insert("energy".to_string(), energy.to_string());
+            molecule.output(format)?
+        }
+    };
+    let path = working_directory.join(filename);
+    std::fs::write(&path, content)
+        .with_context(|| format!("Unable to write mock result at {:?}", path))
+}
+
+/// -0.5 Hartree per proton, with a weak repulsion of each pair of atoms.
+fn fake_energy(molecule: &BasicIOMolecule) -> f64 {
+    let mut energy = molecule
+        .atoms
+        .iter()
+        .map(|atom| -0.5 * atom.element as f64)
+        .sum::<f64>();
+    for (index, a) in molecule.atoms.iter().enumerate() {
+        for b in &molecule.atoms[index + 1..] {
+            energy += 0.001 / (a.position - b.position).norm().max(0.1);
+        }
+    }
+    energy
+}
+
+fn symbol(element: usize) -> Result<&'static str> {
+    element_num_to_symbol(element)
+        .with_context(|| format!("Invalid element number found {}", element))
+}
+
+fn fake_gaussian_log(molecule: &BasicIOMolecule, energy: f64) -> Result<String> {
+    let dash = format!(" {}", "-".repeat(69));
+    let mut lines = vec![
+        format!(" Entering Gaussian System, {}", MOCK_PROGRAM),
+        " Input orientation:".to_string(),
+        dash.clone(),
+        " Center     Atomic      Atomic             Coordinates (Angstroms)".to_string(),
+        " Number     Number       Type             X           Y           Z".to_string(),
+        dash.clone(),
+    ];
+    for (index, atom) in molecule.atoms.iter().enumerate() {
+        symbol(atom.element)?;
+        lines.push(format!(
+            " {:>6} {:>10} {:>11} {:>14.6} {:>11.6} {:>11.6}",
+            index + 1,
+            atom.element,
+            0,
+            atom.position.x,
+            atom.position.y,
+            atom.position.z
+        ));
+    }
+    lines.push(dash);
+    lines.push(format!(
+        " SCF Done:  E(mock) =  {:.10}     A.U. after    1 cycles",
+        energy
+    ));
+    lines.push(" Normal termination of Gaussian".to_string());
+    Ok(lines.join("\n") + "\n")
+}
+
+fn fake_orca_output(molecule: &BasicIOMolecule, energy: f64) -> Result<String> {
+    let mut lines = vec![
+        "---------------------------------".to_string(),
+        "CARTESIAN COORDINATES (ANGSTROEM)".to_string(),
+        "---------------------------------".to_string(),
+    ];
+    for atom in &molecule.atoms {
+        lines.push(format!(
+            "  {:<4} {:>12.6} {:>12.6} {:>12.6}",
+            symbol(atom.element)?,
+            atom.position.x,
+            atom.position.y,
+            atom.position.z
+        ));
+    }
+    lines.push(String::new());
+    lines.push(format!("FINAL SINGLE POINT ENERGY {:>20.12}", energy));
+    lines.push(String::new());
+    lines.push("                             ****ORCA TERMINATED NORMALLY****".to_string());
+    Ok(lines.join("\n") + "\n")
+}
+
+#[test]
+fn mock_results_are_readable() {
+    use lmers::{chemistry::Atom3D, sparse_molecule::SparseAtomList};
+    use nalgebra::Point3;
+    let atom = |element, x| Atom3D {
+        element,
+        position: Point3::new(x, 0., 0.),
+        formal_charge: 0.,
+    };
+    let structure = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(8, 0.), atom(1, 0.96)]),
+        ..Default::default()
+    };
+    let directory = tempfile::tempdir().unwrap();
+    for format in ["g16log", "orcaout", "sdf"] {
+        let post_file = (format.to_string(), format!("result.{}", format));
+        run_mock(
+            &structure,
+            "hydroxyl",
+            &["jitter=0.01".to_string(), "seed=7".to_string()],
+            Some(&post_file),
+            directory.path(),
+        )
+        .unwrap();
+        let file = std::fs::File::open(directory.path().join(&post_file.1)).unwrap();
+        let result = BasicIOMolecule::input(format, file).unwrap();
+        assert_eq!(result.atoms.len(), 2);
+        let shift = (result.atoms[1].position - Point3::new(0.96, 0., 0.)).amax();
+        assert!(shift <= 0.01 + 1e-6);
+        let energy = result.properties["energy"].parse::<f64>().unwrap();
+        assert!((energy + 4.5).abs() < 0.01);
+    }
+    assert!(run_mock(
+        &structure,
+        "hydroxyl",
+        &["fast".to_string()],
+        None,
+        directory.path()
+    )
+    .is_err());
+}
@@ -1,4 +1,31 @@
+pub mod analyze;
+pub mod boltzmann;
+pub mod clash;
+pub mod cluster;
+pub mod condition;
+pub mod conformer;
+pub mod container;
+pub mod doctor;
+pub mod estimate;
+pub mod exit;
+pub mod extract;
+pub mod features;
+pub mod frequency;
 pub mod input_data;
+pub mod lineage;
+pub mod lock;
+pub mod matrix;
+pub mod measure;
+pub mod mock;
+pub mod optimizer;
+pub mod render;
+pub mod report;
 pub mod runner;
+pub mod scheduler;
+pub mod selection;
 pub mod step;
+pub mod steric;
+pub mod thermo;
+pub mod unit;
+pub mod variable;
 pub mod workflow_data;
@@ -0,0 +1,344 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use lmers::{layer::SelectOne, sparse_molecule::SparseMolecule};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    runner::{attach_substituent, load_substituents, rooted, Runner, RunnerOutput},
+    variable::ScalarFile,
+    workflow_data::{LayerStorage, Window},
+};
+
+/// Genetic algorithm over the substituent chosen for each attachment site.
+///
+/// A candidate chooses one substituent of the library for each site in
+/// `address` (same as the Substituent runner), and is titled by the input title
+/// and the chosen substituents joined by `_`. The new candidates of each
+/// generation are evaluated by the `evaluate` runner (e.g. a Calculation) and
+/// scored by the number read with `score`, candidates failed to be scored are
+/// ranked last. The best `keep` (default `population`) candidates of each
+/// input structure are the output, and all evaluated candidates are recorded
+/// in the `history` file.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct GeneticOptions {
+    address: BTreeMap<String, (SelectOne, SelectOne)>,
+    file_pattern: Vec<String>,
+    evaluate: Box<Runner>,
+    score: ScalarFile,
+    /// Prefer larger scores, smaller scores are preferred by default
+    #[serde(default)]
+    maximize: bool,
+    population: usize,
+    generations: usize,
+    #[serde(default = "GeneticOptions::default_mutation_rate")]
+    mutation_rate: f64,
+    #[serde(default)]
+    seed: u64,
+    #[serde(default)]
+    keep: Option<usize>,
+    #[serde(default = "GeneticOptions::default_history")]
+    history: PathBuf,
+}
+
+#[derive(Debug, Serialize)]
+struct HistoryRecord {
+    generation: usize,
+    title: String,
+    choices: BTreeMap<String, String>,
+    score: Option<f64>,
+}
+
+struct Candidate {
+    title: String,
+    stack_path: Vec<u64>,
+    score: Option<f64>,
+}
+
+impl GeneticOptions {
+    fn default_mutation_rate() -> f64 {
+        0.2
+    }
+
+    fn default_history() -> PathBuf {
+        PathBuf::from("genetic_history.json")
+    }
+
+    /// Put the history and the outputs of the evaluating runner under the
+    /// directory, see `Runner::root_outputs`.
+    pub fn root_outputs(&mut self, directory: &Path) {
+        self.history = rooted(directory, &self.history);
+        self.evaluate.root_outputs(directory);
+    }
+
+    /// Upper bounds of the evaluated and the kept candidates of each input
+    /// structure, with the runner evaluating them, used by `--estimate`.
+    pub fn estimate(&self) -> (usize, usize, &Runner) {
+        (
+            self.population * (self.generations + 1),
+            self.keep.unwrap_or(self.population),
+            &self.evaluate,
+        )
+    }
+
+    /// Order of scores, the better one is less.
+    fn compare(&self, a: Option<f64>, b: Option<f64>) -> Ordering {
+        match (a, b) {
+            (Some(a), Some(b)) if self.maximize => b.total_cmp(&a),
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+
+    /// Genomes of the next population, the best `population` of the evaluated
+    /// candidates, best first.
+    fn select(&self, evaluated: &BTreeMap<Vec<usize>, Candidate>) -> Vec<Vec<usize>> {
+        let mut population = evaluated.keys().cloned().collect::<Vec<_>>();
+        population.sort_by(|a, b| self.compare(evaluated[a].score, evaluated[b].score));
+        population.truncate(self.population);
+        population
+    }
+
+    /// Genome of a child, the parents are chosen by binary tournaments from the
+    /// population sorted best first, each gene is inherited from either parent
+    /// or mutated to a random choice of the library.
+    fn breed(&self, rng: &mut StdRng, population: &[Vec<usize>], choices: usize) -> Vec<usize> {
+        let mut tournament = || {
+            let a = rng.gen_range(0..population.len());
+            let b = rng.gen_range(0..population.len());
+            &population[a.min(b)]
+        };
+        let (father, mother) = (tournament(), tournament());
+        father
+            .iter()
+            .zip(mother)
+            .map(|(father, mother)| {
+                if rng.gen_bool(self.mutation_rate.clamp(0., 1.)) {
+                    rng.gen_range(0..choices)
+                } else if rng.gen_bool(0.5) {
+                    *father
+                } else {
+                    *mother
+                }
+            })
+            .collect()
+    }
+
+    /// The best `keep` candidates.
+    fn best(&self, candidates: impl IntoIterator<Item = Candidate>) -> Vec<Candidate> {
+        let mut candidates = candidates.into_iter().collect::<Vec<_>>();
+        candidates.sort_by(|a, b| self.compare(a.score, b.score));
+        candidates.truncate(self.keep.unwrap_or(self.population));
+        candidates
+    }
+
+    pub fn execute(
+        &self,
+        base: &SparseMolecule,
+        current_window: &Window,
+        layer_storage: &LayerStorage,
+    ) -> Result<RunnerOutput> {
+        if self.population == 0 {
+            Err(anyhow!("Population of genetic optimizer must be positive"))?
+        }
+        let library = load_substituents(&self.file_pattern)?
+            .into_iter()
+            .collect::<Vec<_>>();
+        if library.is_empty() {
+            Err(anyhow!(
+                "No substituent matched by {:?} for genetic optimizer",
+                self.file_pattern
+            ))?
+        }
+        let space = (library.len() as u128)
+            .checked_pow(self.address.len() as u32)
+            .unwrap_or(u128::MAX);
+        let score = self.score.reader()?;
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut history = vec![];
+        let mut results = vec![];
+        for (title, stack_path) in current_window {
+            let mut evaluated: BTreeMap<Vec<usize>, Candidate> = BTreeMap::new();
+            let mut population: Vec<Vec<usize>> = vec![];
+            for generation in 0..=self.generations {
+                let mut offspring = BTreeSet::new();
+                let target = (self.population as u128).min(space - evaluated.len() as u128);
+                let mut attempts = 0;
+                while (offspring.len() as u128) < target && attempts < self.population * 100 {
+                    attempts += 1;
+                    let genome = if population.len() < 2 {
+                        (0..self.address.len())
+                            .map(|_| rng.gen_range(0..library.len()))
+                            .collect::<Vec<_>>()
+                    } else {
+                        self.breed(&mut rng, &population, library.len())
+                    };
+                    if !evaluated.contains_key(&genome) {
+                        offspring.insert(genome);
+                    }
+                }
+                if offspring.is_empty() {
+                    println!("Search space of genetic optimizer for {} exhausted", title);
+                    break;
+                }
+                let mut window = Window::new();
+                let mut titles = BTreeMap::new();
+                for genome in &offspring {
+                    let mut stack_path = stack_path.clone();
+                    let mut names = vec![title.to_string()];
+                    for ((g_name, (center, replace)), gene) in self.address.iter().zip(genome) {
+                        let (substituent_name, substituent) = &library[*gene];
+                        attach_substituent(
+                            base,
+                            layer_storage,
+                            &mut stack_path,
+                            g_name,
+                            (center, replace),
+                            (substituent_name, substituent),
+                        )?;
+                        names.push(substituent_name.to_string());
+                    }
+                    let candidate_title = names.join("_");
+                    titles.insert(genome.clone(), candidate_title.clone());
+                    window.insert(candidate_title, stack_path);
+                }
+                let evaluated_window = match self
+                    .evaluate
+                    .execute(base, &window, layer_storage)
+                    .with_context(|| format!("Failed to evaluate generation {}", generation))?
+                {
+                    RunnerOutput::SingleWindow(window) => window,
+                    RunnerOutput::MultiWindow(windows) => windows.into_values().flatten().collect(),
+                    RunnerOutput::Partition { mut windows, keep } => {
+                        windows.remove(&keep).unwrap_or_default()
+                    }
+                    RunnerOutput::WithFailures { window, .. } => window,
+                    RunnerOutput::None => window.clone(),
+                };
+                let mut scores = score.read_window(&evaluated_window)?;
+                for genome in offspring {
+                    let candidate_title = titles.remove(&genome).unwrap();
+                    let (stack_path, score) =
+                        if let Some(stack_path) = evaluated_window.get(&candidate_title) {
+                            let score = scores
+                                .remove(&candidate_title)
+                                .unwrap()
+                                .map_err(|err| {
+                                    println!("Unable to score {}: {:#}", candidate_title, err)
+                                })
+                                .ok();
+                            (stack_path.clone(), score)
+                        } else {
+                            (window[&candidate_title].clone(), None)
+                        };
+                    history.push(HistoryRecord {
+                        generation,
+                        title: candidate_title.clone(),
+                        choices: self
+                            .address
+                            .keys()
+                            .zip(&genome)
+                            .map(|(site, gene)| (site.to_string(), library[*gene].0.to_string()))
+                            .collect(),
+                        score,
+                    });
+                    evaluated.insert(
+                        genome,
+                        Candidate {
+                            title: candidate_title,
+                            stack_path,
+                            score,
+                        },
+                    );
+                }
+                population = self.select(&evaluated);
+                let best = &evaluated[&population[0]];
+                println!(
+                    "Generation {} of {}: {} candidates evaluated, best {} with score {:?}",
+                    generation,
+                    title,
+                    evaluated.len(),
+                    best.title,
+                    best.score
+                );
+                let file = File::create(&self.history).with_context(|| {
+                    format!("Unable to create history file at {:?}", self.history)
+                })?;
+                serde_json::to_writer_pretty(file, &history).with_context(|| {
+                    format!("Unable to write history file at {:?}", self.history)
+                })?;
+            }
+            results.extend(self.best(evaluated.into_values()));
+        }
+        Ok(RunnerOutput::SingleWindow(
+            results
+                .into_iter()
+                .map(|candidate| (candidate.title, candidate.stack_path))
+                .collect(),
+        ))
+    }
+}
+
+#[test]
+fn genetic_operators() {
+    let options = serde_yaml::from_str::<GeneticOptions>(
+        "address: {R: [1, 2]}
+file_pattern: ['*.mol2']
+evaluate: {with: CheckPoint}
+score: {path: score.txt}
+population: 2
+generations: 3
+mutation_rate: 0
+keep: 1",
+    )
+    .unwrap();
+    let candidate = |title: &str, score| Candidate {
+        title: title.to_string(),
+        stack_path: vec![],
+        score,
+    };
+    // Failed candidates are ranked last, smaller scores are better
+    let evaluated = BTreeMap::from([
+        (vec![0, 0], candidate("a", None)),
+        (vec![0, 1], candidate("b", Some(2.))),
+        (vec![1, 0], candidate("c", Some(-1.))),
+    ]);
+    assert_eq!(options.select(&evaluated), [vec![1, 0], vec![0, 1]]);
+    let maximize = GeneticOptions {
+        maximize: true,
+        ..options.clone()
+    };
+    assert_eq!(maximize.select(&evaluated), [vec![0, 1], vec![1, 0]]);
+    // Without mutations each gene is inherited from a parent
+    let mut rng = StdRng::seed_from_u64(0);
+    let population = [vec![0, 1, 2], vec![3, 4, 5]];
+    for _ in 0..20 {
+        let child = options.breed(&mut rng, &population, 6);
+        for (index, gene) in child.iter().enumerate() {
+            assert!(*gene == population[0][index] || *gene == population[1][index]);
+        }
+    }
+    let mutated = GeneticOptions {
+        mutation_rate: 1.,
+        ..options.clone()
+    };
+    let children = (0..20)
+        .map(|_| mutated.breed(&mut rng, &population, 100))
+        .collect::<Vec<_>>();
+    assert!(children.iter().flatten().all(|gene| *gene < 100));
+    assert!(children.iter().flatten().any(|gene| *gene >= 6));
+    // The best `keep` candidates
+    let best = options.best(evaluated.into_values());
+    assert_eq!(best.len(), 1);
+    assert_eq!(best[0].title, "c");
+}
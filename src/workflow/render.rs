@@ -0,0 +1,118 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use lmers::utils::process::{new_command, with_modules, ProgramFailure};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::runner::rooted;
+
+/// Render an image of each structure file written by Output with an external
+/// program, e.g. `{program: obabel, args: ['{input}', '-O', '{output}']}`,
+/// or PyMOL and VMD with a script rendering the structure.
+///
+/// `{input}` and `{output}` in `args` are replaced by the absolute paths of the
+/// structure file and the image, which is `<name>.<extension>` in `directory`
+/// (e.g. the directory of a report), named like the structure file. The
+/// program runs in `directory` with the `envs` and `modules` like the programs
+/// of Calculation.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RenderOptions {
+    program: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    envs: BTreeMap<String, String>,
+    #[serde(default)]
+    modules: Vec<String>,
+    directory: PathBuf,
+    #[serde(default = "RenderOptions::default_extension")]
+    extension: String,
+}
+
+impl RenderOptions {
+    fn default_extension() -> String {
+        "png".to_string()
+    }
+
+    pub fn root_outputs(&mut self, directory: &Path) {
+        self.directory = rooted(directory, &self.directory);
+    }
+
+    /// The renderer program, checked by `--doctor`.
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    /// Render the structure file as the image of the name, returns the path
+    /// of the image.
+    pub fn render(&self, input: &Path, name: &str) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.directory)
+            .with_context(|| format!("Unable to create directory at {:?}", self.directory))?;
+        let input = std::path::absolute(input)?;
+        let output =
+            std::path::absolute(self.directory.join(format!("{}.{}", name, self.extension)))?;
+        if output.exists() {
+            // A stale image would hide a renderer writing nothing
+            std::fs::remove_file(&output)
+                .with_context(|| format!("Unable to remove previous image at {:?}", output))?;
+        }
+        let mut command = new_command(&self.program);
+        command
+            .current_dir(&self.directory)
+            .args(self.args.iter().map(|arg| {
+                arg.replace("{input}", &input.to_string_lossy())
+                    .replace("{output}", &output.to_string_lossy())
+            }))
+            .envs(&self.envs);
+        if !self.modules.is_empty() {
+            command = with_modules(&command, &self.modules);
+        }
+        let result = command.output().with_context(|| {
+            ProgramFailure(format!("Failed to start renderer {}", self.program))
+        })?;
+        if !result.status.success() {
+            Err(ProgramFailure(format!(
+                "Renderer {} failed for {:?} with {}: {}",
+                self.program,
+                input,
+                result.status,
+                String::from_utf8_lossy(&result.stderr).trim()
+            )))?
+        }
+        if !output.is_file() {
+            Err(anyhow!(
+                "Renderer {} wrote no image at {:?}",
+                self.program,
+                output
+            ))?
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn render_with_program() {
+    let directory = tempfile::tempdir().unwrap();
+    let input = directory.path().join("LME_Me.xyz");
+    std::fs::write(&input, "1\nLME_Me\nC 0 0 0\n").unwrap();
+    let mut options = serde_yaml::from_str::<RenderOptions>(
+        "program: sh
+args: ['-c', 'cp \"$0\" \"$1\"', '{input}', '{output}']
+directory: images
+extension: txt",
+    )
+    .unwrap();
+    options.root_outputs(directory.path());
+    let image = options.render(&input, "LME_Me").unwrap();
+    assert_eq!(image, directory.path().join("images").join("LME_Me.txt"));
+    assert!(std::fs::read_to_string(image).unwrap().contains("LME_Me"));
+    options.args = vec!["-c".to_string(), "echo broken >&2; exit 1".to_string()];
+    let error = options.render(&input, "LME_Me").unwrap_err();
+    assert!(error.to_string().contains("broken"));
+}
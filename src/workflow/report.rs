@@ -0,0 +1,281 @@
+use std::{collections::BTreeMap, fs::File, io::Write, ops::Range, path::Path, path::PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use plotters::prelude::*;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{runner::rooted, unit::EnergyUnit, variable::ScalarFile, workflow_data::Window};
+
+const PLOT_SIZE: (u32, u32) = (640, 400);
+
+/// A plot of the properties in the report.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(tag = "type", deny_unknown_fields)]
+pub enum Plot {
+    /// Distribution of a property, e.g. the energies of the conformers. With
+    /// `relative` set, the values are relative to the minimum, and with `unit`
+    /// set, the values (read in Hartree with a declared unit) are converted to
+    /// the unit, e.g. `kcal/mol`.
+    Histogram {
+        property: String,
+        #[serde(default = "Plot::default_bins")]
+        bins: usize,
+        #[serde(default)]
+        relative: bool,
+        #[serde(default)]
+        unit: Option<EnergyUnit>,
+    },
+    /// Scatter of two properties, e.g. two steric descriptors.
+    Scatter { x: String, y: String },
+}
+
+impl Plot {
+    fn default_bins() -> usize {
+        20
+    }
+
+    fn properties(&self) -> Vec<&str> {
+        match self {
+            Self::Histogram { property, .. } => vec![property],
+            Self::Scatter { x, y } => vec![x, y],
+        }
+    }
+
+    /// Render the plot as an SVG document.
+    fn render(&self, table: &BTreeMap<String, BTreeMap<String, f64>>) -> Result<String> {
+        let column = |name: &str| {
+            table
+                .values()
+                .filter_map(|row| row.get(name).copied())
+                .collect::<Vec<_>>()
+        };
+        let mut svg = String::new();
+        let root = SVGBackend::with_string(&mut svg, PLOT_SIZE).into_drawing_area();
+        root.fill(&WHITE)?;
+        match self {
+            Self::Histogram {
+                property,
+                bins,
+                relative,
+                unit,
+            } => {
+                let mut values = column(property);
+                let minimum = values.iter().copied().fold(f64::INFINITY, f64::min);
+                for value in values.iter_mut() {
+                    if *relative {
+                        *value -= minimum;
+                    }
+                    if let Some(unit) = unit {
+                        *value = unit.convert_hartree(*value);
+                    }
+                }
+                let range = padded_range(&values);
+                let bins = (*bins).max(1);
+                let width = (range.end - range.start) / bins as f64;
+                let mut counts = vec![0_u32; bins];
+                for value in values {
+                    let bin = ((value - range.start) / width) as usize;
+                    counts[bin.min(bins - 1)] += 1;
+                }
+                let max_count = counts.iter().copied().max().unwrap_or_default();
+                let mut chart = ChartBuilder::on(&root)
+                    .margin(10)
+                    .x_label_area_size(40)
+                    .y_label_area_size(50)
+                    .build_cartesian_2d(range.clone(), 0..max_count + 1)?;
+                chart
+                    .configure_mesh()
+                    .disable_x_mesh()
+                    .x_desc(property.as_str())
+                    .y_desc("count")
+                    .draw()?;
+                chart.draw_series(counts.into_iter().enumerate().map(|(bin, count)| {
+                    let start = range.start + bin as f64 * width;
+                    Rectangle::new([(start, 0), (start + width, count)], BLUE.mix(0.6).filled())
+                }))?;
+            }
+            Self::Scatter { x, y } => {
+                let points = table
+                    .values()
+                    .filter_map(|row| Some((*row.get(x)?, *row.get(y)?)))
+                    .collect::<Vec<_>>();
+                let x_values = points.iter().map(|(x, _)| *x).collect::<Vec<_>>();
+                let y_values = points.iter().map(|(_, y)| *y).collect::<Vec<_>>();
+                let mut chart = ChartBuilder::on(&root)
+                    .margin(10)
+                    .x_label_area_size(40)
+                    .y_label_area_size(50)
+                    .build_cartesian_2d(padded_range(&x_values), padded_range(&y_values))?;
+                chart
+                    .configure_mesh()
+                    .x_desc(x.as_str())
+                    .y_desc(y.as_str())
+                    .draw()?;
+                chart.draw_series(
+                    points
+                        .into_iter()
+                        .map(|point| Circle::new(point, 3, BLUE.mix(0.6).filled())),
+                )?;
+            }
+        }
+        root.present()?;
+        drop(root);
+        Ok(svg)
+    }
+}
+
+/// Range of the values with a margin, so that the points are not on the axes.
+fn padded_range(values: &[f64]) -> Range<f64> {
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if !min.is_finite() || !max.is_finite() {
+        return 0. ..1.;
+    }
+    let margin = if max > min {
+        (max - min) * 0.05
+    } else {
+        min.abs().max(1.) * 0.05
+    };
+    min - margin..max + margin
+}
+
+fn escape_html(content: &str) -> String {
+    content
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Write an HTML summary of the window, a table of the properties of each
+/// structure (see `ScalarFile`) with the plots embedded as SVG, so the results
+/// can be reviewed in a browser. Properties failed to read are left empty.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ReportOptions {
+    path: PathBuf,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    properties: BTreeMap<String, ScalarFile>,
+    #[serde(default)]
+    plots: Vec<Plot>,
+}
+
+impl ReportOptions {
+    pub fn root_outputs(&mut self, directory: &Path) {
+        self.path = rooted(directory, &self.path);
+    }
+
+    pub fn execute(&self, current_window: &Window) -> Result<()> {
+        for plot in &self.plots {
+            if let Some(name) = plot
+                .properties()
+                .into_iter()
+                .find(|name| !self.properties.contains_key(*name))
+            {
+                Err(anyhow!(
+                    "Property {} of the plot is not in the report",
+                    name
+                ))?
+            }
+        }
+        let mut table = current_window
+            .keys()
+            .map(|title| (title.to_string(), BTreeMap::new()))
+            .collect::<BTreeMap<_, _>>();
+        for (name, file) in &self.properties {
+            for (title, value) in file.read_window(current_window)? {
+                match value {
+                    Ok(value) => {
+                        table
+                            .get_mut(&title)
+                            .unwrap()
+                            .insert(name.to_string(), value);
+                    }
+                    Err(err) => println!("Unable to read {} of {}: {:#}", name, title, err),
+                }
+            }
+        }
+        let title = escape_html(self.title.as_deref().unwrap_or("LME report"));
+        let mut content = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+            <style>table {{ border-collapse: collapse; }} \
+            th, td {{ border: 1px solid #ccc; padding: 2px 8px; }}</style>\n\
+            </head>\n<body>\n<h1>{}</h1>\n<p>{} structures</p>\n",
+            title,
+            title,
+            table.len()
+        );
+        for plot in &self.plots {
+            content.push_str(&plot.render(&table)?);
+            content.push('\n');
+        }
+        content.push_str("<table>\n<tr><th>title</th>");
+        for name in self.properties.keys() {
+            content.push_str(&format!("<th>{}</th>", escape_html(name)));
+        }
+        content.push_str("</tr>\n");
+        for (title, row) in &table {
+            content.push_str(&format!("<tr><td>{}</td>", escape_html(title)));
+            for name in self.properties.keys() {
+                let value = row.get(name).map(f64::to_string).unwrap_or_default();
+                content.push_str(&format!("<td>{}</td>", value));
+            }
+            content.push_str("</tr>\n");
+        }
+        content.push_str("</table>\n</body>\n</html>\n");
+        File::create(&self.path)
+            .with_context(|| format!("Unable to create report at {:?}", self.path))?
+            .write_all(content.as_bytes())
+            .with_context(|| format!("Unable to write report at {:?}", self.path))?;
+        println!(
+            "Report of {} structures with {} plots written to {:?}",
+            table.len(),
+            self.plots.len(),
+            self.path
+        );
+        Ok(())
+    }
+}
+
+#[test]
+fn html_report() {
+    let directory = tempfile::tempdir().unwrap();
+    for (title, energy, volume) in [("a", -1.2, 10.), ("b", -1.5, 12.), ("c<", -1.0, 9.)] {
+        let calc = directory.path().join(title);
+        std::fs::create_dir_all(&calc).unwrap();
+        std::fs::write(calc.join("energy"), energy.to_string()).unwrap();
+        std::fs::write(calc.join("volume"), volume.to_string()).unwrap();
+    }
+    let window = Window::from_iter(
+        ["a", "b", "c<", "d"]
+            .into_iter()
+            .enumerate()
+            .map(|(index, title)| (title.to_string(), vec![index as u64])),
+    );
+    let mut options = serde_yaml::from_str::<ReportOptions>(&format!(
+        "path: report.html
+properties:
+  energy: {{path: '{0}/{{title}}/energy'}}
+  volume: {{path: '{0}/{{title}}/volume'}}
+plots:
+- {{type: Histogram, property: energy, relative: true, unit: kcal/mol}}
+- {{type: Scatter, x: volume, y: energy}}",
+        directory.path().display()
+    ))
+    .unwrap();
+    options.root_outputs(directory.path());
+    options.execute(&window).unwrap();
+    let content = std::fs::read_to_string(directory.path().join("report.html")).unwrap();
+    assert_eq!(content.matches("<svg").count(), 2);
+    assert!(content.contains("<tr><td>b</td><td>-1.5</td><td>12</td></tr>"));
+    assert!(content.contains("<tr><td>c&lt;</td>"));
+    assert!(content.contains("<tr><td>d</td><td></td><td></td></tr>"));
+    options.plots = vec![Plot::Scatter {
+        x: "volume".to_string(),
+        y: "missing".to_string(),
+    }];
+    assert!(options.execute(&window).is_err());
+}
@@ -2,29 +2,62 @@ use anyhow::{anyhow, Context, Result};
 use cached::{proc_macro::cached, SizedCache};
 use fancy_regex::Regex;
 use lmers::layer::{LayerStorageError, SelectMany};
-use lmers::utils::fs::copy_skeleton;
+use lmers::utils::fs::{link_skeleton, stage_files, SkeletonMode};
+use lmers::utils::process::{
+    new_command, wait_with_timeout, with_modules, ProgramFailure, ResourceUsage,
+};
 use nalgebra::Vector3;
 use std::collections::BTreeSet;
 use std::fs::File;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use std::{collections::BTreeMap, io::Write};
 
 use lmers::{
     external::{obabel::obabel, regexsed::regex_sed},
-    io::{BasicIOMolecule, NamespaceMapping},
+    io::{BasicIOMolecule, GaussianOptions, NamespaceMapping},
     layer::{Layer, SelectOne},
+    oniom::oniom_levels,
     sparse_molecule::SparseMolecule,
+    stereo::{
+        chirality, double_bond_geometry, flip_double_bond, inversion, stereo_double_bonds,
+        stereocenters,
+    },
 };
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{de::Error as _, Deserialize, Deserializer};
 use tempfile::tempdir;
 
 use glob::glob;
+use lazy_static::lazy_static;
 use rayon::prelude::*;
 
+use super::analyze::AnalyzeOptions;
+use super::boltzmann::BoltzmannOptions;
+use super::clash::ClashOptions;
+use super::cluster::{DeduplicateOptions, TorsionClusterOptions};
+use super::conformer::ConformerOptions;
+use super::container::ContainerOptions;
+use super::extract::ExtractOptions;
+use super::features::FeatureOptions;
+use super::frequency::FrequencyFilterOptions;
+use super::matrix::ForEachOptions;
+use super::measure::MeasureOptions;
+use super::mock::{run_mock, MOCK_PROGRAM};
+use super::optimizer::GeneticOptions;
+use super::render::RenderOptions;
+use super::report::ReportOptions;
+use super::scheduler::Scheduler;
+use super::selection::{pareto, FilterOptions, ParetoAxis, SortOptions};
+use super::steric::StericOptions;
+use super::thermo::ThermochemistryOptions;
+use super::variable::ScalarFile;
 use super::workflow_data::{LayerStorage, Window};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct RenameOptions {
     #[serde(default)]
     prefix: Option<String>,
@@ -53,7 +86,8 @@ impl RenameOptions {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct FormatOptions {
     format: String,
     #[serde(default)]
@@ -64,10 +98,309 @@ pub struct FormatOptions {
     openbabel: bool,
     #[serde(default)]
     regex: Vec<String>,
+    /// Decimal places of the coordinates, see `BasicIOMolecule::with_precision`.
+    /// The coordinates are written as computed if not set.
+    #[serde(default)]
+    precision: Option<usize>,
+    /// Write the namespace mapping next to the output file, with the extension
+    /// replaced by `map.json`.
     #[serde(default)]
     export_map: bool,
+    /// Write the namespace mapping next to the output file with the given file
+    /// name (e.g. `atommap.json`), implies `export_map`.
+    #[serde(default)]
+    map_filename: Option<String>,
+    /// Charge used by the `gjf` and `orca` formats. If not set here, the charge
+    /// of the structure (see the SetCharge layer) or the workflow is used, or
+    /// the sum of formal charges at last.
+    #[serde(default)]
+    charge: Option<i32>,
+    /// Multiplicity used by the `gjf` and `orca` formats. If not set here, the
+    /// multiplicity of the structure or the workflow is used, or the lowest
+    /// allowed by the electron count at last.
+    #[serde(default)]
+    multiplicity: Option<u32>,
+    /// Charge and multiplicity of the workflow, see `Runner::set_default_charge`
+    #[serde(skip)]
+    default_charge: (Option<i32>, Option<u32>),
+    /// Write a complete Gaussian input with the route, title and other
+    /// sections, only for the `gjf` format. Charge and multiplicity not set
+    /// here fall back to the ones above.
+    #[serde(default)]
+    gaussian: Option<GaussianOptions>,
+    /// Write the ONIOM level of each atom tagged by the Oniom layer and the
+    /// link atoms, requires `gaussian`.
+    #[serde(default)]
+    oniom: bool,
+}
+
+impl FormatOptions {
+    fn render(&self, structure: &SparseMolecule, title: &str) -> Result<String> {
+        let basic_molecule = BasicIOMolecule::from((structure.clone(), title.to_string()))
+            .with_precision(self.precision);
+        let charge = self.charge.or(structure.charge).or(self.default_charge.0);
+        let multiplicity = self
+            .multiplicity
+            .or(structure.multiplicity)
+            .or(self.default_charge.1);
+        let content = if let Some(gaussian) = &self.gaussian {
+            if self.format != "gjf" {
+                Err(anyhow!(
+                    "Gaussian options are only available for gjf format, found {}",
+                    self.format
+                ))?
+            }
+            let options = GaussianOptions {
+                charge: gaussian.charge.or(charge),
+                multiplicity: gaussian.multiplicity.or(multiplicity),
+                ..gaussian.clone()
+            };
+            if self.oniom {
+                basic_molecule.output_to_oniom(&options, &oniom_levels(structure))?
+            } else {
+                basic_molecule.output_to_gaussian(&options)?
+            }
+        } else if self.oniom {
+            Err(anyhow!("ONIOM output requires Gaussian options"))?
+        } else {
+            basic_molecule.output_with_charge(&self.format, charge, multiplicity)?
+        };
+        let content = if self.openbabel {
+            let format = openbabel_format(&self.format);
+            obabel(&content, format, format, false, false)?
+        } else {
+            content
+        };
+        let mut content = regex_sed(&content, &self.regex.join("; "))?;
+        if !self.prefix.is_empty() {
+            content = format!("{}\n{}", self.prefix, content)
+        }
+        if !self.suffix.is_empty() {
+            // gjf and orca inputs already end with a newline, and an empty
+            // line in them ends a section
+            if matches!(self.format.as_str(), "gjf" | "orca") && content.ends_with('\n') {
+                content.push_str(&self.suffix);
+            } else {
+                content = format!("{}\n{}", content, self.suffix)
+            }
+        }
+        Ok(content)
+    }
+
+    /// Write the structure to the path in the format, and the namespace mapping
+    /// file next to it if `export_map` or `map_filename` is set.
+    fn write(&self, structure: &SparseMolecule, title: &str, path: &Path) -> Result<()> {
+        let content = self.render(structure, title)?;
+        File::create(path)
+            .with_context(|| format!("Unable to create output file at {:?}", path))?
+            .write_all(content.as_bytes())
+            .with_context(|| format!("Unable to write to output file at {:?}", path))?;
+        if let Some(map_filename) = &self.map_filename {
+            NamespaceMapping::from(structure.clone())
+                .write_to(&path.with_file_name(map_filename))?;
+        } else if self.export_map {
+            NamespaceMapping::from(structure.clone())
+                .write_to(&NamespaceMapping::sidecar_path(path))?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of the format in openbabel, which differs for the ORCA input.
+fn openbabel_format(format: &str) -> &str {
+    match format {
+        "orca" => "orcainp",
+        format => format,
+    }
 }
 
+/// Expand the output path template for the structure.
+///
+/// `{title}` is replaced by the whole title, `{0}`, `{1}`... by the components of
+/// the title split by `_`, `{name}` by the component at the same position of
+/// `name` in `components`, and other `{name}` by the property of the structure
+/// read for `name`, which is an error if it failed to be read.
+fn expand_path_template(
+    template: &str,
+    title: &str,
+    components: &[String],
+    properties: &BTreeMap<String, Result<f64>>,
+) -> Result<PathBuf> {
+    let parts = title.split('_').collect::<Vec<_>>();
+    let mut path = template.replace("{title}", title);
+    for (index, part) in parts.iter().enumerate() {
+        path = path.replace(&format!("{{{}}}", index), part);
+    }
+    for (name, part) in components.iter().zip(parts.iter()) {
+        path = path.replace(&format!("{{{}}}", name), part);
+    }
+    for (name, value) in properties {
+        let placeholder = format!("{{{}}}", name);
+        if path.contains(&placeholder) {
+            let value = value.as_ref().map_err(|err| {
+                anyhow!(
+                    "Property {} in output path template {} is missing for structure {}: {:#}",
+                    name,
+                    template,
+                    title,
+                    err
+                )
+            })?;
+            path = path.replace(&placeholder, &value.to_string());
+        }
+    }
+    if let Ok(Some(unresolved)) = TEMPLATE_PLACEHOLDER_RE.find(&path) {
+        Err(anyhow!(
+            "Placeholder {} in output path template {} is not resolved for structure {}",
+            unresolved.as_str(),
+            template,
+            title
+        ))?
+    }
+    Ok(PathBuf::from(path))
+}
+
+/// How titles are converted to file and directory names.
+///
+/// Characters in `characters`, whitespaces and control characters are replaced
+/// by `replacement` and the name is truncated to `max_length` bytes. A title
+/// changed by the conversion gets a suffix of its hash, e.g. `a b` becomes
+/// `a-b-<16 hex digits>`, so different titles never share a name and the name
+/// of a title doesn't depend on the other titles of the window. Runners and
+/// captures reading the files of a Calculation find them with the same options.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SanitizeOptions {
+    #[serde(default = "SanitizeOptions::default_characters")]
+    characters: String,
+    #[serde(default = "SanitizeOptions::default_replacement")]
+    replacement: String,
+    #[serde(default = "SanitizeOptions::default_max_length")]
+    max_length: usize,
+}
+
+impl Default for SanitizeOptions {
+    fn default() -> Self {
+        Self {
+            characters: Self::default_characters(),
+            replacement: Self::default_replacement(),
+            max_length: Self::default_max_length(),
+        }
+    }
+}
+
+impl SanitizeOptions {
+    fn default_characters() -> String {
+        r#"/\:*?"<>|"#.to_string()
+    }
+
+    fn default_replacement() -> String {
+        "-".to_string()
+    }
+
+    fn default_max_length() -> usize {
+        200
+    }
+
+    fn sanitize(&self, title: &str, max_length: usize) -> String {
+        let mut name = String::new();
+        for c in title.chars() {
+            if c.is_whitespace() || c.is_control() || self.characters.contains(c) {
+                name.push_str(&self.replacement)
+            } else {
+                name.push(c)
+            }
+        }
+        if name.len() > max_length {
+            let mut end = max_length;
+            while !name.is_char_boundary(end) {
+                end -= 1;
+            }
+            name.truncate(end);
+        }
+        if name.is_empty() || name == "." || name == ".." {
+            name = self.replacement.repeat(name.len().max(1));
+        }
+        name
+    }
+
+    /// File system safe name of the title, unique among titles.
+    pub(crate) fn name(&self, title: &str) -> String {
+        let name = self.sanitize(title, self.max_length);
+        if name == title {
+            return name;
+        }
+        // stable across runs so the names of a restarted workflow match
+        let suffix = format!("-{:016x}", fnv1a(title));
+        format!(
+            "{}{}",
+            self.sanitize(title, self.max_length.saturating_sub(suffix.len())),
+            suffix
+        )
+    }
+}
+
+/// FNV-1a hash of the text, which unlike `DefaultHasher` is the same on all
+/// platforms and Rust releases.
+pub(crate) fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Files copied into the working directory of each structure before the
+/// calculation, as a glob pattern relative to the workflow directory, or with
+/// `symlink` to link the matched files instead of copying them (e.g. large
+/// basis set and ECP files).
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum StageIn {
+    Pattern(String),
+    Options {
+        pattern: String,
+        #[serde(default)]
+        symlink: bool,
+    },
+}
+
+impl StageIn {
+    fn stage(&self, working_directory: &Path) -> Result<()> {
+        let (pattern, symlink) = match self {
+            Self::Pattern(pattern) => (pattern, false),
+            Self::Options { pattern, symlink } => (pattern, *symlink),
+        };
+        if stage_files(pattern, working_directory, symlink)? == 0 {
+            Err(anyhow!("No file matched by stage_in pattern {}", pattern))?
+        }
+        Ok(())
+    }
+}
+
+/// Files copied out of the working directory of each structure after the
+/// calculation (even if it failed), the glob pattern is relative to the
+/// working directory, and `{title}` in the destination directory is replaced
+/// like the working directories, e.g. `{pattern: "*.chk", to: "chk/{title}"}`.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct StageOut {
+    pattern: String,
+    to: String,
+}
+
+impl StageOut {
+    fn stage(&self, working_directory: &Path, name: &str) -> Result<()> {
+        let pattern = working_directory.join(&self.pattern);
+        let destination = PathBuf::from(self.to.replace("{title}", name));
+        stage_files(&pattern.to_string_lossy(), &destination, false)?;
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref TEMPLATE_PLACEHOLDER_RE: Regex = Regex::new(r"\{[^{}/]*\}").unwrap();
+}
+
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 #[serde(untagged)]
 pub enum Property3D {
@@ -75,6 +408,7 @@ pub enum Property3D {
     Angle(SelectOne, SelectOne, SelectOne),
 }
 
+#[allow(dead_code)]
 impl Property3D {
     fn compute(&self, structure: &SparseMolecule) -> Result<f64, anyhow::Error> {
         match self {
@@ -95,6 +429,7 @@ impl Property3D {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct Retain3DItem {
     min: f64,
@@ -102,6 +437,7 @@ pub struct Retain3DItem {
     target: Property3D,
 }
 
+#[allow(dead_code)]
 impl Retain3DItem {
     fn is_valid(&self, structure: &SparseMolecule) -> Result<bool, anyhow::Error> {
         let result = self.target.compute(structure)?;
@@ -113,8 +449,9 @@ impl Retain3DItem {
     }
 }
 
-#[derive(Default, Debug, Deserialize)]
-#[serde(tag = "with")]
+#[allow(clippy::large_enum_variant)]
+#[derive(Default, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(tag = "with", deny_unknown_fields)]
 pub enum Runner {
     ManualBreak {
         filepath: String,
@@ -127,6 +464,15 @@ pub enum Runner {
         layers: Vec<Layer>,
     },
     DistributeLayers(BTreeMap<String, Layer>),
+    /// Superimpose the structures onto a reference, see `Layer::AlignTo`. The
+    /// reference is a named base of the workflow or a checkpoint of a single
+    /// structure, resolved by the workflow into an `AlignTo` layer appended to
+    /// the structures, so it can only be used as the runner of a step.
+    AlignTo {
+        reference: String,
+        #[serde(default)]
+        mapping: Vec<(SelectOne, SelectOne)>,
+    },
     Substituent {
         address: BTreeMap<String, (SelectOne, SelectOne)>,
         file_pattern: Vec<String>,
@@ -148,27 +494,168 @@ pub enum Runner {
         pre_filename: String,
         #[serde(default)]
         serial_mode: bool,
+        /// Max count of structures handled at the same time, e.g. the count of
+        /// licenses, in a thread pool of its own instead of the global one
+        #[serde(default)]
+        max_parallel: Option<usize>,
         #[serde(default)]
         skeleton: Option<PathBuf>,
+        /// Copy the skeleton files or link them, see `SkeletonMode`
+        #[serde(default)]
+        skeleton_mode: SkeletonMode,
+        /// Files staged into the working directory of each structure, see `StageIn`
+        #[serde(default)]
+        stage_in: Vec<StageIn>,
+        /// Files staged out of the working directory of each structure, see `StageOut`
+        #[serde(default)]
+        stage_out: Vec<StageOut>,
         #[serde(default)]
         redirect_to: Option<RenameOptions>,
         #[serde(default)]
         stdin: bool,
+        /// Program to run in the working directory of each structure,
+        /// `lme-mock` is a built-in mock backend, see `run_mock`
         #[serde(default)]
         program: Option<String>,
         #[serde(default)]
         args: Vec<String>,
         #[serde(default)]
         envs: BTreeMap<String, String>,
+        /// Run the program inside a container, see `ContainerOptions`
+        #[serde(default)]
+        container: Option<ContainerOptions>,
+        /// Environment modules loaded before running the program (or the
+        /// container engine), e.g. `[gaussian/16, openmpi/4]`
+        #[serde(default)]
+        modules: Vec<String>,
+        /// Submit the program of each structure to a batch scheduler instead
+        /// of running it on this node, see `Scheduler`
+        #[serde(default)]
+        scheduler: Option<Scheduler>,
+        /// Format and file name of the result to import after calculation, the
+        /// format can be a structure format or the output log of Gaussian
+        /// (`g16log`) and ORCA (`orcaout`). Properties read from the file (e.g.
+        /// energy, frequencies and ESP charges of the logs) are written to
+        /// `properties.json` in the working directory, with the resource usage
+        /// of the program (also for failed structures).
         #[serde(default)]
         post_file: Option<(String, String)>,
+        /// Import every frame of the post-calculation file (e.g. an optimization
+        /// trajectory) as a structure titled `<title>_<frame index>`, only the
+        /// first frame is imported by default.
+        #[serde(default)]
+        post_frames: bool,
+        /// Skip the program of a structure if its `post_file` already exists in
+        /// the working directory and can be imported, e.g. when the workflow is
+        /// run again after a crash. Only the results of programs exited
+        /// normally are reused (see `COMPLETED_MARKER`), as an interrupted
+        /// program may leave a truncated file which can still be imported.
+        #[serde(default)]
+        reuse_existing: bool,
+        /// Drop the failed structures instead of stopping the workflow, their
+        /// errors are written to `failures.json` in the working directory
         #[serde(default)]
         ignore_failed: bool,
+        /// Kill the program of a structure running longer than the seconds,
+        /// which fails the structure. Jobs of a scheduler should be limited by
+        /// its directives instead.
+        #[serde(default, deserialize_with = "positive_seconds")]
+        timeout_seconds: Option<f64>,
+        /// Run the program of a failed (or killed) structure again for at most
+        /// the times, the failures of each attempt are written to
+        /// `failures.json` in its working directory
+        #[serde(default)]
+        retries: usize,
+        /// Environment variables overriding `envs` in the retries, e.g. a larger
+        /// memory limit
+        #[serde(default)]
+        retry_envs: BTreeMap<String, String>,
         #[serde(default)]
         stdout: Option<String>,
         #[serde(default)]
         stderr: Option<String>,
+        /// How titles are converted to names of the working directories
+        #[serde(default)]
+        sanitize: SanitizeOptions,
+        /// Expected wall hours of each structure, only used by `--estimate`.
+        /// The average of the existing `resources.json` files in the working
+        /// directory is used if not given.
+        #[serde(default)]
+        estimated_hours: Option<f64>,
     },
+    Output {
+        path: String,
+        format: FormatOptions,
+        #[serde(default)]
+        components: Vec<String>,
+        /// How titles are converted before expanding the path template
+        #[serde(default)]
+        sanitize: SanitizeOptions,
+        /// Properties of the structures used as `{name}` in the path template,
+        /// e.g. `{charge}/{title}.xyz`, see `ScalarFile`
+        #[serde(default)]
+        properties: BTreeMap<String, ScalarFile>,
+        /// Render an image of each written file, see `RenderOptions`
+        #[serde(default)]
+        render: Option<RenderOptions>,
+    },
+    GeneticOptimize(GeneticOptions),
+    /// Enumerate the stereoisomers by inverting the stereocenters, all
+    /// perceived stereocenters are used if `centers` is not given. Each
+    /// isomer is titled by the descriptors of the centers, e.g. `<title>_2R_5S`.
+    /// At most 16 centers are enumerated for each structure.
+    Stereoisomers {
+        #[serde(default)]
+        centers: Vec<SelectOne>,
+    },
+    /// Enumerate the E/Z isomers by flipping the double bonds, all double
+    /// bonds with E/Z isomerism not in a ring are used if `bonds` is not given.
+    /// Each isomer is titled by the descriptors of the bonds, e.g.
+    /// `<title>_2-3E`. At most 16 bonds are enumerated for each structure.
+    DoubleBondIsomers {
+        #[serde(default)]
+        bonds: Vec<(SelectOne, SelectOne)>,
+    },
+    /// Cluster conformers by torsion fingerprints, see `TorsionClusterOptions`.
+    TorsionCluster(TorsionClusterOptions),
+    /// Write a feature table of the window, see `FeatureOptions`.
+    Features(FeatureOptions),
+    /// Filter by the count of imaginary frequencies, see `FrequencyFilterOptions`.
+    FrequencyFilter(FrequencyFilterOptions),
+    /// Write reaction and activation energies, see `ThermochemistryOptions`.
+    Thermochemistry(ThermochemistryOptions),
+    /// Keep the Pareto-optimal structures on the property axes, see `pareto`.
+    Pareto {
+        axes: Vec<ParetoAxis>,
+    },
+    /// Keep the structures matching the predicates, see `FilterOptions`.
+    Filter(FilterOptions),
+    /// Keep the best structures by a number read from files, see `SortOptions`.
+    Sort(SortOptions),
+    /// Remove the duplicated conformers by RMSD, see `DeduplicateOptions`.
+    DeduplicateByRMSD(DeduplicateOptions),
+    /// Run a runner for each combination of parameters, see `ForEachOptions`.
+    ForEach(ForEachOptions),
+    /// Write an HTML summary with plots of the properties, see `ReportOptions`.
+    Report(ReportOptions),
+    /// Write a results table of values extracted from the calculation outputs,
+    /// see `ExtractOptions`.
+    PropertyExtract(ExtractOptions),
+    /// Weight the structures by Boltzmann populations, see `BoltzmannOptions`.
+    Boltzmann(BoltzmannOptions),
+    /// Generate conformers by torsion enumeration, see `ConformerOptions`.
+    Conformers(ConformerOptions),
+    /// Write distances and angles of each structure, see `MeasureOptions`.
+    Measure(MeasureOptions),
+    /// Write the Sterimol parameters and cone angle of a substituent, see
+    /// `StericOptions`.
+    StericDescriptors(StericOptions),
+    /// Report, remove or rename the structures with clashed atoms, see
+    /// `ClashOptions`.
+    ClashCheck(ClashOptions),
+    /// Find the point groups of the structures and save the windows of each
+    /// point group, see `AnalyzeOptions`.
+    Analyze(AnalyzeOptions),
     #[default]
     CheckPoint,
 }
@@ -177,10 +664,84 @@ pub enum Runner {
 pub enum RunnerOutput {
     SingleWindow(Window),
     MultiWindow(BTreeMap<String, Window>),
+    /// Windows saved like MultiWindow, but only the `keep` window is passed to
+    /// the next step.
+    Partition {
+        windows: BTreeMap<String, Window>,
+        keep: String,
+    },
+    /// The window passed to the next step, with the errors of the structures
+    /// failed and ignored by the runner, keyed by their titles. The failed
+    /// structures are saved as checkpoint `<name>_failed` if the step is named.
+    WithFailures {
+        window: Window,
+        failures: BTreeMap<String, String>,
+    },
     None,
 }
 
 impl Runner {
+    /// Use the workflow-level charge and multiplicity for the structure writers
+    /// of the step, unless they are set in the step or the structures.
+    pub fn set_default_charge(&mut self, charge: Option<i32>, multiplicity: Option<u32>) {
+        if let Self::Calculation {
+            pre_format: format, ..
+        }
+        | Self::Output { format, .. } = self
+        {
+            format.default_charge = (charge, multiplicity);
+        }
+        if let Self::ForEach(options) = self {
+            options.set_default_charge(charge, multiplicity);
+        }
+    }
+
+    /// Put the relative paths of files and directories written by the step
+    /// under the directory, paths read by the step are not changed.
+    pub fn root_outputs(&mut self, directory: &Path) {
+        match self {
+            Self::Calculation {
+                working_directory,
+                stage_out,
+                ..
+            } => {
+                *working_directory = rooted(directory, working_directory);
+                for stage_out in stage_out {
+                    stage_out.to = rooted(directory, Path::new(&stage_out.to))
+                        .to_string_lossy()
+                        .to_string();
+                }
+            }
+            Self::Output { path, render, .. } => {
+                *path = rooted(directory, Path::new(path))
+                    .to_string_lossy()
+                    .to_string();
+                if let Some(render) = render {
+                    render.root_outputs(directory);
+                }
+            }
+            Self::Features(options) => options.root_outputs(directory),
+            Self::Thermochemistry(options) => options.root_outputs(directory),
+            Self::Report(options) => options.root_outputs(directory),
+            Self::PropertyExtract(options) => options.root_outputs(directory),
+            Self::Boltzmann(options) => options.root_outputs(directory),
+            Self::Measure(options) => options.root_outputs(directory),
+            Self::StericDescriptors(options) => options.root_outputs(directory),
+            Self::Analyze(options) => options.root_outputs(directory),
+            Self::GeneticOptimize(options) => options.root_outputs(directory),
+            Self::ForEach(options) => options.root_outputs(directory),
+            _ => {}
+        }
+    }
+
+    /// Name of the runner as written in the `with` field.
+    pub fn name(&self) -> String {
+        format!("{:?}", self)
+            .chars()
+            .take_while(|c| c.is_alphanumeric())
+            .collect()
+    }
+
     pub fn execute<'a>(
         &self,
         base: &SparseMolecule,
@@ -190,7 +751,7 @@ impl Runner {
         match self {
             Self::CheckPoint => Ok(RunnerOutput::None),
             Self::Retain { negate, pattern } => {
-                let regex = Regex::new(&pattern)
+                let regex = Regex::new(pattern)
                     .with_context(|| format!("Failed to create regex with {pattern}"))?;
                 let mut current_window = current_window.clone();
                 current_window.retain(|k, _| {
@@ -206,7 +767,7 @@ impl Runner {
                 let layer_ids = layer_storage.create_layers(layers);
                 Ok(RunnerOutput::SingleWindow(
                     current_window
-                        .into_iter()
+                        .iter()
                         .map(|(title, current)| {
                             let mut current = current.clone();
                             current.extend(layer_ids.clone());
@@ -215,6 +776,10 @@ impl Runner {
                         .collect(),
                 ))
             }
+            Self::AlignTo { reference, .. } => Err(anyhow!(
+                "Reference {} of AlignTo is resolved only for the runner of a step",
+                reference
+            )),
             Self::DistributeLayers(maps) => {
                 let new_layers = maps.values().cloned().collect::<Vec<_>>();
                 let new_layers = layer_storage.create_layers(&new_layers).collect::<Vec<_>>();
@@ -247,7 +812,7 @@ impl Runner {
                 let input = current_window
                     .into_par_iter()
                     .map(|(title, stack_path)| {
-                        Ok((title, cached_read_stack(base, &layer_storage, &stack_path)?))
+                        Ok((title, cached_read_stack(base, layer_storage, stack_path)?))
                     })
                     .collect::<Result<BTreeMap<_, _>>>()?;
                 let input = serde_json::to_string(&input)?;
@@ -266,16 +831,18 @@ impl Runner {
                         filepath
                     )
                 })?;
-                let exit_status = Command::new(&command)
+                let exit_status = new_command(command)
                     .args(arguments)
                     .current_dir(&temp_directory)
                     .status()
-                    .with_context(|| format!("Failed to start external program for {:#?}", self))?;
+                    .with_context(|| {
+                        ProgramFailure(format!("Failed to start external program for {:#?}", self))
+                    })?;
                 if !exit_status.success() {
-                    Err(anyhow!(
+                    Err(ProgramFailure(format!(
                         "External process exited with non-zero code {}",
                         exit_status.code().unwrap_or_default()
-                    ))?;
+                    )))?;
                 }
                 let filepath = temp_directory.path().join("output.json");
                 let file = File::open(&filepath).with_context(|| {
@@ -292,325 +859,355 @@ impl Runner {
             Self::Calculation {
                 working_directory,
                 serial_mode,
+                max_parallel,
                 pre_format,
                 pre_filename,
                 skeleton,
+                skeleton_mode,
+                stage_in,
+                stage_out,
                 stdin,
                 program,
                 args,
                 envs,
+                container,
+                modules,
+                scheduler,
                 post_file,
+                post_frames,
+                reuse_existing,
                 ignore_failed,
+                timeout_seconds,
+                retries,
+                retry_envs,
                 stdout,
                 stderr,
                 redirect_to,
+                sanitize,
+                estimated_hours: _,
             } => {
-                std::fs::create_dir_all(&working_directory).with_context(|| {
+                std::fs::create_dir_all(working_directory).with_context(|| {
                     format!("Unable to create directory at {:?}", working_directory)
                 })?;
+                let redirect = |title: &str| {
+                    if let Some(redirect_to) = redirect_to {
+                        redirect_to.rename(title)
+                    } else {
+                        Ok(title.to_string())
+                    }
+                };
+                // Resource usage of the structures failed after the program ran
+                let failed_usages = Mutex::new(BTreeMap::new());
                 let handler = |(title, stack_path): (&'a String, &'a Vec<u64>)| {
                     // Prepare the working directory
-                    let title = if let Some(redirect_to) = redirect_to {
-                        redirect_to.rename(title)?
-                    } else {
-                        title.to_string()
-                    };
-                    let working_directory = working_directory.join(&title);
+                    let title = redirect(title)?;
+                    let directory_name = sanitize.name(&title);
+                    let working_directory = working_directory.join(&directory_name);
                     std::fs::create_dir_all(&working_directory).with_context(|| {
                         format!(
                             "Unable to create directory at {:?} for structure titled {}",
                             working_directory, &title
                         )
                     })?;
-                    if let Some(skeleton) = skeleton {
-                        copy_skeleton(skeleton, &working_directory).with_context(|| {
-                            format!(
-                                "Unable to copy skeleton folder from {:?} to {:?}",
-                                skeleton, working_directory
-                            )
-                        })?
+                    let structure = cached_read_stack(base, layer_storage, stack_path)?;
+                    let completed = working_directory.join(COMPLETED_MARKER);
+                    let reusable = *reuse_existing && program.is_some() && completed.exists();
+                    if let Some(post_file) = post_file.as_ref().filter(|_| reusable) {
+                        if let Ok(structures) = read_post_file(
+                            &structure,
+                            &title,
+                            &working_directory,
+                            post_file,
+                            *post_frames,
+                        ) {
+                            println!("Existing result of structure {} reused", title);
+                            let usage = File::open(working_directory.join("resources.json"))
+                                .ok()
+                                .and_then(|file| serde_json::from_reader(file).ok())
+                                .unwrap_or_default();
+                            record_usage(&working_directory, &usage)?;
+                            return Ok((title, stack_path, structures, Some(usage)));
+                        }
                     }
-                    // Prepare the input file for external program
-                    let structure = cached_read_stack(base, &layer_storage, stack_path)?;
-                    let bonds = structure.bonds.clone().to_continuous_list(&structure.atoms);
-                    let atoms = structure.atoms.clone().into();
-                    let basic_molecule = BasicIOMolecule::new(title.to_string(), atoms, bonds);
-                    let pre_content = basic_molecule.output(&pre_format.format)?;
-                    let pre_content = if pre_format.openbabel {
-                        obabel(
-                            &pre_content,
-                            &pre_format.format,
-                            &pre_format.format,
-                            false,
-                            false,
+                    if let Some(skeleton) = skeleton {
+                        link_skeleton(skeleton, &working_directory, *skeleton_mode).with_context(
+                            || {
+                                format!(
+                                    "Unable to copy skeleton folder from {:?} to {:?}",
+                                    skeleton, working_directory
+                                )
+                            },
                         )?
-                    } else {
-                        pre_content
-                    };
-                    let mut pre_content = regex_sed(&pre_content, &pre_format.regex.join("; "))?;
-
-                    if pre_format.prefix.len() > 0 {
-                        pre_content = format!("{}\n{}", pre_format.prefix, pre_content)
                     }
-                    if pre_format.suffix.len() > 0 {
-                        pre_content = format!("{}\n{}", pre_content, pre_format.suffix)
-                    }
-
-                    let pre_path = working_directory.join(pre_filename);
-                    File::create(&pre_path)
-                        .with_context(|| {
-                            format!(
-                                "Unable to create pre-file for calculation at {:?}",
-                                pre_path
-                            )
-                        })?
-                        .write_all(pre_content.as_bytes())
-                        .with_context(|| {
-                            format!(
-                                "Unable to write to pre-file for calculation at {:?}",
-                                pre_path
-                            )
-                        })?;
-                    if pre_format.export_map {
-                        let mut map_file_path = working_directory.join(&pre_filename);
-                        map_file_path.set_extension("map.json");
-                        let content = NamespaceMapping::from(structure.clone());
-                        let file = File::create(&map_file_path).with_context(|| {
-                            format!("Unable to create map file at {:?}", map_file_path)
-                        })?;
-                        serde_json::to_writer(file, &content).with_context(|| {
-                            format!(
-                                "Unable to serialize map file at {:?}, content: {:#?}",
-                                map_file_path, content
-                            )
+                    for stage_in in stage_in {
+                        stage_in.stage(&working_directory).with_context(|| {
+                            format!("Unable to stage files in for structure {}", title)
                         })?;
                     }
+                    // Prepare the input file for external program
+                    let pre_path = working_directory.join(pre_filename);
+                    pre_format.write(&structure, &title, &pre_path)?;
                     // Execute the program
                     if let Some(program) = program {
-                        let mut command = Command::new(program);
-                        command
-                            .current_dir(&working_directory)
-                            .args(args)
-                            .envs(envs);
-                        if *stdin {
-                            let stdin = Stdio::from(File::open(&pre_path).with_context(|| {
-                                format!("Unable to open created pre-file at {:?}", pre_content)
-                            })?);
-                            command.stdin(stdin);
-                        }
-                        if let Some(stdout) = stdout {
-                            let stdout_path = working_directory.join(stdout);
-                            let stdout_file = File::create(&stdout_path).with_context(|| {
-                                format!(
-                                    "Unable to create stdout file at {:?} for structure titled {}",
-                                    stdout_path, title
-                                )
+                        if completed.exists() {
+                            std::fs::remove_file(&completed).with_context(|| {
+                                format!("Unable to remove completion marker at {:?}", completed)
                             })?;
-                            command.stdout(Stdio::from(stdout_file));
-                        } else {
-                            command.stdout(Stdio::null());
                         }
-
-                        if let Some(stderr) = stderr {
-                            let stderr_path = working_directory.join(stderr);
-                            let stderr_file = File::create(&stderr_path).with_context(|| {
-                                format!(
-                                    "Unable to create stdout file at {:?} for structure titled {}",
-                                    stderr_path, title
-                                )
+                        let (failure, usage) = if program == MOCK_PROGRAM {
+                            let started = Instant::now();
+                            run_mock(
+                                &structure,
+                                &title,
+                                args,
+                                post_file.as_ref(),
+                                &working_directory,
+                            )
+                            .with_context(|| {
+                                format!("Mock calculation failed for structure {}", title)
                             })?;
-                            command.stderr(Stdio::from(stderr_file));
+                            let usage = ResourceUsage {
+                                wall_time: started.elapsed().as_secs_f64(),
+                                ..Default::default()
+                            };
+                            (None, usage)
                         } else {
-                            command.stderr(Stdio::null());
-                        }
-
-                        let mut child = command.spawn().with_context(|| {
-                            format!(
-                                "Failed to start process for structure {}, process detail: {:#?}",
-                                title, command
-                            )
+                            let program_run = ProgramRun {
+                                program,
+                                args,
+                                container: container.as_ref(),
+                                modules,
+                                scheduler: scheduler.as_ref(),
+                                working_directory: &working_directory,
+                                job_name: &directory_name,
+                                title: &title,
+                                stdin: stdin.then_some(pre_filename.as_str()),
+                                stdout: stdout.as_deref(),
+                                stderr: stderr.as_deref(),
+                                timeout_seconds: *timeout_seconds,
+                            };
+                            let mut failures = vec![];
+                            let (failure, usage) = loop {
+                                let mut envs = envs.clone();
+                                if !failures.is_empty() {
+                                    envs.extend(retry_envs.clone());
+                                }
+                                let (failure, usage) = program_run.run(&envs)?;
+                                match failure {
+                                    Some(failure) if failures.len() < *retries => {
+                                        println!(
+                                            "Structure {} failed ({}), retry {}/{}",
+                                            title,
+                                            failure,
+                                            failures.len() + 1,
+                                            retries
+                                        );
+                                        failures.push(failure);
+                                    }
+                                    failure => {
+                                        failures.extend(failure.clone());
+                                        break (failure, usage);
+                                    }
+                                }
+                            };
+                            if !failures.is_empty() {
+                                let failures_path = working_directory.join("failures.json");
+                                let failures_file =
+                                    File::create(&failures_path).with_context(|| {
+                                        format!(
+                                            "Unable to create failure record at {:?}",
+                                            failures_path
+                                        )
+                                    })?;
+                                serde_json::to_writer_pretty(failures_file, &failures)
+                                    .with_context(|| {
+                                        format!(
+                                            "Unable to write failure record at {:?}",
+                                            failures_path
+                                        )
+                                    })?;
+                            }
+                            (failure, usage)
+                        };
+                        let usage_path = working_directory.join("resources.json");
+                        let usage_file = File::create(&usage_path).with_context(|| {
+                            format!("Unable to create resource usage file at {:?}", usage_path)
                         })?;
-                        let result = child.wait().with_context(|| format!("Unable to wait the process handling structure {}, process detail: {:#?}", title, child))?;
-
-                        if !result.success() {
-                            Err(anyhow!(
-                                "Handling process for structure {} failed. Error code {:?}",
-                                title,
-                                result.code()
-                            ))?;
-                        }
-                        if let Some((post_format, post_filename)) = post_file {
-                            let post_path = working_directory.join(post_filename);
-                            let post_file = File::open(&post_path).with_context(|| {
-                                format!(
-                                    "Failed to open post-calculation file at {:?} for structure {}",
-                                    post_path, title
-                                )
-                            })?;
-                            let post_content = BasicIOMolecule::input(&post_format, post_file)?;
-                            let updated_atoms = structure
-                                .atoms
-                                .update_from_continuous_list(&post_content.atoms)
-                                .with_context(|| {
-                                    format!(
-                                        "Failed to import atoms from calculated result for structure {}",
-                                        title
-                                    )
-                                })?;
-                            let updated_bonds = post_content
-                                .bonds
-                                .into_iter()
-                                .map(|(a, b, bond)| {
-                                    Some((
-                                        structure.atoms.from_continuous_index(a)?,
-                                        structure.atoms.from_continuous_index(b)?,
-                                        bond,
-                                    ))
-                                })
-                                .collect::<Option<Vec<_>>>()
+                        serde_json::to_writer_pretty(usage_file, &usage).with_context(|| {
+                            format!("Unable to write resource usage file at {:?}", usage_path)
+                        })?;
+
+                        for stage_out in stage_out {
+                            stage_out
+                                .stage(&working_directory, &directory_name)
                                 .with_context(|| {
-                                    format!(
-                                        "Failed to import bonds from calculated results for structure {}",
-                                        title
-                                    )
+                                    format!("Unable to stage files out for structure {}", title)
                                 })?;
-                            let mut structure = SparseMolecule::default();
-                            structure.extend_to(structure.len());
-                            structure.atoms.migrate(updated_atoms);
-                            for (a, b, bond) in updated_bonds {
-                                structure.bonds.set_bond(a, b, Some(bond));
-                            }
-                            Ok::<_, anyhow::Error>((title, stack_path, structure))
-                        } else {
-                            Ok((title, stack_path, SparseMolecule::default()))
                         }
+
+                        let structures = match post_file {
+                            Some(post_file) if failure.is_none() => read_post_file(
+                                &structure,
+                                &title,
+                                &working_directory,
+                                post_file,
+                                *post_frames,
+                            ),
+                            _ => Ok(vec![]),
+                        };
+                        // after the properties of the post file are written
+                        record_usage(&working_directory, &usage)?;
+                        if failure.is_some() || structures.is_err() {
+                            failed_usages
+                                .lock()
+                                .unwrap()
+                                .insert(title.to_string(), usage.clone());
+                        }
+                        if let Some(failure) = failure {
+                            Err(ProgramFailure(format!(
+                                "Handling process for structure {} failed. {}",
+                                title, failure
+                            )))?;
+                        }
+                        let structures = structures?;
+                        File::create(&completed).with_context(|| {
+                            format!("Unable to create completion marker at {:?}", completed)
+                        })?;
+                        Ok::<_, anyhow::Error>((title, stack_path, structures, Some(usage)))
                     } else {
-                        Ok((title, stack_path, SparseMolecule::default()))
+                        Ok((title, stack_path, vec![], None))
                     }
                 };
-                let results = if *serial_mode {
-                    let outputs = current_window.iter().map(handler);
+                let handle = |item: (&'a String, &'a Vec<u64>)| {
+                    handler(item).map_err(|err| (item.0.to_string(), err))
+                };
+                let parallel = || {
+                    let outputs = current_window.par_iter().map(handle);
                     if *ignore_failed {
-                        outputs.filter_map(|item| item.ok()).collect::<Vec<_>>()
+                        outputs.collect::<Vec<_>>()
                     } else {
-                        outputs.collect::<Result<Vec<_>>>()?
+                        // Stop at the first failure
+                        outputs
+                            .map(|output| output.map(Ok))
+                            .collect::<Result<Vec<_>, _>>()
+                            .unwrap_or_else(|failure| vec![Err(failure)])
                     }
-                } else {
-                    let outputs = current_window.par_iter().map(handler);
+                };
+                let outputs = if *serial_mode {
+                    let outputs = current_window.iter().map(handle);
                     if *ignore_failed {
-                        outputs.filter_map(|item| item.ok()).collect::<Vec<_>>()
+                        outputs.collect::<Vec<_>>()
                     } else {
-                        outputs.collect::<Result<Vec<_>>>()?
+                        outputs
+                            .map(|output| output.map(Ok))
+                            .collect::<Result<Vec<_>, _>>()
+                            .unwrap_or_else(|failure| vec![Err(failure)])
                     }
+                } else if let Some(threads) = max_parallel.or_else(|| {
+                    // Each thread waits for a job, so the count of threads
+                    // limits the jobs in the queue rather than the local cores
+                    let scheduler = scheduler.as_ref().filter(|_| program.is_some())?;
+                    Some(scheduler.max_jobs().min(current_window.len()))
+                }) {
+                    rayon::ThreadPoolBuilder::new()
+                        .num_threads(threads.max(1))
+                        .build()
+                        .with_context(|| "Unable to create threads for the calculations")?
+                        .install(parallel)
+                } else {
+                    parallel()
                 };
+                let mut results = vec![];
+                let mut failures = BTreeMap::new();
+                for output in outputs {
+                    match output {
+                        Ok(result) => results.push(result),
+                        Err((title, err)) if *ignore_failed => {
+                            failures.insert(title, format!("{:#}", err));
+                        }
+                        Err((_, err)) => Err(err)?,
+                    }
+                }
+                if *ignore_failed {
+                    report_failures(working_directory, &failures, current_window.len())?;
+                }
                 // Receive the execution result
+                let mut usages = failed_usages.into_inner().unwrap();
+                usages.extend(
+                    results.iter().filter_map(|(title, _, _, usage)| {
+                        Some((title.to_string(), usage.clone()?))
+                    }),
+                );
+                if let Some((title, usage)) = usages
+                    .iter()
+                    .max_by(|(_, a), (_, b)| a.wall_time.total_cmp(&b.wall_time))
+                {
+                    let report_path = working_directory.join("resources.json");
+                    let report_file = File::create(&report_path).with_context(|| {
+                        format!("Unable to create resource report at {:?}", report_path)
+                    })?;
+                    serde_json::to_writer_pretty(report_file, &usages).with_context(|| {
+                        format!("Unable to write resource report at {:?}", report_path)
+                    })?;
+                    println!(
+                        "Resource usage of {} calculations written to {:?}, the slowest is {} ({:.1} s)",
+                        usages.len(),
+                        report_path,
+                        title,
+                        usage.wall_time
+                    );
+                }
                 if post_file.is_some() {
                     let mut window = BTreeMap::new();
-                    for (title, stack_path, updated) in results {
-                        let updated_layer =
-                            layer_storage.create_layers(&[Layer::Fill { data: updated }]);
-                        let mut stack_path = stack_path.clone();
-                        stack_path.extend(updated_layer);
-                        window.insert(title.to_string(), stack_path);
+                    for (title, stack_path, structures, _) in results {
+                        let current = cached_read_stack(base, layer_storage, stack_path)?;
+                        for (index, updated) in structures.into_iter().enumerate() {
+                            let updated = Layer::Fill { data: updated }.filter(current.clone())?;
+                            let updated_layers =
+                                layer_storage.create_layers(&current.diff(&updated));
+                            let mut stack_path = stack_path.clone();
+                            stack_path.extend(updated_layers);
+                            let title = if *post_frames {
+                                format!("{}_{}", title, index)
+                            } else {
+                                title.to_string()
+                            };
+                            window.insert(title, stack_path);
+                        }
                     }
-                    Ok(RunnerOutput::SingleWindow(window))
-                } else {
+                    if failures.is_empty() {
+                        Ok(RunnerOutput::SingleWindow(window))
+                    } else {
+                        Ok(RunnerOutput::WithFailures { window, failures })
+                    }
+                } else if failures.is_empty() {
                     Ok(RunnerOutput::None)
+                } else {
+                    let mut window = current_window.clone();
+                    window.retain(|title, _| !failures.contains_key(title));
+                    Ok(RunnerOutput::WithFailures { window, failures })
                 }
             }
             Self::Substituent {
                 address,
                 file_pattern,
             } => {
-                let matched_files = file_pattern
-                    .iter()
-                    .map(|item| Ok(glob(item)?.collect::<Result<Vec<_>, _>>()?))
-                    .collect::<Result<Vec<_>>>()?;
-                let matched_files = matched_files.into_iter().flatten().collect::<BTreeSet<_>>();
-                let substituents = matched_files
-                    .into_par_iter()
-                    .map(|path| {
-                        let file = File::open(&path).with_context(|| {
-                            format!("Unable to open and deserialize matched file {:#?}", path)
-                        })?;
-                        let substituent_name = path
-                            .file_stem()
-                            .with_context(|| {
-                                format!("Unable to get file name from path {:?}", path)
-                            })?
-                            .to_string_lossy()
-                            .to_string();
-                        Ok((
-                            substituent_name,
-                            serde_yaml::from_reader(file).with_context(|| {
-                                format!("Unable to deserialize matched file {:?}", path)
-                            })?,
-                        ))
-                    })
-                    .collect::<Result<BTreeMap<String, SparseMolecule>>>()?;
-
+                let substituents = load_substituents(file_pattern)?;
                 let mut result = BTreeMap::new();
                 for (substituent_name, substituent) in substituents {
-                    let replace_atom =
-                        SelectOne::Index(1)
-                            .get_atom(&substituent)
-                            .with_context(|| {
-                                format!(
-                                    "Substituent must have at least 2 atoms, substituent title: {}",
-                                    substituent_name
-                                )
-                            })?;
                     let mut updated_stacks = BTreeMap::new();
                     for (current_title, stack_path) in current_window {
                         let title = format!("{}_{}", current_title, substituent_name);
                         let mut stack_path = stack_path.clone();
                         for (g_name, (center, replace)) in address {
-                            let current_structure =
-                                cached_read_stack(base, &layer_storage, &stack_path)?;
-                            let center_layer = Layer::SetCenter {
-                                select: center.clone(),
-                                center: Default::default(),
-                            };
-                            let align_layer = Layer::DirectionAlign {
-                                select: replace.clone(),
-                                direction: Vector3::x(),
-                            };
-                            let align_layers =
-                                layer_storage.create_layers(&[center_layer, align_layer]);
-                            let mut substituent = substituent.clone();
-                            SelectOne::Index(0).set_atom(&mut substituent, None);
-                            SelectOne::Index(1).set_atom(&mut substituent, None);
-                            let substituent = Layer::GroupMap {
-                                groups: vec![(g_name.to_string(), SelectMany::All)],
-                            }
-                            .filter(substituent)
-                            .expect("SelectOne error will never happend at substituent rename");
-                            let offset = current_structure.atoms.len();
-                            let mut substituent = substituent.offset(offset);
-                            substituent.ids = current_structure.ids.clone();
-                            replace
-                                .set_atom(&mut substituent, Some(replace_atom))
-                                .with_context(|| {
-                                    format!(
-                                        "The replace selector {:?} in {:?} is not validated",
-                                        replace, substituent
-                                    )
-                                })?;
-                            let replaced_index = replace.to_index(&substituent).unwrap();
-                            let updated_bonds = substituent
-                                .bonds
-                                .get_neighbors(offset + 1)
-                                .unwrap()
-                                .enumerate()
-                                .map(|(index, bond)| (replaced_index, index, bond.clone()))
-                                .collect::<Vec<_>>();
-                            for (a, b, bond) in updated_bonds {
-                                substituent.bonds.set_bond(a, b, bond);
-                            }
-                            stack_path.extend(align_layers);
-                            stack_path.extend(
-                                layer_storage.create_layers(&[Layer::Fill { data: substituent }]),
-                            );
+                            attach_substituent(
+                                base,
+                                layer_storage,
+                                &mut stack_path,
+                                g_name,
+                                (center, replace),
+                                (&substituent_name, &substituent),
+                            )?;
                         }
                         updated_stacks.insert(title, stack_path);
                     }
@@ -618,6 +1215,168 @@ impl Runner {
                 }
                 Ok(RunnerOutput::MultiWindow(result))
             }
+            Self::GeneticOptimize(options) => options.execute(base, current_window, layer_storage),
+            Self::Pareto { axes } => pareto(axes, current_window),
+            Self::Filter(options) => options.execute(base, current_window, layer_storage),
+            Self::Sort(options) => options.execute(current_window),
+            Self::Boltzmann(options) => options.execute(current_window),
+            Self::Conformers(options) => options.execute(base, current_window, layer_storage),
+            Self::ClashCheck(options) => options.execute(base, current_window, layer_storage),
+            Self::Analyze(options) => options.execute(base, current_window, layer_storage),
+            Self::DeduplicateByRMSD(options) => {
+                options.execute(base, current_window, layer_storage)
+            }
+            Self::ForEach(options) => options.execute(base, current_window, layer_storage),
+            Self::TorsionCluster(options) => options.execute(base, current_window, layer_storage),
+            Self::FrequencyFilter(options) => options.execute(base, current_window, layer_storage),
+            Self::Stereoisomers { centers } => {
+                let mut window = Window::new();
+                for (title, stack_path) in current_window {
+                    let structure = cached_read_stack(base, layer_storage, stack_path)?;
+                    let centers = if centers.is_empty() {
+                        stereocenters(&structure)
+                            .into_iter()
+                            .map(|(center, _)| center)
+                            .collect::<Vec<_>>()
+                    } else {
+                        centers
+                            .iter()
+                            .map(|center| {
+                                center.to_index(&structure).with_context(|| {
+                                    format!("Stereocenter {:?} not found in {}", center, title)
+                                })
+                            })
+                            .collect::<Result<Vec<_>>>()?
+                    };
+                    window.extend(enumerate_isomers(
+                        (title, stack_path, &structure),
+                        layer_storage,
+                        &centers,
+                        |isomer, center| {
+                            inversion(isomer, *center).with_context(|| {
+                                format!("Unable to invert atom {} of {}", center, title)
+                            })
+                        },
+                        |isomer, center| {
+                            let descriptor = chirality(isomer, *center)
+                                .map(|chirality| chirality.to_string())
+                                .unwrap_or("X".to_string());
+                            format!("{}{}", center, descriptor)
+                        },
+                    )?);
+                }
+                Ok(RunnerOutput::SingleWindow(window))
+            }
+            Self::DoubleBondIsomers { bonds } => {
+                let mut window = Window::new();
+                for (title, stack_path) in current_window {
+                    let structure = cached_read_stack(base, layer_storage, stack_path)?;
+                    let bonds = if bonds.is_empty() {
+                        stereo_double_bonds(&structure)
+                            .into_iter()
+                            .map(|(bond, _)| bond)
+                            .collect::<Vec<_>>()
+                    } else {
+                        bonds
+                            .iter()
+                            .map(|(b, c)| {
+                                b.to_index(&structure)
+                                    .zip(c.to_index(&structure))
+                                    .with_context(|| {
+                                        format!(
+                                            "Double bond {:?}={:?} not found in {}",
+                                            b, c, title
+                                        )
+                                    })
+                            })
+                            .collect::<Result<Vec<_>>>()?
+                    };
+                    window.extend(enumerate_isomers(
+                        (title, stack_path, &structure),
+                        layer_storage,
+                        &bonds,
+                        |isomer, (b, c)| {
+                            flip_double_bond(isomer, *b, *c).with_context(|| {
+                                format!("Unable to flip double bond {}={} of {}", b, c, title)
+                            })
+                        },
+                        |isomer, (b, c)| {
+                            let descriptor = double_bond_geometry(isomer, *b, *c)
+                                .map(|geometry| geometry.to_string())
+                                .unwrap_or("X".to_string());
+                            format!("{}-{}{}", b, c, descriptor)
+                        },
+                    )?);
+                }
+                Ok(RunnerOutput::SingleWindow(window))
+            }
+            Self::Features(options) => {
+                options.execute(base, current_window, layer_storage)?;
+                Ok(RunnerOutput::None)
+            }
+            Self::Thermochemistry(options) => {
+                options.execute(current_window)?;
+                Ok(RunnerOutput::None)
+            }
+            Self::Report(options) => {
+                options.execute(current_window)?;
+                Ok(RunnerOutput::None)
+            }
+            Self::PropertyExtract(options) => {
+                options.execute(current_window)?;
+                Ok(RunnerOutput::None)
+            }
+            Self::Measure(options) => {
+                options.execute(base, current_window, layer_storage)?;
+                Ok(RunnerOutput::None)
+            }
+            Self::StericDescriptors(options) => {
+                options.execute(base, current_window, layer_storage)?;
+                Ok(RunnerOutput::None)
+            }
+            Self::Output {
+                path,
+                format,
+                components,
+                sanitize,
+                properties,
+                render,
+            } => {
+                let mut values = current_window
+                    .keys()
+                    .map(|title| (title.to_string(), BTreeMap::new()))
+                    .collect::<BTreeMap<_, _>>();
+                for (name, file) in properties {
+                    for (title, value) in file.read_window(current_window)? {
+                        values
+                            .get_mut(&title)
+                            .unwrap()
+                            .insert(name.to_string(), value);
+                    }
+                }
+                current_window
+                    .par_iter()
+                    .map(|(title, stack_path)| {
+                        let name = sanitize.name(title);
+                        let output_path =
+                            expand_path_template(path, &name, components, &values[title])?;
+                        if let Some(parent) = output_path.parent() {
+                            std::fs::create_dir_all(parent).with_context(|| {
+                                format!("Unable to create directory at {:?}", parent)
+                            })?;
+                        }
+                        let structure = cached_read_stack(base, layer_storage, stack_path)?;
+                        format.write(&structure, title, &output_path)?;
+                        if let Some(render) = render {
+                            render
+                                .render(&output_path, &name)
+                                .with_context(|| format!("Unable to render structure {}", title))?;
+                        }
+                        Ok(())
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(RunnerOutput::None)
+            }
             Self::Rename(options) => Ok(RunnerOutput::SingleWindow(
                 current_window
                     .iter()
@@ -667,6 +1426,455 @@ impl Runner {
     }
 }
 
+/// Max number of sites enumerated by `enumerate_isomers`, which generates
+/// 2^n isomers for n sites.
+const MAX_ISOMER_SITES: usize = 16;
+
+/// Generate an isomer for each combination of the sites changed by the layer
+/// of `change`, the isomers are titled by the input title and the `label` of
+/// each site in the isomer, joined with `_`.
+fn enumerate_isomers<T>(
+    (title, stack_path, structure): (&str, &[u64], &SparseMolecule),
+    layer_storage: &LayerStorage,
+    sites: &[T],
+    change: impl Fn(&SparseMolecule, &T) -> Result<Layer>,
+    label: impl Fn(&SparseMolecule, &T) -> String,
+) -> Result<Window> {
+    if sites.len() > MAX_ISOMER_SITES {
+        Err(anyhow!(
+            "{} has {} sites to enumerate, more than the limit of {} ({} isomers)",
+            title,
+            sites.len(),
+            MAX_ISOMER_SITES,
+            1 << MAX_ISOMER_SITES
+        ))?
+    }
+    let mut window = Window::new();
+    for changed in 0..1_usize << sites.len() {
+        let mut isomer = structure.clone();
+        let mut layers = vec![];
+        for (bit, site) in sites.iter().enumerate() {
+            if changed & (1 << bit) != 0 {
+                let layer = change(&isomer, site)?;
+                isomer = layer.filter(isomer)?;
+                layers.push(layer);
+            }
+        }
+        let labels = sites.iter().map(|site| label(&isomer, site));
+        let mut stack_path = stack_path.to_vec();
+        stack_path.extend(layer_storage.create_layers(&layers));
+        window.insert(
+            [title.to_string()]
+                .into_iter()
+                .chain(labels)
+                .collect::<Vec<_>>()
+                .join("_"),
+            stack_path,
+        );
+    }
+    Ok(window)
+}
+
+/// The path under the directory if it's relative.
+pub(super) fn rooted(directory: &Path, path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        directory.join(path)
+    }
+}
+
+/// Convert the structure read from the post-calculation file to the namespace
+/// of the input structure, atoms are matched by the continuous index.
+fn import_calculated(
+    structure: &SparseMolecule,
+    post_content: BasicIOMolecule,
+) -> Result<SparseMolecule> {
+    let metadata = post_content.atom_metadata()?;
+    let updated_atoms = structure
+        .atoms
+        .update_from_continuous_list(&post_content.atoms)
+        .with_context(|| "Failed to import atoms from calculated result")?;
+    let updated_bonds = post_content
+        .bonds
+        .into_iter()
+        .map(|(a, b, bond)| {
+            Some((
+                structure.atoms.from_continuous_index(a)?,
+                structure.atoms.from_continuous_index(b)?,
+                bond,
+            ))
+        })
+        .collect::<Option<Vec<_>>>()
+        .with_context(|| "Failed to import bonds from calculated results")?;
+    let mut updated = SparseMolecule::default();
+    updated.atoms.migrate(updated_atoms);
+    for (a, b, bond) in updated_bonds {
+        updated.bonds.set_bond(a, b, Some(bond));
+    }
+    // Keep the partial charges of the calculation in the layers
+    for (continuous, metadata) in metadata.into_iter().enumerate() {
+        let index = structure
+            .atoms
+            .from_continuous_index(continuous)
+            .with_context(|| "Failed to import atom metadata from calculated results")?;
+        updated.atoms.set_metadata(index, metadata);
+    }
+    Ok(updated)
+}
+
+/// Write the errors of the failed structures to `failures.json` in the
+/// directory and print them as a table.
+fn report_failures(
+    directory: &Path,
+    failures: &BTreeMap<String, String>,
+    total: usize,
+) -> Result<()> {
+    let manifest_path = directory.join("failures.json");
+    let manifest_file = File::create(&manifest_path)
+        .with_context(|| format!("Unable to create failure manifest at {:?}", manifest_path))?;
+    serde_json::to_writer_pretty(manifest_file, failures)
+        .with_context(|| format!("Unable to write failure manifest at {:?}", manifest_path))?;
+    if failures.is_empty() {
+        return Ok(());
+    }
+    println!(
+        "{} of {} structures failed and ignored, written to {:?}",
+        failures.len(),
+        total,
+        manifest_path
+    );
+    print!("{}", failure_table(failures));
+    Ok(())
+}
+
+/// Title and the first line of the error of each failed structure.
+fn failure_table(failures: &BTreeMap<String, String>) -> String {
+    let width = failures.keys().map(String::len).max().unwrap_or_default();
+    failures
+        .iter()
+        .map(|(title, error)| {
+            let error = error.lines().next().unwrap_or_default();
+            format!("  {:<width$}  {}\n", title, error, width = width)
+        })
+        .collect()
+}
+
+/// The structures of the window failed in `failures`, saved as the checkpoint
+/// `<name>_failed` of a `WithFailures` output.
+pub fn failed_structures(window: &Window, failures: &BTreeMap<String, String>) -> Window {
+    window
+        .iter()
+        .filter(|(title, _)| failures.contains_key(*title))
+        .map(|(title, stack_path)| (title.to_string(), stack_path.clone()))
+        .collect()
+}
+
+/// File created in the working directory of a structure after its program
+/// exited normally and the result was imported, see `reuse_existing`.
+const COMPLETED_MARKER: &str = ".completed";
+
+/// Add the resource usage of the program to `properties.json` in the working
+/// directory, see `ResourceUsage::properties`.
+fn record_usage(working_directory: &Path, usage: &ResourceUsage) -> Result<()> {
+    let properties_path = working_directory.join("properties.json");
+    let mut properties: BTreeMap<String, String> = File::open(&properties_path)
+        .ok()
+        .and_then(|file| serde_json::from_reader(file).ok())
+        .unwrap_or_default();
+    properties.extend(usage.properties());
+    let properties_file = File::create(&properties_path)
+        .with_context(|| format!("Unable to create properties file at {:?}", properties_path))?;
+    serde_json::to_writer_pretty(properties_file, &properties)
+        .with_context(|| format!("Unable to write properties file at {:?}", properties_path))
+}
+
+/// Import the structures of the post-calculation file in the working directory,
+/// properties of the last frame are written to `properties.json`.
+fn read_post_file(
+    structure: &SparseMolecule,
+    title: &str,
+    working_directory: &Path,
+    (post_format, post_filename): &(String, String),
+    post_frames: bool,
+) -> Result<Vec<SparseMolecule>> {
+    let post_path = working_directory.join(post_filename);
+    let post_file = File::open(&post_path).with_context(|| {
+        format!(
+            "Failed to open post-calculation file at {:?} for structure {}",
+            post_path, title
+        )
+    })?;
+    let frames = if post_frames {
+        BasicIOMolecule::input_multi(post_format, post_file)?
+    } else {
+        vec![BasicIOMolecule::input(post_format, post_file)?]
+    };
+    if frames.is_empty() {
+        Err(anyhow!(
+            "No frame found in post-calculation file at {:?} for structure {}",
+            post_path,
+            title
+        ))?
+    }
+    if let Some(properties) = frames
+        .last()
+        .map(|frame| &frame.properties)
+        .filter(|properties| !properties.is_empty())
+    {
+        let properties_path = working_directory.join("properties.json");
+        let properties_file = File::create(&properties_path).with_context(|| {
+            format!("Unable to create properties file at {:?}", properties_path)
+        })?;
+        serde_json::to_writer_pretty(properties_file, properties)
+            .with_context(|| format!("Unable to write properties file at {:?}", properties_path))?;
+    }
+    frames
+        .into_iter()
+        .map(|post_content| {
+            import_calculated(structure, post_content).with_context(|| {
+                format!("Failed to import calculated result for structure {}", title)
+            })
+        })
+        .collect()
+}
+
+/// Load substituents from files matched by the glob patterns, the substituent
+/// name is the file stem.
+pub(super) fn load_substituents(
+    file_pattern: &[String],
+) -> Result<BTreeMap<String, SparseMolecule>> {
+    let matched_files = file_pattern
+        .iter()
+        .map(|item| Ok(glob(item)?.collect::<Result<Vec<_>, _>>()?))
+        .collect::<Result<Vec<_>>>()?;
+    let matched_files = matched_files.into_iter().flatten().collect::<BTreeSet<_>>();
+    matched_files
+        .into_par_iter()
+        .map(|path| {
+            let file = File::open(&path).with_context(|| {
+                format!("Unable to open and deserialize matched file {:#?}", path)
+            })?;
+            let substituent_name = path
+                .file_stem()
+                .with_context(|| format!("Unable to get file name from path {:?}", path))?
+                .to_string_lossy()
+                .to_string();
+            Ok((
+                substituent_name,
+                serde_yaml::from_reader(file)
+                    .with_context(|| format!("Unable to deserialize matched file {:?}", path))?,
+            ))
+        })
+        .collect()
+}
+
+/// Replace the atom selected by `replace` (bonded to `center`) with the
+/// substituent, the created layers are appended to the stack path.
+///
+/// The first atom of the substituent is the dummy atom at the position of
+/// `center`, and the second one replaces the atom selected by `replace`.
+pub(super) fn attach_substituent(
+    base: &SparseMolecule,
+    layer_storage: &LayerStorage,
+    stack_path: &mut Vec<u64>,
+    g_name: &str,
+    (center, replace): (&SelectOne, &SelectOne),
+    (substituent_name, substituent): (&str, &SparseMolecule),
+) -> Result<()> {
+    let replace_atom = SelectOne::Index(1).get_atom(substituent).with_context(|| {
+        format!(
+            "Substituent must have at least 2 atoms, substituent title: {}",
+            substituent_name
+        )
+    })?;
+    let current_structure = cached_read_stack(base, layer_storage, stack_path)?;
+    let center_layer = Layer::SetCenter {
+        select: center.clone(),
+        center: Default::default(),
+    };
+    let align_layer = Layer::DirectionAlign {
+        select: replace.clone(),
+        direction: Vector3::x(),
+    };
+    let align_layers = layer_storage.create_layers(&[center_layer, align_layer]);
+    let mut substituent = substituent.clone();
+    SelectOne::Index(0).set_atom(&mut substituent, None);
+    SelectOne::Index(1).set_atom(&mut substituent, None);
+    let substituent = Layer::GroupMap {
+        groups: vec![(g_name.to_string(), SelectMany::All)],
+    }
+    .filter(substituent)
+    .expect("SelectOne error will never happend at substituent rename");
+    let offset = current_structure.atoms.len();
+    let mut substituent = substituent.offset(offset);
+    substituent.ids = current_structure.ids.clone();
+    replace
+        .set_atom(&mut substituent, Some(replace_atom))
+        .with_context(|| {
+            format!(
+                "The replace selector {:?} in {:?} is not validated",
+                replace, substituent
+            )
+        })?;
+    let replaced_index = replace.to_index(&substituent).unwrap();
+    let updated_bonds = substituent
+        .bonds
+        .get_neighbors(offset + 1)
+        .unwrap()
+        .enumerate()
+        .map(|(index, bond)| (replaced_index, index, *bond))
+        .collect::<Vec<_>>();
+    for (a, b, bond) in updated_bonds {
+        substituent.bonds.set_bond(a, b, bond);
+    }
+    stack_path.extend(align_layers);
+    stack_path.extend(layer_storage.create_layers(&[Layer::Fill { data: substituent }]));
+    Ok(())
+}
+
+/// Timeout in seconds, which must be a positive finite number.
+fn positive_seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    let seconds = Option::<f64>::deserialize(deserializer)?;
+    match seconds {
+        Some(seconds) if !(seconds.is_finite() && seconds > 0.) => Err(D::Error::custom(format!(
+            "timeout must be a positive number of seconds, got {}",
+            seconds
+        ))),
+        _ => Ok(seconds),
+    }
+}
+
+/// External program of Calculation run in the working directory of a structure.
+struct ProgramRun<'a> {
+    program: &'a str,
+    args: &'a [String],
+    container: Option<&'a ContainerOptions>,
+    modules: &'a [String],
+    scheduler: Option<&'a Scheduler>,
+    working_directory: &'a Path,
+    job_name: &'a str,
+    title: &'a str,
+    /// File names in the working directory
+    stdin: Option<&'a str>,
+    stdout: Option<&'a str>,
+    stderr: Option<&'a str>,
+    timeout_seconds: Option<f64>,
+}
+
+impl ProgramRun<'_> {
+    /// Run the program once with the environment variables, the failure is the
+    /// reason if the program failed or was killed.
+    fn run(&self, envs: &BTreeMap<String, String>) -> Result<(Option<String>, ResourceUsage)> {
+        let title = self.title;
+        let container_name = ContainerOptions::container_name();
+        let mut command = if let Some(container) = self.container {
+            container
+                .command(
+                    &container_name,
+                    self.working_directory,
+                    self.program,
+                    self.args,
+                    envs,
+                )
+                .with_context(|| format!("Unable to run container for structure {}", title))?
+        } else {
+            let mut command = new_command(self.program);
+            command
+                .current_dir(self.working_directory)
+                .args(self.args)
+                .envs(envs);
+            command
+        };
+        if !self.modules.is_empty() {
+            command = with_modules(&command, self.modules);
+        }
+        if let Some(scheduler) = self.scheduler {
+            let (status, usage) = scheduler
+                .run(
+                    &command,
+                    self.job_name,
+                    self.working_directory,
+                    self.stdin,
+                    self.stdout,
+                    self.stderr,
+                )
+                .with_context(|| format!("Unable to run job for structure {}", title))?;
+            let failure = (!status.success()).then(|| {
+                format!(
+                    "Job {} ended in state {}, exit code {:?}",
+                    status.id, status.state, status.exit_code
+                )
+            });
+            return Ok((failure, usage));
+        }
+        #[cfg(unix)]
+        if self.timeout_seconds.is_some() {
+            // Lead a process group to be killed with the programs it started
+            std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        }
+        if let Some(stdin) = self.stdin {
+            let pre_path = self.working_directory.join(stdin);
+            let stdin = File::open(&pre_path)
+                .with_context(|| format!("Unable to open created pre-file at {:?}", pre_path))?;
+            command.stdin(Stdio::from(stdin));
+        }
+        if let Some(stdout) = self.stdout {
+            let stdout_path = self.working_directory.join(stdout);
+            let stdout_file = File::create(&stdout_path).with_context(|| {
+                format!(
+                    "Unable to create stdout file at {:?} for structure titled {}",
+                    stdout_path, title
+                )
+            })?;
+            command.stdout(Stdio::from(stdout_file));
+        } else {
+            command.stdout(Stdio::null());
+        }
+        if let Some(stderr) = self.stderr {
+            let stderr_path = self.working_directory.join(stderr);
+            let stderr_file = File::create(&stderr_path).with_context(|| {
+                format!(
+                    "Unable to create stderr file at {:?} for structure titled {}",
+                    stderr_path, title
+                )
+            })?;
+            command.stderr(Stdio::from(stderr_file));
+        } else {
+            command.stderr(Stdio::null());
+        }
+        let started = Instant::now();
+        let mut child = command.spawn().with_context(|| {
+            ProgramFailure(format!(
+                "Failed to start process for structure {}, process detail: {:#?}",
+                title, command
+            ))
+        })?;
+        let timeout = self.timeout_seconds.map(Duration::from_secs_f64);
+        let (result, usage) =
+            wait_with_timeout(&mut child, started, timeout).with_context(|| {
+                format!(
+                    "Unable to wait the process handling structure {}, process detail: {:#?}",
+                    title, child
+                )
+            })?;
+        if let (None, Some(container)) = (&result, self.container) {
+            container
+                .kill(&container_name)
+                .with_context(|| format!("Unable to stop the container of structure {}", title))?;
+        }
+        let failure = match result {
+            None => Some(format!(
+                "Killed after {} seconds",
+                self.timeout_seconds.unwrap_or_default()
+            )),
+            Some(result) if !result.success() => Some(format!("Error code {:?}", result.code())),
+            Some(_) => None,
+        };
+        Ok((failure, usage))
+    }
+}
+
 /// In a workflow, the base and existed layers will not be modified or deleted,
 /// so the result of read_stack function is in fact only dependent on the path
 /// parameter so create a cached function here is reasonable.
@@ -694,3 +1902,269 @@ pub fn cached_read_stack(
         Ok(base.clone())
     }
 }
+
+#[test]
+fn output_path_template() {
+    let components = vec!["base".to_string(), "substituent".to_string()];
+    assert_eq!(
+        expand_path_template(
+            "output/{substituent}/{2}/final.xyz",
+            "LME_CF3_A1",
+            &components,
+            &BTreeMap::new()
+        )
+        .unwrap(),
+        PathBuf::from("output/CF3/A1/final.xyz")
+    );
+    assert_eq!(
+        expand_path_template("{title}.xyz", "LME_CF3", &[], &BTreeMap::new()).unwrap(),
+        PathBuf::from("LME_CF3.xyz")
+    );
+    assert!(expand_path_template("{3}/final.xyz", "LME_CF3", &[], &BTreeMap::new()).is_err());
+    let properties = BTreeMap::from([
+        ("charge".to_string(), Ok(-1.)),
+        ("energy".to_string(), Err(anyhow!("No file matched"))),
+    ]);
+    assert_eq!(
+        expand_path_template("{charge}/{title}.xyz", "LME_CF3", &[], &properties).unwrap(),
+        PathBuf::from("-1/LME_CF3.xyz")
+    );
+    let missing = expand_path_template("{energy}/{title}.xyz", "LME_CF3", &[], &properties)
+        .unwrap_err()
+        .to_string();
+    assert!(missing.contains("Property energy") && missing.contains("LME_CF3"));
+}
+
+#[test]
+fn sanitize_titles() {
+    let options = SanitizeOptions {
+        max_length: 26,
+        ..Default::default()
+    };
+    assert_eq!(options.name("LME_CF3"), "LME_CF3");
+    assert_eq!(options.name("a b"), "a-b-e63f991904833892");
+    let (slash, colon) = (options.name("LME_C/F3"), options.name("LME_C:F3"));
+    assert!(slash.starts_with("LME_C-F3-") && colon.starts_with("LME_C-F3-"));
+    assert_ne!(slash, colon);
+    assert!(options.name("..").starts_with("---"));
+    let long = options.name("LME_verylongnameverylongname");
+    assert!(long.starts_with("LME_veryl-"));
+    assert_eq!(long.len(), 26);
+}
+
+#[test]
+fn root_step_outputs() {
+    let mut runner: Runner = serde_yaml::from_str(
+        "with: Calculation\nworking_directory: calc\npre_format: {format: xyz}\npre_filename: a.xyz",
+    )
+    .unwrap();
+    runner.root_outputs(Path::new("run_1/3_opt"));
+    let Runner::Calculation {
+        working_directory, ..
+    } = &runner
+    else {
+        panic!("Calculation expected")
+    };
+    assert_eq!(working_directory, Path::new("run_1/3_opt/calc"));
+    let mut runner: Runner =
+        serde_yaml::from_str("with: Output\npath: /tmp/{title}.xyz\nformat: {format: xyz}")
+            .unwrap();
+    runner.root_outputs(Path::new("run_1/4_Output"));
+    assert_eq!(runner.name(), "Output");
+    assert!(matches!(runner, Runner::Output { path, .. } if path == "/tmp/{title}.xyz"));
+}
+
+#[test]
+fn structure_charge_for_writers() {
+    use lmers::chemistry::Atom3D;
+    let mut runner: Runner =
+        serde_yaml::from_str("with: Output\npath: '{title}.gjf'\nformat: {format: gjf}").unwrap();
+    runner.set_default_charge(Some(1), Some(2));
+    let Runner::Output { format, .. } = &runner else {
+        panic!("Output expected")
+    };
+    let mut structure = SparseMolecule::default();
+    structure.atoms.set_atoms(
+        0,
+        vec![Some(Atom3D {
+            element: 8,
+            ..Default::default()
+        })],
+    );
+    assert!(format
+        .render(&structure, "oxygen")
+        .unwrap()
+        .contains("\n1 2\n"));
+    let structure = Layer::SetCharge {
+        charge: Some(-2),
+        multiplicity: None,
+    }
+    .filter(structure)
+    .unwrap();
+    assert!(format
+        .render(&structure, "oxygen")
+        .unwrap()
+        .contains("\n-2 2\n"));
+    let suffixed: FormatOptions = serde_yaml::from_str("{format: gjf, suffix: end}").unwrap();
+    assert!(suffixed
+        .render(&structure, "oxygen")
+        .unwrap()
+        .ends_with("\n\nend"));
+    let suffixed: FormatOptions = serde_yaml::from_str("{format: xyz, suffix: end}").unwrap();
+    let xyz = suffixed.render(&structure, "oxygen").unwrap();
+    assert!(xyz.ends_with("\nend"));
+}
+
+#[test]
+fn limit_isomer_sites() {
+    let directory = tempfile::tempdir().unwrap();
+    let storage = LayerStorage::new(directory.path().join(".layers.db"));
+    let structure = SparseMolecule::default();
+    let enumerate = |sites: &[usize]| {
+        enumerate_isomers(
+            ("mol", &[], &structure),
+            &storage,
+            sites,
+            |_, _| Ok(Layer::Transparent),
+            |_, site| site.to_string(),
+        )
+    };
+    assert!(enumerate(&[1, 2]).is_ok());
+    let sites = (0..MAX_ISOMER_SITES + 1).collect::<Vec<_>>();
+    assert!(enumerate(&sites).unwrap_err().to_string().contains("limit"));
+}
+
+#[test]
+fn validate_timeout() {
+    let calculation = "with: Calculation\nworking_directory: calc\npre_format: {format: xyz}\npre_filename: a.xyz\ntimeout_seconds: ";
+    for invalid in ["-1", "0", ".nan", ".inf"] {
+        let runner = serde_yaml::from_str::<Runner>(&format!("{}{}", calculation, invalid));
+        assert!(runner.is_err(), "timeout {} accepted", invalid);
+    }
+    serde_yaml::from_str::<Runner>(&format!("{}{}", calculation, "1.5")).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn calculation_failure_is_program_failure() {
+    let directory = tempfile::tempdir().unwrap();
+    let storage = LayerStorage::new(directory.path().join(".layers.db"));
+    let mut runner: Runner = serde_yaml::from_str(
+        "with: Calculation\nworking_directory: calc\npre_format: {format: xyz}\npre_filename: a.xyz\nprogram: \"false\"",
+    )
+    .unwrap();
+    runner.root_outputs(directory.path());
+    let window = Window::from([("mol".to_string(), vec![])]);
+    let err = runner
+        .execute(&SparseMolecule::default(), &window, &storage)
+        .unwrap_err();
+    assert!(err.downcast_ref::<ProgramFailure>().is_some());
+    assert_eq!(
+        super::exit::Exit::Internal.of(&err),
+        super::exit::Exit::Program
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn ignore_failed_structures() {
+    let directory = tempfile::tempdir().unwrap();
+    let storage = LayerStorage::new(directory.path().join(".layers.db"));
+    let mut runner: Runner = serde_yaml::from_str(
+        "with: Calculation
+working_directory: calc
+pre_format: {format: xyz}
+pre_filename: a.xyz
+program: sh
+args: [-c, 'case \"$PWD\" in *bad) echo broken >&2; exit 3;; esac']
+ignore_failed: true",
+    )
+    .unwrap();
+    runner.root_outputs(directory.path());
+    let window = Window::from([("bad".to_string(), vec![]), ("good".to_string(), vec![])]);
+    let RunnerOutput::WithFailures {
+        window: passed,
+        failures,
+    } = runner
+        .execute(&SparseMolecule::default(), &window, &storage)
+        .unwrap()
+    else {
+        panic!("WithFailures expected")
+    };
+    assert_eq!(passed.keys().collect::<Vec<_>>(), ["good"]);
+    assert_eq!(failures.keys().collect::<Vec<_>>(), ["bad"]);
+    assert!(failures["bad"].contains("Error code Some(3)"));
+    let failed = failed_structures(&window, &failures);
+    assert_eq!(failed, Window::from([("bad".to_string(), vec![])]));
+    let manifest = std::fs::read_to_string(directory.path().join("calc/failures.json")).unwrap();
+    let manifest: BTreeMap<String, String> = serde_json::from_str(&manifest).unwrap();
+    assert_eq!(manifest, failures);
+    // Resource usage of the failed structures is recorded too
+    let read_json = |path: &str| -> BTreeMap<String, serde_json::Value> {
+        serde_json::from_reader(File::open(directory.path().join(path)).unwrap()).unwrap()
+    };
+    assert!(read_json("calc/bad/properties.json").contains_key("wall_time"));
+    assert!(read_json("calc/good/properties.json").contains_key("wall_time"));
+    let report = read_json("calc/resources.json");
+    assert_eq!(report.keys().collect::<Vec<_>>(), ["bad", "good"]);
+    let table = failure_table(&BTreeMap::from([
+        ("a".to_string(), "first line\nsecond line".to_string()),
+        ("long_title".to_string(), "failed".to_string()),
+    ]));
+    assert_eq!(table, "  a           first line\n  long_title  failed\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn reuse_existing_results() {
+    let directory = tempfile::tempdir().unwrap();
+    let storage = LayerStorage::new(directory.path().join(".layers.db"));
+    let mut runner: Runner = serde_yaml::from_str(
+        "with: Calculation
+working_directory: calc
+pre_format: {format: xyz}
+pre_filename: a.xyz
+program: sh
+args: [-c, 'echo run >> ../runs; cp a.xyz out.xyz']
+post_file: [xyz, out.xyz]
+reuse_existing: true",
+    )
+    .unwrap();
+    runner.root_outputs(directory.path());
+    let base = SparseMolecule::default();
+    let window = Window::from([("mol".to_string(), vec![])]);
+    let runs = || {
+        std::fs::read_to_string(directory.path().join("calc/runs"))
+            .unwrap()
+            .lines()
+            .count()
+    };
+    runner.execute(&base, &window, &storage).unwrap();
+    runner.execute(&base, &window, &storage).unwrap();
+    assert_eq!(runs(), 1);
+    // The result of an interrupted program is not reused even if it's readable
+    std::fs::remove_file(directory.path().join("calc/mol").join(COMPLETED_MARKER)).unwrap();
+    runner.execute(&base, &window, &storage).unwrap();
+    assert_eq!(runs(), 2);
+    assert!(directory
+        .path()
+        .join("calc/mol")
+        .join(COMPLETED_MARKER)
+        .exists());
+}
+
+#[test]
+fn empty_trajectory() {
+    let directory = tempfile::tempdir().unwrap();
+    std::fs::write(directory.path().join("trj.xyz"), "\n").unwrap();
+    let post = ("xyz".to_string(), "trj.xyz".to_string());
+    let err = read_post_file(
+        &SparseMolecule::default(),
+        "mol",
+        directory.path(),
+        &post,
+        true,
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("No frame found"));
+}
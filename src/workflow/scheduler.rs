@@ -0,0 +1,722 @@
+use std::{
+    ffi::OsStr,
+    path::Path,
+    process::{Command, Stdio},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use fancy_regex::Regex;
+use lmers::utils::process::{new_command, shell_quote, ProgramFailure, ResourceUsage};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+/// Batch scheduler submitting the program of each structure as a job instead
+/// of running it on the current node, e.g.
+/// `scheduler: {type: Slurm, directives: [--partition=cpu, --cpus-per-task=16]}`.
+///
+/// A `job.sh` is written to the working directory of each structure and
+/// submitted, the runner waits for the job to finish and then stages files out
+/// and imports the post file as usual. The program, arguments, `envs`,
+/// `modules`, `container` and the stdin/stdout/stderr files of the Calculation
+/// are all kept in the job script.
+///
+/// Every scheduler accepts `directives` added to the header of the job script,
+/// `poll_interval` in seconds between the queries of the job (30 by default),
+/// and `max_jobs`, the max count of jobs in the queue at the same time (100
+/// by default). Each job in the queue is waited for by a thread of the LME.
+///
+/// A failed query of the queue (e.g. the controller is not responding) is
+/// retried at the next poll, the structure fails after 10 failures in a row.
+/// A job whose final state can't be found is in the state `UNKNOWN`, which
+/// fails the structure rather than importing a possibly partial result.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(tag = "type")]
+pub enum Scheduler {
+    Slurm(SlurmOptions),
+    Pbs(PbsOptions),
+    Custom(CustomOptions),
+}
+
+/// Adapter of a batch queue system, the job script is written, submitted and
+/// waited for by these methods. See `Scheduler::run` for the lifecycle.
+pub trait BatchScheduler {
+    /// Lines of the job script between the shebang and the commands, e.g. the
+    /// `#SBATCH` directives.
+    fn header(&self, name: &str, directory: &Path) -> Vec<String>;
+    /// Submit the job script and return the job id.
+    fn submit(&self, script: &Path) -> Result<String>;
+    /// Whether the job is still queued or running, an error if the queue is
+    /// unable to be queried, which is retried.
+    fn is_active(&self, id: &str) -> Result<bool>;
+    /// Final status and the elapsed seconds (if reported) of a finished job,
+    /// in the state `UNKNOWN` if it's not reported.
+    fn status(&self, id: &str) -> (JobStatus, Option<f64>);
+    fn poll_interval(&self) -> u64;
+    fn max_jobs(&self) -> Option<usize>;
+}
+
+/// Options of the SLURM scheduler.
+///
+/// `directives` are added as `#SBATCH` lines, e.g. `--time=24:00:00`. Jobs are
+/// polled by `squeue`, and the final state and exit code are read by `sacct`,
+/// a job not ended in `COMPLETED` fails the structure, including a job not
+/// found by `sacct` (e.g. the accounting is not enabled).
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SlurmOptions {
+    #[serde(default)]
+    directives: Vec<String>,
+    #[serde(default = "default_poll_interval")]
+    poll_interval: u64,
+    #[serde(default)]
+    max_jobs: Option<usize>,
+}
+
+/// Options of the PBS Pro and Torque schedulers.
+///
+/// `directives` are added as `#PBS` lines, e.g. `-l walltime=24:00:00` or
+/// `-q batch`. Jobs are submitted by `qsub` and polled by `qstat -f`, a job
+/// with a non-zero exit status fails the structure. Finished jobs are queried
+/// by `qstat -f -x` (PBS Pro) or `qstat -f` (Torque keeping completed jobs),
+/// if neither reports the exit status the job fails. Job names are cut to 15
+/// characters for old Torque servers.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PbsOptions {
+    #[serde(default)]
+    directives: Vec<String>,
+    #[serde(default = "default_poll_interval")]
+    poll_interval: u64,
+    #[serde(default)]
+    max_jobs: Option<usize>,
+}
+
+/// Queue system driven by user-given commands, for sites without SLURM or PBS.
+///
+/// - `submit`: command and arguments submitting the job, the path of the job
+///   script is appended. The job id is the first capture group of `id_regex`
+///   matched on its stdout, or the whole trimmed stdout without `id_regex`.
+/// - `poll`: command and arguments with the job id appended, the job is active
+///   while it prints a non-empty stdout and finished once it prints nothing.
+///   A failed poll command is retried.
+/// - `status`: optional command and arguments with the job id appended after
+///   the job finished, the job is failed if it exits with an error. Without
+///   it the result is checked by the post file.
+/// - `directive_prefix`: prefix of the `directives` lines, e.g. `#BSUB`.
+///
+/// e.g. for LSF: `{type: Custom, submit: [sh, -c, 'bsub < "$0"'], id_regex:
+/// 'Job <(\d+)>', poll: [sh, -c, 'bjobs -noheader -o stat "$0" | grep -v
+/// -e DONE -e EXIT; [ $? -le 1 ]'], directive_prefix: '#BSUB', directives:
+/// [-n 16]}`.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CustomOptions {
+    submit: Vec<String>,
+    #[serde(default)]
+    id_regex: Option<String>,
+    poll: Vec<String>,
+    #[serde(default)]
+    status: Vec<String>,
+    #[serde(default)]
+    directive_prefix: Option<String>,
+    #[serde(default)]
+    directives: Vec<String>,
+    #[serde(default = "default_poll_interval")]
+    poll_interval: u64,
+    #[serde(default)]
+    max_jobs: Option<usize>,
+}
+
+fn default_poll_interval() -> u64 {
+    30
+}
+
+/// Max count of jobs in the queue at the same time if `max_jobs` is not given.
+const DEFAULT_MAX_JOBS: usize = 100;
+/// Failed queries of the queue in a row before the job is given up.
+const POLL_RETRIES: usize = 10;
+/// Queries of the final status of a job not reported yet, e.g. the accounting
+/// is updated after the job left the queue.
+const STATUS_RETRIES: usize = 3;
+
+/// Final state of a submitted job.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobStatus {
+    pub id: String,
+    pub state: String,
+    pub exit_code: Option<i32>,
+}
+
+impl JobStatus {
+    /// Status of a job considered completed, e.g. the scheduler keeps no record
+    /// of it.
+    fn completed(id: &str, exit_code: Option<i32>) -> Self {
+        Self {
+            id: id.to_string(),
+            state: "COMPLETED".to_string(),
+            exit_code,
+        }
+    }
+
+    /// Status of a job not reported by the scheduler.
+    fn unknown(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            state: "UNKNOWN".to_string(),
+            exit_code: None,
+        }
+    }
+
+    /// Status by the exit code, unknown if not reported.
+    fn from_exit_code(id: &str, exit_code: Option<i32>) -> Self {
+        match exit_code {
+            Some(0) => Self::completed(id, exit_code),
+            Some(_) => Self {
+                id: id.to_string(),
+                state: "FAILED".to_string(),
+                exit_code,
+            },
+            None => Self::unknown(id),
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.state == "COMPLETED"
+    }
+
+    fn is_unknown(&self) -> bool {
+        self.state == "UNKNOWN"
+    }
+}
+
+impl Scheduler {
+    fn backend(&self) -> &dyn BatchScheduler {
+        match self {
+            Self::Slurm(options) => options,
+            Self::Pbs(options) => options,
+            Self::Custom(options) => options,
+        }
+    }
+
+    /// Max count of jobs in the queue at the same time.
+    pub fn max_jobs(&self) -> usize {
+        self.backend().max_jobs().unwrap_or(DEFAULT_MAX_JOBS)
+    }
+
+    /// Program submitting the jobs, e.g. `sbatch` of Slurm.
+    pub fn submit_program(&self) -> Option<&str> {
+        match self {
+            Self::Slurm(_) => Some("sbatch"),
+            Self::Pbs(_) => Some("qsub"),
+            Self::Custom(options) => options.submit.first().map(String::as_str),
+        }
+    }
+
+    /// Submit the command as a job named `name` in the working directory and
+    /// wait for it to finish. `stdin`, `stdout` and `stderr` are file names
+    /// relative to the working directory.
+    pub fn run(
+        &self,
+        command: &Command,
+        name: &str,
+        working_directory: &Path,
+        stdin: Option<&str>,
+        stdout: Option<&str>,
+        stderr: Option<&str>,
+    ) -> Result<(JobStatus, ResourceUsage)> {
+        let backend = self.backend();
+        let directory = std::fs::canonicalize(working_directory)
+            .with_context(|| format!("Unable to get absolute path of {:?}", working_directory))?;
+        let header = backend.header(name, &directory);
+        let script = job_script(&header, command, &directory, stdin, stdout, stderr);
+        let script_path = directory.join("job.sh");
+        std::fs::write(&script_path, script)
+            .with_context(|| format!("Unable to write job script at {:?}", script_path))?;
+        let submitted = Instant::now();
+        let id = backend
+            .submit(&script_path)
+            .with_context(|| format!("Unable to submit job script {:?}", script_path))?;
+        if id.is_empty() {
+            Err(anyhow!(
+                "No job id returned when submitting {:?}",
+                script_path
+            ))?
+        }
+        println!("Submitted job {} for {}", id, name);
+        let interval = Duration::from_secs(backend.poll_interval());
+        let mut errors = 0;
+        loop {
+            sleep(interval);
+            match backend.is_active(&id) {
+                Ok(true) => errors = 0,
+                Ok(false) => break,
+                Err(err) if errors < POLL_RETRIES => {
+                    errors += 1;
+                    println!(
+                        "Unable to query job {} ({:#}), retry {}/{}",
+                        id, err, errors, POLL_RETRIES
+                    );
+                }
+                Err(err) => Err(err).with_context(|| {
+                    format!("Unable to query job {} in {} retries", id, POLL_RETRIES)
+                })?,
+            }
+        }
+        let (mut status, mut elapsed) = backend.status(&id);
+        for _ in 0..STATUS_RETRIES {
+            if !status.is_unknown() {
+                break;
+            }
+            sleep(interval);
+            (status, elapsed) = backend.status(&id);
+        }
+        let usage = ResourceUsage {
+            wall_time: elapsed.unwrap_or_else(|| submitted.elapsed().as_secs_f64()),
+            ..Default::default()
+        };
+        Ok((status, usage))
+    }
+}
+
+/// Content of the job script running the command in the directory.
+fn job_script(
+    header: &[String],
+    command: &Command,
+    directory: &Path,
+    stdin: Option<&str>,
+    stdout: Option<&str>,
+    stderr: Option<&str>,
+) -> String {
+    let mut script = "#!/bin/sh\n".to_string();
+    for line in header {
+        script.push_str(line);
+        script.push('\n');
+    }
+    script.push_str(&format!(
+        "cd {} || exit 1\n",
+        shell_quote(&directory.to_string_lossy())
+    ));
+    for (key, value) in command.get_envs() {
+        let key = key.to_string_lossy();
+        match value {
+            Some(value) => script.push_str(&format!(
+                "export {}={}\n",
+                key,
+                shell_quote(&value.to_string_lossy())
+            )),
+            None => script.push_str(&format!("unset {}\n", key)),
+        }
+    }
+    let mut line = vec!["exec".to_string()];
+    line.push(shell_quote(&command.get_program().to_string_lossy()));
+    for arg in command.get_args() {
+        line.push(shell_quote(&arg.to_string_lossy()));
+    }
+    if let Some(stdin) = stdin {
+        line.push(format!("< {}", shell_quote(stdin)));
+    }
+    line.push(format!("> {}", shell_quote(stdout.unwrap_or("/dev/null"))));
+    line.push(format!("2> {}", shell_quote(stderr.unwrap_or("/dev/null"))));
+    script.push_str(&line.join(" "));
+    script.push('\n');
+    script
+}
+
+impl BatchScheduler for SlurmOptions {
+    fn header(&self, name: &str, directory: &Path) -> Vec<String> {
+        let mut header = vec![
+            format!("#SBATCH --job-name={}", name),
+            format!("#SBATCH --chdir={}", directory.to_string_lossy()),
+            "#SBATCH --output=slurm-%j.out".to_string(),
+        ];
+        header.extend(
+            self.directives
+                .iter()
+                .map(|directive| format!("#SBATCH {}", directive)),
+        );
+        header
+    }
+
+    fn submit(&self, script: &Path) -> Result<String> {
+        let id = query(new_command("sbatch").arg("--parsable").arg(script))?;
+        // `--parsable` prints `id[;cluster]`
+        Ok(id.split(';').next().unwrap_or_default().trim().to_string())
+    }
+
+    fn is_active(&self, id: &str) -> Result<bool> {
+        let (state, error) = probe(new_command("squeue").args(["-h", "-j", id, "-o", "%T"]))?;
+        match error {
+            None => Ok(!state.trim().is_empty()),
+            // The id is already purged from the controller
+            Some(error) if error.contains("Invalid job id") => Ok(false),
+            Some(error) => Err(ProgramFailure(format!("squeue failed: {}", error)))?,
+        }
+    }
+
+    fn status(&self, id: &str) -> (JobStatus, Option<f64>) {
+        let accounting = query(new_command("sacct").args([
+            "-n",
+            "-P",
+            "-X",
+            "-j",
+            id,
+            "-o",
+            "State,ExitCode,ElapsedRaw",
+        ]))
+        .ok()
+        .and_then(|output| output.lines().next().map(str::to_string))
+        .filter(|line| !line.trim().is_empty());
+        parse_accounting(id, accounting.as_deref())
+    }
+
+    fn poll_interval(&self) -> u64 {
+        self.poll_interval
+    }
+
+    fn max_jobs(&self) -> Option<usize> {
+        self.max_jobs
+    }
+}
+
+impl BatchScheduler for PbsOptions {
+    fn header(&self, name: &str, directory: &Path) -> Vec<String> {
+        let mut header = vec![
+            format!("#PBS -N {}", name.chars().take(15).collect::<String>()),
+            "#PBS -j oe".to_string(),
+            format!("#PBS -o {}", directory.join("pbs.out").to_string_lossy()),
+        ];
+        header.extend(
+            self.directives
+                .iter()
+                .map(|directive| format!("#PBS {}", directive)),
+        );
+        header
+    }
+
+    fn submit(&self, script: &Path) -> Result<String> {
+        Ok(query(new_command("qsub").arg(script))?.trim().to_string())
+    }
+
+    fn is_active(&self, id: &str) -> Result<bool> {
+        let (output, error) = probe(new_command("qstat").args(["-f", id]))?;
+        match error {
+            None => {
+                Ok(qstat_field(&output, "job_state")
+                    .is_some_and(|state| state != "C" && state != "F"))
+            }
+            // Unknown to Torque, or finished and kept only in the history of
+            // PBS Pro
+            Some(error) if error.contains("Unknown Job Id") || error.contains("has finished") => {
+                Ok(false)
+            }
+            Some(error) => Err(ProgramFailure(format!("qstat failed: {}", error)))?,
+        }
+    }
+
+    fn status(&self, id: &str) -> (JobStatus, Option<f64>) {
+        let output = query(new_command("qstat").args(["-f", "-x", id]))
+            .or_else(|_| query(new_command("qstat").args(["-f", id])))
+            .ok();
+        parse_qstat(id, output.as_deref())
+    }
+
+    fn poll_interval(&self) -> u64 {
+        self.poll_interval
+    }
+
+    fn max_jobs(&self) -> Option<usize> {
+        self.max_jobs
+    }
+}
+
+impl CustomOptions {
+    /// Command of the program and arguments with the last argument appended.
+    fn command(program: &[String], last: impl AsRef<OsStr>, name: &str) -> Result<Command> {
+        let (program, args) = program
+            .split_first()
+            .with_context(|| format!("No {} command of custom scheduler given", name))?;
+        let mut command = new_command(program);
+        command.args(args).arg(last);
+        Ok(command)
+    }
+}
+
+impl BatchScheduler for CustomOptions {
+    fn header(&self, _: &str, _: &Path) -> Vec<String> {
+        match &self.directive_prefix {
+            Some(prefix) => self
+                .directives
+                .iter()
+                .map(|directive| format!("{} {}", prefix, directive))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    fn submit(&self, script: &Path) -> Result<String> {
+        let output = query(&mut Self::command(&self.submit, script, "submit")?)?;
+        if let Some(id_regex) = &self.id_regex {
+            let regex = Regex::new(id_regex)
+                .with_context(|| format!("Invalid job id regex {}", id_regex))?;
+            let id = regex
+                .captures(&output)?
+                .and_then(|captures| captures.get(1))
+                .with_context(|| format!("No job id matched by {} in {:?}", id_regex, output))?;
+            Ok(id.as_str().to_string())
+        } else {
+            Ok(output.trim().to_string())
+        }
+    }
+
+    fn is_active(&self, id: &str) -> Result<bool> {
+        let output = query(&mut Self::command(&self.poll, id, "poll")?)?;
+        Ok(!output.trim().is_empty())
+    }
+
+    fn status(&self, id: &str) -> (JobStatus, Option<f64>) {
+        if self.status.is_empty() {
+            return (JobStatus::completed(id, None), None);
+        }
+        let status = Self::command(&self.status, id, "status").and_then(|mut command| {
+            command
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .status()
+                .with_context(|| format!("Unable to run {:?}", command))
+        });
+        let exit_code = match status {
+            Ok(status) => status.code(),
+            Err(_) => Some(-1),
+        };
+        (JobStatus::from_exit_code(id, exit_code), None)
+    }
+
+    fn poll_interval(&self) -> u64 {
+        self.poll_interval
+    }
+
+    fn max_jobs(&self) -> Option<usize> {
+        self.max_jobs
+    }
+}
+
+/// Run the command and return its stdout, fails if the command exits with error.
+fn query(command: &mut Command) -> Result<String> {
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| ProgramFailure(format!("Unable to run {:?}", command)))?;
+    if !output.status.success() {
+        Err(ProgramFailure(format!(
+            "{:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))?
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Run the command and return its stdout, and its stderr if it exits with
+/// error. Fails only if the command is unable to run.
+fn probe(command: &mut Command) -> Result<(String, Option<String>)> {
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| ProgramFailure(format!("Unable to run {:?}", command)))?;
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let error = (!output.status.success())
+        .then(|| String::from_utf8_lossy(&output.stderr).trim().to_string());
+    Ok((stdout, error))
+}
+
+/// Status and elapsed seconds from a `State|ExitCode|ElapsedRaw` line of
+/// sacct, the state is unknown if the line is not available.
+fn parse_accounting(id: &str, line: Option<&str>) -> (JobStatus, Option<f64>) {
+    let Some(line) = line else {
+        return (JobStatus::unknown(id), None);
+    };
+    let mut fields = line.trim().split('|');
+    // Cancelled jobs are reported like `CANCELLED by 1000`
+    let state = fields
+        .next()
+        .and_then(|state| state.split_whitespace().next())
+        .unwrap_or("UNKNOWN")
+        .to_string();
+    let exit_code = fields
+        .next()
+        .and_then(|code| code.split(':').next())
+        .and_then(|code| code.parse().ok());
+    let elapsed = fields.next().and_then(|elapsed| elapsed.parse().ok());
+    let status = JobStatus {
+        id: id.to_string(),
+        state,
+        exit_code,
+    };
+    (status, elapsed)
+}
+
+/// Value of the `key = value` line in the output of `qstat -f`, the key is case
+/// insensitive as PBS Pro and Torque differ (`Exit_status`/`exit_status`).
+fn qstat_field<'a>(output: &'a str, key: &str) -> Option<&'a str> {
+    output.lines().find_map(|line| {
+        let (name, value) = line.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case(key)
+            .then_some(value.trim())
+    })
+}
+
+/// Status and elapsed seconds from the output of `qstat -f`, the state is
+/// unknown if the output or the exit status is not available.
+fn parse_qstat(id: &str, output: Option<&str>) -> (JobStatus, Option<f64>) {
+    let Some(output) = output else {
+        return (JobStatus::unknown(id), None);
+    };
+    let exit_code = qstat_field(output, "exit_status").and_then(|code| code.parse().ok());
+    let elapsed = qstat_field(output, "resources_used.walltime").and_then(|walltime| {
+        walltime.split(':').try_fold(0., |seconds, part| {
+            Some(seconds * 60. + part.parse::<f64>().ok()?)
+        })
+    });
+    (JobStatus::from_exit_code(id, exit_code), elapsed)
+}
+
+#[test]
+fn scheduler_job_scripts() {
+    let Scheduler::Slurm(options) = serde_yaml::from_str(
+        "type: Slurm\ndirectives: [--partition=cpu, --time=24:00:00]\nmax_jobs: 20",
+    )
+    .unwrap() else {
+        panic!("Slurm expected")
+    };
+    assert_eq!(options.poll_interval, 30);
+    assert_eq!(options.max_jobs, Some(20));
+    let scheduler: Scheduler = serde_yaml::from_str("type: Slurm").unwrap();
+    assert_eq!(scheduler.max_jobs(), DEFAULT_MAX_JOBS);
+    let mut command = Command::new("g16");
+    command
+        .arg("input.gjf")
+        .env("GAUSS_SCRDIR", "/scratch/it's");
+    let directory = Path::new("/work/calc/mol_1");
+    let script = job_script(
+        &options.header("mol_1", directory),
+        &command,
+        directory,
+        Some("input.gjf"),
+        Some("output.log"),
+        None,
+    );
+    assert_eq!(
+        script,
+        "#!/bin/sh
+#SBATCH --job-name=mol_1
+#SBATCH --chdir=/work/calc/mol_1
+#SBATCH --output=slurm-%j.out
+#SBATCH --partition=cpu
+#SBATCH --time=24:00:00
+cd '/work/calc/mol_1' || exit 1
+export GAUSS_SCRDIR='/scratch/it'\\''s'
+exec 'g16' 'input.gjf' < 'input.gjf' > 'output.log' 2> '/dev/null'
+"
+    );
+    let (status, elapsed) = parse_accounting("42", Some("CANCELLED by 1000|0:15|3600"));
+    assert!(!status.success());
+    assert_eq!(status.state, "CANCELLED");
+    assert_eq!(status.exit_code, Some(0));
+    assert_eq!(elapsed, Some(3600.));
+    let (status, elapsed) = parse_accounting("42", None);
+    assert!(!status.success());
+    assert_eq!(status.state, "UNKNOWN");
+    assert_eq!(elapsed, None);
+    assert!(parse_qstat("42.server", None).0.is_unknown());
+    assert!(parse_qstat("42.server", Some("Job Id: 42.server\n"))
+        .0
+        .is_unknown());
+
+    let Scheduler::Pbs(options) =
+        serde_yaml::from_str("type: Pbs\ndirectives: ['-l walltime=24:00:00']").unwrap()
+    else {
+        panic!("Pbs expected")
+    };
+    assert_eq!(
+        options.header("a_very_long_structure_name", directory),
+        [
+            "#PBS -N a_very_long_str",
+            "#PBS -j oe",
+            "#PBS -o /work/calc/mol_1/pbs.out",
+            "#PBS -l walltime=24:00:00"
+        ]
+    );
+    let qstat = "Job Id: 42.server
+    job_state = F
+    resources_used.walltime = 01:02:03
+    Exit_status = 271
+";
+    let (status, elapsed) = parse_qstat("42.server", Some(qstat));
+    assert!(!status.success());
+    assert_eq!(status.exit_code, Some(271));
+    assert_eq!(elapsed, Some(3723.));
+
+    let Scheduler::Custom(options) = serde_yaml::from_str(
+        "type: Custom
+submit: [echo, 'Job <7> is submitted']
+id_regex: 'Job <(\\d+)>'
+poll: ['true']
+status: ['false']
+directive_prefix: '#BSUB'
+directives: [-n 16]",
+    )
+    .unwrap() else {
+        panic!("Custom expected")
+    };
+    assert_eq!(options.header("mol_1", directory), ["#BSUB -n 16"]);
+    let id = options.submit(Path::new("job.sh")).unwrap();
+    assert_eq!(id, "7");
+    assert!(!options.is_active(&id).unwrap());
+    assert!(!options.status(&id).0.success());
+}
+
+#[test]
+fn retry_scheduler_queries() {
+    let directory = tempfile::tempdir().unwrap();
+    let polled = directory.path().join("polled");
+    // The first poll fails, then the job is finished
+    let scheduler: Scheduler = serde_yaml::from_str(&format!(
+        "type: Custom
+submit: [echo, '7']
+poll: [sh, -c, 'test -f {0} || {{ touch {0}; exit 1; }}']
+status: ['true']
+poll_interval: 0",
+        polled.to_string_lossy()
+    ))
+    .unwrap();
+    let (status, _) = scheduler
+        .run(
+            &Command::new("true"),
+            "mol_1",
+            directory.path(),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+    assert!(status.success());
+    assert!(polled.exists());
+    let scheduler: Scheduler = serde_yaml::from_str(
+        "type: Custom\nsubmit: [echo, '7']\npoll: ['false']\npoll_interval: 0",
+    )
+    .unwrap();
+    let err = scheduler
+        .run(
+            &Command::new("true"),
+            "mol_1",
+            directory.path(),
+            None,
+            None,
+            None,
+        )
+        .unwrap_err();
+    assert!(format!("{:#}", err).contains("retries"));
+}
@@ -0,0 +1,362 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context, Result};
+use fancy_regex::Regex;
+use lmers::{chemistry::validated_element_num, sparse_molecule::SparseMolecule};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{
+    condition::Condition,
+    runner::{cached_read_stack, RunnerOutput},
+    unit::Energy,
+    variable::ScalarFile,
+    workflow_data::{LayerStorage, Window},
+};
+
+/// Keep the structures matching all the given predicates, or the ones not
+/// matching if `negate` is set.
+///
+/// The `expression` is a condition (see `Condition`) over the variables of each
+/// structure: `atoms` (count of atoms), `heavy_atoms` (count of non-hydrogen
+/// atoms), `bonds` (count of bonds) and `charge` (sum of formal charges), e.g.
+/// `heavy_atoms <= 30 && charge == 0`.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct FilterOptions {
+    /// Regular expression matching the title
+    #[serde(default)]
+    title: Option<String>,
+    /// Inclusive range of the count of atoms
+    #[serde(default)]
+    atoms: Option<(usize, usize)>,
+    /// Ids which must exist in the structure
+    #[serde(default)]
+    ids: Vec<String>,
+    #[serde(default)]
+    expression: Option<Condition>,
+    #[serde(default)]
+    negate: bool,
+}
+
+impl FilterOptions {
+    pub fn execute(
+        &self,
+        base: &SparseMolecule,
+        current_window: &Window,
+        layer_storage: &LayerStorage,
+    ) -> Result<RunnerOutput> {
+        let title = self
+            .title
+            .as_ref()
+            .map(|pattern| {
+                Regex::new(pattern).with_context(|| format!("Invalid title regex {}", pattern))
+            })
+            .transpose()?;
+        let needs_structure =
+            self.atoms.is_some() || !self.ids.is_empty() || self.expression.is_some();
+        let mut window = Window::new();
+        for (name, stack_path) in current_window {
+            let mut matched = match &title {
+                Some(title) => title.is_match(name).with_context(|| {
+                    format!("Unable to match title {} with {}", name, title.as_str())
+                })?,
+                None => true,
+            };
+            if matched && needs_structure {
+                let structure = cached_read_stack(base, layer_storage, stack_path)?;
+                matched = self
+                    .matches(&structure)
+                    .with_context(|| format!("Unable to filter structure {}", name))?;
+            }
+            if matched != self.negate {
+                window.insert(name.to_string(), stack_path.clone());
+            }
+        }
+        println!(
+            "{} of {} structures kept by the filter",
+            window.len(),
+            current_window.len()
+        );
+        Ok(RunnerOutput::SingleWindow(window))
+    }
+
+    fn matches(&self, structure: &SparseMolecule) -> Result<bool> {
+        let atoms = structure
+            .atoms
+            .data()
+            .iter()
+            .flatten()
+            .filter(|atom| validated_element_num(atom.element))
+            .collect::<Vec<_>>();
+        if let Some((min, max)) = self.atoms {
+            if !(min..=max).contains(&atoms.len()) {
+                return Ok(false);
+            }
+        }
+        let ids = structure.ids.as_ref();
+        if !self
+            .ids
+            .iter()
+            .all(|id| ids.map(|ids| ids.contains_key(id)).unwrap_or_default())
+        {
+            return Ok(false);
+        }
+        let Some(expression) = &self.expression else {
+            return Ok(true);
+        };
+        let lookup = |name: &str| match name {
+            "atoms" => Some(atoms.len() as f64),
+            "heavy_atoms" => Some(atoms.iter().filter(|atom| atom.element != 1).count() as f64),
+            "bonds" => Some(structure.bonds.to_continuous_list(&structure.atoms).len() as f64),
+            "charge" => Some(atoms.iter().map(|atom| atom.formal_charge).sum()),
+            _ => None,
+        };
+        expression.evaluate(&lookup)
+    }
+}
+
+/// Sort the structures by a number read from files (e.g. the energies in the
+/// output logs) and keep the best ones, smaller values are preferred unless
+/// `descending` is set.
+///
+/// The window is partitioned into `top` (the first `top` structures, all by
+/// default, within `range` of the best value if given), `rest` and `unscored`
+/// (structures with the number failed to read), and `top` is kept. With
+/// `rank_titles`, the kept titles are prefixed by their ranks, e.g. `003_Me`.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SortOptions {
+    file: ScalarFile,
+    #[serde(default)]
+    descending: bool,
+    #[serde(default)]
+    top: Option<usize>,
+    /// Max difference to the best value, e.g. an energy window `3 kcal/mol`
+    /// with the `unit` of `file` declared, or a number in the unit of the file
+    #[serde(default)]
+    range: Option<Energy>,
+    #[serde(default)]
+    rank_titles: bool,
+}
+
+impl SortOptions {
+    /// Count of the structures kept at most.
+    pub fn limit(&self) -> Option<usize> {
+        self.top
+    }
+
+    pub fn execute(&self, window: &Window) -> Result<RunnerOutput> {
+        let range = self
+            .range
+            .as_ref()
+            .map(|range| range.resolve(self.file.normalized()))
+            .transpose()?;
+        let mut values = self.file.read_window(window)?;
+        let mut windows = BTreeMap::from([
+            ("top".to_string(), Window::new()),
+            ("rest".to_string(), Window::new()),
+        ]);
+        let mut scored = vec![];
+        for (title, stack_path) in window {
+            match values.remove(title).unwrap() {
+                Ok(value) if value.is_finite() => scored.push((title, stack_path, value)),
+                value => {
+                    match value {
+                        Ok(value) => println!("Invalid value {} of {}", value, title),
+                        Err(err) => println!("Unable to read value of {}: {:#}", title, err),
+                    }
+                    windows
+                        .entry("unscored".to_string())
+                        .or_default()
+                        .insert(title.to_string(), stack_path.clone());
+                }
+            }
+        }
+        scored.sort_by(|(_, _, a), (_, _, b)| {
+            if self.descending {
+                b.total_cmp(a)
+            } else {
+                a.total_cmp(b)
+            }
+        });
+        let best = scored.first().map(|(_, _, value)| *value);
+        let width = scored.len().to_string().len();
+        for (rank, (title, stack_path, value)) in scored.into_iter().enumerate() {
+            let in_range = match (range, best) {
+                (Some(range), Some(best)) => (value - best).abs() <= range,
+                _ => true,
+            };
+            if in_range && self.top.map(|top| rank < top).unwrap_or(true) {
+                println!("{:>width$} {} {}", rank + 1, title, value, width = width);
+                let title = if self.rank_titles {
+                    format!("{:0width$}_{}", rank + 1, title, width = width)
+                } else {
+                    title.to_string()
+                };
+                windows
+                    .get_mut("top")
+                    .unwrap()
+                    .insert(title, stack_path.clone());
+            } else {
+                windows
+                    .get_mut("rest")
+                    .unwrap()
+                    .insert(title.to_string(), stack_path.clone());
+            }
+        }
+        Ok(RunnerOutput::Partition {
+            windows,
+            keep: "top".to_string(),
+        })
+    }
+}
+
+/// A property axis of Pareto filtering, smaller values are preferred unless
+/// `maximize` is set.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ParetoAxis {
+    file: ScalarFile,
+    #[serde(default)]
+    maximize: bool,
+}
+
+/// Partition the window into the `pareto` (non-dominated) and `dominated`
+/// windows, structures with any property failed to read go to `unscored`. The
+/// `pareto` window is kept.
+pub fn pareto(axes: &[ParetoAxis], window: &Window) -> Result<RunnerOutput> {
+    if axes.is_empty() {
+        Err(anyhow!(
+            "At least one axis is required for Pareto filtering"
+        ))?
+    }
+    let mut values = axes
+        .iter()
+        .map(|axis| axis.file.read_window(window))
+        .collect::<Result<Vec<_>>>()?;
+    let mut windows = BTreeMap::from([
+        ("pareto".to_string(), Window::new()),
+        ("dominated".to_string(), Window::new()),
+    ]);
+    let mut points = vec![];
+    for (title, stack_path) in window {
+        let point = values
+            .iter_mut()
+            .zip(axes)
+            .map(|(values, axis)| {
+                let value = values.remove(title).unwrap()?;
+                Ok(if axis.maximize { -value } else { value })
+            })
+            .collect::<Result<Vec<_>>>();
+        match point {
+            Ok(point) => points.push((title, stack_path, point)),
+            Err(err) => {
+                println!("Unable to read properties of {}: {:#}", title, err);
+                windows
+                    .entry("unscored".to_string())
+                    .or_default()
+                    .insert(title.to_string(), stack_path.clone());
+            }
+        }
+    }
+    let front = nondominated(
+        &points
+            .iter()
+            .map(|(_, _, point)| point.as_slice())
+            .collect::<Vec<_>>(),
+    );
+    for ((title, stack_path, _), is_front) in points.into_iter().zip(front) {
+        let name = if is_front { "pareto" } else { "dominated" };
+        windows
+            .get_mut(name)
+            .unwrap()
+            .insert(title.to_string(), stack_path.clone());
+    }
+    Ok(RunnerOutput::Partition {
+        windows,
+        keep: "pareto".to_string(),
+    })
+}
+
+/// Check if each point is not dominated by the others, smaller values are better.
+fn nondominated(points: &[&[f64]]) -> Vec<bool> {
+    let dominates = |a: &[f64], b: &[f64]| {
+        a.iter().zip(b).all(|(a, b)| a <= b) && a.iter().zip(b).any(|(a, b)| a < b)
+    };
+    points
+        .iter()
+        .map(|point| !points.iter().any(|other| dominates(other, point)))
+        .collect()
+}
+
+#[test]
+fn pareto_front() {
+    let points: Vec<&[f64]> = vec![&[1., 5.], &[2., 2.], &[3., 3.], &[5., 1.], &[2., 2.]];
+    assert_eq!(nondominated(&points), vec![true, true, false, true, true]);
+}
+
+#[test]
+fn filter_predicates() {
+    use lmers::{chemistry::Atom3D, sparse_molecule::SparseAtomList};
+    let atom = |element: usize, formal_charge: f64| Atom3D {
+        element,
+        formal_charge,
+        ..Default::default()
+    };
+    let structure = SparseMolecule {
+        atoms: SparseAtomList::from(vec![atom(7, 1.), atom(1, 0.), atom(1, 0.), atom(6, 0.)]),
+        ids: Some(BTreeMap::from([("N".to_string(), 0)])),
+        ..Default::default()
+    };
+    let options = |yaml: &str| serde_yaml::from_str::<FilterOptions>(yaml).unwrap();
+    assert!(options("atoms: [2, 4]").matches(&structure).unwrap());
+    assert!(!options("atoms: [5, 10]").matches(&structure).unwrap());
+    assert!(options("ids: [N]").matches(&structure).unwrap());
+    assert!(!options("ids: [N, O]").matches(&structure).unwrap());
+    assert!(options("expression: heavy_atoms == 2 && charge == 1")
+        .matches(&structure)
+        .unwrap());
+    assert!(options("expression: unknown > 1")
+        .matches(&structure)
+        .is_err());
+}
+
+#[test]
+fn sort_top_structures() {
+    let directory = tempfile::tempdir().unwrap();
+    for (title, energy) in [("a", -1.2), ("b", -1.5), ("c", -1.0), ("d", -1.45)] {
+        let calc = directory.path().join(title);
+        std::fs::create_dir_all(&calc).unwrap();
+        std::fs::write(
+            calc.join("opt.out"),
+            format!("E = {}\nE = {}\n", 0., energy),
+        )
+        .unwrap();
+    }
+    let window = Window::from_iter(
+        ["a", "b", "c", "d", "e"]
+            .into_iter()
+            .enumerate()
+            .map(|(index, title)| (title.to_string(), vec![index as u64])),
+    );
+    let options = serde_yaml::from_str::<SortOptions>(&format!(
+        "file: {{path: '{}/{{title}}/*.out', pattern: 'E = (\\S+)'}}\ntop: 3\nrange: 0.4\nrank_titles: true",
+        directory.path().display()
+    ))
+    .unwrap();
+    let RunnerOutput::Partition { windows, keep } = options.execute(&window).unwrap() else {
+        panic!("Partition expected")
+    };
+    assert_eq!(keep, "top");
+    assert_eq!(
+        windows["top"],
+        Window::from([
+            ("1_b".to_string(), vec![1]),
+            ("2_d".to_string(), vec![3]),
+            ("3_a".to_string(), vec![0])
+        ])
+    );
+    assert_eq!(windows["rest"].keys().collect::<Vec<_>>(), ["c"]);
+    assert_eq!(windows["unscored"].keys().collect::<Vec<_>>(), ["e"]);
+}
@@ -1,25 +1,81 @@
-use std::{collections::BTreeMap, env::current_dir, fs::File, io::Read};
+use std::{collections::BTreeMap, env::current_dir, fs::File, io::Read, path::Path};
 
 use anyhow::{anyhow, Context, Result};
 use fancy_regex::Regex;
 use lazy_static::lazy_static;
+use lmers::utils::input::{from_input_list_reader, from_yaml_str, from_yaml_value};
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
 use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
 use url::Url;
 
-use super::runner::Runner;
+use super::{
+    condition::Condition,
+    runner::Runner,
+    variable::{has_variables, substitute_variables, Capture, Variables},
+};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone)]
 pub struct Step {
+    pub base: Option<String>,
     pub from: Option<String>,
     pub name: Option<String>,
     pub bookmark: Option<String>,
-    pub run: Runner,
+    pub when: Option<Condition>,
+    pub run: StepRunner,
+    pub capture: BTreeMap<String, Capture>,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum StepRunner {
+    Ready(Runner),
+    /// The runner refers to workflow variables with `${name}`, deserialized
+    /// when the step is executed and the variables are captured.
+    Deferred(serde_yaml::Value),
+    /// Repeat the steps, see `LoopLoader`.
+    Loop {
+        steps: Vec<Step>,
+        until: Option<Condition>,
+        max_iterations: usize,
+    },
+}
+
+impl StepRunner {
+    fn new(value: Option<serde_yaml::Value>) -> Result<Self> {
+        match value {
+            None => Ok(Self::Ready(Runner::default())),
+            Some(value) if has_variables(&value) => Ok(Self::Deferred(value)),
+            Some(value) => Ok(Self::Ready(from_yaml_value(value, "step runner")?)),
+        }
+    }
+
+    pub fn resolve(self, variables: &Variables) -> Result<Runner> {
+        match self {
+            Self::Ready(runner) => Ok(runner),
+            Self::Deferred(value) => from_yaml_value(
+                substitute_variables(value, variables)?,
+                "step runner with variables",
+            ),
+            Self::Loop { .. } => Err(anyhow!("A loop block can't be resolved to a runner")),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Default)]
 #[serde(try_from = "StepsLoader")]
 pub struct Steps(pub Vec<Step>);
 
+impl JsonSchema for Steps {
+    fn schema_name() -> String {
+        "Steps".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        StepsLoader::json_schema(gen)
+    }
+}
+
 impl Steps {
     fn concat(mut a: Self, mut b: Self) -> Self {
         let mut steps = vec![];
@@ -33,7 +89,7 @@ impl Steps {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, JsonSchema)]
 struct StepsLoader(Vec<StepLoader>);
 
 impl TryFrom<StepsLoader> for Steps {
@@ -49,8 +105,11 @@ impl TryFrom<StepsLoader> for Steps {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
 struct StepLoader {
+    #[serde(default)]
+    base: Option<String>,
     #[serde(default)]
     from: Option<String>,
     #[serde(default)]
@@ -58,11 +117,127 @@ struct StepLoader {
     #[serde(default)]
     bookmark: Option<String>,
     #[serde(default)]
-    run: Option<Runner>,
+    when: Option<Condition>,
+    #[serde(default)]
+    #[schemars(with = "Option<Runner>")]
+    run: Option<serde_yaml::Value>,
     #[serde(default)]
     load: Option<String>,
     #[serde(default)]
     parameters: BTreeMap<String, String>,
+    #[serde(default)]
+    capture: BTreeMap<String, Capture>,
+    #[serde(default, rename = "loop")]
+    repeat: Option<LoopLoader>,
+    #[serde(default)]
+    stage: Option<StageLoader>,
+}
+
+/// Repeat the steps until the condition is met or `max_iterations` reached.
+///
+/// The condition is checked after each iteration, it can refer to the workflow
+/// variables, `iteration` (starts from 1), `previous.<name>` for the value of
+/// a variable before the iteration, `count` (number of structures in the
+/// window) and `changed` (1 if the window is changed by the iteration), and the
+/// functions of the `when` conditions, e.g. `!changed || exists('converged')`
+/// to stop when the window is unchanged or a sentinel file is written.
+#[derive(Deserialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct LoopLoader {
+    steps: Steps,
+    #[serde(default)]
+    until: Option<Condition>,
+    max_iterations: usize,
+}
+
+/// Steps sharing the options of their Calculation runners.
+///
+/// Each option in `defaults` is used by the Calculation runners of the steps
+/// (including those in loops, nested stages and nested in ForEach or
+/// GeneticOptimize runners, but not loaded from files)
+/// which don't set it, except that the `working_directory` of a runner is
+/// relative to the one in `defaults`, and the `envs` are merged with those of
+/// the runner taking precedence, e.g.
+/// `{defaults: {working_directory: campaign, program: g16, ignore_failed: true}, steps: [...]}`.
+#[derive(Deserialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct StageLoader {
+    #[serde(default)]
+    #[schemars(with = "BTreeMap<String, serde_json::Value>")]
+    defaults: Mapping,
+    #[schemars(with = "Vec<StepLoader>")]
+    steps: Vec<Value>,
+}
+
+impl StageLoader {
+    fn steps(self) -> Result<Steps> {
+        let mut steps = self.steps;
+        for step in &mut steps {
+            apply_stage_defaults(&self.defaults, step);
+        }
+        from_yaml_value(Value::Sequence(steps), "steps of the stage")
+    }
+}
+
+fn apply_stage_defaults(defaults: &Mapping, step: &mut Value) {
+    if let Some(run) = step.get_mut("run").and_then(Value::as_mapping_mut) {
+        apply_runner_defaults(defaults, run);
+    }
+    let loop_steps = step
+        .get_mut("loop")
+        .and_then(|repeat| repeat.get_mut("steps"))
+        .and_then(Value::as_sequence_mut);
+    for step in loop_steps.into_iter().flatten() {
+        apply_stage_defaults(defaults, step);
+    }
+    if let Some(stage) = step.get_mut("stage").and_then(Value::as_mapping_mut) {
+        let inner = stage
+            .entry("defaults".into())
+            .or_insert_with(|| Value::Mapping(Mapping::new()));
+        if let Some(inner) = inner.as_mapping_mut() {
+            merge_stage_defaults(defaults, inner);
+        }
+    }
+}
+
+/// Merge the defaults into a Calculation runner, or the Calculation runners
+/// nested in ForEach and GeneticOptimize.
+fn apply_runner_defaults(defaults: &Mapping, run: &mut Mapping) {
+    let nested = match run.get("with").and_then(Value::as_str) {
+        Some("Calculation") => return merge_stage_defaults(defaults, run),
+        Some("ForEach") => "run",
+        Some("GeneticOptimize") => "evaluate",
+        _ => return,
+    };
+    if let Some(run) = run.get_mut(nested).and_then(Value::as_mapping_mut) {
+        apply_runner_defaults(defaults, run);
+    }
+}
+
+fn merge_stage_defaults(defaults: &Mapping, options: &mut Mapping) {
+    for (key, value) in defaults {
+        match (key.as_str(), options.get_mut(key)) {
+            (Some("working_directory"), Some(Value::String(directory))) => {
+                if let Some(root) = value.as_str() {
+                    *directory = Path::new(root)
+                        .join(&*directory)
+                        .to_string_lossy()
+                        .to_string();
+                }
+            }
+            (Some("envs"), Some(Value::Mapping(envs))) => {
+                for (name, env) in value.as_mapping().into_iter().flatten() {
+                    if !envs.contains_key(name) {
+                        envs.insert(name.clone(), env.clone());
+                    }
+                }
+            }
+            (_, Some(_)) => {}
+            (_, None) => {
+                options.insert(key.clone(), value.clone());
+            }
+        }
+    }
 }
 
 lazy_static! {
@@ -72,16 +247,90 @@ lazy_static! {
 /// Generate step list from input file.
 ///
 /// The `run` field specify the first step in the loader, if no `run` field specified, the CheckPoint runner will be used.
-/// The `from` field will be always attached to the first step.
+/// Strings in `run` can refer to workflow variables with `${name}`, the runner is deserialized after the variables captured.
+/// The `from` field will be always attached to the first step. It's the name of a checkpoint, or a glob pattern merging
+/// the matched checkpoints (e.g. `attach_*` for the windows of a MultiWindow output), see `read_checkpoints`.
 ///
-/// The `load` field speicifies steps loaded from other files, which would be put after the first step. if no `loader` specified,
+/// The `base` field switches to a named base of the workflow (see `WorkflowInput`) before the step, the window is
+/// replaced by the base structure titled by its name, so the following steps work on another scaffold. Like `from`,
+/// it's attached to the first step, and they can't be used together.
+///
+/// The `load` field speicifies steps loaded from other files (YAML, or JSON/TOML by the file extension with the list written as
+/// `steps = [...]` in TOML, templates are always YAML),
+/// which would be put after the first step. if no `loader` specified,
 /// the `name` field will be attached to the first step, otherwise a CheckPoint step will be automatically created at the end of
 /// the step queue and the `name` field will be attached to it.
 ///
+/// The `capture` field specifies variables captured after the last step in the loader.
+///
+/// The `loop` field repeats a block of steps, it can't be used with `run` or `load`. The `name`, `bookmark` and
+/// `capture` fields are attached to the whole block.
+///
+/// The `stage` field groups steps sharing the options of their Calculation runners (see `StageLoader`), it can't be
+/// used with other fields.
+///
+/// The `when` field is a condition (see `Condition`) checked before the step (after the `from` checkpoint loaded), the
+/// step is skipped if it's false. It can refer to the workflow variables, `count` (number of structures in the current
+/// window), `matches('<regex>')` (number of titles matching the regex), `completed('<name>')` (1 if the checkpoint
+/// of the named step is created in this run, or by the steps skipped when restarting, a checkpoint left by a previous
+/// run doesn't count) and `exists('<path>')` (1 if the file exists). It can't be used with `load`, put the loaded
+/// steps in a loop block instead.
+///
 impl TryFrom<StepLoader> for Steps {
     type Error = anyhow::Error;
     fn try_from(value: StepLoader) -> Result<Self> {
+        if let Some(stage) = value.stage {
+            let others = value.base.is_some()
+                || value.from.is_some()
+                || value.name.is_some()
+                || value.bookmark.is_some()
+                || value.when.is_some()
+                || value.run.is_some()
+                || value.load.is_some()
+                || value.repeat.is_some()
+                || !value.capture.is_empty();
+            if others {
+                Err(anyhow!(
+                    "`stage` can't be used together with other fields, put them in its steps"
+                ))?
+            }
+            return stage.steps();
+        }
+        if value.base.is_some() && value.from.is_some() {
+            Err(anyhow!("`base` can't be used together with `from`"))?
+        }
+        if let Some(repeat) = value.repeat {
+            if value.run.is_some() || value.load.is_some() {
+                Err(anyhow!(
+                    "`loop` can't be used together with `run` or `load`"
+                ))?
+            }
+            return Ok(Steps(vec![Step {
+                base: value.base,
+                from: value.from,
+                name: value.name,
+                bookmark: value.bookmark,
+                when: value.when,
+                run: StepRunner::Loop {
+                    steps: repeat.steps.0,
+                    until: repeat.until,
+                    max_iterations: repeat.max_iterations,
+                },
+                capture: value.capture,
+            }]));
+        }
+        if value.when.is_some() && value.load.is_some() {
+            Err(anyhow!(
+                "`when` can't be used together with `load`, use a loop block with max_iterations 1"
+            ))?
+        }
+        let (capture, mut load_capture) = if value.load.is_none() {
+            (value.capture, BTreeMap::new())
+        } else {
+            (BTreeMap::new(), value.capture)
+        };
         let mut steps = Steps(vec![Step {
+            base: value.base,
             from: value.from,
             name: if value.load.is_none() {
                 value.name.clone()
@@ -93,7 +342,9 @@ impl TryFrom<StepLoader> for Steps {
             } else {
                 None
             },
-            run: value.run.unwrap_or_default(),
+            when: value.when,
+            run: StepRunner::new(value.run)?,
+            capture,
         }]);
 
         if let Some(filepath) = value.load {
@@ -131,23 +382,29 @@ impl TryFrom<StepLoader> for Steps {
                 }
                 for (k, v) in &value.parameters {
                     let k = format!("{{{{ {} }}}}", k);
-                    content = content.replace(&k, &v);
+                    content = content.replace(&k, v);
                 }
                 let content = YAML_NULLABLE_VARIABLE_RE.replace_all(&content, "null");
                 println!("Input from template generated: \n{}", content);
-                steps = Steps::concat(steps, serde_yaml::from_str(&content)?);
+                steps = Steps::concat(
+                    steps,
+                    from_yaml_str(&content, &format!("template {:?}", filepath))?,
+                );
             } else {
                 println!("Loading {:?}", filepath);
                 let file = File::open(&filepath)
                     .with_context(|| format!("Failed to open target file {:?}", filepath))?;
-                steps = Steps::concat(steps, serde_yaml::from_reader(file)?);
+                steps = Steps::concat(steps, from_input_list_reader(file, &filepath, "steps")?);
             }
-            if value.name.is_some() {
+            if value.name.is_some() || !load_capture.is_empty() {
                 steps.push(Step {
+                    base: None,
                     from: None,
                     name: value.name,
                     bookmark: value.bookmark,
-                    run: Runner::default(),
+                    when: None,
+                    run: StepRunner::Ready(Runner::default()),
+                    capture: std::mem::take(&mut load_capture),
                 });
             }
         };
@@ -155,3 +412,75 @@ impl TryFrom<StepLoader> for Steps {
         Ok(steps)
     }
 }
+
+#[test]
+fn stage_defaults() {
+    let steps: Steps = serde_yaml::from_str(
+        "
+- stage:
+    defaults:
+      working_directory: campaign
+      program: lme-mock
+      envs: {OMP_NUM_THREADS: '4', MEM: 1GB}
+      ignore_failed: true
+    steps:
+      - run: {with: Calculation, working_directory: opt, pre_format: {format: xyz}, pre_filename: input.xyz, envs: {MEM: 8GB}}
+      - run:
+          with: ForEach
+          matrix: {functional: [b3lyp, pbe0]}
+          run: {with: Calculation, working_directory: '${functional}', pre_format: {format: xyz}, pre_filename: input.xyz}
+      - stage:
+          defaults: {working_directory: sp, ignore_failed: false}
+          steps:
+            - run: {with: Calculation, pre_format: {format: xyz}, pre_filename: input.xyz}
+",
+    )
+    .unwrap();
+    assert_eq!(steps.0.len(), 3);
+    let calculation = |step: &Step| match &step.run {
+        StepRunner::Ready(Runner::Calculation {
+            working_directory,
+            envs,
+            program,
+            ignore_failed,
+            ..
+        }) => (
+            working_directory.clone(),
+            envs.clone(),
+            program.clone(),
+            *ignore_failed,
+        ),
+        _ => unreachable!(),
+    };
+    let (directory, envs, program, ignore_failed) = calculation(&steps.0[0]);
+    assert_eq!(directory, Path::new("campaign/opt"));
+    assert_eq!(envs["MEM"], "8GB");
+    assert_eq!(envs["OMP_NUM_THREADS"], "4");
+    assert_eq!(program.as_deref(), Some("lme-mock"));
+    assert!(ignore_failed);
+    let StepRunner::Ready(Runner::ForEach(options)) = &steps.0[1].run else {
+        unreachable!()
+    };
+    for (name, runner) in options.runners().unwrap() {
+        let step = Step {
+            base: None,
+            from: None,
+            name: None,
+            bookmark: None,
+            when: None,
+            run: StepRunner::Ready(runner),
+            capture: Default::default(),
+        };
+        let (directory, envs, program, ignore_failed) = calculation(&step);
+        assert_eq!(directory, Path::new("campaign").join(name));
+        assert_eq!(envs["MEM"], "1GB");
+        assert_eq!(program.as_deref(), Some("lme-mock"));
+        assert!(ignore_failed);
+    }
+    let (directory, _, program, ignore_failed) = calculation(&steps.0[2]);
+    assert_eq!(directory, Path::new("campaign/sp"));
+    assert_eq!(program.as_deref(), Some("lme-mock"));
+    assert!(!ignore_failed);
+    let invalid = serde_yaml::from_str::<Steps>("- {name: a, stage: {steps: []}}");
+    assert!(invalid.is_err());
+}
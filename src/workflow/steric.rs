@@ -0,0 +1,225 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use lmers::{
+    layer::SelectOne,
+    sparse_molecule::SparseMolecule,
+    utils::sterimol::{
+        buried_volume, get_molecular_graph, sterimol, tolman_cone_angle, RadiisTable,
+    },
+};
+use rayon::prelude::*;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{
+    features::quote_field,
+    runner::{cached_read_stack, rooted},
+    workflow_data::{LayerStorage, Window},
+};
+
+const COLUMNS: [&str; 5] = ["L", "B1", "B5", "cone_angle", "buried_volume"];
+
+/// Write the Sterimol parameters (L, B1 and B5 in Angstrom), the Tolman cone
+/// angle (degrees) and the percent buried volume of a substituent of each
+/// structure as a table.
+///
+/// The substituent is the part on the second atom side of the `axis`, e.g.
+/// `[M1, P1]` for a phosphine ligand on the metal, measured along the axis
+/// like `obabelme import -S`. The radii of the elements are read from the
+/// `radii_table` (a JSON list of `{symbol, value}` indexed by the element
+/// number). The buried volume is of a sphere of `sphere_radius` (3.5 Angstrom
+/// by default) around the first atom, sampled with a `mesh` of 0.1 Angstrom by
+/// default, see `lmers::utils::sterimol::buried_volume`. The table is written
+/// as CSV, or tab separated if the path ends with `.tsv`. Values of structures
+/// failed to compute are left empty.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct StericOptions {
+    axis: (SelectOne, SelectOne),
+    radii_table: PathBuf,
+    output: PathBuf,
+    #[serde(default = "StericOptions::default_sphere_radius")]
+    sphere_radius: f64,
+    #[serde(default = "StericOptions::default_mesh")]
+    mesh: f64,
+}
+
+impl StericOptions {
+    fn default_sphere_radius() -> f64 {
+        3.5
+    }
+
+    fn default_mesh() -> f64 {
+        0.1
+    }
+
+    pub fn root_outputs(&mut self, directory: &Path) {
+        self.output = rooted(directory, &self.output);
+    }
+
+    /// Descriptors of the substituent in the order of `COLUMNS`.
+    fn descriptors(&self, structure: &SparseMolecule, table: &RadiisTable) -> Result<[f64; 5]> {
+        let index = |select: &SelectOne| {
+            select
+                .to_index(structure)
+                .filter(|index| structure.atoms.read_atom(*index).is_some())
+                .with_context(|| format!("Atom {:?} not found", select))
+        };
+        let (a, b) = (index(&self.axis.0)?, index(&self.axis.1)?);
+        // Atom a and b are the first two atoms of the substituent graph
+        let mut indexes = vec![a, b];
+        indexes.extend(
+            structure
+                .bond_side(a, b)
+                .into_iter()
+                .filter(|index| *index != a && *index != b),
+        );
+        let position = |index: usize| indexes.iter().position(|item| *item == index);
+        let atoms = indexes
+            .iter()
+            .map(|index| structure.atoms.read_atom(*index).unwrap())
+            .collect::<Vec<_>>();
+        let mut bonds = vec![(0, 1, 1.)];
+        for (from, index) in indexes.iter().enumerate().skip(1) {
+            for neighbor in structure.neighbors(*index) {
+                match position(neighbor) {
+                    Some(to) if to > from => bonds.push((
+                        from,
+                        to,
+                        structure.bonds.read_bond(*index, neighbor).unwrap(),
+                    )),
+                    _ => {}
+                }
+            }
+        }
+        let graph = get_molecular_graph(&atoms, &bonds);
+        let (l, b1, b5) = sterimol(&graph, table)?;
+        let cone_angle = tolman_cone_angle(&graph)?.to_degrees();
+        let buried_volume = buried_volume(
+            &atoms[1..],
+            &atoms[0].position,
+            table,
+            self.sphere_radius,
+            self.mesh,
+        )?;
+        Ok([l, b1, b5, cone_angle, buried_volume])
+    }
+
+    pub fn execute(
+        &self,
+        base: &SparseMolecule,
+        current_window: &Window,
+        layer_storage: &LayerStorage,
+    ) -> Result<()> {
+        let delimiter = match self.output.extension().and_then(|ext| ext.to_str()) {
+            Some("tsv") => "\t",
+            Some("csv") | None => ",",
+            Some(ext) => Err(anyhow!(
+                "Unsupported steric descriptor table format {}, use csv or tsv",
+                ext
+            ))?,
+        };
+        let file = File::open(&self.radii_table)
+            .with_context(|| format!("Unable to open radii table {:?}", self.radii_table))?;
+        let table: RadiisTable = serde_json::from_reader(file)
+            .with_context(|| format!("Unable to parse radii table {:?}", self.radii_table))?;
+        let rows = current_window
+            .par_iter()
+            .map(|(title, stack_path)| {
+                let structure = cached_read_stack(base, layer_storage, stack_path)?;
+                let values = self
+                    .descriptors(&structure, &table)
+                    .map_err(|err| {
+                        println!(
+                            "Unable to compute steric descriptors of {}: {:#}",
+                            title, err
+                        )
+                    })
+                    .ok();
+                Ok((title.as_str(), values))
+            })
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        let mut content = ["title"]
+            .into_iter()
+            .chain(COLUMNS)
+            .collect::<Vec<_>>()
+            .join(delimiter);
+        content.push('\n');
+        for (title, values) in rows {
+            let mut fields = vec![quote_field(title, delimiter)];
+            match values {
+                Some(values) => fields.extend(values.map(|value| value.to_string())),
+                None => fields.extend(COLUMNS.map(|_| String::new())),
+            }
+            content.push_str(&fields.join(delimiter));
+            content.push('\n');
+        }
+        File::create(&self.output)
+            .with_context(|| format!("Unable to create steric table at {:?}", self.output))?
+            .write_all(content.as_bytes())
+            .with_context(|| format!("Unable to write steric table at {:?}", self.output))?;
+        println!(
+            "Steric descriptors of {} structures written to {:?}",
+            current_window.len(),
+            self.output
+        );
+        Ok(())
+    }
+}
+
+#[test]
+fn steric_descriptors() {
+    use lmers::{
+        chemistry::{element_num_to_symbol, Atom3D},
+        sparse_molecule::SparseAtomList,
+        utils::sterimol::RadiisItem,
+    };
+    use nalgebra::Point3;
+    let atom = |element, x, y, z| Atom3D {
+        element,
+        position: Point3::new(x, y, z),
+        formal_charge: 0.,
+    };
+    // A methyl group on a hydrogen along the x axis, with a distant atom
+    let mut structure = SparseMolecule {
+        atoms: SparseAtomList::from(vec![
+            atom(1, -1.09, 0., 0.),
+            atom(6, 0., 0., 0.),
+            atom(1, 0.363, 1.028, 0.),
+            atom(1, 0.363, -0.514, 0.890),
+            atom(1, 0.363, -0.514, -0.890),
+            atom(6, -5., 0., 0.),
+        ]),
+        ..Default::default()
+    };
+    for (a, b) in [(0, 1), (1, 2), (1, 3), (1, 4)] {
+        structure.bonds.set_bond(a, b, Some(1.));
+    }
+    let table = (0..=6)
+        .map(|element| RadiisItem {
+            symbol: element_num_to_symbol(element).unwrap_or("X").to_string(),
+            value: if element == 1 { 1.1 } else { 1.7 },
+        })
+        .collect::<Vec<_>>();
+    let options = serde_yaml::from_str::<StericOptions>(
+        "{axis: [0, 1], radii_table: radii.json, output: steric.csv}",
+    )
+    .unwrap();
+    let [l, b1, b5, cone_angle, buried_volume] = options.descriptors(&structure, &table).unwrap();
+    assert!((l - (1.09 + 0.363 + 1.1)).abs() < 1e-9);
+    assert!((b1 - (1.028 + 1.1)).abs() < 1e-3);
+    assert!(b5 >= b1);
+    assert!((cone_angle - 70.55).abs() < 0.05);
+    assert!(buried_volume > 0. && buried_volume < 100.);
+    let options = serde_yaml::from_str::<StericOptions>(
+        "{axis: [0, 9], radii_table: radii.json, output: steric.csv}",
+    )
+    .unwrap();
+    assert!(options.descriptors(&structure, &table).is_err());
+}
@@ -0,0 +1,259 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use lmers::io::BasicIOMolecule;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{
+    features::quote_field,
+    runner::{rooted, SanitizeOptions},
+    unit::EnergyUnit::KcalPerMol,
+    workflow_data::Window,
+};
+
+/// Energy quantities read from the output logs.
+const QUANTITIES: [(&str, &str); 3] = [("E", "energy"), ("H", "enthalpy"), ("G", "free_energy")];
+
+/// A species of the reaction, read from the output log (`g16log` or `orcaout`)
+/// of each structure.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Species {
+    /// Path of the output log, `{title}` is replaced like the working
+    /// directories of Calculation, e.g. `ts/{title}/input.log`
+    log: String,
+    format: String,
+    /// Stoichiometric coefficient
+    #[serde(default = "Species::default_coefficient")]
+    coefficient: f64,
+    /// Use the structure with the title for all rows instead of the one with
+    /// the same title, e.g. a common reagent
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    sanitize: SanitizeOptions,
+}
+
+impl Species {
+    fn default_coefficient() -> f64 {
+        1.
+    }
+
+    /// Energies of each title in Hartree, in the order of `QUANTITIES`.
+    fn energies(&self, window: &Window) -> BTreeMap<String, [Option<f64>; 3]> {
+        let titles = if let Some(title) = &self.title {
+            vec![title.to_string()]
+        } else {
+            window.keys().cloned().collect()
+        };
+        let read = |title: &str| -> Result<[Option<f64>; 3]> {
+            let path = PathBuf::from(self.log.replace("{title}", &self.sanitize.name(title)));
+            let file = File::open(&path).with_context(|| format!("Unable to open {:?}", path))?;
+            let log = BasicIOMolecule::input(&self.format, file)?;
+            let mut energies = [None; 3];
+            for (energy, (_, name)) in energies.iter_mut().zip(QUANTITIES) {
+                if let Some(value) = log.properties.get(name) {
+                    *energy =
+                        Some(value.parse::<f64>().with_context(|| {
+                            format!("Invalid {} {:?} in {:?}", name, value, path)
+                        })?);
+                }
+            }
+            Ok(energies)
+        };
+        let energies = titles
+            .iter()
+            .map(|title| {
+                let energies = read(title).unwrap_or_else(|err| {
+                    println!("Unable to read energies of {}: {:#}", title, err);
+                    [None; 3]
+                });
+                (title.to_string(), energies)
+            })
+            .collect::<BTreeMap<_, _>>();
+        if let Some(title) = &self.title {
+            window
+                .keys()
+                .map(|row| (row.to_string(), energies[title]))
+                .collect()
+        } else {
+            energies
+        }
+    }
+}
+
+/// Write a table of the reaction and activation energies of each structure in
+/// the window.
+///
+/// Species of the reactants, products and transition states are matched by
+/// title, e.g. the logs of the same substituent in the reactant and transition
+/// state steps. The reaction energies `dE`, `dH` and `dG` and the activation
+/// energies `dE_act`, `dH_act` and `dG_act` (if `transition_states` is given)
+/// are in kcal/mol. Values that can't be computed (e.g. no frequency
+/// calculation for free energies) are left empty. The table is written as CSV,
+/// or tab separated if the path ends with `.tsv`.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ThermochemistryOptions {
+    path: PathBuf,
+    reactants: Vec<Species>,
+    #[serde(default)]
+    products: Vec<Species>,
+    #[serde(default)]
+    transition_states: Vec<Species>,
+}
+
+impl ThermochemistryOptions {
+    pub fn root_outputs(&mut self, directory: &Path) {
+        self.path = rooted(directory, &self.path);
+    }
+
+    pub fn execute(&self, current_window: &Window) -> Result<()> {
+        let delimiter = match self.path.extension().and_then(|ext| ext.to_str()) {
+            Some("tsv") => "\t",
+            Some("csv") | None => ",",
+            Some(ext) => Err(anyhow!(
+                "Unsupported thermochemistry table format {}, use csv or tsv",
+                ext
+            ))?,
+        };
+        if self.reactants.is_empty() {
+            Err(anyhow!(
+                "At least one reactant is required for thermochemistry"
+            ))?
+        }
+        let sum = |species: &[Species]| {
+            let energies = species
+                .iter()
+                .map(|species| (species.coefficient, species.energies(current_window)))
+                .collect::<Vec<_>>();
+            current_window
+                .keys()
+                .map(|title| {
+                    let mut total = [Some(0.); 3];
+                    for (coefficient, energies) in &energies {
+                        for (total, energy) in total.iter_mut().zip(energies[title]) {
+                            *total = total.zip(energy).map(|(a, b)| a + coefficient * b);
+                        }
+                    }
+                    (title.to_string(), total)
+                })
+                .collect::<BTreeMap<_, _>>()
+        };
+        let reactants = sum(&self.reactants);
+        let mut targets = vec![];
+        if !self.products.is_empty() {
+            targets.push(("", sum(&self.products)));
+        }
+        if !self.transition_states.is_empty() {
+            targets.push(("_act", sum(&self.transition_states)));
+        }
+        let mut header = vec!["title".to_string()];
+        for (suffix, _) in &targets {
+            header.extend(
+                QUANTITIES
+                    .iter()
+                    .map(|(quantity, _)| format!("d{}{}", quantity, suffix)),
+            );
+        }
+        let mut content = header.join(delimiter);
+        content.push('\n');
+        for title in current_window.keys() {
+            let mut fields = vec![quote_field(title, delimiter)];
+            for (_, energies) in &targets {
+                for (target, reactant) in energies[title].iter().zip(reactants[title]) {
+                    let delta = target
+                        .zip(reactant)
+                        .map(|(target, reactant)| KcalPerMol.convert_hartree(target - reactant));
+                    fields.push(
+                        delta
+                            .map(|delta| format!("{:.2}", delta))
+                            .unwrap_or_default(),
+                    );
+                }
+            }
+            content.push_str(&fields.join(delimiter));
+            content.push('\n');
+        }
+        File::create(&self.path)
+            .with_context(|| format!("Unable to create thermochemistry table at {:?}", self.path))?
+            .write_all(content.as_bytes())
+            .with_context(|| format!("Unable to write thermochemistry table at {:?}", self.path))?;
+        println!(
+            "Thermochemistry of {} structures written to {:?}",
+            current_window.len(),
+            self.path
+        );
+        Ok(())
+    }
+}
+
+#[test]
+fn reaction_free_energies() {
+    use std::fs::read_to_string;
+    let directory = tempfile::tempdir().unwrap();
+    let log = |name: &str, energy: f64, free_energy: Option<f64>| {
+        let mut content = " Input orientation:
+ ---------------------------------------------------------------------
+ Center     Atomic      Atomic             Coordinates (Angstroms)
+ Number     Number       Type             X           Y           Z
+ ---------------------------------------------------------------------
+      1          1           0        0.000000    0.000000    0.000000
+ ---------------------------------------------------------------------
+"
+        .to_string();
+        content.push_str(&format!(" SCF Done:  E(RB3LYP) =  {}     A.U.\n", energy));
+        if let Some(free_energy) = free_energy {
+            content.push_str(&format!(
+                " Sum of electronic and thermal Free Energies=  {}\n",
+                free_energy
+            ));
+        }
+        std::fs::write(directory.path().join(name), content).unwrap();
+    };
+    log("R_Me.log", -100., Some(-99.9));
+    log("R_Ph.log", -200., None);
+    log("TS_Me.log", -99.95, Some(-99.86));
+    log("TS_Ph.log", -199.96, Some(-199.9));
+    log("P_Me.log", -100.01, Some(-99.92));
+    log("P_Ph.log", -200.02, Some(-199.91));
+    log("H2.log", -1., Some(-1.01));
+    let species = |pattern: &str, title: Option<&str>| Species {
+        log: directory.path().join(pattern).to_string_lossy().to_string(),
+        format: "g16log".to_string(),
+        coefficient: 1.,
+        title: title.map(String::from),
+        sanitize: Default::default(),
+    };
+    let path = directory.path().join("thermo.csv");
+    let options = ThermochemistryOptions {
+        path: path.clone(),
+        reactants: vec![
+            species("R_{title}.log", None),
+            species("{title}.log", Some("H2")),
+        ],
+        products: vec![species("P_{title}.log", None)],
+        transition_states: vec![species("TS_{title}.log", None)],
+    };
+    let window = Window::from([("Me".to_string(), vec![]), ("Ph".to_string(), vec![])]);
+    options.execute(&window).unwrap();
+    let kcal = |hartree: f64| format!("{:.2}", KcalPerMol.convert_hartree(hartree));
+    assert_eq!(
+        read_to_string(path).unwrap(),
+        format!(
+            "title,dE,dH,dG,dE_act,dH_act,dG_act\nMe,{},,{},{},,{}\nPh,{},,,{},,\n",
+            kcal(0.99),
+            kcal(0.99),
+            kcal(1.05),
+            kcal(1.05),
+            kcal(0.98),
+            kcal(1.04)
+        )
+    );
+}
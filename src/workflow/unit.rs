@@ -0,0 +1,113 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+/// Units of the energies written by the programs. Numbers read with a declared
+/// unit are converted to Hartree, so energies of different programs can be
+/// sorted and compared together.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema)]
+pub enum EnergyUnit {
+    Hartree,
+    #[serde(rename = "eV")]
+    ElectronVolt,
+    #[serde(rename = "kcal/mol")]
+    KcalPerMol,
+    #[serde(rename = "kJ/mol")]
+    KjPerMol,
+    #[serde(rename = "cm-1")]
+    Wavenumber,
+}
+
+impl EnergyUnit {
+    /// Value of one Hartree in the unit.
+    pub fn per_hartree(self) -> f64 {
+        match self {
+            Self::Hartree => 1.,
+            Self::ElectronVolt => 27.211386245988,
+            Self::KcalPerMol => 627.509474,
+            Self::KjPerMol => 2625.4996394799,
+            Self::Wavenumber => 219474.6313632,
+        }
+    }
+
+    /// Convert a value in the unit to Hartree.
+    pub fn to_hartree(self, value: f64) -> f64 {
+        value / self.per_hartree()
+    }
+
+    /// Convert a value in Hartree to the unit.
+    pub fn convert_hartree(self, value: f64) -> f64 {
+        value * self.per_hartree()
+    }
+}
+
+impl FromStr for EnergyUnit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "hartree" | "eh" | "au" | "a.u." => Self::Hartree,
+            "ev" => Self::ElectronVolt,
+            "kcal/mol" => Self::KcalPerMol,
+            "kj/mol" => Self::KjPerMol,
+            "cm-1" | "cm^-1" => Self::Wavenumber,
+            _ => Err(anyhow!("Unknown energy unit {}", s))?,
+        })
+    }
+}
+
+/// An energy threshold, a number in the unit of the compared values, or a
+/// string with the unit, e.g. `3 kcal/mol`, which requires the compared
+/// values to be read with a declared unit.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Energy {
+    Number(f64),
+    WithUnit(String),
+}
+
+impl Energy {
+    /// The threshold in the unit of the compared values, `normalized` tells if
+    /// the values are converted to Hartree.
+    pub fn resolve(&self, normalized: bool) -> Result<f64> {
+        match self {
+            Self::Number(value) => Ok(*value),
+            Self::WithUnit(content) => {
+                let (value, unit) = content
+                    .trim()
+                    .split_once(char::is_whitespace)
+                    .with_context(|| format!("No unit in energy {}", content))?;
+                let value = value
+                    .parse::<f64>()
+                    .with_context(|| format!("Invalid number {} in energy {}", value, content))?;
+                let unit = unit.parse::<EnergyUnit>()?;
+                if !normalized {
+                    Err(anyhow!(
+                        "Energy {} has a unit but the compared values have no declared unit",
+                        content
+                    ))?
+                }
+                Ok(unit.to_hartree(value))
+            }
+        }
+    }
+}
+
+#[test]
+fn energy_units() {
+    let kcal = EnergyUnit::KcalPerMol;
+    assert!((kcal.to_hartree(627.509474) - 1.).abs() < 1e-12);
+    let ev = serde_yaml::from_str::<EnergyUnit>("eV").unwrap();
+    assert!((ev.convert_hartree(kcal.to_hartree(23.0605)) - 1.).abs() < 1e-4);
+    let threshold = serde_yaml::from_str::<Energy>("3 kcal/mol").unwrap();
+    assert!((threshold.resolve(true).unwrap() - 3. / 627.509474).abs() < 1e-12);
+    assert!(threshold.resolve(false).is_err());
+    let threshold = serde_yaml::from_str::<Energy>("0.4").unwrap();
+    assert_eq!(threshold.resolve(false).unwrap(), 0.4);
+    assert!(serde_yaml::from_str::<Energy>("3 kcal")
+        .unwrap()
+        .resolve(true)
+        .is_err());
+}
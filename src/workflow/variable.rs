@@ -0,0 +1,375 @@
+use std::{
+    collections::BTreeMap,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_yaml::{value::TaggedValue, Mapping, Value};
+
+use super::{runner::SanitizeOptions, unit::EnergyUnit, workflow_data::Window};
+
+/// Workflow variables captured from the results of steps.
+pub type Variables = BTreeMap<String, f64>;
+
+lazy_static! {
+    static ref VARIABLE_RE: Regex = Regex::new(r"\$\{\s*([^{}\s]+)\s*\}").unwrap();
+}
+
+/// A scalar captured from the window after a step is executed.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(tag = "value", deny_unknown_fields)]
+pub enum Capture {
+    /// Number of structures in the window
+    Count,
+    /// Read a number of each structure from a file and reduce them to one.
+    ///
+    /// `{title}` in the path is replaced by the title, converted by the
+    /// `sanitize` options like the working directories of Calculation. The
+    /// number is the first capture group of the last match of `pattern` in the
+    /// file, or the whole file content if no pattern given. Numbers with a
+    /// declared `unit` are converted to Hartree.
+    FromFile {
+        path: String,
+        #[serde(default)]
+        pattern: Option<String>,
+        reduce: Reduce,
+        #[serde(default)]
+        sanitize: SanitizeOptions,
+        #[serde(default)]
+        unit: Option<EnergyUnit>,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub enum Reduce {
+    Min,
+    Max,
+    Sum,
+    Mean,
+}
+
+impl Capture {
+    pub fn evaluate(&self, window: &Window) -> Result<f64> {
+        match self {
+            Self::Count => Ok(window.len() as f64),
+            Self::FromFile {
+                path,
+                pattern,
+                reduce,
+                sanitize,
+                unit,
+            } => {
+                let pattern = compile_pattern(pattern.as_deref())?;
+                let values = window
+                    .keys()
+                    .map(|title| {
+                        let path = PathBuf::from(path.replace("{title}", &sanitize.name(title)));
+                        read_scalar(&path, pattern.as_ref())
+                            .map(|value| to_hartree(*unit, value))
+                            .with_context(|| {
+                                format!("Unable to capture value of {} from {:?}", title, path)
+                            })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                if values.is_empty() {
+                    Err(anyhow!("No structure to capture value from {}", path))?
+                }
+                Ok(match reduce {
+                    Reduce::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+                    Reduce::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                    Reduce::Sum => values.iter().sum(),
+                    Reduce::Mean => values.iter().sum::<f64>() / values.len() as f64,
+                })
+            }
+        }
+    }
+}
+
+/// A number of each structure read from a file.
+///
+/// `{title}` in the path is replaced by the title converted with `sanitize`,
+/// like the working directories of Calculation. The path can be a glob pattern
+/// (with `*` or `?`), e.g. `calc/{title}/*.out`, then the last matched file in
+/// alphabetical order is read. The number is the first capture group of the
+/// last match of `pattern`, or the whole file content. `{title}` in the
+/// pattern is replaced by the escaped title, so the values can be read from
+/// one table, e.g. `{path: results.csv, pattern: '(?m)^{title},([^,]+)'}`.
+///
+/// With `unit` declared, e.g. `eV` for the energies of a semi-empirical
+/// program, the numbers are converted to Hartree, so energies from different
+/// programs are compared in the same unit, and thresholds with units (see
+/// `Energy`) are converted accordingly.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ScalarFile {
+    path: String,
+    #[serde(default)]
+    pattern: Option<String>,
+    #[serde(default)]
+    sanitize: SanitizeOptions,
+    #[serde(default)]
+    unit: Option<EnergyUnit>,
+}
+
+impl ScalarFile {
+    /// Read the number of each structure in the window.
+    pub fn read_window(&self, window: &Window) -> Result<BTreeMap<String, Result<f64>>> {
+        self.reader()?.read_window(window)
+    }
+
+    /// Reader with the pattern compiled, to read many windows.
+    pub fn reader(&self) -> Result<ScalarReader<'_>> {
+        let title_pattern = self
+            .pattern
+            .as_deref()
+            .filter(|pattern| pattern.contains("{title}"));
+        let shared = compile_pattern(self.pattern.as_deref().filter(|_| title_pattern.is_none()))?;
+        Ok(ScalarReader {
+            file: self,
+            title_pattern,
+            shared,
+        })
+    }
+
+    /// If the numbers are converted to Hartree from a declared unit.
+    pub fn normalized(&self) -> bool {
+        self.unit.is_some()
+    }
+}
+
+/// `ScalarFile` with the pattern compiled, a pattern with `{title}` is
+/// compiled for each title.
+pub struct ScalarReader<'a> {
+    file: &'a ScalarFile,
+    title_pattern: Option<&'a str>,
+    shared: Option<Regex>,
+}
+
+impl ScalarReader<'_> {
+    /// Read the number of each structure in the window.
+    pub fn read_window(&self, window: &Window) -> Result<BTreeMap<String, Result<f64>>> {
+        let read = |title: &str| {
+            let path = matched_path(&self.file.path, &self.file.sanitize.name(title))?;
+            if let Some(pattern) = self.title_pattern {
+                let pattern = pattern.replace("{title}", &fancy_regex::escape(title));
+                read_scalar(&path, compile_pattern(Some(&pattern))?.as_ref())
+            } else {
+                read_scalar(&path, self.shared.as_ref())
+            }
+        };
+        Ok(window
+            .keys()
+            .map(|title| {
+                let value = read(title).map(|value| to_hartree(self.file.unit, value));
+                (title.to_string(), value)
+            })
+            .collect())
+    }
+}
+
+/// Path of the template with `{title}` replaced by the name, the last matched
+/// file in alphabetical order if the template is a glob pattern.
+pub(super) fn matched_path(template: &str, name: &str) -> Result<PathBuf> {
+    if !template.contains(['*', '?']) {
+        return Ok(PathBuf::from(template.replace("{title}", name)));
+    }
+    let pattern = template.replace("{title}", &glob::Pattern::escape(name));
+    let mut paths = glob::glob(&pattern)
+        .with_context(|| format!("Invalid glob pattern {}", pattern))?
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+    paths
+        .pop()
+        .with_context(|| format!("No file matched by {}", pattern))
+}
+
+fn to_hartree(unit: Option<EnergyUnit>, value: f64) -> f64 {
+    unit.map(|unit| unit.to_hartree(value)).unwrap_or(value)
+}
+
+pub(super) fn compile_pattern(pattern: Option<&str>) -> Result<Option<Regex>> {
+    pattern
+        .map(|pattern| {
+            Regex::new(pattern).with_context(|| format!("Invalid capture pattern {}", pattern))
+        })
+        .transpose()
+}
+
+/// Read a number from the file, the first capture group of the last match of
+/// the pattern, or the whole file content if no pattern given.
+pub(super) fn read_scalar(path: &Path, pattern: Option<&Regex>) -> Result<f64> {
+    let content =
+        read_to_string(path).with_context(|| format!("Unable to read file {:?}", path))?;
+    let matched = if let Some(pattern) = pattern {
+        let captures = pattern
+            .captures_iter(&content)
+            .filter_map(|captures| captures.ok())
+            .last()
+            .with_context(|| format!("Pattern {} not matched in {:?}", pattern, path))?;
+        captures
+            .get(1)
+            .or(captures.get(0))
+            .map(|matched| matched.as_str().to_string())
+            .unwrap_or_default()
+    } else {
+        content
+    };
+    matched
+        .trim()
+        .parse::<f64>()
+        .with_context(|| format!("Unable to parse {:?} in {:?} as a number", matched, path))
+}
+
+/// Check if there are `${name}` variable references in the strings of the value.
+pub fn has_variables(value: &Value) -> bool {
+    let mut found = false;
+    let _ = substitute(value.clone(), &mut |_| {
+        found = true;
+        Ok(None)
+    });
+    found
+}
+
+/// Replace `${name}` in the strings of the value with the variables. A string
+/// contains only one reference is replaced by the number, so it can be used in
+/// numeric fields.
+pub fn substitute_variables(value: Value, variables: &Variables) -> Result<Value> {
+    substitute(value, &mut |name| {
+        let value = variables
+            .get(name)
+            .copied()
+            .with_context(|| format!("Variable {} is not captured by previous steps", name))?;
+        Ok(Some(if value.fract() == 0. && value.abs() < 1e15 {
+            Value::from(value as i64)
+        } else {
+            Value::from(value)
+        }))
+    })
+}
+
+/// Text of a scalar value used in names and strings.
+pub(super) fn scalar_string(value: &Value) -> Result<String> {
+    match value {
+        Value::String(value) => Ok(value.to_string()),
+        Value::Number(value) => Ok(value.to_string()),
+        Value::Bool(value) => Ok(value.to_string()),
+        other => Err(anyhow!("Only scalars are supported, found {:?}", other)),
+    }
+}
+
+/// Replace `${name}` in the strings of the value with the values looked up, a
+/// string of only one reference is replaced by the value itself so it keeps the
+/// type. References looked up as None are kept, so are the parameters of the
+/// matrix of a nested ForEach runner in its `run`, which are replaced by the
+/// runner itself.
+pub(super) fn substitute(
+    value: Value,
+    lookup: &mut dyn FnMut(&str) -> Result<Option<Value>>,
+) -> Result<Value> {
+    Ok(match value {
+        Value::String(content) => {
+            if let Ok(Some(captures)) = VARIABLE_RE.captures(&content) {
+                if captures.get(0).map(|matched| matched.as_str()) == Some(content.trim()) {
+                    return Ok(lookup(&captures[1])?.unwrap_or(Value::String(content)));
+                }
+            }
+            let mut result = String::new();
+            let mut last = 0;
+            for captures in VARIABLE_RE.captures_iter(&content) {
+                let captures = captures?;
+                let matched = captures.get(0).unwrap();
+                result.push_str(&content[last..matched.start()]);
+                match lookup(&captures[1])? {
+                    Some(value) => result.push_str(&scalar_string(&value)?),
+                    None => result.push_str(matched.as_str()),
+                }
+                last = matched.end();
+            }
+            result.push_str(&content[last..]);
+            Value::String(result)
+        }
+        Value::Sequence(items) => Value::Sequence(
+            items
+                .into_iter()
+                .map(|item| substitute(item, lookup))
+                .collect::<Result<_>>()?,
+        ),
+        Value::Mapping(mapping) => {
+            let parameters = matrix_parameters(&mapping);
+            Value::Mapping(
+                mapping
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let value = if !parameters.is_empty() && key.as_str() == Some("run") {
+                            substitute(value, &mut |name| {
+                                if parameters.iter().any(|parameter| parameter == name) {
+                                    Ok(None)
+                                } else {
+                                    lookup(name)
+                                }
+                            })?
+                        } else {
+                            substitute(value, lookup)?
+                        };
+                        Ok((substitute(key, lookup)?, value))
+                    })
+                    .collect::<Result<_>>()?,
+            )
+        }
+        Value::Tagged(tagged) => Value::Tagged(Box::new(TaggedValue {
+            tag: tagged.tag,
+            value: substitute(tagged.value, lookup)?,
+        })),
+        value => value,
+    })
+}
+
+/// Parameter names of the mapping if it is a ForEach runner.
+fn matrix_parameters(mapping: &Mapping) -> Vec<String> {
+    if mapping.get("with").and_then(Value::as_str) != Some("ForEach") {
+        return vec![];
+    }
+    mapping
+        .get("matrix")
+        .and_then(Value::as_mapping)
+        .map(|matrix| {
+            matrix
+                .keys()
+                .filter_map(|key| key.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[test]
+fn substitute_in_runner() {
+    let variables = Variables::from([("lowest".to_string(), -1.5), ("count".to_string(), 3.)]);
+    let value: Value =
+        serde_yaml::from_str("with: CountBreak\nfilepath: 'break_${ count }'\ntimes: ${count}")
+            .unwrap();
+    assert!(has_variables(&value));
+    let value = substitute_variables(value, &variables).unwrap();
+    assert_eq!(
+        value,
+        serde_yaml::from_str::<Value>("with: CountBreak\nfilepath: 'break_3'\ntimes: 3").unwrap()
+    );
+    let value: Value = serde_yaml::from_str("threshold: ${missing}").unwrap();
+    assert!(substitute_variables(value, &variables).is_err());
+    // Parameters of a ForEach are left to the runner
+    let value: Value = serde_yaml::from_str(
+        "with: ForEach\nmatrix: {group: [Me]}\nrun: {with: Retain, pattern: '${group}_${count}'}",
+    )
+    .unwrap();
+    assert!(has_variables(&value));
+    let value = substitute_variables(value, &variables).unwrap();
+    assert_eq!(value["run"]["pattern"], Value::from("${group}_3"));
+    let value: Value =
+        serde_yaml::from_str("with: ForEach\nmatrix: {group: [Me]}\nrun: {pattern: '${group}'}")
+            .unwrap();
+    assert!(!has_variables(&value));
+}
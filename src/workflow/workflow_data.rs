@@ -1,17 +1,160 @@
+use anyhow::{anyhow, Context, Result};
 use lmers::{layer::Layer, sparse_molecule::SparseMolecule};
-use redb::{Database, ReadableTableMetadata, TableDefinition};
+use redb::{Database, ReadableTable, TableDefinition, TableError, TypeName, Value};
 use std::{
     collections::{BTreeMap, BTreeSet},
     ops::Range,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
-const LAYER_TABLE: TableDefinition<u64, Layer> = TableDefinition::new("layer_table");
+pub const LAYER_TABLE: TableDefinition<u64, Layer> = TableDefinition::new("layer_table");
+const RAW_LAYER_TABLE: TableDefinition<u64, RawLayer> = TableDefinition::new("layer_table");
 
 use serde::{Deserialize, Serialize};
 
 pub type Window = BTreeMap<String, Vec<u64>>;
 
+/// Number of references to each layer from the stack paths of the windows.
+/// Layers not counted are used by none of the windows.
+pub fn reference_counts<'a>(windows: impl IntoIterator<Item = &'a Window>) -> BTreeMap<u64, usize> {
+    let mut counts = BTreeMap::new();
+    for window in windows {
+        for layer_id in window.values().flatten() {
+            *counts.entry(*layer_id).or_default() += 1;
+        }
+    }
+    counts
+}
+
+/// Windows of all checkpoints saved in the directory, including those of the
+/// partitions and failures not named by a step. Hidden files (the layer
+/// database, variables and so on) are skipped, and a checkpoint unable to be
+/// read is an error, so that layers are never removed by a partial scan.
+pub fn checkpoint_windows(directory: &Path) -> Result<BTreeMap<String, Window>> {
+    let mut windows = BTreeMap::new();
+    let entries = std::fs::read_dir(directory)
+        .with_context(|| format!("Unable to read checkpoint directory {:?}", directory))?;
+    for entry in entries {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if name.starts_with('.') || !path.is_file() {
+            continue;
+        }
+        let file = std::fs::File::open(&path)
+            .with_context(|| format!("Unable to open checkpoint {:?}", path))?;
+        let window = serde_json::from_reader(file)
+            .with_context(|| format!("Unable to read checkpoint {:?}", path))?;
+        windows.insert(name, window);
+    }
+    Ok(windows)
+}
+
+/// Window of the checkpoint saved in the directory, or the merged windows of
+/// the checkpoints matching the name as a glob pattern if no checkpoint has
+/// the exact name, e.g. `attach_*` for the windows of the MultiWindow output
+/// of the step `attach`. A title in more than one matched window with
+/// different stack paths is an error.
+pub fn read_checkpoints(directory: &Path, name: &str) -> Result<Window> {
+    let path = directory.join(name);
+    if path.is_file() {
+        let file = std::fs::File::open(&path)
+            .with_context(|| format!("Unable to open the checkpoint file {:?}", path))?;
+        return serde_json::from_reader(file).with_context(|| {
+            format!("Failed to deserialize the checkpoint file for the {}", name)
+        });
+    }
+    let pattern =
+        glob::Pattern::new(name).with_context(|| format!("Invalid checkpoint pattern {}", name))?;
+    let matched = checkpoint_windows(directory)?
+        .into_iter()
+        .filter(|(checkpoint, _)| pattern.matches(checkpoint))
+        .collect::<Vec<_>>();
+    if matched.is_empty() {
+        Err(anyhow!("No checkpoint named or matching {} found", name))?
+    }
+    let mut merged = Window::new();
+    for (checkpoint, window) in matched {
+        for (title, stack_path) in window {
+            match merged.get(&title) {
+                Some(existing) if existing != &stack_path => Err(anyhow!(
+                    "Structure {} of checkpoint {} conflicts with another matched checkpoint",
+                    title,
+                    checkpoint
+                ))?,
+                _ => {
+                    merged.insert(title, stack_path);
+                }
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// Save the window as the checkpoint in the directory. It's written to a hidden
+/// file and then renamed, so an interrupted run never leaves a partial
+/// checkpoint.
+pub fn write_checkpoint(directory: &Path, name: &str, window: &Window) -> Result<()> {
+    let temporary = directory.join(format!(".{}.tmp", name));
+    let file = std::fs::File::create(&temporary)
+        .with_context(|| format!("Failed to create checkpoint {}", name))?;
+    serde_json::to_writer(file, window)
+        .with_context(|| "Failed to serialize the checkpoint information")?;
+    std::fs::rename(&temporary, directory.join(name))
+        .with_context(|| format!("Failed to create checkpoint {}", name))
+}
+
+/// Layer as the bytes stored in the layer table, so damaged layers can be
+/// checked by `Layer::decode` instead of panicking when read.
+#[derive(Debug)]
+struct RawLayer;
+
+impl Value for RawLayer {
+    type SelfType<'a> = &'a [u8];
+    type AsBytes<'a> = &'a [u8];
+
+    fn fixed_width() -> Option<usize> {
+        None
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> &'a [u8]
+    where
+        Self: 'a,
+    {
+        data
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a &'b [u8]) -> &'a [u8]
+    where
+        Self: 'b,
+    {
+        value
+    }
+
+    fn type_name() -> TypeName {
+        Layer::type_name()
+    }
+}
+
+/// Damages found and repaired by `LayerStorage::recover`.
+#[derive(Debug, Default)]
+pub struct Recovery {
+    /// The database file is repaired by redb.
+    pub repaired: bool,
+    /// Referenced layers unable to be decoded, removed from the database.
+    pub damaged_layers: BTreeSet<u64>,
+    /// Checkpoints unable to be read or referring to missing or damaged
+    /// layers, with the names they are renamed to, `.damaged_<name>` or with a
+    /// number appended if a damaged copy of an earlier run already exists.
+    pub damaged_checkpoints: BTreeMap<String, String>,
+    /// Temporary files of checkpoints (see `write_checkpoint`) left by an
+    /// interrupted run, removed.
+    pub temporary_checkpoints: Vec<String>,
+}
+
+#[allow(dead_code)]
 #[derive(Deserialize, Serialize)]
 pub struct WorkflowData {
     pub base: SparseMolecule,
@@ -43,6 +186,135 @@ impl LayerStorage {
         }
         writer.commit().unwrap();
     }
+
+    /// Identifiers of all layers in the storage.
+    pub fn layer_ids(&self) -> BTreeSet<u64> {
+        let read_txn = self.db.begin_read().unwrap();
+        let Ok(table) = read_txn.open_table(LAYER_TABLE) else {
+            return BTreeSet::new();
+        };
+        table
+            .iter()
+            .unwrap()
+            .map(|entry| entry.unwrap().0.value())
+            .collect()
+    }
+
+    /// Check the database and the checkpoints in the directory after a run not
+    /// finished, e.g. killed while writing. The database is checked and
+    /// repaired by redb, and the layers referenced by the checkpoints are
+    /// decoded. Damaged layers are removed, and damaged checkpoints or those
+    /// referring to missing or damaged layers are hidden, so a restart resumes
+    /// from an intact checkpoint before them (see the `-c` option).
+    pub fn recover(&mut self, directory: &Path) -> Result<Recovery> {
+        let mut recovery = Recovery {
+            repaired: !self.db.check_integrity().with_context(|| {
+                format!("Unable to repair the layer database {:?}", self.db_path)
+            })?,
+            ..Default::default()
+        };
+        let mut windows = BTreeMap::new();
+        let mut damaged = BTreeSet::new();
+        let entries = std::fs::read_dir(directory)
+            .with_context(|| format!("Unable to read checkpoint directory {:?}", directory))?;
+        for entry in entries {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if !path.is_file() {
+                continue;
+            }
+            if name.starts_with('.') {
+                if name.ends_with(".tmp") && !name.starts_with(".damaged_") {
+                    std::fs::remove_file(&path).with_context(|| {
+                        format!("Unable to remove the temporary checkpoint {:?}", path)
+                    })?;
+                    recovery.temporary_checkpoints.push(name);
+                }
+                continue;
+            }
+            let window = std::fs::File::open(&path)
+                .ok()
+                .and_then(|file| serde_json::from_reader::<_, Window>(file).ok());
+            match window {
+                Some(window) => {
+                    windows.insert(name, window);
+                }
+                None => {
+                    damaged.insert(name);
+                }
+            }
+        }
+        let referenced = reference_counts(windows.values());
+        let mut unavailable = BTreeSet::new();
+        let read_txn = self.db.begin_read()?;
+        match read_txn.open_table(RAW_LAYER_TABLE) {
+            Ok(table) => {
+                for layer_id in referenced.keys() {
+                    match table.get(layer_id)? {
+                        Some(data) if Layer::decode(data.value()).is_ok() => {}
+                        Some(_) => {
+                            recovery.damaged_layers.insert(*layer_id);
+                        }
+                        None => {
+                            unavailable.insert(*layer_id);
+                        }
+                    }
+                }
+            }
+            Err(TableError::TableDoesNotExist(_)) => unavailable.extend(referenced.keys()),
+            Err(err) => Err(err)?,
+        }
+        drop(read_txn);
+        if !recovery.damaged_layers.is_empty() {
+            let write_txn = self.db.begin_write()?;
+            {
+                let mut table = write_txn.open_table(RAW_LAYER_TABLE)?;
+                for layer_id in &recovery.damaged_layers {
+                    table.remove(layer_id)?;
+                }
+            }
+            write_txn.commit()?;
+        }
+        unavailable.extend(&recovery.damaged_layers);
+        damaged.extend(
+            windows
+                .into_iter()
+                .filter(|(_, window)| window.values().flatten().any(|id| unavailable.contains(id)))
+                .map(|(name, _)| name),
+        );
+        recovery.temporary_checkpoints.sort();
+        for name in damaged {
+            let hidden = (0..)
+                .map(|count| match count {
+                    0 => format!(".damaged_{}", name),
+                    count => format!(".damaged_{}.{}", name, count),
+                })
+                .find(|hidden| !directory.join(hidden).exists())
+                .unwrap();
+            std::fs::rename(directory.join(&name), directory.join(&hidden))
+                .with_context(|| format!("Unable to hide the damaged checkpoint {}", name))?;
+            recovery.damaged_checkpoints.insert(name, hidden);
+        }
+        Ok(recovery)
+    }
+
+    /// Remove the layers without references (see `reference_counts`), returns
+    /// the identifiers of the removed layers.
+    pub fn remove_unused_layers(&self, counts: &BTreeMap<u64, usize>) -> BTreeSet<u64> {
+        let removed = self
+            .layer_ids()
+            .into_iter()
+            .filter(|layer_id| counts.get(layer_id).copied().unwrap_or_default() == 0)
+            .collect::<BTreeSet<_>>();
+        if !removed.is_empty() {
+            let retains = counts.keys().copied().collect();
+            self.retain(&retains);
+        }
+        removed
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -62,10 +334,16 @@ impl TryFrom<LayerStorageConfig> for LayerStorage {
 }
 
 impl LayerStorage {
+    /// Next to the largest identifier rather than the count of layers, so the
+    /// identifiers are never reused after unused layers removed.
     fn next_layer_id(&self) -> u64 {
         let read_txn = self.db.begin_read().unwrap();
         if let Ok(table) = read_txn.open_table(LAYER_TABLE) {
-            table.len().unwrap()
+            table
+                .last()
+                .unwrap()
+                .map(|(layer_id, _)| layer_id.value() + 1)
+                .unwrap_or_default()
         } else {
             0
         }
@@ -76,7 +354,7 @@ impl LayerStorage {
         let write_txn = self.db.begin_write().unwrap();
         {
             let mut table = write_txn.open_table(LAYER_TABLE).unwrap();
-            for (idx, layer) in layers.into_iter().enumerate() {
+            for (idx, layer) in layers.iter().enumerate() {
                 table.insert(start_id + idx as u64, layer.clone()).unwrap();
             }
         }
@@ -95,3 +373,88 @@ impl LayerStorage {
             .map(|acc| acc.value())
     }
 }
+
+#[test]
+fn remove_unused_layers() {
+    let directory = tempfile::tempdir().unwrap();
+    let storage = LayerStorage::new(directory.path().join(".layers.db"));
+    let layers = vec![Layer::Transparent; 4];
+    assert_eq!(storage.create_layers(&layers), 0..4);
+    // A checkpoint of a partition kept only on disk, and the live window
+    std::fs::write(directory.path().join("filter_minor"), r#"{"a":[0,1]}"#).unwrap();
+    std::fs::write(directory.path().join(".run_id"), "1").unwrap();
+    let mut windows = checkpoint_windows(directory.path()).unwrap();
+    assert_eq!(windows.keys().collect::<Vec<_>>(), ["filter_minor"]);
+    windows.insert(
+        "current".to_string(),
+        Window::from([("b".to_string(), vec![0, 3])]),
+    );
+    let counts = reference_counts(windows.values());
+    assert_eq!(counts, BTreeMap::from([(0, 2), (1, 1), (3, 1)]));
+    assert_eq!(storage.remove_unused_layers(&counts), BTreeSet::from([2]));
+    assert_eq!(storage.layer_ids(), BTreeSet::from([0, 1, 3]));
+    // Identifiers of the remaining layers are not reused
+    assert_eq!(storage.create_layers(&layers[..1]), 4..5);
+    std::fs::write(directory.path().join("broken"), "{").unwrap();
+    assert!(checkpoint_windows(directory.path()).is_err());
+}
+
+#[test]
+fn read_matched_checkpoints() {
+    let directory = tempfile::tempdir().unwrap();
+    let write = |name: &str, window: Window| {
+        let file = std::fs::File::create(directory.path().join(name)).unwrap();
+        serde_json::to_writer(file, &window).unwrap();
+    };
+    write("attach_Me", Window::from([("LME_Me".to_string(), vec![1])]));
+    write("attach_Ph", Window::from([("LME_Ph".to_string(), vec![2])]));
+    write("other", Window::from([("LME".to_string(), vec![])]));
+    let exact = read_checkpoints(directory.path(), "attach_Me").unwrap();
+    assert_eq!(exact.len(), 1);
+    let merged = read_checkpoints(directory.path(), "attach_*").unwrap();
+    assert_eq!(merged.keys().collect::<Vec<_>>(), ["LME_Me", "LME_Ph"]);
+    assert!(read_checkpoints(directory.path(), "missing_*").is_err());
+    write("attach_Et", Window::from([("LME_Me".to_string(), vec![3])]));
+    assert!(read_checkpoints(directory.path(), "attach_*").is_err());
+}
+
+#[test]
+fn recover_damaged_checkpoints() {
+    let directory = tempfile::tempdir().unwrap();
+    let mut storage = LayerStorage::new(directory.path().join(".layers.db"));
+    storage.create_layers(&vec![Layer::Transparent; 3]);
+    let write_txn = storage.db.begin_write().unwrap();
+    {
+        let mut table = write_txn.open_table(RAW_LAYER_TABLE).unwrap();
+        table.insert(3, [0xff; 8].as_slice()).unwrap();
+    }
+    write_txn.commit().unwrap();
+    let window = Window::from([("a".to_string(), vec![0, 1])]);
+    write_checkpoint(directory.path(), "intact", &window).unwrap();
+    std::fs::write(directory.path().join("damaged_layer"), r#"{"b":[0,3]}"#).unwrap();
+    std::fs::write(directory.path().join("missing_layer"), r#"{"c":[7]}"#).unwrap();
+    std::fs::write(directory.path().join("partial"), r#"{"d":[0,"#).unwrap();
+    std::fs::write(directory.path().join(".damaged_partial"), "earlier").unwrap();
+    std::fs::write(directory.path().join(".intact.tmp"), r#"{"a":"#).unwrap();
+    let recovery = storage.recover(directory.path()).unwrap();
+    assert!(!recovery.repaired);
+    assert_eq!(recovery.damaged_layers, BTreeSet::from([3]));
+    assert_eq!(
+        recovery.damaged_checkpoints.keys().collect::<Vec<_>>(),
+        ["damaged_layer", "missing_layer", "partial"]
+    );
+    assert_eq!(
+        recovery.damaged_checkpoints["partial"],
+        ".damaged_partial.1"
+    );
+    assert_eq!(recovery.temporary_checkpoints, [".intact.tmp"]);
+    assert!(!directory.path().join(".intact.tmp").exists());
+    assert_eq!(
+        std::fs::read_to_string(directory.path().join(".damaged_partial")).unwrap(),
+        "earlier"
+    );
+    assert_eq!(storage.layer_ids(), BTreeSet::from([0, 1, 2]));
+    let windows = checkpoint_windows(directory.path()).unwrap();
+    assert_eq!(windows, BTreeMap::from([("intact".to_string(), window)]));
+    assert!(directory.path().join(".damaged_partial.1").is_file());
+}
@@ -0,0 +1,107 @@
+//! End-to-end test of the workflow runner against golden files.
+//!
+//! The workflow in `tests/golden` is run in a temporary copy of the directory,
+//! and every file in `tests/golden/expected` is compared to the file at the same
+//! relative path produced by the workflow. Numbers are compared with a
+//! tolerance, other tokens must be identical. Run with `LME_BLESS=1` to update
+//! the golden files after an intended change of the results.
+#![cfg(unix)]
+
+use std::{
+    fs::{copy, create_dir_all, read_dir, read_to_string, write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const TOLERANCE: f64 = 1e-4;
+
+fn fixture() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+/// Relative paths of all files under the directory.
+fn files(root: &Path, directory: &Path) -> Vec<PathBuf> {
+    let mut files = vec![];
+    for entry in read_dir(directory).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(self::files(root, &path));
+        } else {
+            files.push(path.strip_prefix(root).unwrap().to_path_buf());
+        }
+    }
+    files.sort();
+    files
+}
+
+fn copy_fixture(from: &Path, to: &Path) {
+    for file in files(from, from) {
+        if file.starts_with("expected") {
+            continue;
+        }
+        create_dir_all(to.join(&file).parent().unwrap()).unwrap();
+        copy(from.join(&file), to.join(&file)).unwrap();
+    }
+}
+
+/// Compare the contents token by token, returns the first difference.
+fn compare(expected: &str, produced: &str) -> Option<String> {
+    let expected_lines = expected.lines().collect::<Vec<_>>();
+    let produced_lines = produced.lines().collect::<Vec<_>>();
+    if expected_lines.len() != produced_lines.len() {
+        return Some(format!(
+            "{} lines expected, found {}",
+            expected_lines.len(),
+            produced_lines.len()
+        ));
+    }
+    for (index, (expected, produced)) in expected_lines.iter().zip(&produced_lines).enumerate() {
+        let expected_tokens = expected.split_whitespace().collect::<Vec<_>>();
+        let produced_tokens = produced.split_whitespace().collect::<Vec<_>>();
+        let matched = expected_tokens.len() == produced_tokens.len()
+            && expected_tokens.iter().zip(&produced_tokens).all(|(a, b)| {
+                match (a.parse::<f64>(), b.parse::<f64>()) {
+                    (Ok(a), Ok(b)) => (a - b).abs() <= TOLERANCE,
+                    _ => a == b,
+                }
+            });
+        if !matched {
+            return Some(format!(
+                "line {}: expected {:?}, found {:?}",
+                index + 1,
+                expected,
+                produced
+            ));
+        }
+    }
+    None
+}
+
+#[test]
+fn golden_workflow() {
+    let fixture = fixture();
+    let directory = tempfile::tempdir().unwrap();
+    copy_fixture(&fixture, directory.path());
+    let status = Command::new(env!("CARGO_BIN_EXE_lmers"))
+        .arg("-i")
+        .arg(directory.path().join("workflow.yaml"))
+        .status()
+        .unwrap();
+    assert!(status.success(), "workflow failed with {}", status);
+    let bless = std::env::var_os("LME_BLESS").is_some();
+    let expected_directory = fixture.join("expected");
+    let mut failures = vec![];
+    for file in files(&expected_directory, &expected_directory) {
+        let produced = read_to_string(directory.path().join(&file))
+            .unwrap_or_else(|err| panic!("Unable to read produced {:?}: {}", file, err));
+        if bless {
+            write(expected_directory.join(&file), &produced).unwrap();
+            continue;
+        }
+        let expected = read_to_string(expected_directory.join(&file)).unwrap();
+        if let Some(difference) = compare(&expected, &produced) {
+            failures.push(format!("{:?}: {}", file, difference));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
@@ -0,0 +1,3 @@
+#!/bin/sh
+# Fake optimization for the golden tests: scale the coordinates by 0.9
+awk 'NR <= 2 { print; next } { printf "%s %.6f %.6f %.6f\n", $1, $2 * 0.9, $3 * 0.9, $4 * 0.9 }' input.xyz > output.xyz